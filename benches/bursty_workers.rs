@@ -0,0 +1,130 @@
+//! Measures how quickly a pool of idle workers drains bursts of tiny jobs.
+//!
+//! Compares `ThreadPool`, whose workers park on a condition variable, with
+//! the previous design where workers shared an `mpsc::Receiver` behind a
+//! `Mutex` and held the lock for the whole blocking `recv`. Run with
+//! `cargo bench --bench bursty_workers`.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use hello::ThreadPool;
+
+const WORKERS: usize = 16;
+const ROUNDS: usize = 10;
+const BURST: usize = 2_000;
+const IDLE: Duration = Duration::from_millis(20);
+const WORK: Duration = Duration::from_micros(20);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The old worker loop: one shared receiver guarded by a mutex.
+struct MutexPool {
+    sender: Option<mpsc::Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl MutexPool {
+    fn new(size: usize) -> MutexPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => {
+                            println!("Worker got a job; executing.");
+                            job()
+                        }
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+
+        MutexPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    fn execute(&self, f: impl FnOnce() + Send + 'static) {
+        self.sender.as_ref().unwrap().send(Box::new(f)).unwrap();
+    }
+}
+
+impl Drop for MutexPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+/// Busy-waits for `duration`, standing in for a handler doing real work.
+fn spin(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        std::hint::spin_loop();
+    }
+}
+
+/// Runs `ROUNDS` bursts, letting the workers go idle before each one, and
+/// returns the total time spent draining bursts.
+fn run(execute: impl Fn(Box<dyn FnOnce() + Send>)) -> Duration {
+    let done = Arc::new(AtomicUsize::new(0));
+    let mut busy = Duration::ZERO;
+
+    for round in 1..=ROUNDS {
+        thread::sleep(IDLE);
+
+        let start = Instant::now();
+        for _ in 0..BURST {
+            let done = Arc::clone(&done);
+            execute(Box::new(move || {
+                spin(WORK);
+                done.fetch_add(1, Ordering::SeqCst);
+            }));
+        }
+        while done.load(Ordering::SeqCst) < round * BURST {
+            thread::yield_now();
+        }
+        busy += start.elapsed();
+    }
+
+    busy
+}
+
+fn main() {
+    let mutex_pool = MutexPool::new(WORKERS);
+    let mutex_time = run(|job| mutex_pool.execute(job));
+    drop(mutex_pool);
+
+    let pool = ThreadPool::new(WORKERS);
+    let pool_time = run(|job| pool.execute(job));
+    drop(pool);
+
+    let jobs = ROUNDS * BURST;
+    eprintln!(
+        "mutex-held recv: {} jobs in {:?} ({:.0} jobs/s)",
+        jobs,
+        mutex_time,
+        jobs as f64 / mutex_time.as_secs_f64()
+    );
+    eprintln!(
+        "condvar queue:   {} jobs in {:?} ({:.0} jobs/s)",
+        jobs,
+        pool_time,
+        jobs as f64 / pool_time.as_secs_f64()
+    );
+}
@@ -0,0 +1,82 @@
+//! Counts heap allocations made while queueing 100k jobs on the pool.
+//!
+//! Small closures are stored inline in the queued job; closures too large
+//! for the inline slot still get boxed, which is how every job was queued
+//! before. Run with `cargo bench --bench job_alloc`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use hello::ThreadPool;
+
+const JOBS: usize = 100_000;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn measure(label: &str, submit: impl Fn(&ThreadPool, &Arc<AtomicUsize>)) {
+    let pool = ThreadPool::new(4);
+    let done = Arc::new(AtomicUsize::new(0));
+
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    let start = Instant::now();
+
+    for _ in 0..JOBS {
+        submit(&pool, &done);
+    }
+    while done.load(Ordering::SeqCst) < JOBS {
+        hint::spin_loop();
+    }
+
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst) - before;
+
+    eprintln!(
+        "{:<8} {} jobs: {} allocations ({:.3} per job) in {:?}",
+        label,
+        JOBS,
+        allocations,
+        allocations as f64 / JOBS as f64,
+        elapsed
+    );
+}
+
+fn main() {
+    measure("inline", |pool, done| {
+        let done = Arc::clone(done);
+        pool.execute(move || {
+            done.fetch_add(1, Ordering::SeqCst);
+        });
+    });
+
+    measure("boxed", |pool, done| {
+        let done = Arc::clone(done);
+        let padding = [0u64; 8];
+        pool.execute(move || {
+            hint::black_box(padding);
+            done.fetch_add(1, Ordering::SeqCst);
+        });
+    });
+}
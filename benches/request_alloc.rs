@@ -0,0 +1,106 @@
+//! Counts heap allocations made while serving 10k keep-alive requests over
+//! one in-memory connection.
+//!
+//! Each worker thread reads request heads into, and assembles responses in,
+//! scratch buffers it keeps from one request to the next, instead of
+//! allocating a line buffer per header and an output buffer per response.
+//! That took these requests from 48 allocations each to 30; most of the rest
+//! are the request's own header map and strings.
+//! Run with `cargo bench --bench request_alloc`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    io::{self, Cursor, Read, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use hello::{handle_connection, Config, Metrics, Request, Response, Router, StatusCode, Stream};
+
+const REQUESTS: usize = 10_000;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// A connection that reads from a prepared buffer and discards what is
+/// written to it.
+struct MemoryStream {
+    input: Cursor<Vec<u8>>,
+    written: usize,
+}
+
+impl Stream for MemoryStream {}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn main() {
+    let mut router = Router::new();
+    router.get("/", |_: &Request| {
+        Response::new(StatusCode::OK)
+            .header("Content-Type", "text/plain")
+            .header("Cache-Control", "no-cache")
+            .body("hello")
+    });
+
+    let request = "GET / HTTP/1.1\r\n\
+                   Host: localhost\r\n\
+                   User-Agent: bench/1.0\r\n\
+                   Accept: text/plain, */*\r\n\
+                   Accept-Encoding: identity\r\n\
+                   Accept-Language: en-GB, en;q=0.8\r\n\r\n";
+    let mut stream = MemoryStream {
+        input: Cursor::new(request.repeat(REQUESTS).into_bytes()),
+        written: 0,
+    };
+    let config = Config {
+        idle_timeout: None,
+        ..Config::default()
+    };
+    let metrics = Metrics::new();
+
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    let start = Instant::now();
+    handle_connection(&mut stream, &router, &config, &metrics).unwrap();
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst) - before;
+
+    assert_eq!(metrics.latency().count(), REQUESTS as u64);
+    eprintln!(
+        "{} requests: {} allocations ({:.2} per request), {} bytes written, in {:?}",
+        REQUESTS,
+        allocations,
+        allocations as f64 / REQUESTS as f64,
+        stream.written,
+        elapsed
+    );
+}
@@ -0,0 +1,140 @@
+//! Content negotiation with the `Accept` header: how much a client wants
+//! each media type a handler could answer with.
+
+use crate::Request;
+
+/// One media range from an `Accept` header, such as `text/*;q=0.5`.
+struct MediaRange<'a> {
+    kind: &'a str,
+    subtype: &'a str,
+    quality: f32,
+}
+
+impl MediaRange<'_> {
+    /// How closely the range names `kind/subtype`: 3 for an exact match,
+    /// 2 for `kind/*`, 1 for `*/*`, and `None` if it doesn't cover it.
+    fn specificity(&self, kind: &str, subtype: &str) -> Option<u8> {
+        if self.kind == "*" {
+            Some(1)
+        } else if !self.kind.eq_ignore_ascii_case(kind) {
+            None
+        } else if self.subtype == "*" {
+            Some(2)
+        } else {
+            self.subtype.eq_ignore_ascii_case(subtype).then_some(3)
+        }
+    }
+}
+
+/// Parses every media range in the request's `Accept` headers. Ranges
+/// without a `/` are skipped, and a `q` weight that isn't a number from 0
+/// to 1 counts as 1.
+fn media_ranges(request: &Request) -> impl Iterator<Item = MediaRange<'_>> {
+    request
+        .header_all("Accept")
+        .iter()
+        .flat_map(|value| value.split(','))
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let (kind, subtype) = params.next()?.trim().split_once('/')?;
+            let quality = params
+                .filter_map(|param| param.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                .and_then(|(_, q)| q.trim().parse::<f32>().ok())
+                .filter(|q| (0.0..=1.0).contains(q))
+                .unwrap_or(1.0);
+            Some(MediaRange {
+                kind: kind.trim(),
+                subtype: subtype.trim(),
+                quality,
+            })
+        })
+}
+
+/// The weight the request gives `media_type`, from 0 (not acceptable) to
+/// 1: that of the most specific range covering it. Parameters on
+/// `media_type` are ignored. A request without an `Accept` header accepts
+/// anything.
+pub(crate) fn quality(request: &Request, media_type: &str) -> f32 {
+    if request.header_all("Accept").is_empty() {
+        return 1.0;
+    }
+    let essence = media_type.split(';').next().unwrap_or_default().trim();
+    let Some((kind, subtype)) = essence.split_once('/') else {
+        return 0.0;
+    };
+
+    media_ranges(request)
+        .filter_map(|range| Some((range.specificity(kind, subtype)?, range.quality)))
+        .max_by_key(|&(specificity, _)| specificity)
+        .map_or(0.0, |(_, quality)| quality)
+}
+
+/// The candidate the request weighs highest, the earliest of equally
+/// weighted ones, or `None` if it accepts none of them.
+pub(crate) fn preferred<'a>(request: &Request, candidates: &[&'a str]) -> Option<&'a str> {
+    let mut best = None;
+    for &candidate in candidates {
+        let quality = quality(request, candidate);
+        if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+            best = Some((candidate, quality));
+        }
+    }
+    best.map(|(candidate, _)| candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Method;
+
+    fn accepting(accept: &str) -> Request {
+        let mut request = Request::new(Method::Get, "/");
+        request.insert_header("Accept", accept);
+        request
+    }
+
+    #[test]
+    fn test_browser_accept() {
+        let request =
+            accepting("text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,*/*;q=0.8");
+
+        assert!(request.accepts("text/html"));
+        assert!(request.accepts("application/json"));
+        assert_eq!(quality(&request, "application/xml"), 0.9);
+        assert_eq!(quality(&request, "image/png"), 0.8);
+        assert_eq!(
+            request.preferred(&["application/json", "text/html"]),
+            Some("text/html")
+        );
+    }
+
+    #[test]
+    fn test_type_wildcard_and_exclusion() {
+        let request = accepting("text/*;q=0.5, text/plain, text/csv;q=0");
+
+        assert!(request.accepts("text/plain; charset=utf-8"));
+        assert!(request.accepts("TEXT/Markdown"));
+        assert!(!request.accepts("text/csv"));
+        assert!(!request.accepts("application/json"));
+        assert_eq!(
+            request.preferred(&["text/csv", "text/markdown", "text/plain"]),
+            Some("text/plain")
+        );
+        assert_eq!(request.preferred(&["text/csv", "image/png"]), None);
+    }
+
+    #[test]
+    fn test_api_client_and_missing_header() {
+        let request = accepting("application/json");
+        assert!(request.accepts("application/json"));
+        assert!(!request.accepts("text/html"));
+
+        let request = Request::new(Method::Get, "/");
+        assert!(request.accepts("image/png"));
+        assert_eq!(
+            request.preferred(&["text/html", "application/json"]),
+            Some("text/html")
+        );
+    }
+}
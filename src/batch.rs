@@ -0,0 +1,135 @@
+use std::{
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+};
+
+use crate::{job, ThreadPool};
+
+/// A job from [`ThreadPool::execute_all`] that panicked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PanicInfo {
+    /// Position of the job in the batch, counting from zero.
+    pub index: usize,
+    /// The panic message, when it was a string.
+    pub message: String,
+}
+
+struct Batch {
+    pending: Mutex<usize>,
+    finished: Condvar,
+    panics: Mutex<Vec<PanicInfo>>,
+}
+
+/// Counts a batch job as finished when dropped, whether it ran or was
+/// dropped unrun by a pool shutting down, so the batch never waits forever.
+struct Finished(Arc<Batch>);
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        let mut pending = self.0.pending.lock().unwrap();
+        *pending -= 1;
+        if *pending == 0 {
+            self.0.finished.notify_all();
+        }
+    }
+}
+
+impl ThreadPool {
+    /// Runs every job in `jobs` on the pool and returns once all of them
+    /// have finished, a parallel `for` over the batch. Jobs that panic don't
+    /// stop the rest; their panics are returned, in batch order, once the
+    /// batch is done.
+    ///
+    /// As with [`scope`](ThreadPool::scope), calling this from one of the
+    /// pool's own jobs can deadlock if every worker ends up waiting.
+    pub fn execute_all<I, F>(&self, jobs: I) -> Result<(), Vec<PanicInfo>>
+    where
+        I: IntoIterator<Item = F>,
+        F: FnOnce() + Send + 'static,
+    {
+        let batch = Arc::new(Batch {
+            pending: Mutex::new(0),
+            finished: Condvar::new(),
+            panics: Mutex::new(Vec::new()),
+        });
+
+        for (index, f) in jobs.into_iter().enumerate() {
+            *batch.pending.lock().unwrap() += 1;
+            let finished = Finished(Arc::clone(&batch));
+            self.execute(move || {
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+                    finished.0.panics.lock().unwrap().push(PanicInfo {
+                        index,
+                        message: job::panic_message(&*payload),
+                    });
+                }
+            });
+        }
+
+        let mut pending = batch.pending.lock().unwrap();
+        while *pending > 0 {
+            pending = batch.finished.wait(pending).unwrap();
+        }
+        drop(pending);
+
+        let mut panics = mem::take(&mut *batch.panics.lock().unwrap());
+        if panics.is_empty() {
+            Ok(())
+        } else {
+            panics.sort_by_key(|panic| panic.index);
+            Err(panics)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_execute_all_waits_for_batch() {
+        let pool = ThreadPool::new(4);
+        let total = Arc::new(AtomicUsize::new(0));
+
+        let result = pool.execute_all((0..100).map(|_| {
+            let total = Arc::clone(&total);
+            move || {
+                std::thread::sleep(std::time::Duration::from_micros(100));
+                total.fetch_add(1, Ordering::SeqCst);
+            }
+        }));
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(total.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn test_execute_all_collects_panics() {
+        let pool = ThreadPool::new(2);
+
+        let result = pool.execute_all((0..6).map(|i| {
+            move || {
+                if i % 3 == 1 {
+                    panic!("job {} failed", i);
+                }
+            }
+        }));
+
+        let panics = result.unwrap_err();
+        assert_eq!(
+            panics,
+            [
+                PanicInfo {
+                    index: 1,
+                    message: "job 1 failed".to_string(),
+                },
+                PanicInfo {
+                    index: 4,
+                    message: "job 4 failed".to_string(),
+                },
+            ]
+        );
+    }
+}
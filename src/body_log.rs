@@ -0,0 +1,206 @@
+//! Debug logging of whole requests, bodies included, with sensitive values
+//! masked. Only used when `Config::log_bodies` is set.
+
+use crate::{Config, Request};
+
+/// Written in place of every redacted value.
+const MASK: &str = "***";
+
+/// Renders `request` for the debug log: the request line, every header and
+/// up to `config.log_body_limit` bytes of the body.
+///
+/// Headers named in `config.log_redact` have their values masked, as do
+/// fields of that name in `application/x-www-form-urlencoded` and
+/// `application/json` bodies. Names are matched ignoring case. Bodies of any
+/// other type are logged as they are, so enable this with care.
+pub(crate) fn format(request: &Request, config: &Config) -> String {
+    let redacted = |name: &str| is_redacted(name, config);
+
+    let mut log = format!("{} {}", request.method.as_str(), request.path);
+    if let Some(query) = &request.query {
+        log.push('?');
+        log.push_str(query);
+    }
+    log.push('\n');
+
+    let mut names: Vec<_> = request.headers.keys().collect();
+    names.sort();
+    for name in names {
+        for value in request.header_all(name) {
+            let value = if redacted(name) { MASK } else { value };
+            log.push_str(&format!("{}: {}\n", name, value));
+        }
+    }
+
+    let end = request.body.len().min(config.log_body_limit);
+    let body = String::from_utf8_lossy(&request.body[..end]);
+    let content_type = request.content_type();
+    let body = match content_type
+        .as_ref()
+        .map(|content_type| content_type.mime.as_str())
+    {
+        Some("application/x-www-form-urlencoded") => redact_form(&body, redacted),
+        Some("application/json") => redact_json(&body, redacted),
+        _ => body.into_owned(),
+    };
+
+    log.push('\n');
+    log.push_str(&body);
+    if end < request.body.len() {
+        log.push_str(&format!("\n[{} more bytes]", request.body.len() - end));
+    }
+    log
+}
+
+/// `query` with the values of parameters named in `config.log_redact`
+/// masked, as for form bodies.
+pub(crate) fn redact_query(query: &str, config: &Config) -> String {
+    redact_form(query, |name| is_redacted(name, config))
+}
+
+fn is_redacted(name: &str, config: &Config) -> bool {
+    config
+        .log_redact
+        .iter()
+        .any(|redact| redact.eq_ignore_ascii_case(name))
+}
+
+fn redact_form(body: &str, redacted: impl Fn(&str) -> bool) -> String {
+    body.split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if redacted(name) => format!("{}={}", name, MASK),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Masks the value of every object member whose name is redacted, at any
+/// depth. Works on the text directly so that a body cut short by the log
+/// limit is still redacted up to where it ends.
+fn redact_json(body: &str, redacted: impl Fn(&str) -> bool) -> String {
+    let bytes = body.as_bytes();
+    let mut out = String::with_capacity(body.len());
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        if bytes[pos] != b'"' {
+            let next = body[pos..].find('"').map_or(bytes.len(), |i| pos + i);
+            out.push_str(&body[pos..next]);
+            pos = next;
+            continue;
+        }
+
+        let end = string_end(bytes, pos);
+        out.push_str(&body[pos..end]);
+        // An unclosed string runs to the end of the body, which may be in
+        // the middle of it, so only a closing quote is left out of the name.
+        let name_end = if end - 1 > pos && bytes[end - 1] == b'"' {
+            end - 1
+        } else {
+            end
+        };
+        let name = &body[pos + 1..name_end];
+        pos = end;
+
+        // Only a string followed by `:` is a member name.
+        let after = pos + body[pos..].len() - body[pos..].trim_start().len();
+        if !body[after..].starts_with(':') || !redacted(name) {
+            continue;
+        }
+        let value = after + 1 + body[after + 1..].len() - body[after + 1..].trim_start().len();
+        out.push_str(&body[pos..value]);
+
+        let value_end = if bytes.get(value) == Some(&b'"') {
+            string_end(bytes, value)
+        } else {
+            body[value..]
+                .find([',', '}', ']'])
+                .map_or(bytes.len(), |i| value + i)
+        };
+        out.push_str(&format!("\"{}\"", MASK));
+        pos = value_end;
+    }
+    out
+}
+
+/// The index just past the string literal starting at `start`, or the end
+/// of `bytes` if it isn't closed.
+fn string_end(bytes: &[u8], start: usize) -> usize {
+    let mut pos = start + 1;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'\\' => pos += 2,
+            b'"' => return pos + 1,
+            _ => pos += 1,
+        }
+    }
+    bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Method;
+
+    fn post(content_type: &str, body: &str) -> Request {
+        let mut request = Request::new(Method::Post, "/login");
+        request.insert_header("Content-Type", content_type);
+        request.insert_header("Authorization", "Bearer secret-token");
+        request.body = body.as_bytes().to_vec();
+        request
+    }
+
+    #[test]
+    fn test_masks_password_field() {
+        let config = Config::default();
+
+        let log = format(
+            &post(
+                "application/x-www-form-urlencoded",
+                "user=ann&password=hunter2",
+            ),
+            &config,
+        );
+        assert!(log.starts_with("POST /login\n"));
+        assert!(log.contains("authorization: ***\n"));
+        assert!(log.ends_with("\n\nuser=ann&password=***"));
+        assert!(!log.contains("hunter2") && !log.contains("secret-token"));
+
+        let log = format(
+            &post(
+                "application/json",
+                r#"{"user": "ann", "Password": "hun\"ter2", "pin": 1234}"#,
+            ),
+            &Config {
+                log_redact: vec!["password".to_string(), "pin".to_string()],
+                ..config
+            },
+        );
+        assert!(log.ends_with(r#"{"user": "ann", "Password": "***", "pin": "***"}"#));
+    }
+
+    #[test]
+    fn test_unclosed_non_ascii_name() {
+        let redact = |name: &str| name == "\u{e9}";
+
+        assert_eq!(redact_json("{\"\u{e9}", redact), "{\"\u{e9}");
+        assert_eq!(redact_json("{\"", redact), "{\"");
+        assert_eq!(
+            redact_json("{\"\u{e9}\": 1, \"n\u{e9}", redact),
+            "{\"\u{e9}\": \"***\", \"n\u{e9}"
+        );
+    }
+
+    #[test]
+    fn test_truncates_body() {
+        let config = Config {
+            log_body_limit: 4,
+            ..Config::default()
+        };
+
+        let log = format(&post("text/plain", "abcdefgh"), &config);
+
+        assert!(log.ends_with("\n\nabcd\n[4 more bytes]"));
+    }
+}
@@ -0,0 +1,280 @@
+//! Request bodies that the handler reads from the connection itself, for
+//! routes marked with [`Route::stream_body`](crate::Route::stream_body).
+//!
+//! The handler runs on the connection's thread as usual, so the connection
+//! is read on its behalf by a scoped thread, which serves each read of the
+//! handler's [`BodyReader`] and, once the handler is done with it, drains
+//! whatever of the body is left so the next request can be read.
+
+use std::{
+    io::{self, prelude::*, ErrorKind},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+use crate::{
+    request::{chunk_size, read_chunk_line, read_trailers},
+    HttpError, Request, Response,
+};
+
+/// Most bytes handed over for a single read of a [`BodyReader`].
+const MAX_READ: usize = 16 * 1024;
+
+/// Where a streamed body ends and how much of it the route accepts.
+#[derive(Debug)]
+pub(crate) struct Framing {
+    state: State,
+    /// Bytes of chunked body still accepted before it is refused.
+    allowed: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// This many bytes are left, from `Content-Length`.
+    Length(usize),
+    /// Chunked, with this many bytes left of the current chunk, or at the
+    /// next chunk-size line when none are.
+    Chunked(usize),
+    /// The whole body, and any trailers, have been read.
+    Done,
+}
+
+impl Framing {
+    /// The framing `request` declares for its body, refusing it as
+    /// [`Request::read_body`] would: with `413 Payload Too Large` if its
+    /// `Content-Length` is over `max_body`, or if its headers are invalid.
+    /// Bodies with a coding before `chunked` can only be decoded once
+    /// they're all in, so they aren't streamed and are refused with
+    /// `501 Not Implemented`.
+    pub(crate) fn new(request: &Request, max_body: usize) -> Result<Framing, HttpError> {
+        let codings = request.body_codings()?;
+        let state = match codings.as_slice() {
+            [] => match request.content_length()? {
+                length if length > max_body => return Err(HttpError::PayloadTooLarge),
+                0 => State::Done,
+                length => State::Length(length),
+            },
+            [_] => State::Chunked(0),
+            [coding, ..] => {
+                return Err(HttpError::NotImplemented(format!(
+                    "streamed body with transfer coding {}",
+                    coding
+                )))
+            }
+        };
+        Ok(Framing {
+            state,
+            allowed: max_body,
+        })
+    }
+
+    /// Reads body bytes from `reader` into `buf`, returning 0 once the
+    /// body has ended.
+    fn read<R: BufRead>(&mut self, reader: &mut R, buf: &mut [u8]) -> Result<usize, HttpError> {
+        loop {
+            match self.state {
+                State::Done => return Ok(0),
+                State::Length(left) => {
+                    let len = read_some(reader, buf, left)?;
+                    self.state = match left - len {
+                        0 => State::Done,
+                        left => State::Length(left),
+                    };
+                    return Ok(len);
+                }
+                State::Chunked(0) => {
+                    let mut line = Vec::new();
+                    read_chunk_line(reader, &mut line)?;
+                    let size = chunk_size(&line)?;
+                    if size == 0 {
+                        read_trailers(reader, &mut line)?;
+                        self.state = State::Done;
+                    } else if size > self.allowed {
+                        return Err(HttpError::PayloadTooLarge);
+                    } else {
+                        self.allowed -= size;
+                        self.state = State::Chunked(size);
+                    }
+                }
+                State::Chunked(left) => {
+                    let len = read_some(reader, buf, left)?;
+                    if len == left {
+                        let mut line = Vec::new();
+                        read_chunk_line(reader, &mut line)?;
+                        if !line.is_empty() {
+                            return Err(HttpError::BadRequest(
+                                "chunk longer than its size".to_string(),
+                            ));
+                        }
+                    }
+                    self.state = State::Chunked(left - len);
+                    return Ok(len);
+                }
+            }
+        }
+    }
+
+    /// Reads and discards the rest of the body.
+    fn drain<R: BufRead>(&mut self, reader: &mut R) -> Result<(), HttpError> {
+        let mut buf = [0; 4096];
+        while self.read(reader, &mut buf)? > 0 {}
+        Ok(())
+    }
+}
+
+/// Reads up to `left` bytes into `buf`, and at least one if `buf` isn't
+/// empty, as the body isn't over.
+fn read_some<R: Read>(reader: &mut R, buf: &mut [u8], left: usize) -> Result<usize, HttpError> {
+    let len = left.min(buf.len());
+    let buf = &mut buf[..len];
+    match reader.read(buf)? {
+        0 if !buf.is_empty() => Err(HttpError::Io(ErrorKind::UnexpectedEof.into())),
+        len => Ok(len),
+    }
+}
+
+/// What a [`BodyReader`] asks of the thread reading the connection.
+#[derive(Debug)]
+enum Pull {
+    /// Read up to this many bytes of the body.
+    Read(usize),
+    /// Nothing more will be read, so drain the rest.
+    Done,
+}
+
+/// A request body read from the connection as it is asked for, from
+/// [`Request::body_reader`].
+///
+/// Reads end at the end of the body, as framed by `Content-Length` or
+/// chunked encoding, and fail once a chunked body goes over the route's
+/// `max_body`. Whatever is left unread when the reader is dropped is read
+/// and discarded, so the connection can be kept alive. After the handler
+/// has returned, reads fail.
+#[derive(Debug)]
+pub struct BodyReader {
+    pulls: Sender<Pull>,
+    chunks: Receiver<io::Result<Vec<u8>>>,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let gone = || io::Error::new(ErrorKind::BrokenPipe, "request body is no longer readable");
+        self.pulls
+            .send(Pull::Read(buf.len().min(MAX_READ)))
+            .map_err(|_| gone())?;
+        let chunk = self.chunks.recv().map_err(|_| gone())??;
+        buf[..chunk.len()].copy_from_slice(&chunk);
+        Ok(chunk.len())
+    }
+}
+
+impl Drop for BodyReader {
+    fn drop(&mut self) {
+        let _ = self.pulls.send(Pull::Done);
+    }
+}
+
+/// Answers `request` with `respond`, which can read the body framed by
+/// `framing` from `reader` through [`Request::body_reader`]. Once the
+/// reader is dropped or `respond` returns, the rest of the body is drained.
+/// Returns the response along with how reading the body went, since a body
+/// that failed leaves the connection at an unknown point.
+pub(crate) fn respond<R, F>(
+    reader: &mut R,
+    mut framing: Framing,
+    request: Request,
+    respond: F,
+) -> (Response, Result<(), HttpError>)
+where
+    R: BufRead + Send,
+    F: FnOnce(Request) -> Response,
+{
+    let (pulls, pulled) = mpsc::channel();
+    let (chunks, pumped) = mpsc::channel();
+    *request.body_stream.lock().unwrap() = Some(BodyReader {
+        pulls: pulls.clone(),
+        chunks: pumped,
+    });
+
+    thread::scope(|scope| {
+        let pump = scope.spawn(move || {
+            while let Ok(Pull::Read(len)) = pulled.recv() {
+                let mut chunk = vec![0; len];
+                match framing.read(reader, &mut chunk) {
+                    Ok(len) => {
+                        chunk.truncate(len);
+                        let _ = chunks.send(Ok(chunk));
+                    }
+                    Err(e) => {
+                        let _ = chunks.send(Err(match &e {
+                            HttpError::Io(io) => io.kind().into(),
+                            e => io::Error::new(ErrorKind::InvalidData, e.to_string()),
+                        }));
+                        return Err(e);
+                    }
+                }
+            }
+            framing.drain(reader)
+        });
+        let response = respond(request);
+        let _ = pulls.send(Pull::Done);
+        let drained = pump
+            .join()
+            .unwrap_or_else(|_| Err(HttpError::Io(ErrorKind::Other.into())));
+        (response, drained)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Method, StatusCode};
+
+    fn chunked_request() -> Request {
+        let mut request = Request::new(Method::Post, "/upload");
+        request.insert_header("Transfer-Encoding", "chunked");
+        request
+    }
+
+    #[test]
+    fn test_chunked_body_read_in_small_reads() {
+        let mut input = &b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nTrailer: x\r\n\r\nNEXT"[..];
+        let mut framing = Framing::new(&chunked_request(), 64).unwrap();
+        let mut body = Vec::new();
+        let mut buf = [0; 3];
+        loop {
+            let len = framing.read(&mut input, &mut buf).unwrap();
+            if len == 0 {
+                break;
+            }
+            body.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(body, b"hello world");
+        assert_eq!(input, b"NEXT");
+    }
+
+    #[test]
+    fn test_limits_and_drain() {
+        let mut input = &b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"[..];
+        let mut framing = Framing::new(&chunked_request(), 8).unwrap();
+        assert!(matches!(
+            framing.drain(&mut input),
+            Err(HttpError::PayloadTooLarge)
+        ));
+
+        let mut request = Request::new(Method::Post, "/upload");
+        request.insert_header("Content-Length", "9");
+        let err = Framing::new(&request, 8).unwrap_err();
+        assert_eq!(err.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let mut input = &b"123456789NEXT"[..];
+        let mut framing = Framing::new(&request, 9).unwrap();
+        framing.drain(&mut input).unwrap();
+        assert_eq!(input, b"NEXT");
+
+        request.insert_header("Transfer-Encoding", "gzip, chunked");
+        request.headers.remove("content-length");
+        let err = Framing::new(&request, 9).unwrap_err();
+        assert_eq!(err.status(), StatusCode::NOT_IMPLEMENTED);
+    }
+}
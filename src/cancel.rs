@@ -0,0 +1,32 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// A cheap, cloneable flag that long-running jobs can poll to find out
+/// that they should stop early.
+///
+/// Jobs queued with [`ThreadPool::execute_cancellable`] receive the pool's
+/// token, which is cancelled when the pool shuts down. Cancellation is
+/// cooperative: nothing happens to a job that never checks its token.
+///
+/// [`ThreadPool::execute_cancellable`]: crate::ThreadPool::execute_cancellable
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Marks this token, and every clone of it, as cancelled.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,197 @@
+//! A record of the last few requests served and how they were answered,
+//! kept in memory for troubleshooting intermittent problems without a
+//! packet capture. Off unless `Config::capture_requests` is set.
+//!
+//! Captured requests can still hold personal data, such as paths and query
+//! values not listed in `Config::log_redact`, so a page showing them must
+//! not be reachable from outside.
+
+use std::{
+    collections::VecDeque,
+    fmt::{self, Write as _},
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::{body_log, Config, Request, StatusCode};
+
+/// Request headers kept with each exchange. Others are left out, since
+/// they may hold credentials.
+const HEADERS: [&str; 3] = ["Host", "User-Agent", "Content-Type"];
+
+/// The last requests served, up to a fixed number, read through
+/// [`Metrics::capture`](crate::Metrics::capture). Once full, each new
+/// exchange pushes out the oldest.
+#[derive(Default)]
+pub struct RequestCapture {
+    capacity: usize,
+    exchanges: Mutex<VecDeque<Exchange>>,
+}
+
+/// One request captured by [`RequestCapture`] and the response it got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exchange {
+    pub method: String,
+    /// The path, with the query if there was one. Values of query
+    /// parameters named in `Config::log_redact` are masked.
+    pub target: String,
+    /// The `Host`, `User-Agent` and `Content-Type` headers, those the
+    /// request had.
+    pub headers: Vec<(String, String)>,
+    pub request_bytes: usize,
+    pub status: StatusCode,
+    /// Length of the response body, or `None` for a streamed body whose
+    /// length isn't known up front.
+    pub response_bytes: Option<u64>,
+    /// Time from the request's first byte to its last response byte.
+    pub duration: Duration,
+}
+
+impl RequestCapture {
+    /// Keeps the last `capacity` exchanges; `0` keeps none.
+    pub fn new(capacity: usize) -> RequestCapture {
+        RequestCapture {
+            capacity,
+            exchanges: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// The exchanges kept, the most recent first.
+    pub fn recent(&self) -> Vec<Exchange> {
+        let exchanges = self.exchanges.lock().unwrap();
+        exchanges.iter().rev().cloned().collect()
+    }
+
+    /// Renders the exchanges kept as text, one line each, the most recent
+    /// first.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for exchange in self.recent() {
+            writeln!(out, "{}", exchange).unwrap();
+        }
+        out
+    }
+
+    /// Starts capturing `request`, or returns `None` when capture is off.
+    /// The exchange is kept once [`record`](RequestCapture::record) is
+    /// given it with the response filled in.
+    pub(crate) fn begin(&self, request: &Request, config: &Config) -> Option<Exchange> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let mut target = request.path.clone();
+        if let Some(query) = &request.query {
+            target.push('?');
+            target.push_str(&body_log::redact_query(query, config));
+        }
+        let headers = HEADERS
+            .iter()
+            .filter_map(|&name| Some((name.to_string(), request.header(name)?.to_string())))
+            .collect();
+
+        Some(Exchange {
+            method: request.method.as_str().to_string(),
+            target,
+            headers,
+            request_bytes: request.body.len(),
+            status: StatusCode::OK,
+            response_bytes: None,
+            duration: Duration::ZERO,
+        })
+    }
+
+    pub(crate) fn record(&self, exchange: Exchange) {
+        let mut exchanges = self.exchanges.lock().unwrap();
+        if exchanges.len() == self.capacity {
+            exchanges.pop_front();
+        }
+        exchanges.push_back(exchange);
+    }
+}
+
+impl fmt::Display for Exchange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} -> {} in {:?}, {} bytes in, ",
+            self.method, self.target, self.status, self.duration, self.request_bytes
+        )?;
+        match self.response_bytes {
+            Some(len) => write!(f, "{} bytes out", len)?,
+            None => write!(f, "streamed out")?,
+        }
+        for (name, value) in &self.headers {
+            write!(f, "; {}: {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Method;
+
+    fn capture_get(capture: &RequestCapture, target: &str) {
+        let mut request = Request::new(Method::Get, target);
+        request.insert_header("Host", "localhost");
+        request.insert_header("Authorization", "Bearer secret");
+        let mut exchange = capture.begin(&request, &Config::default()).unwrap();
+        exchange.status = StatusCode::NOT_FOUND;
+        exchange.response_bytes = Some(14);
+        capture.record(exchange);
+    }
+
+    #[test]
+    fn test_keeps_most_recent_exchanges() {
+        let capture = RequestCapture::new(2);
+        for target in ["/a", "/b", "/c?page=2"] {
+            capture_get(&capture, target);
+        }
+
+        let targets: Vec<_> = capture
+            .recent()
+            .into_iter()
+            .map(|exchange| exchange.target)
+            .collect();
+        assert_eq!(targets, ["/c?page=2", "/b"]);
+
+        let render = capture.render();
+        assert!(
+            render.starts_with(
+                "GET /c?page=2 -> 404 Not Found in 0ns, 0 bytes in, 14 bytes out; \
+                 Host: localhost\n"
+            ),
+            "{}",
+            render
+        );
+        assert_eq!(render.lines().count(), 2);
+        assert!(!render.contains("secret"));
+    }
+
+    #[test]
+    fn test_redacts_query_values() {
+        let capture = RequestCapture::new(1);
+        capture_get(&capture, "/login?user=ann&Password=hunter2&next=/");
+
+        assert_eq!(
+            capture.recent()[0].target,
+            "/login?user=ann&Password=***&next=/"
+        );
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        let capture = RequestCapture::default();
+        assert!(!capture.is_enabled());
+        assert!(capture
+            .begin(&Request::new(Method::Get, "/"), &Config::default())
+            .is_none());
+        assert_eq!(capture.render(), "");
+    }
+}
@@ -0,0 +1,434 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::IpNet;
+
+/// The page served at `/` when there is no `hello.html` to serve instead.
+const WELCOME_PAGE: &str = "<!DOCTYPE html>\
+<html lang=\"en\"><head><meta charset=\"utf-8\" /><title>Welcome</title></head>\
+<body><h1>It works!</h1><p>Put a hello.html in the static root to replace this page.</p>\
+</body></html>";
+
+/// Server settings. Start from [`Config::default`] and override the fields
+/// that need changing, or read them from a file with [`Config::from_file`].
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Address the server listens on. Only read at startup.
+    pub bind_addr: String,
+    /// Most connections the kernel queues for the server before it accepts
+    /// them. Only read at startup, and only honoured on Linux.
+    pub listen_backlog: u32,
+    /// Whether to bind with `SO_REUSEPORT`, so that several server
+    /// processes can listen on the same port and the kernel balances
+    /// connections between them. Only read at startup, and only supported
+    /// on Linux.
+    pub reuse_port: bool,
+    /// Number of worker threads serving connections. Only read at startup.
+    pub pool_size: usize,
+    /// Number of worker threads serving requests for routes marked
+    /// [`blocking`](crate::Route::blocking). Only read at startup.
+    pub blocking_pool_size: usize,
+    /// Longest request target, path and query together, accepted, in bytes.
+    /// Longer ones are answered with `414 URI Too Long` once that much of
+    /// the request line has arrived, without reading the rest of it.
+    pub max_uri_length: usize,
+    /// Longest header line accepted, name, value and line ending together,
+    /// in bytes. Longer ones are answered with
+    /// `431 Request Header Fields Too Large` once that much of the line has
+    /// arrived.
+    pub max_header_line: usize,
+    /// Most header lines accepted in a request head. A request with more is
+    /// answered with `431 Request Header Fields Too Large` as soon as the
+    /// first one over arrives.
+    pub max_headers: usize,
+    /// Largest request body accepted, in bytes. Requests declaring a larger
+    /// body are answered with `413 Payload Too Large`. Routes can set a
+    /// limit of their own with [`Route::max_body`](crate::Route::max_body).
+    pub max_body: usize,
+    /// Whether the request line and headers must end in `\r\n`. Lines ended
+    /// by a bare `\n` are then answered with `400 Bad Request` instead of
+    /// being accepted, so that the server can't read a request's framing
+    /// differently from a proxy in front of it. Off by default, for clients
+    /// that send bare line feeds.
+    pub strict_crlf: bool,
+    /// Capacity of the buffer requests are read into, in bytes. A larger
+    /// buffer takes a big header block in fewer reads; a smaller one holds
+    /// less memory per idle connection. Raised to 512 if set lower.
+    pub input_buffer_size: usize,
+    /// Capacity of the buffer responses are assembled in before being
+    /// written to the connection, in bytes. Larger bodies are written in
+    /// segments of this size, or 512 bytes if that is larger.
+    pub output_buffer_size: usize,
+    /// Warns on stderr when a handler answers with a body held in memory
+    /// larger than this many bytes, which would be better streamed with
+    /// [`Response::chunked`](crate::Response::chunked) or
+    /// [`Response::from_reader`](crate::Response::from_reader). `None`
+    /// never warns.
+    pub large_body_warning: Option<usize>,
+    /// Whether to serve further requests on a connection after the first one
+    /// when the client asks for it.
+    pub keep_alive: bool,
+    /// Most requests served on one connection before it is closed. `None`
+    /// serves any number.
+    pub max_keep_alive_requests: Option<usize>,
+    /// Whether responses on a connection kept alive carry a
+    /// `Keep-Alive: timeout=N, max=M` header advertising `idle_timeout`, in
+    /// whole seconds, and how many more requests the connection will serve,
+    /// so that clients know when to stop reusing it.
+    pub advertise_keep_alive: bool,
+    /// How long a read may block while a request is being received. `None`
+    /// waits indefinitely.
+    pub read_timeout: Option<Duration>,
+    /// How long a write may block while a response is sent to a connection
+    /// that is being turned away without a request being read. `None` waits
+    /// indefinitely.
+    pub write_timeout: Option<Duration>,
+    /// How long a kept-alive connection may sit idle waiting for its next
+    /// request before it is closed. `None` waits indefinitely.
+    pub idle_timeout: Option<Duration>,
+    /// Total time one request may take, from its first byte arriving to the
+    /// last byte of its response being written. `None` leaves requests
+    /// bounded only by the per-read timeouts.
+    pub request_timeout: Option<Duration>,
+    /// How long a job may run on a pool worker before it is logged and
+    /// counted as timed out. Jobs past it keep running. `None` doesn't watch
+    /// for slow jobs. Only read at startup.
+    pub job_timeout: Option<Duration>,
+    /// Directory that pages and static files are served from.
+    pub static_root: PathBuf,
+    /// Page served with `404 Not Found`, relative to `static_root`.
+    pub not_found_page: PathBuf,
+    /// HTML served at `/` when `static_root` has no `hello.html`, so that
+    /// the server works without any files of its own. A built-in welcome
+    /// page by default.
+    pub welcome_page: String,
+    /// Whether `TRACE` requests reach the handler. Off by default, when they
+    /// are answered with `501 Not Implemented`.
+    pub allow_trace: bool,
+    /// Whether `CONNECT` requests reach the handler, for a server acting as
+    /// a proxy. Off by default, when they are answered with
+    /// `501 Not Implemented`.
+    pub allow_connect: bool,
+    /// Whether a `POST` may name the method it is routed as in an
+    /// `X-HTTP-Method-Override` header or a `_method` form field, for
+    /// clients that can only send `GET` and `POST`. Off by default, since it
+    /// lets a plain form submission reach `DELETE` and `PUT` routes.
+    pub method_override: bool,
+    /// Paths answered with `404 Not Found` without reaching their route.
+    pub disabled_routes: Vec<String>,
+    /// Reverse proxies whose `X-Forwarded-For` and `X-Real-IP` headers are
+    /// believed when working out a request's client address.
+    pub trusted_proxies: Vec<IpNet>,
+    /// Seconds that clients turned away with `503 Service Unavailable`
+    /// while the server drains for shutdown are told to wait, in
+    /// `Retry-After`, before trying again.
+    pub drain_retry_after: u64,
+    /// How long requests in flight when the server shuts down are given to
+    /// finish. Requests still running or waiting for a worker after it are
+    /// dropped, and their workers abandoned so that the process can exit.
+    /// `None` waits for every one of them.
+    pub shutdown_grace: Option<Duration>,
+    /// Most connections served or waiting for a worker at once. Connections
+    /// accepted beyond it are answered with `503 Service Unavailable` and
+    /// closed without reaching the pool. `None` accepts any number.
+    pub max_connections: Option<usize>,
+    /// Most connections left waiting for a free worker. Once this many are
+    /// queued, newly accepted ones are turned away like those over
+    /// `max_connections`. `None` queues any number.
+    pub max_queued: Option<usize>,
+    /// Connections waiting for a worker at which the server's
+    /// [`Readiness`](crate::Readiness) reports it not ready, so that a load
+    /// balancer can send traffic elsewhere before `max_queued` starts
+    /// turning it away. `None` never reports the server saturated.
+    pub ready_max_queued: Option<usize>,
+    /// Logs every request, body included, to stderr for debugging. Off by
+    /// default: bodies of types other than form and JSON are logged without
+    /// redaction, so secrets in them end up in the log.
+    pub log_bodies: bool,
+    /// Most body bytes logged per request when `log_bodies` is set.
+    pub log_body_limit: usize,
+    /// Header and form or JSON field names whose values are masked when
+    /// `log_bodies` is set, and query parameters whose values are masked in
+    /// captured requests, matched ignoring case.
+    pub log_redact: Vec<String>,
+    /// Warns on stderr when a request is refused for going over
+    /// `max_uri_length`, `max_header_line`, `max_headers` or `max_body`,
+    /// naming the client and the setting. Each setting is warned about at most once every ten
+    /// seconds. The client is the one `trusted_proxies` resolves the
+    /// request's head to, so a request refused before its head was read,
+    /// for too long a request or header line or too many headers, is blamed
+    /// on the connection's peer, which may be a proxy.
+    pub log_limits: bool,
+    /// Headers added to every response that doesn't set them already, such
+    /// as `X-Content-Type-Options: nosniff` or a `Content-Security-Policy`.
+    /// A header the handler set, under any case, is left as it is.
+    pub default_headers: Vec<(String, String)>,
+    /// How many of the most recent requests to keep, with their method,
+    /// target, status, timing, sizes and a few headers, for troubleshooting
+    /// through [`Metrics::capture`](crate::Metrics::capture). `0`, the
+    /// default, keeps none. Only read at startup. The captured requests
+    /// aren't authenticated in any way, so don't serve them where clients
+    /// can reach them.
+    pub capture_requests: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind_addr: "127.0.0.1:7878".to_string(),
+            listen_backlog: 128,
+            reuse_port: false,
+            pool_size: 4,
+            blocking_pool_size: 4,
+            max_uri_length: 8 * 1024,
+            max_header_line: 8 * 1024,
+            max_headers: 100,
+            max_body: 1024 * 1024,
+            strict_crlf: false,
+            input_buffer_size: 8 * 1024,
+            output_buffer_size: 8 * 1024,
+            large_body_warning: None,
+            keep_alive: true,
+            max_keep_alive_requests: None,
+            advertise_keep_alive: false,
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            idle_timeout: Some(Duration::from_secs(5)),
+            request_timeout: Some(Duration::from_secs(60)),
+            job_timeout: None,
+            static_root: PathBuf::from("."),
+            not_found_page: PathBuf::from("404.html"),
+            welcome_page: WELCOME_PAGE.to_string(),
+            allow_trace: false,
+            allow_connect: false,
+            method_override: false,
+            disabled_routes: Vec::new(),
+            trusted_proxies: Vec::new(),
+            drain_retry_after: 5,
+            shutdown_grace: Some(Duration::from_secs(30)),
+            max_connections: None,
+            max_queued: None,
+            ready_max_queued: None,
+            log_bodies: false,
+            log_body_limit: 4096,
+            log_redact: ["authorization", "cookie", "password"]
+                .map(str::to_string)
+                .to_vec(),
+            log_limits: false,
+            default_headers: Vec::new(),
+            capture_requests: 0,
+        }
+    }
+}
+
+impl Config {
+    /// Reads settings from a file of `key = value` lines, starting from the
+    /// defaults. Blank lines and lines starting with `#` are skipped.
+    /// Timeouts are given in milliseconds, with `0` meaning none, as does `0`
+    /// for `max_keep_alive_requests`, `max_connections`, `max_queued` and
+    /// `ready_max_queued`, and
+    /// `disabled_routes`, `trusted_proxies` and `log_redact` are
+    /// comma-separated lists. Each `default_header = Name: value` line adds
+    /// one of `default_headers`.
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Config> {
+        Config::parse(&fs::read_to_string(path)?)
+    }
+
+    fn parse(contents: &str) -> io::Result<Config> {
+        let mut config = Config::default();
+
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid =
+                || io::Error::new(ErrorKind::InvalidData, format!("invalid setting: {}", line));
+            let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+            let value = value.trim();
+            let number = || value.parse::<u64>().map_err(|_| invalid());
+            let timeout = || number().map(|ms| (ms > 0).then(|| Duration::from_millis(ms)));
+            let limit = || number().map(|n| (n > 0).then_some(n as usize));
+
+            match key.trim() {
+                "bind_addr" => config.bind_addr = value.to_string(),
+                "listen_backlog" => {
+                    config.listen_backlog = u32::try_from(number()?).map_err(|_| invalid())?;
+                }
+                "reuse_port" => config.reuse_port = value.parse().map_err(|_| invalid())?,
+                "pool_size" => config.pool_size = number()? as usize,
+                "blocking_pool_size" => config.blocking_pool_size = number()? as usize,
+                "max_uri_length" => config.max_uri_length = number()? as usize,
+                "max_header_line" => config.max_header_line = number()? as usize,
+                "max_headers" => config.max_headers = number()? as usize,
+                "max_body" => config.max_body = number()? as usize,
+                "strict_crlf" => config.strict_crlf = value.parse().map_err(|_| invalid())?,
+                "input_buffer_size" => config.input_buffer_size = number()? as usize,
+                "output_buffer_size" => config.output_buffer_size = number()? as usize,
+                "large_body_warning" => config.large_body_warning = limit()?,
+                "keep_alive" => config.keep_alive = value.parse().map_err(|_| invalid())?,
+                "max_keep_alive_requests" => config.max_keep_alive_requests = limit()?,
+                "advertise_keep_alive" => {
+                    config.advertise_keep_alive = value.parse().map_err(|_| invalid())?;
+                }
+                "read_timeout" => config.read_timeout = timeout()?,
+                "write_timeout" => config.write_timeout = timeout()?,
+                "idle_timeout" => config.idle_timeout = timeout()?,
+                "request_timeout" => config.request_timeout = timeout()?,
+                "job_timeout" => config.job_timeout = timeout()?,
+                "static_root" => config.static_root = PathBuf::from(value),
+                "not_found_page" => config.not_found_page = PathBuf::from(value),
+                "welcome_page" => config.welcome_page = value.to_string(),
+                "allow_trace" => config.allow_trace = value.parse().map_err(|_| invalid())?,
+                "allow_connect" => {
+                    config.allow_connect = value.parse().map_err(|_| invalid())?;
+                }
+                "method_override" => {
+                    config.method_override = value.parse().map_err(|_| invalid())?;
+                }
+                "disabled_routes" => {
+                    config.disabled_routes = list(value).map(str::to_string).collect();
+                }
+                "trusted_proxies" => {
+                    config.trusted_proxies = list(value)
+                        .map(str::parse)
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| invalid())?;
+                }
+                "drain_retry_after" => config.drain_retry_after = number()?,
+                "shutdown_grace" => config.shutdown_grace = timeout()?,
+                "max_connections" => config.max_connections = limit()?,
+                "max_queued" => config.max_queued = limit()?,
+                "ready_max_queued" => config.ready_max_queued = limit()?,
+                "log_bodies" => config.log_bodies = value.parse().map_err(|_| invalid())?,
+                "log_body_limit" => config.log_body_limit = number()? as usize,
+                "log_redact" => config.log_redact = list(value).map(str::to_string).collect(),
+                "log_limits" => config.log_limits = value.parse().map_err(|_| invalid())?,
+                "default_header" => {
+                    let (name, value) = value.split_once(':').ok_or_else(invalid)?;
+                    let (name, value) = (name.trim(), value.trim());
+                    if name.is_empty() {
+                        return Err(invalid());
+                    }
+                    config
+                        .default_headers
+                        .push((name.to_string(), value.to_string()));
+                }
+                "capture_requests" => config.capture_requests = number()? as usize,
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Takes on the settings in `new`, except for those only read at
+    /// startup, which keep their current values with a warning if `new`
+    /// tried to change them.
+    pub fn reload(&mut self, new: Config) {
+        if new.bind_addr != self.bind_addr {
+            eprintln!("Ignoring changed bind_addr on reload; restart to apply it");
+        }
+        if new.listen_backlog != self.listen_backlog {
+            eprintln!("Ignoring changed listen_backlog on reload; restart to apply it");
+        }
+        if new.reuse_port != self.reuse_port {
+            eprintln!("Ignoring changed reuse_port on reload; restart to apply it");
+        }
+        if new.pool_size != self.pool_size {
+            eprintln!("Ignoring changed pool_size on reload; restart to apply it");
+        }
+        if new.blocking_pool_size != self.blocking_pool_size {
+            eprintln!("Ignoring changed blocking_pool_size on reload; restart to apply it");
+        }
+        if new.job_timeout != self.job_timeout {
+            eprintln!("Ignoring changed job_timeout on reload; restart to apply it");
+        }
+        if new.capture_requests != self.capture_requests {
+            eprintln!("Ignoring changed capture_requests on reload; restart to apply it");
+        }
+
+        *self = Config {
+            bind_addr: std::mem::take(&mut self.bind_addr),
+            listen_backlog: self.listen_backlog,
+            reuse_port: self.reuse_port,
+            pool_size: self.pool_size,
+            blocking_pool_size: self.blocking_pool_size,
+            job_timeout: self.job_timeout,
+            capture_requests: self.capture_requests,
+            ..new
+        };
+    }
+}
+
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_settings() {
+        let config = Config::parse(
+            "# site\n\
+             static_root = /srv/www\n\
+             idle_timeout = 0\n\
+             max_headers = 20\n\
+             write_timeout = 2500\n\
+             disabled_routes = /sleep, /admin\n\
+             trusted_proxies = 10.0.0.0/8, ::1\n\
+             drain_retry_after = 30\n\
+             shutdown_grace = 10000\n\
+             default_header = X-Frame-Options: DENY\n\
+             default_header = Content-Security-Policy: default-src 'self'\n\
+             capture_requests = 50\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.static_root, PathBuf::from("/srv/www"));
+        assert_eq!(config.idle_timeout, None);
+        assert_eq!(config.max_headers, 20);
+        assert_eq!(config.write_timeout, Some(Duration::from_millis(2500)));
+        assert_eq!(config.disabled_routes, ["/sleep", "/admin"]);
+        assert_eq!(config.trusted_proxies.len(), 2);
+        assert_eq!(config.drain_retry_after, 30);
+        assert_eq!(config.shutdown_grace, Some(Duration::from_secs(10)));
+        assert_eq!(config.capture_requests, 50);
+        assert_eq!(
+            config.default_headers,
+            [
+                ("X-Frame-Options".to_string(), "DENY".to_string()),
+                (
+                    "Content-Security-Policy".to_string(),
+                    "default-src 'self'".to_string()
+                ),
+            ]
+        );
+        assert_eq!(config.pool_size, Config::default().pool_size);
+        assert!(Config::parse("pool_size = many").is_err());
+    }
+
+    #[test]
+    fn test_reload_keeps_startup_settings() {
+        let mut config = Config::default();
+        config.reload(Config {
+            bind_addr: "0.0.0.0:80".to_string(),
+            pool_size: 64,
+            max_body: 16,
+            ..Config::default()
+        });
+
+        assert_eq!(config.bind_addr, "127.0.0.1:7878");
+        assert_eq!(config.pool_size, 4);
+        assert_eq!(config.max_body, 16);
+    }
+}
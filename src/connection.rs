@@ -0,0 +1,1766 @@
+use std::{
+    io::{self, prelude::*, BufReader, ErrorKind},
+    net::{IpAddr, SocketAddr, TcpStream},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+#[cfg(unix)]
+use std::os::{fd::AsRawFd, unix::net::UnixStream};
+
+use crate::{
+    body_log, body_stream, limit_log, proxy, scratch, sendfile, trace, websocket, CloseReason,
+    Config, Exchange, HttpError, Method, Metrics, Request, Response, Router, StatusCode, Version,
+};
+
+/// Smallest buffer requests are read into, whatever
+/// `config.input_buffer_size` says, so that a request line doesn't take
+/// dozens of reads.
+const MIN_INPUT_BUFFER: usize = 512;
+
+/// A bidirectional byte stream that a connection can be served over.
+pub trait Stream: Read + Write {
+    /// Sets how long a read may block before failing. Streams that can't
+    /// time out ignore this.
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Sets how long a write may block before failing. Streams that can't
+    /// time out ignore this.
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The address of the other end of the connection, if it has one.
+    fn peer_addr(&self) -> Option<IpAddr> {
+        None
+    }
+
+    /// The address and port of the other end of the connection, if it has
+    /// one.
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// The address and port the connection was accepted on, if it has one.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Whether the stream is encrypted with TLS, for a stream that wraps a
+    /// TLS session.
+    fn is_tls(&self) -> bool {
+        false
+    }
+
+    /// The socket's descriptor, if file bodies may be sent to it directly
+    /// with `sendfile` rather than written through the stream.
+    fn socket_fd(&self) -> Option<sendfile::Fd> {
+        None
+    }
+}
+
+impl Stream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn peer_addr(&self) -> Option<IpAddr> {
+        TcpStream::peer_addr(self).ok().map(|addr| addr.ip())
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+
+    #[cfg(unix)]
+    fn socket_fd(&self) -> Option<sendfile::Fd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl Stream for &TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn peer_addr(&self) -> Option<IpAddr> {
+        TcpStream::peer_addr(self).ok().map(|addr| addr.ip())
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+
+    #[cfg(unix)]
+    fn socket_fd(&self) -> Option<sendfile::Fd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+/// Unix socket peers have no IP address, so requests over one have no
+/// [`client_addr`](Request::client_addr).
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn socket_fd(&self) -> Option<sendfile::Fd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+#[cfg(unix)]
+impl Stream for &UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn socket_fd(&self) -> Option<sendfile::Fd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl<S: Stream + ?Sized> Stream for &mut S {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_write_timeout(timeout)
+    }
+
+    fn peer_addr(&self) -> Option<IpAddr> {
+        (**self).peer_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        (**self).remote_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        (**self).local_addr()
+    }
+
+    fn is_tls(&self) -> bool {
+        (**self).is_tls()
+    }
+
+    fn socket_fd(&self) -> Option<sendfile::Fd> {
+        (**self).socket_fd()
+    }
+}
+
+/// Ids given to connections as they start being served, from 1, leaving 0
+/// for requests that didn't arrive over one.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// What is known about the connection a request arrived over, read with
+/// [`Request::connection`]. Requests built with [`Request::new`] have a
+/// default one, with no addresses and an id of 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ConnectionInfo {
+    remote_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    is_tls: bool,
+    id: u64,
+}
+
+impl ConnectionInfo {
+    /// Describes the connection over `stream`, giving it the next id.
+    pub(crate) fn accepted<S: Stream>(stream: &S) -> ConnectionInfo {
+        ConnectionInfo {
+            remote_addr: stream.remote_addr(),
+            local_addr: stream.local_addr(),
+            is_tls: stream.is_tls(),
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// The address of the peer, which is a proxy's for proxied requests;
+    /// [`Request::client_addr`] looks through trusted proxies. `None` for
+    /// a Unix socket.
+    pub fn remote_addr(&self) -> Option<IpAddr> {
+        self.remote_addr.map(|addr| addr.ip())
+    }
+
+    pub fn remote_port(&self) -> Option<u16> {
+        self.remote_addr.map(|addr| addr.port())
+    }
+
+    /// The address and port the connection was accepted on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.is_tls
+    }
+
+    /// A number identifying the connection among those the process has
+    /// served, the same for every request it carries.
+    pub fn connection_id(&self) -> u64 {
+        self.id
+    }
+}
+
+/// Serves requests from `stream`, dispatching each through `router` and
+/// writing the response back, until the client closes the connection or
+/// either side asks for it to be closed.
+///
+/// The stream is generic so that anything implementing [`Stream`] can be
+/// served, not just a `TcpStream`. Each request's
+/// [`client_addr`](Request::client_addr) is the stream's peer address, or
+/// the address forwarded by the peer if it is one of
+/// `config.trusted_proxies`. Between requests the connection waits at
+/// most `config.idle_timeout` for the next one to start and is closed
+/// cleanly if it doesn't; once a request has started, `config.read_timeout`
+/// applies instead. The time from a request's first byte to its last
+/// response byte is recorded in `metrics`, and is bounded overall by
+/// `config.request_timeout`: a request whose head or body doesn't arrive in
+/// time is answered with `408 Request Timeout`, one whose handler runs past
+/// the deadline with `504 Gateway Timeout`, and if the deadline passes while
+/// the response is being written the connection is dropped. HTTP/1.1
+/// requests without a `Host` header are rejected with `400 Bad Request`.
+/// HTTP/2 isn't spoken: a request to upgrade to it with `Upgrade: h2c` is
+/// answered over HTTP/1.1, and a connection opening with the HTTP/2 preface
+/// gets `505 HTTP Version Not Supported` and is closed. Requests are read
+/// through a buffer of `config.input_buffer_size` bytes. Each response is
+/// assembled in a buffer of `config.output_buffer_size` bytes so that the
+/// status line, headers and small bodies leave in a single write; bodies
+/// larger than the buffer are passed straight through to the stream.
+pub fn handle_connection<S: Stream + Send>(
+    stream: S,
+    router: &Router,
+    config: &Config,
+    metrics: &Metrics,
+) -> io::Result<()> {
+    serve(
+        stream,
+        |request| router.dispatch(request),
+        |_| None,
+        |request| router.max_body(request),
+        |request| router.streams_body(request),
+        config,
+        metrics,
+    )?;
+    Ok(())
+}
+
+/// Why [`serve`] handed a connection back to its caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Divert {
+    /// A WebSocket upgrade request, already answered with
+    /// `101 Switching Protocols`.
+    WebSocket,
+    /// A request for a blocking route, not answered yet.
+    Blocking,
+}
+
+/// A request that [`serve`] stopped at, leaving the caller the stream to
+/// carry on with.
+pub(crate) struct Handoff {
+    pub(crate) divert: Divert,
+    pub(crate) request: Request,
+    /// When the request must be answered by, from `config.request_timeout`.
+    pub(crate) deadline: Option<Instant>,
+    /// Bytes the client sent after the request that were already read.
+    pub(crate) buffered: Vec<u8>,
+}
+
+/// Like [`handle_connection`], but with every request answered by `handler`
+/// rather than a router.
+///
+/// Requests for which `divert` returns a reason aren't passed to `handler`;
+/// instead `serve` returns them, after answering a WebSocket upgrade. Bodies
+/// are limited to what `max_body` returns for the request's head, or to
+/// `config.max_body` if it returns `None`. They are left for `handler` to
+/// read through [`Request::body_reader`] if `stream_body` returns `true`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn serve<S, H, D, L, B>(
+    stream: S,
+    handler: H,
+    divert: D,
+    max_body: L,
+    stream_body: B,
+    config: &Config,
+    metrics: &Metrics,
+) -> io::Result<Option<Handoff>>
+where
+    S: Stream + Send,
+    H: Fn(Request) -> Response,
+    D: Fn(&Request) -> Option<Divert>,
+    L: Fn(&Request) -> Option<usize>,
+    B: Fn(&Request) -> bool,
+{
+    let peer = stream.peer_addr();
+    let info = ConnectionInfo::accepted(&stream);
+    let connection_span = trace::Span::connection(peer);
+    let _connection_entered = connection_span.enter();
+    let mut reader =
+        BufReader::with_capacity(config.input_buffer_size.max(MIN_INPUT_BUFFER), stream);
+    let mut first_request = true;
+    let mut served = 0;
+    let mut lifecycle = Lifecycle {
+        metrics,
+        requests: 0,
+        reason: CloseReason::Error,
+    };
+
+    loop {
+        let wait = if first_request {
+            config.read_timeout
+        } else {
+            config.idle_timeout
+        };
+        reader.get_ref().set_read_timeout(wait)?;
+
+        // The client closing the connection, or going quiet for too long,
+        // between requests is the normal way for a keep-alive connection
+        // to end.
+        match reader.fill_buf() {
+            Ok([]) => {
+                lifecycle.reason = CloseReason::ClientClosed;
+                return Ok(None);
+            }
+            Ok(_) => {}
+            Err(e) if is_timeout(&e) => {
+                lifecycle.reason = CloseReason::IdleTimeout;
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        }
+
+        let start = Instant::now();
+        let deadline = config.request_timeout.map(|timeout| start + timeout);
+        reader.get_ref().set_read_timeout(config.read_timeout)?;
+        first_request = false;
+
+        let request_span = trace::Span::request();
+        let _request_entered = request_span.enter();
+
+        // The body is read once the head shows which limit applies to it.
+        // The client is only known from the head, so a head that fails is
+        // blamed on the peer.
+        let mut client = peer;
+        let parsed = trace::Span::parse().in_scope(|| {
+            let mut reader = DeadlineReader {
+                reader: &mut reader,
+                deadline,
+                read_timeout: config.read_timeout,
+            };
+            let mut request = Request::parse_head(&mut reader, config)?;
+            client = peer.map(|peer| proxy::client_addr(peer, &request, &config.trusted_proxies));
+            // A method named in a header picks the route, and so the limit,
+            // before the body is read. One named in a form field can only be
+            // found in the body, which is limited as a `POST`.
+            if config.method_override {
+                request.apply_method_override();
+            }
+            let limit = max_body(&request).unwrap_or(config.max_body);
+            if stream_body(&request) {
+                let framing = body_stream::Framing::new(&request, limit)?;
+                return Ok((request, Some(framing)));
+            }
+            request.read_body(&mut reader, limit)?;
+            if config.method_override {
+                request.apply_method_override();
+            }
+            Ok((request, None))
+        });
+        match &parsed {
+            Ok((request, _)) => request_span.record_request(&request.method, &request.path),
+            Err(e) => limit_log::report(e, client, config),
+        }
+
+        // Once the deadline has passed, the error response is still sent,
+        // but without a deadline of its own.
+        let mut captured = None;
+        // Why the connection closes after this request, if it does.
+        let (response, keep_alive, write_deadline, close) = match parsed {
+            Ok((request, _))
+                if request.version == Version::Http11 && request.header("Host").is_none() =>
+            {
+                (
+                    HttpError::BadRequest("missing Host header".to_string()).into_response(),
+                    false,
+                    None,
+                    CloseReason::Error,
+                )
+            }
+            Ok((mut request, framing)) => {
+                request.connection = info;
+                request.client_addr = client;
+                if config.log_bodies {
+                    eprintln!("{}", body_log::format(&request, config));
+                }
+                captured = metrics.capture().begin(&request, config);
+                served += 1;
+                let mut close = if !config.keep_alive || !request.keep_alive() {
+                    CloseReason::NoKeepAlive
+                } else {
+                    CloseReason::MaxRequests
+                };
+                let mut keep_alive = config.keep_alive
+                    && request.keep_alive()
+                    && config
+                        .max_keep_alive_requests
+                        .is_none_or(|max| served < max);
+                let response = if config.disabled_routes.contains(&request.path) {
+                    HttpError::NotFound.into_response()
+                } else if let Some(status) = refused_method(&request.method, config) {
+                    Response::new(status)
+                } else if let Some(divert) = divert(&request) {
+                    if divert == Divert::WebSocket {
+                        let response = websocket::handshake(&request);
+                        request_span.record_status(response.status());
+                        let status = response.status();
+                        response.write_to(reader.get_mut())?;
+                        metrics.record_request(start.elapsed());
+                        metrics.record_response(status);
+                    }
+                    // A blocking request is answered by the caller, which
+                    // then closes the connection.
+                    lifecycle.requests += 1;
+                    lifecycle.reason = match divert {
+                        Divert::WebSocket => CloseReason::Upgraded,
+                        Divert::Blocking => CloseReason::NoKeepAlive,
+                    };
+                    return Ok(Some(Handoff {
+                        divert,
+                        request,
+                        deadline,
+                        buffered: reader.buffer().to_vec(),
+                    }));
+                } else {
+                    let version = request.version;
+                    request.strip_hop_by_hop();
+                    let mut response = match framing {
+                        // A body left unread by the handler is drained
+                        // before the next request, but one that failed
+                        // leaves nowhere to carry on from.
+                        Some(framing) => {
+                            let mut reader = DeadlineReader {
+                                reader: &mut reader,
+                                deadline,
+                                read_timeout: config.read_timeout,
+                            };
+                            let (response, drained) =
+                                body_stream::respond(&mut reader, framing, request, |request| {
+                                    respond(request, &handler, config)
+                                });
+                            if drained.is_err() {
+                                keep_alive = false;
+                                close = CloseReason::Error;
+                            }
+                            response
+                        }
+                        None => respond(request, &handler, config),
+                    };
+                    // Without chunked framing only the connection closing
+                    // ends the body.
+                    if version == Version::Http10 && response.unframe() {
+                        keep_alive = false;
+                        close = CloseReason::NoKeepAlive;
+                    }
+                    response
+                };
+                if has_passed(deadline) {
+                    (
+                        Response::new(StatusCode::GATEWAY_TIMEOUT),
+                        false,
+                        None,
+                        CloseReason::Error,
+                    )
+                } else {
+                    (response, keep_alive, deadline, close)
+                }
+            }
+            Err(HttpError::Io(e)) if is_timeout(&e) => (
+                HttpError::RequestTimeout.into_response(),
+                false,
+                None,
+                CloseReason::Error,
+            ),
+            Err(e) => (e.into_response(), false, None, CloseReason::Error),
+        };
+
+        // An event stream has no length, so only closing the connection
+        // tells the client it has ended, and it runs for as long as it has
+        // events rather than within the request's deadline.
+        let (keep_alive, write_deadline, close) = if response.is_event_stream() {
+            (false, None, CloseReason::NoKeepAlive)
+        } else {
+            (keep_alive, write_deadline, close)
+        };
+        let response = if !keep_alive {
+            response.header("Connection", "close")
+        } else if let Some(advertised) = config
+            .advertise_keep_alive
+            .then(|| keep_alive_header(config, served))
+            .flatten()
+        {
+            response.header("Keep-Alive", &advertised)
+        } else {
+            response
+        };
+        let response = response.default_headers(&config.default_headers);
+        request_span.record_status(response.status());
+
+        reader
+            .get_ref()
+            .set_write_timeout(write_timeout(write_deadline))?;
+        let socket = reader.get_ref().socket_fd();
+        let status = response.status();
+        let body_len = response.body_len();
+        let writer = DeadlineWriter {
+            writer: reader.get_mut(),
+            deadline: write_deadline,
+        };
+        scratch::with_output(config.output_buffer_size, writer, |writer| {
+            trace::Span::write().in_scope(|| response.write_to_socket(writer, socket))
+        })?;
+        metrics.record_request(start.elapsed());
+        metrics.record_response(status);
+        record_exchange(metrics, captured, status, body_len, start.elapsed());
+        request_span.record_duration(start.elapsed());
+        lifecycle.requests += 1;
+
+        if !keep_alive {
+            lifecycle.reason = close;
+            return Ok(None);
+        }
+    }
+}
+
+/// Records in `metrics`, once [`serve`] is done with a connection, how
+/// many requests it answered and why it ended, which is an error unless
+/// `reason` was set to something else first.
+struct Lifecycle<'m> {
+    metrics: &'m Metrics,
+    requests: u64,
+    reason: CloseReason,
+}
+
+impl Drop for Lifecycle<'_> {
+    fn drop(&mut self) {
+        self.metrics
+            .record_connection_closed(self.reason, self.requests);
+    }
+}
+
+/// Answers `request` with `handler`, warning if the response holds a body in
+/// memory larger than `config.large_body_warning`.
+fn respond<H>(request: Request, handler: &H, config: &Config) -> Response
+where
+    H: Fn(Request) -> Response,
+{
+    let Some(threshold) = config.large_body_warning else {
+        return handler(request);
+    };
+    let path = request.path.clone();
+    let response = handler(request);
+    if let Some(len) = response.memory_body_len().filter(|&len| len > threshold) {
+        eprintln!(
+            "Warning: response for {} holds a {}-byte body in memory; \
+             consider streaming it with Response::chunked or Response::from_reader",
+            path, len
+        );
+    }
+    response
+}
+
+/// Answers `request`, which [`serve`] diverted as blocking, with `handler`
+/// and closes the connection. The response must be written by `deadline`,
+/// as in `serve`, and is replaced with `504 Gateway Timeout` if the handler
+/// runs past it.
+pub(crate) fn serve_diverted<S, H>(
+    stream: S,
+    mut request: Request,
+    deadline: Option<Instant>,
+    handler: H,
+    config: &Config,
+    metrics: &Metrics,
+) -> io::Result<()>
+where
+    S: Stream,
+    H: Fn(Request) -> Response,
+{
+    let start = Instant::now();
+    let captured = metrics.capture().begin(&request, config);
+    request.strip_hop_by_hop();
+    let response = respond(request, &handler, config);
+    let (response, deadline) = if has_passed(deadline) {
+        (Response::new(StatusCode::GATEWAY_TIMEOUT), None)
+    } else {
+        (response, deadline)
+    };
+    let response = response
+        .header("Connection", "close")
+        .default_headers(&config.default_headers);
+
+    stream.set_write_timeout(write_timeout(deadline))?;
+    let socket = stream.socket_fd();
+    let status = response.status();
+    let body_len = response.body_len();
+    let writer = DeadlineWriter {
+        writer: stream,
+        deadline,
+    };
+    scratch::with_output(config.output_buffer_size, writer, |writer| {
+        trace::Span::write().in_scope(|| response.write_to_socket(writer, socket))
+    })?;
+    metrics.record_request(start.elapsed());
+    metrics.record_response(status);
+    record_exchange(metrics, captured, status, body_len, start.elapsed());
+    Ok(())
+}
+
+/// Keeps an exchange begun with `RequestCapture::begin`, if capture is on,
+/// now that its response has been sent.
+fn record_exchange(
+    metrics: &Metrics,
+    captured: Option<Exchange>,
+    status: StatusCode,
+    response_bytes: Option<u64>,
+    duration: Duration,
+) {
+    if let Some(mut exchange) = captured {
+        exchange.status = status;
+        exchange.response_bytes = response_bytes;
+        exchange.duration = duration;
+        metrics.capture().record(exchange);
+    }
+}
+
+/// Turns away a connection accepted while the server is draining for
+/// shutdown, answering it with `503 Service Unavailable` and a
+/// `Retry-After` of `config.drain_retry_after` seconds without reading a
+/// request from it.
+pub fn reject_draining<S: Stream>(stream: S, config: &Config) -> io::Result<()> {
+    reject(
+        stream,
+        config,
+        Response::new(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Retry-After", &config.drain_retry_after.to_string())
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body("Server is shutting down\n"),
+    )
+}
+
+/// Answers a connection with `response` without reading a request from it,
+/// telling the client the connection is closing.
+pub(crate) fn reject<S: Stream>(
+    mut stream: S,
+    config: &Config,
+    response: Response,
+) -> io::Result<()> {
+    stream.set_write_timeout(config.write_timeout)?;
+    response
+        .header("Connection", "close")
+        .default_headers(&config.default_headers)
+        .write_to(&mut stream)
+}
+
+/// The status `TRACE` and `CONNECT` requests are refused with unless the
+/// settings allow them. It is `501 Not Implemented` for both, as the server
+/// then supports them on no path at all; a `405` would have to list the
+/// methods the path does allow.
+fn refused_method(method: &Method, config: &Config) -> Option<StatusCode> {
+    match method {
+        Method::Trace if !config.allow_trace => Some(StatusCode::NOT_IMPLEMENTED),
+        Method::Connect if !config.allow_connect => Some(StatusCode::NOT_IMPLEMENTED),
+        _ => None,
+    }
+}
+
+/// The `Keep-Alive` header for a connection kept open after `served`
+/// requests, or `None` if it has neither an idle timeout nor a request limit
+/// to advertise.
+fn keep_alive_header(config: &Config, served: usize) -> Option<String> {
+    let timeout = config
+        .idle_timeout
+        .map(|timeout| format!("timeout={}", timeout.as_secs()));
+    let max = config
+        .max_keep_alive_requests
+        .map(|max| format!("max={}", max - served));
+    let params: Vec<String> = timeout.into_iter().chain(max).collect();
+    (!params.is_empty()).then(|| params.join(", "))
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+fn has_passed(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Reads a request from a connection without letting any single read block
+/// past `deadline`, failing with `TimedOut` once it has passed.
+struct DeadlineReader<'a, S: Stream> {
+    reader: &'a mut BufReader<S>,
+    deadline: Option<Instant>,
+    read_timeout: Option<Duration>,
+}
+
+impl<S: Stream> BufRead for DeadlineReader<'_, S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if let (Some(deadline), []) = (self.deadline, self.reader.buffer()) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+
+            let timeout = self.read_timeout.map_or(remaining, |t| t.min(remaining));
+            self.reader.get_ref().set_read_timeout(Some(timeout))?;
+        }
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.reader.consume(amount);
+    }
+}
+
+impl<S: Stream> Read for DeadlineReader<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+/// The socket write timeout for a response due by `deadline`. A zero
+/// timeout is rejected by sockets, so an expired deadline is left for
+/// [`DeadlineWriter`] to report.
+fn write_timeout(deadline: Option<Instant>) -> Option<Duration> {
+    deadline.map(|deadline| {
+        deadline
+            .saturating_duration_since(Instant::now())
+            .max(Duration::from_millis(1))
+    })
+}
+
+/// Writes a response, failing with `TimedOut` once `deadline` has passed.
+struct DeadlineWriter<W> {
+    writer: W,
+    deadline: Option<Instant>,
+}
+
+impl<W: Write> Write for DeadlineWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if has_passed(self.deadline) {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Event, Request, Response, StatusCode};
+    use std::{
+        io::Cursor,
+        net::{TcpListener, TcpStream},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    /// An in-memory stream that records each write and flush it receives,
+    /// and counts its reads.
+    struct RecordingStream {
+        input: Cursor<Vec<u8>>,
+        reads: usize,
+        writes: Vec<Vec<u8>>,
+        flushes: usize,
+    }
+
+    impl RecordingStream {
+        fn new(input: &[u8]) -> RecordingStream {
+            RecordingStream {
+                input: Cursor::new(input.to_vec()),
+                reads: 0,
+                writes: Vec::new(),
+                flushes: 0,
+            }
+        }
+    }
+
+    impl Stream for RecordingStream {}
+
+    impl Read for RecordingStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for RecordingStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    /// A stream that delivers its input one byte per read, pausing before
+    /// each.
+    struct SlowStream {
+        input: Cursor<Vec<u8>>,
+        delay: Duration,
+        output: Vec<u8>,
+    }
+
+    impl Stream for SlowStream {}
+
+    impl Read for SlowStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(self.delay);
+            let len = buf.len().min(1);
+            self.input.read(&mut buf[..len])
+        }
+    }
+
+    impl Write for SlowStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn hello_router() -> Router {
+        let mut router = Router::new();
+        router.get("/", |_| Response::new(StatusCode::OK).body("hello"));
+        router.route(Method::Post, "/echo", |request| {
+            Response::new(StatusCode::OK).body(request.body.clone())
+        });
+        router
+    }
+
+    fn written(stream: &RecordingStream) -> String {
+        String::from_utf8(stream.writes.concat()).unwrap()
+    }
+
+    #[test]
+    fn test_small_response_single_write() {
+        let mut stream =
+            RecordingStream::new(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+
+        let metrics = Metrics::new();
+        handle_connection(&mut stream, &hello_router(), &Config::default(), &metrics).unwrap();
+
+        assert_eq!(metrics.latency().count(), 1);
+        assert_eq!(metrics.responses(2), 1);
+        assert_eq!(stream.writes.len(), 1);
+        assert_eq!(stream.flushes, 1);
+        assert!(stream.writes[0].starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(stream.writes[0].ends_with(b"\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_connection_reuse_and_close_reason() {
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let config = Config {
+            max_keep_alive_requests: Some(3),
+            ..Config::default()
+        };
+        let metrics = Metrics::new();
+
+        let mut stream = RecordingStream::new(&request.repeat(4));
+        handle_connection(&mut stream, &hello_router(), &config, &metrics).unwrap();
+        assert_eq!(metrics.connections_closed(CloseReason::MaxRequests), 1);
+        assert_eq!(metrics.requests_per_connection(), Some(3.0));
+
+        let mut stream = RecordingStream::new(&request.repeat(2));
+        handle_connection(&mut stream, &hello_router(), &config, &metrics).unwrap();
+        assert_eq!(metrics.connections_closed(CloseReason::ClientClosed), 1);
+        assert_eq!(metrics.requests_per_connection(), Some(2.5));
+
+        let mut stream = RecordingStream::new(b"GET / HTTP/1.1\r\n\r\n");
+        handle_connection(&mut stream, &hello_router(), &config, &metrics).unwrap();
+        assert_eq!(metrics.connections_closed(CloseReason::Error), 1);
+    }
+
+    #[test]
+    fn test_capture_keeps_recent_requests() {
+        let mut stream = RecordingStream::new(
+            b"GET /?first HTTP/1.1\r\nHost: localhost\r\n\r\n\
+              GET /missing HTTP/1.1\r\nHost: localhost\r\n\r\n\
+              POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\
+              Connection: close\r\n\r\nabc",
+        );
+
+        let metrics = Metrics::with_capture(2);
+        handle_connection(&mut stream, &hello_router(), &Config::default(), &metrics).unwrap();
+
+        let recent = metrics.capture().recent();
+        assert_eq!(recent.len(), 2, "the first request was pushed out");
+        assert_eq!(recent[0].method, "POST");
+        assert_eq!(recent[0].target, "/echo");
+        assert_eq!(recent[0].status, StatusCode::OK);
+        assert_eq!(recent[0].request_bytes, 3);
+        assert_eq!(recent[0].response_bytes, Some(3));
+        assert_eq!(recent[1].target, "/missing");
+        assert_eq!(recent[1].status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            recent[1].headers,
+            [("Host".to_string(), "localhost".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_route_max_body_overrides_global_limit() {
+        let mut router = Router::new();
+        router
+            .route(Method::Post, "/upload", |request| {
+                Response::new(StatusCode::OK).body(format!("{} bytes", request.body.len()))
+            })
+            .max_body(1024);
+        router.route(Method::Post, "/api", |_| Response::new(StatusCode::OK));
+        router
+            .route(Method::Post, "/tiny", |_| Response::new(StatusCode::OK))
+            .max_body(4);
+        let config = Config {
+            max_body: 16,
+            ..Config::default()
+        };
+        let respond = |path: &str| {
+            let raw = format!(
+                "POST {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+                 Content-Length: 100\r\n\r\n{}",
+                path,
+                "x".repeat(100)
+            );
+            let mut stream = RecordingStream::new(raw.as_bytes());
+            handle_connection(&mut stream, &router, &config, &Metrics::new()).unwrap();
+            written(&stream)
+        };
+
+        assert!(respond("/upload").ends_with("100 bytes"));
+        assert!(respond("/api").starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(respond("/tiny").starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    }
+
+    #[test]
+    fn test_streamed_body_read_in_small_reads_keeps_connection_alive() {
+        let mut router = hello_router();
+        router
+            .route(Method::Post, "/upload", |request| {
+                let mut reader = request.body_reader().unwrap();
+                let mut body = Vec::new();
+                let mut buf = [0; 3];
+                loop {
+                    match reader.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(len) => body.extend_from_slice(&buf[..len]),
+                        Err(_) => return Response::new(StatusCode::BAD_REQUEST),
+                    }
+                }
+                assert!(request.body.is_empty());
+                Response::new(StatusCode::OK).body(body)
+            })
+            .stream_body()
+            .max_body(16);
+        // Reads two bytes and leaves the rest to be drained.
+        router
+            .route(Method::Post, "/peek", |request| {
+                let mut buf = [0; 2];
+                request.body_reader().unwrap().read_exact(&mut buf).unwrap();
+                Response::new(StatusCode::OK).body(buf.to_vec())
+            })
+            .stream_body();
+        let respond = |raw: &[u8]| {
+            let mut stream = RecordingStream::new(raw);
+            handle_connection(&mut stream, &router, &Config::default(), &Metrics::new()).unwrap();
+            written(&stream)
+        };
+
+        let written = respond(
+            b"POST /upload HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n\
+              POST /peek HTTP/1.1\r\nHost: a\r\nContent-Length: 10\r\n\r\n0123456789\
+              GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n",
+        );
+        let responses: Vec<&str> = written.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 3, "{}", written);
+        assert!(responses[0].starts_with("200 OK\r\n"));
+        assert!(responses[0].ends_with("\r\n\r\nhello world"));
+        assert!(responses[1].ends_with("\r\n\r\n01"));
+        assert!(responses[2].starts_with("200 OK\r\n"));
+
+        // A chunked body over the route's limit fails the handler's read, and
+        // the connection is closed rather than read from mid-body.
+        let written = respond(
+            b"POST /upload HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+              a\r\n0123456789\r\na\r\n0123456789\r\n0\r\n\r\n\
+              GET / HTTP/1.1\r\nHost: a\r\n\r\n",
+        );
+        assert!(written.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(written.contains("Connection: close\r\n"));
+        assert_eq!(written.matches("HTTP/1.1 ").count(), 1);
+
+        let written = respond(b"POST /upload HTTP/1.1\r\nHost: a\r\nContent-Length: 17\r\n\r\n");
+        assert!(written.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    }
+
+    #[test]
+    fn test_method_override_only_when_enabled() {
+        let mut router = hello_router();
+        router.route(Method::Post, "/notes", |_| {
+            Response::new(StatusCode::OK).body("posted")
+        });
+        router.route(Method::Delete, "/notes", |_| {
+            Response::new(StatusCode::OK).body("deleted")
+        });
+        let respond = |config: &Config| {
+            let mut stream = RecordingStream::new(
+                b"POST /notes HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+                  X-HTTP-Method-Override: DELETE\r\nContent-Length: 0\r\n\r\n",
+            );
+            handle_connection(&mut stream, &router, config, &Metrics::new()).unwrap();
+            written(&stream)
+        };
+
+        assert!(respond(&Config::default()).ends_with("posted"));
+        let config = Config {
+            method_override: true,
+            ..Config::default()
+        };
+        assert!(respond(&config).ends_with("deleted"));
+    }
+
+    #[test]
+    fn test_method_override_picks_body_limit() {
+        let mut router = Router::new();
+        router
+            .route(Method::Post, "/notes", |_| Response::new(StatusCode::OK))
+            .max_body(1024);
+        router
+            .route(Method::Delete, "/notes", |_| {
+                Response::new(StatusCode::OK).body("deleted")
+            })
+            .max_body(4);
+        let config = Config {
+            method_override: true,
+            ..Config::default()
+        };
+        let mut stream = RecordingStream::new(
+            b"POST /notes HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+              X-HTTP-Method-Override: DELETE\r\nContent-Length: 10\r\n\r\n0123456789",
+        );
+        handle_connection(&mut stream, &router, &config, &Metrics::new()).unwrap();
+
+        assert!(written(&stream).starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    }
+
+    #[test]
+    fn test_trace_and_connect_refused_by_default() {
+        let mut router = hello_router();
+        router.route(Method::Trace, "/", |_| Response::new(StatusCode::OK));
+        router.route(Method::Connect, "example.com:443", |_| {
+            Response::new(StatusCode::OK)
+        });
+        let respond = |raw: &[u8], config: &Config| {
+            let mut stream = RecordingStream::new(raw);
+            handle_connection(&mut stream, &router, config, &Metrics::new()).unwrap();
+            written(&stream)
+        };
+        let trace = b"TRACE / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        let connect = b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\
+                        Connection: close\r\n\r\n";
+
+        let config = Config::default();
+        assert!(respond(trace, &config).starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+        assert!(respond(connect, &config).starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+
+        let config = Config {
+            allow_trace: true,
+            allow_connect: true,
+            ..Config::default()
+        };
+        assert!(respond(trace, &config).starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(respond(connect, &config).starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_default_headers_added_unless_set() {
+        let mut router = Router::new();
+        router.get("/", |_| Response::new(StatusCode::OK).body("plain"));
+        router.get("/framed", |_| {
+            Response::new(StatusCode::OK).header("x-frame-options", "SAMEORIGIN")
+        });
+        let config = Config {
+            default_headers: vec![
+                ("X-Frame-Options".to_string(), "DENY".to_string()),
+                ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+            ],
+            ..Config::default()
+        };
+        let respond = |path: &str| {
+            let raw = format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                path
+            );
+            let mut stream = RecordingStream::new(raw.as_bytes());
+            handle_connection(&mut stream, &router, &config, &Metrics::new()).unwrap();
+            written(&stream)
+        };
+
+        let plain = respond("/");
+        assert!(plain.contains("\r\nX-Frame-Options: DENY\r\n"));
+        assert!(plain.contains("\r\nX-Content-Type-Options: nosniff\r\n"));
+
+        let framed = respond("/framed");
+        assert!(framed.contains("\r\nx-frame-options: SAMEORIGIN\r\n"));
+        assert!(!framed.contains("DENY"));
+        assert!(framed.contains("\r\nX-Content-Type-Options: nosniff\r\n"));
+
+        let mut rejected = RecordingStream::new(b"");
+        reject_draining(&mut rejected, &config).unwrap();
+        assert!(written(&rejected).contains("\r\nX-Frame-Options: DENY\r\n"));
+    }
+
+    #[test]
+    fn test_large_body_bypasses_buffer() {
+        let mut router = Router::new();
+        router.get("/big", |_| {
+            Response::new(StatusCode::OK).body(vec![b'x'; 4096])
+        });
+        let config = Config {
+            output_buffer_size: 64,
+            ..Config::default()
+        };
+        let mut stream = RecordingStream::new(b"GET /big HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+        handle_connection(&mut stream, &router, &config, &Metrics::new()).unwrap();
+
+        let written: usize = stream.writes.iter().map(Vec::len).sum();
+        // Segments are never smaller than 512 bytes.
+        assert_eq!(
+            stream
+                .writes
+                .iter()
+                .filter(|write| write.len() == 512)
+                .count(),
+            8
+        );
+        assert!(written > 4096);
+    }
+
+    #[test]
+    fn test_large_body_written_in_flushed_segments() {
+        const LEN: usize = 256 * 1024;
+        let body: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+        let expected = body.clone();
+        let mut router = Router::new();
+        router.get("/big", move |_| {
+            Response::new(StatusCode::OK).body(body.clone())
+        });
+        let config = Config {
+            output_buffer_size: 4096,
+            large_body_warning: Some(64 * 1024),
+            ..Config::default()
+        };
+        let mut stream = RecordingStream::new(
+            b"GET /big HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        );
+
+        handle_connection(&mut stream, &router, &config, &Metrics::new()).unwrap();
+
+        let output = stream.writes.concat();
+        let head_end = output.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        assert!(String::from_utf8_lossy(&output[..head_end])
+            .contains(&format!("Content-Length: {}\r\n", LEN)));
+        assert!(output[head_end..] == expected[..], "body delivered whole");
+        assert!(stream.writes.iter().all(|write| write.len() <= 4096));
+        assert!(stream.flushes >= LEN / 4096);
+    }
+
+    #[test]
+    fn test_larger_input_buffer_reads_headers_in_fewer_reads() {
+        let mut raw = b"GET / HTTP/1.1\r\nHost: localhost\r\n".to_vec();
+        for i in 0..100 {
+            raw.extend(format!("X-Header-{}: {}\r\n", i, "v".repeat(40)).bytes());
+        }
+        raw.extend(b"Connection: close\r\n\r\n");
+
+        let reads = |input_buffer_size| {
+            let mut stream = RecordingStream::new(&raw);
+            let config = Config {
+                input_buffer_size,
+                max_headers: 128,
+                ..Config::default()
+            };
+            handle_connection(&mut stream, &hello_router(), &config, &Metrics::new()).unwrap();
+            assert!(written(&stream).starts_with("HTTP/1.1 200 OK\r\n"));
+            stream.reads
+        };
+
+        let small = reads(512);
+        let large = reads(16 * 1024);
+        assert!(raw.len() > 5 * 512);
+        assert!(small >= 5, "{} reads", small);
+        assert!(large <= 2, "{} reads", large);
+    }
+
+    #[test]
+    fn test_keep_alive_serves_bodyless_gets() {
+        let mut stream = RecordingStream::new(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\nGET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+
+        handle_connection(
+            &mut stream,
+            &hello_router(),
+            &Config::default(),
+            &Metrics::new(),
+        )
+        .unwrap();
+
+        assert_eq!(written(&stream).matches("HTTP/1.1 200 OK").count(), 2);
+    }
+
+    #[test]
+    fn test_keep_alive_zero_content_length() {
+        let mut stream = RecordingStream::new(
+            b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n\
+              GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        );
+
+        handle_connection(
+            &mut stream,
+            &hello_router(),
+            &Config::default(),
+            &Metrics::new(),
+        )
+        .unwrap();
+
+        let written = written(&stream);
+        assert_eq!(written.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(written.contains("Content-Length: 0\r\n\r\nHTTP/1.1 200 OK"));
+        assert!(written.ends_with("Connection: close\r\nContent-Length: 5\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_connection_close_stops_after_first_request() {
+        let mut stream = RecordingStream::new(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\nGET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+
+        handle_connection(
+            &mut stream,
+            &hello_router(),
+            &Config::default(),
+            &Metrics::new(),
+        )
+        .unwrap();
+
+        assert_eq!(written(&stream).matches("HTTP/1.1 200 OK").count(), 1);
+    }
+
+    #[test]
+    fn test_keep_alive_header_advertises_limits() {
+        let get = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let mut stream = RecordingStream::new(&get.repeat(4));
+        let config = Config {
+            idle_timeout: Some(Duration::from_secs(7)),
+            max_keep_alive_requests: Some(3),
+            advertise_keep_alive: true,
+            ..Config::default()
+        };
+
+        handle_connection(&mut stream, &hello_router(), &config, &Metrics::new()).unwrap();
+
+        let written = written(&stream);
+        let responses: Vec<&str> = written.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 3);
+        assert!(responses[0].contains("Keep-Alive: timeout=7, max=2\r\n"));
+        assert!(responses[1].contains("Keep-Alive: timeout=7, max=1\r\n"));
+        assert!(responses[2].contains("Connection: close\r\n"));
+        assert!(!responses[2].contains("Keep-Alive"));
+    }
+
+    #[test]
+    fn test_keep_alive_header_off_by_default() {
+        let mut stream = RecordingStream::new(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+        handle_connection(
+            &mut stream,
+            &hello_router(),
+            &Config::default(),
+            &Metrics::new(),
+        )
+        .unwrap();
+
+        assert!(!written(&stream).contains("Keep-Alive"));
+    }
+
+    #[test]
+    fn test_draining_rejects_with_retry_after() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let config = Config {
+            drain_retry_after: 42,
+            ..Config::default()
+        };
+
+        reject_draining(server, &config).unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("Retry-After: 42\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn test_diverted_request_keeps_deadline() {
+        let slow = |_| {
+            thread::sleep(Duration::from_millis(20));
+            Response::new(StatusCode::OK).body("done")
+        };
+        let request = || Request::new(Method::Get, "/slow");
+
+        let mut stream = RecordingStream::new(b"");
+        let deadline = Instant::now() + Duration::from_millis(5);
+        serve_diverted(
+            &mut stream,
+            request(),
+            Some(deadline),
+            slow,
+            &Config::default(),
+            &Metrics::new(),
+        )
+        .unwrap();
+        assert!(written(&stream).starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+
+        let mut stream = RecordingStream::new(b"");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        serve_diverted(
+            &mut stream,
+            request(),
+            Some(deadline),
+            slow,
+            &Config::default(),
+            &Metrics::new(),
+        )
+        .unwrap();
+        let response = written(&stream);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("done"));
+    }
+
+    #[test]
+    fn test_requests_carry_connection_info() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\nGET / HTTP/1.0\r\n\r\n")
+            .unwrap();
+
+        let seen = std::sync::Mutex::new(Vec::new());
+        serve(
+            &server,
+            |request| {
+                seen.lock().unwrap().push(*request.connection());
+                Response::new(StatusCode::NO_CONTENT)
+            },
+            |_| None,
+            |_| None,
+            |_| false,
+            &Config::default(),
+            &Metrics::new(),
+        )
+        .unwrap();
+
+        let seen = seen.into_inner().unwrap();
+        assert_eq!(seen.len(), 2);
+        let info = seen[0];
+        let client_addr = client.local_addr().unwrap();
+        assert_eq!(info.remote_addr(), Some(client_addr.ip()));
+        assert_eq!(info.remote_port(), Some(client_addr.port()));
+        assert_eq!(info.local_addr(), Some(listener.local_addr().unwrap()));
+        assert!(!info.is_tls());
+        assert!(info.connection_id() > 0);
+        assert_eq!(seen[1], info);
+
+        // Another connection gets another id.
+        let mut stream = RecordingStream::new(b"GET / HTTP/1.0\r\n\r\n");
+        let other = std::sync::Mutex::new(None);
+        serve(
+            &mut stream,
+            |request| {
+                *other.lock().unwrap() = Some(*request.connection());
+                Response::new(StatusCode::NO_CONTENT)
+            },
+            |_| None,
+            |_| None,
+            |_| false,
+            &Config::default(),
+            &Metrics::new(),
+        )
+        .unwrap();
+        let other = other.into_inner().unwrap().unwrap();
+        assert_eq!(other.remote_port(), None);
+        assert_ne!(other.connection_id(), info.connection_id());
+        assert_eq!(
+            Request::new(Method::Get, "/").connection().connection_id(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_http2_preface_answered_with_505() {
+        let mut stream = RecordingStream::new(
+            b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\x00\x00\x12\x04\x00\x00\x00\x00\x00\
+              \x00\x03\x00\x00\x00\x64\x00\x04\x00\x00\xff\xff\x00\x02\x00\x00\x00\x00",
+        );
+
+        handle_connection(
+            &mut stream,
+            &hello_router(),
+            &Config::default(),
+            &Metrics::new(),
+        )
+        .unwrap();
+
+        let written = written(&stream);
+        assert!(written.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"));
+        assert!(written.contains("Connection: close\r\n"));
+        assert_eq!(written.matches("HTTP/1.1 ").count(), 1);
+    }
+
+    #[test]
+    fn test_h2c_upgrade_served_as_http11() {
+        let mut stream = RecordingStream::new(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+              Upgrade: h2c\r\nHTTP2-Settings: AAMAAABkAARAAAAAAAIAAAAA\r\n\r\n",
+        );
+
+        handle_connection(
+            &mut stream,
+            &hello_router(),
+            &Config::default(),
+            &Metrics::new(),
+        )
+        .unwrap();
+
+        let written = written(&stream);
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"), "{}", written);
+        assert!(!written.contains("Upgrade"));
+    }
+
+    #[test]
+    fn test_chunked_body_framing_by_version() {
+        let mut router = Router::new();
+        router.get("/stream", |_| {
+            Response::chunked(StatusCode::OK, ["one ", "two"])
+        });
+
+        let mut stream =
+            RecordingStream::new(b"GET /stream HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+        handle_connection(&mut stream, &router, &Config::default(), &Metrics::new()).unwrap();
+        let out = written(&stream);
+        let (head, body) = out.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("\r\nTransfer-Encoding: chunked"));
+        assert_eq!(body, "4\r\none \r\n3\r\ntwo\r\n0\r\n\r\n");
+
+        // HTTP/1.0 has no chunked encoding, so the body runs to the close,
+        // even when the client asked to keep the connection alive.
+        let mut stream = RecordingStream::new(
+            b"GET /stream HTTP/1.0\r\nConnection: keep-alive\r\n\r\n\
+              GET /stream HTTP/1.0\r\n\r\n",
+        );
+        handle_connection(&mut stream, &router, &Config::default(), &Metrics::new()).unwrap();
+        let out = written(&stream);
+        let (head, body) = out.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!head.contains("Transfer-Encoding"));
+        assert!(!head.contains("Content-Length"));
+        assert!(head.contains("\r\nConnection: close"));
+        assert_eq!(body, "one two");
+    }
+
+    #[test]
+    fn test_http11_requires_host() {
+        let mut stream = RecordingStream::new(b"GET / HTTP/1.1\r\n\r\nGET / HTTP/1.0\r\n\r\n");
+
+        handle_connection(
+            &mut stream,
+            &hello_router(),
+            &Config::default(),
+            &Metrics::new(),
+        )
+        .unwrap();
+
+        let written = written(&stream);
+        assert!(written.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(!written.contains("200 OK"));
+    }
+
+    #[test]
+    fn test_keep_alive_over_tcp_does_not_block() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(
+                &stream,
+                &hello_router(),
+                &Config::default(),
+                &Metrics::new(),
+            )
+            .unwrap();
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+
+        for _ in 0..2 {
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+
+            let mut status_line = String::new();
+            reader.read_line(&mut status_line).unwrap();
+            assert_eq!(status_line, "HTTP/1.1 200 OK\r\n");
+
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+            }
+
+            let mut body = [0; 5];
+            reader.read_exact(&mut body).unwrap();
+            assert_eq!(&body, b"hello");
+        }
+
+        drop(reader);
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_empty_file_then_keep_alive() {
+        let path = std::env::temp_dir().join(format!("hello-empty-{}", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let file_path = path.clone();
+        let server = thread::spawn(move || {
+            let mut router = hello_router();
+            router.get("/empty", move |_| {
+                let file = std::fs::File::open(&file_path).unwrap();
+                Response::from_file(StatusCode::OK, file).unwrap()
+            });
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(&stream, &router, &Config::default(), &Metrics::new()).unwrap();
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+
+        client
+            .write_all(b"GET /empty HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut head = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            head.push(line);
+        }
+        assert_eq!(head[0], "HTTP/1.1 200 OK\r\n");
+        assert!(head.contains(&"Content-Length: 0\r\n".to_string()));
+
+        // The next bytes belong to the second response, not an empty body.
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert!(rest.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(rest.ends_with("\r\n\r\nhello"));
+
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_event_stream_until_client_disconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut router = Router::new();
+        router.get("/events", |_| {
+            Response::event_stream((0..).map(|i| {
+                thread::sleep(Duration::from_millis(5));
+                Event::new(format!("tick {}", i))
+            }))
+        });
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(&stream, &router, &Config::default(), &Metrics::new())
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+            .write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            reader.read_line(&mut head).unwrap();
+        }
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Content-Type: text/event-stream\r\n"));
+        assert!(head.contains("Connection: close\r\n"));
+
+        for i in 0..3 {
+            let mut event = String::new();
+            while !event.ends_with("\n\n") {
+                reader.read_line(&mut event).unwrap();
+            }
+            assert_eq!(event, format!("data: tick {}\n\n", i));
+        }
+
+        drop(reader);
+        drop(client);
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_idle_keep_alive_connection_is_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = Config {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..Config::default()
+        };
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(&stream, &hello_router(), &config, &Metrics::new()).unwrap();
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+
+        let start = Instant::now();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_slow_headers_exceed_request_timeout() {
+        let mut stream = SlowStream {
+            input: Cursor::new(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Padding: aaaaaaaaaaaaaaaa\r\n\r\n"
+                    .to_vec(),
+            ),
+            delay: Duration::from_millis(10),
+            output: Vec::new(),
+        };
+        let config = Config {
+            request_timeout: Some(Duration::from_millis(100)),
+            ..Config::default()
+        };
+
+        let start = Instant::now();
+        handle_connection(&mut stream, &hello_router(), &config, &Metrics::new()).unwrap();
+
+        let output = String::from_utf8(stream.output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(output.contains("Connection: close\r\n"));
+        assert!(start.elapsed() < Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_slow_handler_exceeds_request_timeout() {
+        let mut router = Router::new();
+        router.get("/slow", |_| {
+            thread::sleep(Duration::from_millis(50));
+            Response::new(StatusCode::OK)
+        });
+        let config = Config {
+            request_timeout: Some(Duration::from_millis(20)),
+            ..Config::default()
+        };
+        let mut stream = RecordingStream::new(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+        handle_connection(&mut stream, &router, &config, &Metrics::new()).unwrap();
+
+        assert!(written(&stream).starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+    }
+}
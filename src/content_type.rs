@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+/// A parsed `Content-Type` header: the media type and its parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentType {
+    /// The media type, such as `multipart/form-data`, lowercased.
+    pub mime: String,
+    /// Parameters such as `charset` and `boundary`, keyed by lowercased
+    /// name. Quoted values are unquoted.
+    pub params: HashMap<String, String>,
+}
+
+impl ContentType {
+    /// Parses a header value such as `text/html; charset="utf-8"`, or
+    /// returns `None` if it has no media type.
+    pub fn parse(value: &str) -> Option<ContentType> {
+        let (mime, params) = split_params(value);
+        if mime.is_empty() {
+            return None;
+        }
+
+        Some(ContentType {
+            mime: mime.to_ascii_lowercase(),
+            params: params.into_iter().collect(),
+        })
+    }
+
+    /// Looks up a parameter by name, ignoring case.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+}
+
+/// Splits `value; key=value; key="quoted value"` into the leading value and
+/// its parameters. Parameter names are lowercased, and quoted values may
+/// contain `;` and backslash-escaped characters.
+pub(crate) fn split_params(value: &str) -> (&str, Vec<(String, String)>) {
+    let (head, rest) = value.split_once(';').unwrap_or((value, ""));
+    let mut params = Vec::new();
+    let mut chars = rest.chars().peekable();
+
+    loop {
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|&c| c != '=' && c != ';') {
+            key.push(c);
+        }
+        match chars.next() {
+            Some('=') => {}
+            // A parameter without a value is skipped.
+            Some(_) => continue,
+            None => break,
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+            // Anything between the closing quote and the next `;` is junk.
+            for c in chars.by_ref() {
+                if c == ';' {
+                    break;
+                }
+            }
+        } else {
+            for c in chars.by_ref() {
+                if c == ';' {
+                    break;
+                }
+                value.push(c);
+            }
+            value.truncate(value.trim_end().len());
+        }
+
+        params.push((key.trim().to_ascii_lowercase(), value));
+    }
+
+    (head.trim(), params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_json() {
+        let content_type = ContentType::parse("Application/JSON").unwrap();
+
+        assert_eq!(content_type.mime, "application/json");
+        assert!(content_type.params.is_empty());
+    }
+
+    #[test]
+    fn test_parse_charset() {
+        let content_type = ContentType::parse("text/html; Charset=\"UTF-8\"").unwrap();
+
+        assert_eq!(content_type.mime, "text/html");
+        assert_eq!(content_type.param("charset"), Some("UTF-8"));
+        assert_eq!(content_type.param("CHARSET"), Some("UTF-8"));
+    }
+
+    #[test]
+    fn test_parse_boundary() {
+        let content_type =
+            ContentType::parse("multipart/form-data; boundary=\"a;b \\\"c\\\"\"; x-extra = plain ")
+                .unwrap();
+
+        assert_eq!(content_type.mime, "multipart/form-data");
+        assert_eq!(content_type.param("boundary"), Some("a;b \"c\""));
+        assert_eq!(content_type.param("x-extra"), Some("plain"));
+        assert_eq!(ContentType::parse(" ; charset=utf-8"), None);
+    }
+}
@@ -0,0 +1,84 @@
+use std::{error, fmt, io};
+
+use crate::{Response, StatusCode};
+
+/// Errors raised while reading or handling a request that map onto an HTTP
+/// error response.
+#[derive(Debug)]
+pub enum HttpError {
+    BadRequest(String),
+    Forbidden,
+    NotFound,
+    RequestTimeout,
+    PayloadTooLarge,
+    UriTooLong,
+    /// A header line was longer than `Config::max_header_line`.
+    HeaderTooLarge,
+    /// The head had more header lines than `Config::max_headers`. Answered
+    /// with `431` like [`HeaderTooLarge`](HttpError::HeaderTooLarge).
+    TooManyHeaders,
+    VersionNotSupported,
+    /// The request needs something the server doesn't support, such as a
+    /// transfer coding it can't decode.
+    NotImplemented(String),
+    Io(io::Error),
+}
+
+impl HttpError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            HttpError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            HttpError::Forbidden => StatusCode::FORBIDDEN,
+            HttpError::NotFound => StatusCode::NOT_FOUND,
+            HttpError::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
+            HttpError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            HttpError::UriTooLong => StatusCode::URI_TOO_LONG,
+            HttpError::HeaderTooLarge | HttpError::TooManyHeaders => {
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+            }
+            HttpError::VersionNotSupported => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            HttpError::NotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
+            HttpError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn into_response(self) -> Response {
+        let status = self.status();
+        Response::new(status)
+            .header("Content-Type", "text/plain")
+            .body(format!("{}\n", status))
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::BadRequest(reason) => write!(f, "bad request: {}", reason),
+            HttpError::Forbidden => write!(f, "forbidden"),
+            HttpError::NotFound => write!(f, "not found"),
+            HttpError::RequestTimeout => write!(f, "request timed out"),
+            HttpError::PayloadTooLarge => write!(f, "payload too large"),
+            HttpError::UriTooLong => write!(f, "URI too long"),
+            HttpError::HeaderTooLarge => write!(f, "header too large"),
+            HttpError::TooManyHeaders => write!(f, "too many headers"),
+            HttpError::VersionNotSupported => write!(f, "HTTP version not supported"),
+            HttpError::NotImplemented(what) => write!(f, "not implemented: {}", what),
+            HttpError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl error::Error for HttpError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            HttpError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HttpError {
+    fn from(e: io::Error) -> HttpError {
+        HttpError::Io(e)
+    }
+}
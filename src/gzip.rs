@@ -0,0 +1,573 @@
+//! A small streaming gzip encoder: deflate with the fixed Huffman codes and
+//! greedy LZ77 matching. It compresses less than a full implementation, but
+//! needs no tables in the output and can be flushed to a byte boundary after
+//! every chunk, so that each chunk can be decompressed as soon as it arrives.
+//!
+//! Also a decoder for whole gzip bodies, from any encoder, for requests
+//! sent with `Transfer-Encoding: gzip, chunked`.
+
+/// The gzip header: magic, deflate, no flags, no modification time, no extra
+/// flags and an unknown OS.
+const HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+
+/// Furthest back a match may refer.
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 12;
+
+/// Base lengths of length codes 257 to 285, and how many extra bits each
+/// takes.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Base distances of distance codes 0 to 29, and how many extra bits each
+/// takes.
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Compresses a body one chunk at a time.
+///
+/// Each call to [`chunk`](GzipEncoder::chunk) returns the compressed bytes
+/// for that chunk, ending on a byte boundary with a sync flush, and
+/// [`finish`](GzipEncoder::finish) returns the end of the stream. The gzip
+/// header goes out with the first chunk.
+pub(crate) struct GzipEncoder {
+    out: BitWriter,
+    crc: u32,
+    size: u32,
+}
+
+impl GzipEncoder {
+    pub(crate) fn new() -> GzipEncoder {
+        let mut out = BitWriter::default();
+        out.bytes.extend_from_slice(&HEADER);
+        GzipEncoder {
+            out,
+            crc: !0,
+            size: 0,
+        }
+    }
+
+    /// Compresses `data` and returns everything produced since the last
+    /// call. Matches don't reach back into earlier chunks.
+    pub(crate) fn chunk(&mut self, data: &[u8]) -> Vec<u8> {
+        self.crc = crc32_update(self.crc, data);
+        self.size = self.size.wrapping_add(data.len() as u32);
+
+        if !data.is_empty() {
+            // A non-final block with the fixed codes.
+            self.out.bits(0b010, 3);
+            compress(&mut self.out, data);
+            self.out.code(256);
+        }
+
+        // A sync flush: an empty stored block, which ends on a byte boundary.
+        self.out.bits(0b000, 3);
+        self.out.align();
+        self.out.bytes.extend_from_slice(&[0, 0, 0xff, 0xff]);
+
+        std::mem::take(&mut self.out.bytes)
+    }
+
+    /// Ends the stream and returns its last bytes: an empty final block and
+    /// the checksum and length of everything compressed.
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        self.out.bits(0b011, 3);
+        self.out.code(256);
+        self.out.align();
+        self.out.bytes.extend_from_slice(&(!self.crc).to_le_bytes());
+        self.out.bytes.extend_from_slice(&self.size.to_le_bytes());
+        self.out.bytes
+    }
+}
+
+/// Compresses a whole body at once.
+pub(crate) fn compress_all(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzipEncoder::new();
+    let mut out = encoder.chunk(data);
+    out.extend(encoder.finish());
+    out
+}
+
+/// Writes `data` as literals and length/distance pairs, matching greedily
+/// against the most recent earlier occurrence of each three-byte prefix.
+fn compress(out: &mut BitWriter, data: &[u8]) {
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let hash = |i: usize| {
+        let key = u32::from(data[i]) << 16 | u32::from(data[i + 1]) << 8 | u32::from(data[i + 2]);
+        (key.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+    };
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut length = 0;
+        let mut distance = 0;
+        if i + MIN_MATCH <= data.len() {
+            let h = hash(i);
+            let candidate = head[h];
+            head[h] = i;
+            if candidate != usize::MAX && i - candidate <= WINDOW {
+                let max = (data.len() - i).min(MAX_MATCH);
+                length = (0..max)
+                    .take_while(|&k| data[candidate + k] == data[i + k])
+                    .count();
+                distance = i - candidate;
+            }
+        }
+
+        if length >= MIN_MATCH {
+            out.length(length);
+            out.distance(distance);
+            for j in i + 1..(i + length).min(data.len().saturating_sub(MIN_MATCH - 1)) {
+                head[hash(j)] = j;
+            }
+            i += length;
+        } else {
+            out.code(u16::from(data[i]));
+            i += 1;
+        }
+    }
+}
+
+/// Packs bits into bytes least significant bit first, as deflate does.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, count: u32) {
+        self.buffer |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.bits(0, 8 - self.count);
+        }
+    }
+
+    /// Writes a Huffman code, which deflate stores most significant bit
+    /// first.
+    fn huffman(&mut self, code: u32, len: u32) {
+        let reversed = code.reverse_bits() >> (32 - len);
+        self.bits(reversed, len);
+    }
+
+    /// Writes a literal/length symbol with the fixed codes.
+    fn code(&mut self, symbol: u16) {
+        let symbol = u32::from(symbol);
+        match symbol {
+            0..=143 => self.huffman(0x30 + symbol, 8),
+            144..=255 => self.huffman(0x190 + symbol - 144, 9),
+            256..=279 => self.huffman(symbol - 256, 7),
+            _ => self.huffman(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn length(&mut self, length: usize) {
+        let index = LENGTH_BASE
+            .iter()
+            .rposition(|&base| usize::from(base) <= length)
+            .unwrap();
+        self.code(257 + index as u16);
+        self.bits(
+            (length - usize::from(LENGTH_BASE[index])) as u32,
+            u32::from(LENGTH_EXTRA[index]),
+        );
+    }
+
+    fn distance(&mut self, distance: usize) {
+        let index = DIST_BASE
+            .iter()
+            .rposition(|&base| usize::from(base) <= distance)
+            .unwrap();
+        self.huffman(index as u32, 5);
+        self.bits(
+            (distance - usize::from(DIST_BASE[index])) as u32,
+            u32::from(DIST_EXTRA[index]),
+        );
+    }
+}
+
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// Why [`decompress`] gave up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DecompressError {
+    /// The data isn't a single well-formed gzip member.
+    Invalid,
+    /// It decompresses to more than the limit.
+    TooLarge,
+}
+
+/// Order in which a dynamic block sends the lengths of the code length
+/// code.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Decompresses one gzip member, checking its trailer, and fails with
+/// [`DecompressError::TooLarge`] as soon as the output passes `limit` bytes
+/// rather than inflating a small body into a huge one.
+pub(crate) fn decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, DecompressError> {
+    let mut reader = BitReader { data, pos: 0 };
+    reader.skip_header()?;
+
+    let mut out = Vec::new();
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => reader.stored(&mut out, limit)?,
+            1 => {
+                let (literals, distances) = fixed_codes();
+                reader.codes(&literals, &distances, &mut out, limit)?;
+            }
+            2 => {
+                let (literals, distances) = reader.dynamic_codes()?;
+                reader.codes(&literals, &distances, &mut out, limit)?;
+            }
+            _ => return Err(DecompressError::Invalid),
+        }
+        if last {
+            break;
+        }
+    }
+
+    let end = reader.pos.div_ceil(8);
+    let trailer = data.get(end..).ok_or(DecompressError::Invalid)?;
+    let mut expected = Vec::with_capacity(8);
+    expected.extend((!crc32_update(!0, &out)).to_le_bytes());
+    expected.extend((out.len() as u32).to_le_bytes());
+    if trailer != expected {
+        return Err(DecompressError::Invalid);
+    }
+    Ok(out)
+}
+
+/// A canonical Huffman code: how many codes there are of each length, and
+/// the symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// Builds the code giving symbol `i` a code `lengths[i]` bits long,
+    /// where 0 means the symbol isn't used. Fails if the lengths ask for
+    /// more codes than fit.
+    fn new(lengths: &[u8]) -> Result<Huffman, DecompressError> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[usize::from(len)] += 1;
+        }
+        counts[0] = 0;
+
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return Err(DecompressError::Invalid);
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[usize::from(offsets[usize::from(len)])] = symbol as u16;
+                offsets[usize::from(len)] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    // Built from lengths that are known to fit.
+    let literals = Huffman::new(&lengths).unwrap();
+    let distances = Huffman::new(&[5; 30]).unwrap();
+    (literals, distances)
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position in bits.
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u32) -> Result<u32, DecompressError> {
+        let mut value = 0;
+        for k in 0..count {
+            let byte = self
+                .data
+                .get(self.pos / 8)
+                .ok_or(DecompressError::Invalid)?;
+            value |= u32::from((byte >> (self.pos % 8)) & 1) << k;
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    /// Takes `len` whole bytes, from a byte boundary.
+    fn bytes(&mut self, len: usize) -> Result<&[u8], DecompressError> {
+        let start = self.pos / 8;
+        let bytes = self
+            .data
+            .get(start..start + len)
+            .ok_or(DecompressError::Invalid)?;
+        self.pos += len * 8;
+        Ok(bytes)
+    }
+
+    /// Skips the gzip header, with whichever optional fields it has.
+    fn skip_header(&mut self) -> Result<(), DecompressError> {
+        let header = self.bytes(10)?;
+        if header[..3] != HEADER[..3] || header[3] & 0xe0 != 0 {
+            return Err(DecompressError::Invalid);
+        }
+        let flags = header[3];
+        if flags & 0x04 != 0 {
+            let len = self.bytes(2)?;
+            let len = usize::from(u16::from_le_bytes([len[0], len[1]]));
+            self.bytes(len)?;
+        }
+        for field in [0x08, 0x10] {
+            if flags & field != 0 {
+                while self.bytes(1)? != [0] {}
+            }
+        }
+        if flags & 0x02 != 0 {
+            self.bytes(2)?;
+        }
+        Ok(())
+    }
+
+    fn stored(&mut self, out: &mut Vec<u8>, limit: usize) -> Result<(), DecompressError> {
+        self.pos = self.pos.div_ceil(8) * 8;
+        let len = self.bits(16)? as usize;
+        if self.bits(16)? as usize != !len & 0xffff {
+            return Err(DecompressError::Invalid);
+        }
+        if out.len() + len > limit {
+            return Err(DecompressError::TooLarge);
+        }
+        out.extend_from_slice(self.bytes(len)?);
+        Ok(())
+    }
+
+    /// Reads the codes a dynamic block sends before its data.
+    fn dynamic_codes(&mut self) -> Result<(Huffman, Huffman), DecompressError> {
+        let literal_count = self.bits(5)? as usize + 257;
+        let distance_count = self.bits(5)? as usize + 1;
+        let length_count = self.bits(4)? as usize + 4;
+        if literal_count > 286 || distance_count > 30 {
+            return Err(DecompressError::Invalid);
+        }
+
+        let mut code_lengths = [0u8; 19];
+        for &symbol in &CODE_LENGTH_ORDER[..length_count] {
+            code_lengths[symbol] = self.bits(3)? as u8;
+        }
+        let code_lengths = Huffman::new(&code_lengths)?;
+
+        let mut lengths = Vec::with_capacity(literal_count + distance_count);
+        while lengths.len() < literal_count + distance_count {
+            let (len, repeat) = match self.decode(&code_lengths)? {
+                symbol @ 0..=15 => (symbol as u8, 1),
+                16 => {
+                    let previous = *lengths.last().ok_or(DecompressError::Invalid)?;
+                    (previous, 3 + self.bits(2)?)
+                }
+                17 => (0, 3 + self.bits(3)?),
+                _ => (0, 11 + self.bits(7)?),
+            };
+            if lengths.len() + repeat as usize > literal_count + distance_count {
+                return Err(DecompressError::Invalid);
+            }
+            lengths.extend(std::iter::repeat_n(len, repeat as usize));
+        }
+        if lengths[256] == 0 {
+            // There would be no way to end the block.
+            return Err(DecompressError::Invalid);
+        }
+
+        let (literals, distances) = lengths.split_at(literal_count);
+        Ok((Huffman::new(literals)?, Huffman::new(distances)?))
+    }
+
+    /// Decodes one symbol, reading the code a bit at a time, most
+    /// significant bit first.
+    fn decode(&mut self, huffman: &Huffman) -> Result<u16, DecompressError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &huffman.counts[1..] {
+            code |= self.bits(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return Ok(huffman.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(DecompressError::Invalid)
+    }
+
+    /// Decodes the data of a compressed block up to its end-of-block code.
+    fn codes(
+        &mut self,
+        literals: &Huffman,
+        distances: &Huffman,
+        out: &mut Vec<u8>,
+        limit: usize,
+    ) -> Result<(), DecompressError> {
+        loop {
+            let symbol = usize::from(self.decode(literals)?);
+            if symbol == 256 {
+                return Ok(());
+            }
+            if out.len() >= limit {
+                return Err(DecompressError::TooLarge);
+            }
+            if symbol < 256 {
+                out.push(symbol as u8);
+                continue;
+            }
+
+            let index = symbol - 257;
+            if index >= LENGTH_BASE.len() {
+                return Err(DecompressError::Invalid);
+            }
+            let length =
+                usize::from(LENGTH_BASE[index]) + self.bits(LENGTH_EXTRA[index].into())? as usize;
+            let code = usize::from(self.decode(distances)?);
+            if code >= DIST_BASE.len() {
+                return Err(DecompressError::Invalid);
+            }
+            let distance =
+                usize::from(DIST_BASE[code]) + self.bits(DIST_EXTRA[code].into())? as usize;
+            if distance > out.len() {
+                return Err(DecompressError::Invalid);
+            }
+            if out.len() + length > limit {
+                return Err(DecompressError::TooLarge);
+            }
+            for _ in 0..length {
+                out.push(out[out.len() - distance]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32_check_value() {
+        assert_eq!(!crc32_update(!0, b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_round_trip_compresses_repetition() {
+        let text = "the quick brown fox jumps over the lazy dog. ".repeat(200);
+
+        let compressed = compress_all(text.as_bytes());
+
+        assert!(compressed.len() < text.len() / 10);
+        assert_eq!(
+            decompress(&compressed, usize::MAX).unwrap(),
+            text.as_bytes()
+        );
+    }
+
+    /// 100 random `a`s and `b`s, compressed by zlib into a single block
+    /// with dynamic codes.
+    const DYNAMIC: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x3d, 0x8b, 0x81, 0x0d, 0x00,
+        0x30, 0x08, 0xc2, 0x6e, 0x6d, 0xff, 0x3f, 0x62, 0x4e, 0x51, 0x34, 0x44, 0x0c, 0x55, 0xac,
+        0xa5, 0xfc, 0x8b, 0x1c, 0xc8, 0xe5, 0xcc, 0xf6, 0xe8, 0x2f, 0xe1, 0x5c, 0x31, 0x6c, 0xf3,
+        0x74, 0x60, 0xea, 0xf8, 0x00, 0x16, 0x1f, 0x54, 0x12, 0x64, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_decompress_dynamic_block() {
+        let text = "bbabbabaabbbbbbaaabbbbbababbbbbaaababababaabbabaababbaaaababbababbbbbbbbbabaaabbaabbbaabaaaaabaabaab";
+
+        assert_eq!((DYNAMIC[10] >> 1) & 3, 2, "dynamic block");
+        assert_eq!(decompress(DYNAMIC, usize::MAX).unwrap(), text.as_bytes());
+        assert_eq!(decompress(DYNAMIC, 99), Err(DecompressError::TooLarge));
+
+        let mut corrupt = DYNAMIC.to_vec();
+        let last = corrupt.len() - 5;
+        corrupt[last] ^= 1;
+        assert_eq!(
+            decompress(&corrupt, usize::MAX),
+            Err(DecompressError::Invalid)
+        );
+        assert_eq!(
+            decompress(&DYNAMIC[..30], usize::MAX),
+            Err(DecompressError::Invalid)
+        );
+    }
+
+    #[test]
+    fn test_chunks_round_trip() {
+        let chunks: [&[u8]; 4] = [b"hello, ", b"", &[0, 255, 144, 143, 7], b"hello, hello!"];
+        let mut encoder = GzipEncoder::new();
+
+        let mut compressed = Vec::new();
+        for chunk in chunks {
+            let out = encoder.chunk(chunk);
+            // Each flush leaves the output on an empty stored block.
+            assert!(out.ends_with(&[0, 0, 0xff, 0xff]));
+            compressed.extend(out);
+        }
+        compressed.extend(encoder.finish());
+
+        assert_eq!(
+            decompress(&compressed, usize::MAX).unwrap(),
+            chunks.concat()
+        );
+    }
+}
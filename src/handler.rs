@@ -0,0 +1,25 @@
+use crate::{Request, Response};
+
+/// Something that turns a request into a response.
+///
+/// Closures of the form `Fn(&Request) -> Response` implement `Handler`
+/// already; implement it by hand for handlers that carry their own state,
+/// such as a shared cache or configuration, and register them with
+/// [`Router::route_handler`](crate::Router::route_handler).
+///
+/// [`Router::route`](crate::Router::route) and the like take closures
+/// directly, so their argument type is inferred. A closure passed where any
+/// `Handler` is accepted needs it annotated (`|request: &Request| ...`) so
+/// the compiler infers one that accepts a request borrowed for any lifetime.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: &Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
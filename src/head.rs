@@ -0,0 +1,439 @@
+//! Incremental parsing of a request head: the request line and the header
+//! block up to the blank line that ends it.
+//!
+//! A [`HeadParser`] is fed bytes as they arrive, in chunks of any size, and
+//! keeps the start of a line whose end hasn't arrived yet, so a head split
+//! across many reads parses the same as one that arrives whole. Bytes are
+//! only consumed once the parser has taken them, which leaves whatever
+//! follows the head, the body or the next request, for the caller.
+
+use std::{
+    io::{self, BufRead, ErrorKind},
+    mem, str,
+};
+
+use crate::{scratch, Config, HttpError, Method, Request, Version};
+
+/// Bytes of a request line allowed besides the target: room for the
+/// method, the version, the spaces between them and the line ending.
+pub(crate) const REQUEST_LINE_SLACK: usize = 32;
+
+/// The request line that opens the HTTP/2 connection preface, sent by a
+/// client that assumes the server speaks HTTP/2 over cleartext. Binary
+/// frames follow the rest of the preface.
+const HTTP2_PREFACE: &str = "PRI * HTTP/2.0";
+
+pub(crate) struct HeadParser<'c> {
+    config: &'c Config,
+    /// The request, once its request line has been parsed.
+    request: Option<Request>,
+    /// The start of a line that ended a chunk. Lines that arrive whole are
+    /// parsed straight from the chunk, so this is only filled when a line
+    /// is split between reads.
+    partial: Vec<u8>,
+    /// Header lines parsed so far.
+    headers: usize,
+}
+
+impl<'c> HeadParser<'c> {
+    /// Creates a parser that keeps split lines in `buffer`, emptied first.
+    pub(crate) fn new(config: &'c Config, mut buffer: Vec<u8>) -> HeadParser<'c> {
+        buffer.clear();
+        HeadParser {
+            config,
+            request: None,
+            partial: buffer,
+            headers: 0,
+        }
+    }
+
+    /// Gives back the buffer passed to [`new`](HeadParser::new), emptied.
+    pub(crate) fn into_buffer(mut self) -> Vec<u8> {
+        self.partial.clear();
+        self.partial
+    }
+
+    /// Parses the next bytes received. Returns how many of them were taken,
+    /// and the request once the blank line ending its head has been taken.
+    /// Bytes after that line are left untaken.
+    ///
+    /// A request line longer than `config.max_uri_length` allows is refused
+    /// with [`HttpError::UriTooLong`] as soon as that many bytes of it have
+    /// arrived, without taking any more, and a header line longer than
+    /// `config.max_header_line` likewise with [`HttpError::HeaderTooLarge`].
+    /// A head with more than `config.max_headers` header lines is refused
+    /// with [`HttpError::TooManyHeaders`] at the first line over.
+    pub(crate) fn feed(&mut self, data: &[u8]) -> Result<(usize, Option<Request>), HttpError> {
+        let mut taken = 0;
+
+        while taken < data.len() {
+            let rest = &data[taken..];
+            let Some(end) = rest.iter().position(|&b| b == b'\n') else {
+                let room = self.line_limit().saturating_sub(self.partial.len());
+                if rest.len() >= room {
+                    return Err(self.too_long());
+                }
+                self.partial.extend_from_slice(rest);
+                return Ok((data.len(), None));
+            };
+
+            let line = &rest[..=end];
+            if self.partial.len() + line.len() > self.line_limit() {
+                return Err(self.too_long());
+            }
+            taken += line.len();
+
+            let done = if self.partial.is_empty() {
+                self.line(line)?
+            } else {
+                let mut partial = std::mem::take(&mut self.partial);
+                partial.extend_from_slice(line);
+                let done = self.line(&partial);
+                partial.clear();
+                self.partial = partial;
+                done?
+            };
+            if done {
+                return Ok((taken, self.request.take()));
+            }
+        }
+
+        Ok((taken, None))
+    }
+
+    /// Ends the head when the stream ends before its blank line. A line cut
+    /// short by the end of the stream is parsed as it is.
+    pub(crate) fn finish(&mut self) -> Result<Request, HttpError> {
+        if !self.partial.is_empty() {
+            let mut partial = mem::take(&mut self.partial);
+            let parsed = self.line(&partial);
+            partial.clear();
+            self.partial = partial;
+            parsed?;
+        }
+        self.request
+            .take()
+            .ok_or_else(|| HttpError::BadRequest("empty request".to_string()))
+    }
+
+    /// Reads a head from `reader`, taking from it only the bytes that belong
+    /// to the head.
+    fn read_from<R: BufRead>(&mut self, reader: &mut R) -> Result<Request, HttpError> {
+        loop {
+            let data = match reader.fill_buf() {
+                Ok([]) => return self.finish(),
+                Ok(data) => data,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let (taken, request) = self.feed(data)?;
+            reader.consume(taken);
+            if let Some(request) = request {
+                return Ok(request);
+            }
+        }
+    }
+
+    /// Longest line accepted: the request line is limited by the longest
+    /// target an acceptable request could have, and header lines by
+    /// `config.max_header_line`.
+    fn line_limit(&self) -> usize {
+        match self.request {
+            None => self
+                .config
+                .max_uri_length
+                .saturating_add(REQUEST_LINE_SLACK),
+            Some(_) => self.config.max_header_line,
+        }
+    }
+
+    /// The error for a line over [`line_limit`](HeadParser::line_limit).
+    fn too_long(&self) -> HttpError {
+        match self.request {
+            None => HttpError::UriTooLong,
+            Some(_) => HttpError::HeaderTooLarge,
+        }
+    }
+
+    /// Parses one whole line, the request line or a header, and returns
+    /// whether it was the blank line ending the head.
+    fn line(&mut self, line: &[u8]) -> Result<bool, HttpError> {
+        let line = str::from_utf8(line).map_err(|_| {
+            io::Error::new(ErrorKind::InvalidData, "stream did not contain valid UTF-8")
+        })?;
+        check_line_ending(line, self.config)?;
+
+        let Some(request) = &mut self.request else {
+            let (method, target, version) = parse_request_line(line)?;
+            if target.len() > self.config.max_uri_length {
+                return Err(HttpError::UriTooLong);
+            }
+
+            let mut request = Request::new(Method::parse(method), target);
+            request.version = version;
+            self.request = Some(request);
+            return Ok(false);
+        };
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(true);
+        }
+
+        self.headers += 1;
+        if self.headers > self.config.max_headers {
+            return Err(HttpError::TooManyHeaders);
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| HttpError::BadRequest(format!("malformed header: {}", line)))?;
+        request.append_header(name.trim(), value.trim());
+        Ok(false)
+    }
+}
+
+/// Reads a request head from `reader` with a [`HeadParser`], taking from
+/// the reader only the bytes that belong to the head.
+///
+/// A line split between reads may end partway through a character, so the
+/// parser keeps it as bytes, in the allocation of the thread's line buffer.
+pub(crate) fn read<R: BufRead>(reader: &mut R, config: &Config) -> Result<Request, HttpError> {
+    scratch::with_line(|line| {
+        let mut parser = HeadParser::new(config, mem::take(line).into_bytes());
+        let request = parser.read_from(reader);
+        *line = String::from_utf8(parser.into_buffer()).unwrap_or_default();
+        request
+    })
+}
+
+/// Splits a request line into exactly three single-space separated tokens:
+/// method, target and version.
+///
+/// The HTTP/2 connection preface is refused with
+/// [`HttpError::VersionNotSupported`] as soon as its first line is in, so
+/// that the connection is answered with `505` and closed before the binary
+/// frames behind it are read as headers.
+fn parse_request_line(line: &str) -> Result<(&str, &str, Version), HttpError> {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    if line == HTTP2_PREFACE {
+        return Err(HttpError::VersionNotSupported);
+    }
+    let malformed = || HttpError::BadRequest(format!("malformed request line: {:?}", line));
+
+    let mut tokens = line.split(' ');
+    let (method, target, version) = match (tokens.next(), tokens.next(), tokens.next()) {
+        (Some(method), Some(target), Some(version)) if tokens.next().is_none() => {
+            (method, target, version)
+        }
+        _ => return Err(malformed()),
+    };
+
+    if method.is_empty() || !method.bytes().all(is_token_char) || target.is_empty() {
+        return Err(malformed());
+    }
+
+    Ok((method, target, Version::parse(version)?))
+}
+
+/// Refuses a line ended by a bare `\n` when `config.strict_crlf` is set.
+fn check_line_ending(line: &str, config: &Config) -> Result<(), HttpError> {
+    if config.strict_crlf && line.ends_with('\n') && !line.ends_with("\r\n") {
+        return Err(HttpError::BadRequest("bare LF line ending".to_string()));
+    }
+    Ok(())
+}
+
+/// Whether `b` may appear in a method token (RFC 9110 `tchar`).
+fn is_token_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StatusCode;
+
+    const RAW: &[u8] = "POST /upload?name=caf\u{e9} HTTP/1.1\r\n\
+                        Host: localhost\r\n\
+                        Content-Length: 4\r\n\
+                        X-Note: \u{e9}t\u{e9}\r\n\
+                        \r\n\
+                        body"
+        .as_bytes();
+
+    /// Feeds `raw` in chunks of `size` bytes, and returns the request and
+    /// how many bytes were taken in all.
+    fn feed_in_chunks(raw: &[u8], size: usize, config: &Config) -> (Request, usize) {
+        let mut parser = HeadParser::new(config, Vec::new());
+        let mut offset = 0;
+        for chunk in raw.chunks(size) {
+            let (taken, request) = parser.feed(chunk).unwrap();
+            offset += taken;
+            if let Some(request) = request {
+                return (request, offset);
+            }
+            assert_eq!(taken, chunk.len());
+        }
+        panic!("head never completed");
+    }
+
+    #[test]
+    fn test_same_head_in_any_chunking() {
+        let head_len = RAW.len() - "body".len();
+
+        for size in [1, 2, 3, 7, 16, RAW.len()] {
+            let (request, taken) = feed_in_chunks(RAW, size, &Config::default());
+
+            assert_eq!(taken, head_len, "chunks of {}", size);
+            assert_eq!(request.method, Method::Post);
+            assert_eq!(request.path, "/upload");
+            assert_eq!(request.query.as_deref(), Some("name=caf\u{e9}"));
+            assert_eq!(request.header("Host"), Some("localhost"));
+            assert_eq!(request.header("X-Note"), Some("\u{e9}t\u{e9}"));
+        }
+    }
+
+    #[test]
+    fn test_incomplete_head_waits_for_more() {
+        let config = Config::default();
+        let mut parser = HeadParser::new(&config, Vec::new());
+
+        assert_eq!(parser.feed(b"GET / HTTP/1.1\r\nHo").unwrap().0, 18);
+        assert!(parser.feed(b"st: a\r\n").unwrap().1.is_none());
+        assert!(parser.feed(b"\r").unwrap().1.is_none());
+
+        let (taken, request) = parser.feed(b"\nGET /next").unwrap();
+        assert_eq!(taken, 1);
+        assert_eq!(request.unwrap().header("Host"), Some("a"));
+    }
+
+    #[test]
+    fn test_split_request_line_respects_uri_limit() {
+        let config = Config {
+            max_uri_length: 16,
+            ..Config::default()
+        };
+
+        let fits = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(15));
+        let (request, _) = feed_in_chunks(fits.as_bytes(), 4, &config);
+        assert_eq!(request.path.len(), 16);
+
+        let mut parser = HeadParser::new(&config, Vec::new());
+        let mut result = Ok((0, None));
+        for chunk in "GET /".bytes().chain(std::iter::repeat(b'a')).take(1024) {
+            result = parser.feed(&[chunk]);
+            if result.is_err() {
+                break;
+            }
+        }
+        assert_eq!(
+            result.unwrap_err().status(),
+            StatusCode::URI_TOO_LONG,
+            "refused once the limit is reached"
+        );
+        assert_eq!(parser.partial.len(), 16 + REQUEST_LINE_SLACK - 1);
+    }
+
+    #[test]
+    fn test_split_header_line_limited() {
+        let config = Config {
+            max_header_line: 32,
+            ..Config::default()
+        };
+
+        let fits = format!("GET / HTTP/1.1\r\nX-Pad: {}\r\n\r\n", "a".repeat(23));
+        let (request, _) = feed_in_chunks(fits.as_bytes(), 4, &config);
+        assert_eq!(request.header("X-Pad").map(str::len), Some(23));
+
+        let mut parser = HeadParser::new(&config, Vec::new());
+        parser.feed(b"GET / HTTP/1.1\r\nX-Pad: ").unwrap();
+        let mut result = Ok((0, None));
+        for _ in 0..1024 {
+            result = parser.feed(b"a");
+            if result.is_err() {
+                break;
+            }
+        }
+        assert_eq!(
+            result.unwrap_err().status(),
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+        assert_eq!(parser.partial.len(), 31);
+
+        // A long line that arrives whole is refused too.
+        let long = format!("GET / HTTP/1.1\r\nX-Pad: {}\r\n\r\n", "a".repeat(24));
+        let mut parser = HeadParser::new(&config, Vec::new());
+        assert!(matches!(
+            parser.feed(long.as_bytes()),
+            Err(HttpError::HeaderTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_too_many_headers_refused() {
+        let config = Config {
+            max_headers: 3,
+            ..Config::default()
+        };
+        let head = |headers: usize| {
+            let mut head = "GET / HTTP/1.1\r\n".to_string();
+            for i in 0..headers {
+                head.push_str(&format!("X-{}: a\r\n", i));
+            }
+            head + "\r\n"
+        };
+
+        let (request, _) = feed_in_chunks(head(3).as_bytes(), 5, &config);
+        assert_eq!(request.header("X-2"), Some("a"));
+
+        // Short lines without end are refused at the first one over.
+        let mut parser = HeadParser::new(&config, Vec::new());
+        let error = parser.feed(head(1000).as_bytes()).unwrap_err();
+        assert!(matches!(error, HttpError::TooManyHeaders));
+        assert_eq!(error.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_read_keeps_split_line_in_line_buffer() {
+        // Each test runs on a thread of its own, whose buffer starts empty.
+        assert_eq!(scratch::with_line(|line| line.capacity()), 0);
+
+        // Cut off by the end of the stream, the header line is kept.
+        let mut input = &b"GET / HTTP/1.1\r\nHost: a"[..];
+        let request = read(&mut input, &Config::default()).unwrap();
+        assert_eq!(request.header("Host"), Some("a"));
+
+        // The allocation is back in place, empty, for the next head.
+        let (len, capacity) = scratch::with_line(|line| (line.len(), line.capacity()));
+        assert_eq!(len, 0);
+        assert!(capacity > 0);
+    }
+
+    #[test]
+    fn test_http2_preface_refused_at_first_line() {
+        let config = Config::default();
+        let mut parser = HeadParser::new(&config, Vec::new());
+
+        // The first line alone is refused, before the SETTINGS frame that
+        // follows the preface.
+        assert!(parser.feed(b"PRI * HTTP/2.0\r").unwrap().1.is_none());
+        assert!(matches!(
+            parser.feed(b"\n\r\nSM\r\n\r\n\x00\x00\x00\x04\x00"),
+            Err(HttpError::VersionNotSupported)
+        ));
+    }
+
+    #[test]
+    fn test_finish_at_end_of_stream() {
+        let config = Config::default();
+
+        let mut parser = HeadParser::new(&config, Vec::new());
+        assert!(matches!(parser.finish(), Err(HttpError::BadRequest(_))));
+
+        let mut parser = HeadParser::new(&config, Vec::new());
+        parser.feed(b"GET / HTTP/1.1\r\nHost: a").unwrap();
+        assert_eq!(parser.finish().unwrap().header("Host"), Some("a"));
+    }
+}
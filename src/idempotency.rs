@@ -0,0 +1,282 @@
+//! Safe retries for requests carrying an `Idempotency-Key` header: the
+//! first request with a key runs, and later ones with the same key get a
+//! copy of its response instead of running again.
+
+use std::{
+    collections::HashMap,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{Handler, Request, Response};
+
+/// Wraps a handler so that a client can retry a request without it taking
+/// effect twice, by sending the same `Idempotency-Key` header each time.
+///
+/// The first request with a key runs the handler and its response is kept
+/// for `ttl`; requests with the same key, method and path in the meantime
+/// are answered with a copy of it, marked with `Idempotent-Replayed: true`,
+/// without reaching the handler. A duplicate arriving while the first is
+/// still running waits for it to finish rather than running alongside it.
+///
+/// At most `capacity` responses are kept, the oldest being forgotten first.
+/// Responses with a body streamed from a file, a reader or an iterator
+/// can't be copied and aren't kept, so requests repeating one run again.
+/// Requests without the header go straight to the handler.
+pub struct Idempotency<H> {
+    handler: H,
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<HashMap<Key, Entry>>,
+    /// Notified whenever a request that others may be waiting on finishes.
+    finished: Condvar,
+}
+
+/// The key a request is deduplicated on: its `Idempotency-Key`, method and
+/// path, so that a key reused for another endpoint doesn't replay a
+/// response meant for the first one.
+type Key = (String, String, String);
+
+enum Entry {
+    /// The first request with the key is still running.
+    Running,
+    /// Its response, and when it was kept.
+    Done { response: Response, kept: Instant },
+}
+
+impl<H: Handler> Idempotency<H> {
+    pub fn new(handler: H, capacity: usize, ttl: Duration) -> Idempotency<H> {
+        Idempotency {
+            handler,
+            capacity,
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// Runs the handler for the first request with `key` and keeps its
+    /// response, waiting first for any request with the key still running.
+    fn respond(&self, key: Key, request: &Request) -> Response {
+        let mut entries = self.entries.lock().unwrap();
+        loop {
+            match entries.get(&key) {
+                Some(Entry::Running) => entries = self.finished.wait(entries).unwrap(),
+                Some(Entry::Done { response, kept }) if kept.elapsed() < self.ttl => {
+                    if let Some(response) = response.try_clone() {
+                        return response.header("Idempotent-Replayed", "true");
+                    }
+                }
+                _ => break,
+            }
+        }
+        entries.insert(key.clone(), Entry::Running);
+        drop(entries);
+
+        let mut running = Running {
+            idempotency: self,
+            key: Some(key),
+        };
+        let response = self.handler.handle(request);
+        running.finish(&response);
+        response
+    }
+
+    /// Forgets expired responses, then the oldest kept ones until there is
+    /// room for one more.
+    fn evict(&self, entries: &mut HashMap<Key, Entry>) {
+        entries.retain(|_, entry| match entry {
+            Entry::Running => true,
+            Entry::Done { kept, .. } => kept.elapsed() < self.ttl,
+        });
+        while entries.len() >= self.capacity {
+            let oldest = entries
+                .iter()
+                .filter_map(|(key, entry)| match entry {
+                    Entry::Running => None,
+                    Entry::Done { kept, .. } => Some((key, *kept)),
+                })
+                .min_by_key(|&(_, kept)| kept)
+                .map(|(key, _)| key.clone());
+            let Some(oldest) = oldest else {
+                break;
+            };
+            entries.remove(&oldest);
+        }
+    }
+}
+
+impl<H: Handler> Handler for Idempotency<H> {
+    fn handle(&self, request: &Request) -> Response {
+        let Some(key) = request.header("Idempotency-Key") else {
+            return self.handler.handle(request);
+        };
+        let key = (
+            key.to_string(),
+            request.method.to_string(),
+            request.path.clone(),
+        );
+        self.respond(key, request)
+    }
+}
+
+/// Marks a key as running until its response is kept. Dropped without
+/// finishing, when the handler panics, it forgets the key so that a request
+/// waiting on it runs the handler itself.
+struct Running<'a, H> {
+    idempotency: &'a Idempotency<H>,
+    key: Option<Key>,
+}
+
+impl<H: Handler> Running<'_, H> {
+    fn finish(&mut self, response: &Response) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        let mut entries = self.idempotency.entries.lock().unwrap();
+        entries.remove(&key);
+        let capacity = self.idempotency.capacity;
+        if let Some(response) = response.try_clone().filter(|_| capacity > 0) {
+            self.idempotency.evict(&mut entries);
+            let kept = Instant::now();
+            entries.insert(key, Entry::Done { response, kept });
+        }
+        self.idempotency.finished.notify_all();
+    }
+}
+
+impl<H> Drop for Running<'_, H> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            let mut entries = match self.idempotency.entries.lock() {
+                Ok(entries) => entries,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            entries.remove(&key);
+            self.idempotency.finished.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Method, StatusCode};
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc,
+        },
+        thread,
+    };
+
+    struct Counter {
+        calls: AtomicUsize,
+    }
+
+    impl Handler for Counter {
+        fn handle(&self, _request: &Request) -> Response {
+            let calls = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            Response::new(StatusCode::OK).body(calls.to_string())
+        }
+    }
+
+    fn counter(capacity: usize, ttl: Duration) -> Idempotency<Counter> {
+        Idempotency::new(
+            Counter {
+                calls: AtomicUsize::new(0),
+            },
+            capacity,
+            ttl,
+        )
+    }
+
+    fn post(key: Option<&str>) -> Request {
+        let mut request = Request::new(Method::Post, "/orders");
+        if let Some(key) = key {
+            request.insert_header("Idempotency-Key", key);
+        }
+        request
+    }
+
+    fn body(response: Response) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        out.split_once("\r\n\r\n").unwrap().1.to_string()
+    }
+
+    #[test]
+    fn test_repeated_key_replays_response() {
+        let orders = counter(16, Duration::from_secs(60));
+
+        let first = orders.handle(&post(Some("a")));
+        assert_eq!(first.header_value("Idempotent-Replayed"), None);
+        assert_eq!(body(first), "1");
+
+        let replay = orders.handle(&post(Some("a")));
+        assert_eq!(replay.status(), StatusCode::OK);
+        assert_eq!(replay.header_value("Idempotent-Replayed"), Some("true"));
+        assert_eq!(body(replay), "1");
+
+        assert_eq!(body(orders.handle(&post(Some("b")))), "2");
+        assert_eq!(body(orders.handle(&post(None))), "3");
+        assert_eq!(body(orders.handle(&post(None))), "4");
+    }
+
+    #[test]
+    fn test_concurrent_duplicates_wait_for_first() {
+        let (started, wait_started) = mpsc::channel();
+        let (release, wait_release) = mpsc::channel::<()>();
+        let (started, wait_release) = (Mutex::new(started), Mutex::new(wait_release));
+        let slow = Idempotency::new(
+            move |_: &Request| {
+                started.lock().unwrap().send(()).unwrap();
+                wait_release.lock().unwrap().recv().unwrap();
+                Response::new(StatusCode::OK).body("done")
+            },
+            16,
+            Duration::from_secs(60),
+        );
+
+        let bodies: Vec<String> = thread::scope(|scope| {
+            let first = scope.spawn(|| body(slow.handle(&post(Some("a")))));
+            wait_started.recv().unwrap();
+            let duplicates: Vec<_> = (0..3)
+                .map(|_| scope.spawn(|| body(slow.handle(&post(Some("a"))))))
+                .collect();
+            release.send(()).unwrap();
+
+            std::iter::once(first)
+                .chain(duplicates)
+                .map(|thread| thread.join().unwrap())
+                .collect()
+        });
+
+        assert_eq!(bodies, ["done"; 4]);
+        assert!(wait_started.try_recv().is_err(), "handler ran again");
+    }
+
+    #[test]
+    fn test_expired_response_runs_again() {
+        let orders = counter(16, Duration::from_millis(20));
+
+        assert_eq!(body(orders.handle(&post(Some("a")))), "1");
+        assert_eq!(body(orders.handle(&post(Some("a")))), "1");
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(body(orders.handle(&post(Some("a")))), "2");
+    }
+
+    #[test]
+    fn test_oldest_response_forgotten_at_capacity() {
+        let orders = counter(2, Duration::from_secs(60));
+
+        for key in ["a", "b", "c"] {
+            orders.handle(&post(Some(key)));
+        }
+
+        assert_eq!(orders.entries.lock().unwrap().len(), 2);
+        assert_eq!(body(orders.handle(&post(Some("c")))), "3");
+        assert_eq!(body(orders.handle(&post(Some("a")))), "4");
+    }
+}
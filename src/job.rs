@@ -0,0 +1,262 @@
+use std::{
+    any::Any,
+    cell::Cell,
+    error, fmt,
+    mem::{self, ManuallyDrop, MaybeUninit},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+thread_local! {
+    /// Id of the pool worker running on this thread, if it is one.
+    static WORKER_ID: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Marks the calling thread as the pool worker `id`.
+pub(crate) fn set_worker_id(id: usize) {
+    WORKER_ID.with(|worker| worker.set(Some(id)));
+}
+
+/// Id of the pool worker running the calling thread, or `None` outside the
+/// pool.
+pub(crate) fn worker_id() -> Option<usize> {
+    WORKER_ID.with(Cell::get)
+}
+
+/// Inline storage for a job's closure: four machine words.
+type Storage = MaybeUninit<[usize; 4]>;
+
+/// A type-erased `FnOnce() + Send` closure queued on the pool.
+///
+/// Closures that fit in [`Storage`] (which covers the usual handful of
+/// captured `Arc`s and a stream) are stored inline, so queueing them does not
+/// allocate. Larger or over-aligned closures are boxed and the box pointer is
+/// stored inline instead. Each `Job` owns its storage outright, so nothing is
+/// shared or reused between jobs.
+pub(crate) struct Job {
+    storage: Storage,
+    call: unsafe fn(*mut u8),
+    drop: unsafe fn(*mut u8),
+}
+
+// SAFETY: `Job::new` only accepts `Send` closures.
+unsafe impl Send for Job {}
+
+impl Job {
+    pub(crate) fn new<F>(f: F) -> Job
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if fits_inline::<F>() {
+            Job::inline(f)
+        } else {
+            Job::inline(Box::new(f))
+        }
+    }
+
+    fn inline<F>(f: F) -> Job
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        assert!(fits_inline::<F>());
+
+        let mut storage = Storage::uninit();
+        // SAFETY: `F` fits in `storage` in both size and alignment.
+        unsafe { storage.as_mut_ptr().cast::<F>().write(f) };
+
+        Job {
+            storage,
+            call: call_inline::<F>,
+            drop: drop_inline::<F>,
+        }
+    }
+
+    pub(crate) fn run(self) {
+        let mut job = ManuallyDrop::new(self);
+        // SAFETY: `storage` holds an initialized `F` matching `call`; wrapping
+        // the job in `ManuallyDrop` stops `Drop` from dropping it a second time.
+        unsafe { (job.call)(job.storage.as_mut_ptr().cast()) }
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        // SAFETY: a job that is dropped was never run, so `storage` still
+        // holds an initialized `F` matching `drop`.
+        unsafe { (self.drop)(self.storage.as_mut_ptr().cast()) }
+    }
+}
+
+fn fits_inline<F>() -> bool {
+    mem::size_of::<F>() <= mem::size_of::<Storage>()
+        && mem::align_of::<F>() <= mem::align_of::<Storage>()
+}
+
+unsafe fn call_inline<F: FnOnce()>(ptr: *mut u8) {
+    let f = ptr.cast::<F>().read();
+    f()
+}
+
+unsafe fn drop_inline<F>(ptr: *mut u8) {
+    ptr.cast::<F>().drop_in_place()
+}
+
+/// Why a submitted job did not produce a value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobError {
+    /// The job panicked; carries the panic message when it was a string.
+    Panicked(String),
+    /// The job did not finish within the requested timeout.
+    TimedOut,
+    /// The job was dropped without running, e.g. because the pool shut down.
+    Cancelled,
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Panicked(message) => write!(f, "job panicked: {}", message),
+            JobError::TimedOut => write!(f, "job timed out"),
+            JobError::Cancelled => write!(f, "job was cancelled"),
+        }
+    }
+}
+
+impl error::Error for JobError {}
+
+/// Returned by [`ThreadPool::set_size`] once the pool is shut down.
+///
+/// [`ThreadPool::set_size`]: crate::ThreadPool::set_size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutDown;
+
+impl fmt::Display for ShutDown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("pool is shut down")
+    }
+}
+
+impl error::Error for ShutDown {}
+
+/// A handle to the result of a job submitted with [`ThreadPool::submit`].
+///
+/// [`ThreadPool::submit`]: crate::ThreadPool::submit
+pub struct JobHandle<T> {
+    pub(crate) receiver: mpsc::Receiver<thread::Result<T>>,
+    /// Filled in by the worker that picks the job up, as it starts it.
+    pub(crate) worker: Arc<OnceLock<usize>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Id of the worker running or that ran the job, as listed by
+    /// [`ThreadPool::worker_ids`](crate::ThreadPool::worker_ids), or `None`
+    /// while the job is still queued.
+    pub fn worker_id(&self) -> Option<usize> {
+        self.worker.get().copied()
+    }
+
+    /// Blocks until the job finishes and returns its result.
+    pub fn join(self) -> Result<T, JobError> {
+        match self.receiver.recv() {
+            Ok(result) => result.map_err(panic_error),
+            Err(_) => Err(JobError::Cancelled),
+        }
+    }
+
+    /// Like [`join`](JobHandle::join), but gives up after `timeout`.
+    ///
+    /// Giving up does not stop the job: it keeps running on its worker and
+    /// its result is discarded when it finishes.
+    pub fn join_timeout(self, timeout: Duration) -> Result<T, JobError> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(result) => result.map_err(panic_error),
+            Err(RecvTimeoutError::Timeout) => Err(JobError::TimedOut),
+            Err(RecvTimeoutError::Disconnected) => Err(JobError::Cancelled),
+        }
+    }
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
+
+fn panic_error(payload: Box<dyn Any + Send>) -> JobError {
+    JobError::Panicked(panic_message(&*payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn test_small_job_runs_inline() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let captured = Arc::clone(&counter);
+
+        Job::new(move || {
+            captured.fetch_add(1, Ordering::SeqCst);
+        })
+        .run();
+
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        assert_eq!(Arc::strong_count(&counter), 1);
+    }
+
+    #[test]
+    fn test_large_job_runs_boxed() {
+        let data = [7u64; 32];
+        assert!(!fits_inline::<[u64; 32]>());
+
+        let (sender, receiver) = mpsc::channel();
+        Job::new(move || sender.send(data.iter().sum::<u64>()).unwrap()).run();
+
+        assert_eq!(receiver.recv(), Ok(224));
+    }
+
+    #[test]
+    fn test_unrun_job_drops_captures_once() {
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        let small = Arc::clone(&counter);
+        drop(Job::new(move || drop(small)));
+
+        let large = Arc::clone(&counter);
+        let padding = [0u64; 32];
+        drop(Job::new(move || drop((large, padding))));
+
+        assert_eq!(Arc::strong_count(&counter), 1);
+    }
+
+    #[test]
+    fn test_jobs_do_not_share_storage() {
+        let outputs: Vec<usize> = (0..8)
+            .map(|i| {
+                let (sender, receiver) = mpsc::channel();
+                let job = Job::new(move || sender.send(i * 10).unwrap());
+                (job, receiver)
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|(job, receiver)| {
+                job.run();
+                receiver.recv().unwrap()
+            })
+            .collect();
+
+        assert_eq!(outputs, vec![0, 10, 20, 30, 40, 50, 60, 70]);
+    }
+}
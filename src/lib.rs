@@ -1,92 +1,809 @@
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, OnceLock,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
+mod accept;
+mod batch;
+mod body_log;
+mod body_stream;
+mod cancel;
+mod capture;
+mod config;
+mod connection;
+mod content_type;
+mod error;
+mod gzip;
+mod handler;
+mod head;
+mod idempotency;
+mod job;
+mod limit_log;
+mod listener;
+mod log_sink;
+mod metrics;
+mod multipart;
+mod pool_metrics;
+mod precondition;
+mod priority;
+mod proxy;
+mod queue;
+mod range;
+mod reaper;
+mod reload;
+mod request;
+mod response;
+mod router;
+mod scope;
+mod scratch;
+mod semaphore;
+mod sendfile;
+mod server;
+mod shutdown;
+mod sse;
+mod static_files;
+mod status;
+mod task;
+mod template;
+mod trace;
+mod websocket;
+
+pub use batch::PanicInfo;
+pub use body_stream::BodyReader;
+pub use cancel::CancellationToken;
+pub use capture::{Exchange, RequestCapture};
+pub use config::Config;
+pub use connection::{handle_connection, reject_draining, ConnectionInfo, Stream};
+pub use content_type::ContentType;
+pub use error::HttpError;
+pub use handler::Handler;
+pub use idempotency::Idempotency;
+use job::Job;
+pub use job::{JobError, JobHandle, ShutDown};
+pub use log_sink::LogSink;
+pub use metrics::{CloseReason, LatencyHistogram, Metrics, RejectReason};
+pub use multipart::Part;
+use pool_metrics::{JobCounters, JobOutcome};
+pub use pool_metrics::{PoolDiagnostics, PoolMetrics, WorkerDiagnostics, WorkerState};
+pub use priority::{Fairness, Priority};
+pub use proxy::{InvalidIpNet, IpNet};
+pub use queue::OverflowPolicy;
+use queue::Sent;
+use reaper::{Reaper, RunningJobs};
+pub use reload::reload_on_sighup;
+pub use request::{Method, Request, Version};
+pub use response::Response;
+pub use router::{Route, Router, TrailingSlash};
+pub use scope::Scope;
+pub use semaphore::Full;
+use semaphore::{Permit, Semaphore};
+pub use server::{Readiness, Server};
+pub use shutdown::ShutdownHandle;
+pub use sse::Event;
+pub use static_files::StaticFiles;
+pub use status::StatusCode;
+use task::Task;
+pub use template::Templates;
+pub use websocket::{Upgraded, WebSocketMessage, WebSocketStream};
+
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Message>>,
+    sender: Option<queue::Sender<Message>>,
+    /// Kept so that workers added by [`set_size`](ThreadPool::set_size) can
+    /// share the queue.
+    receiver: queue::Receiver<Message>,
+    next_id: usize,
+    token: CancellationToken,
+    running: RunningJobs,
+    counters: Arc<JobCounters>,
+    reaper: Option<Reaper>,
+    /// Permits for jobs queued or running, when their number is limited.
+    in_flight: Option<Arc<Semaphore>>,
+    /// Runs jobs from [`spawn_blocking_with_result`](ThreadPool::spawn_blocking_with_result),
+    /// started with `blocking_size` workers the first time one is queued.
+    blocking: OnceLock<Box<ThreadPool>>,
+    blocking_size: usize,
+    on_submit: Option<SubmitHook>,
+    on_dequeue: Option<DequeueHook>,
+    /// Most jobs left waiting for a worker, and what happens past it.
+    bounded: Option<(usize, OverflowPolicy)>,
+}
+
+/// Called with the queue depth each time a job is queued.
+type SubmitHook = Arc<dyn Fn(usize) + Send + Sync>;
+/// Called with how long a job waited each time a worker picks one up.
+type DequeueHook = Arc<dyn Fn(Duration) + Send + Sync>;
+
+/// Configures a [`ThreadPool`] before starting it, for settings beyond the
+/// worker count that [`ThreadPool::new`] takes.
+pub struct ThreadPoolBuilder {
+    size: usize,
+    max_in_flight: Option<usize>,
+    fairness: Fairness,
+    blocking_size: Option<usize>,
+    on_submit: Option<SubmitHook>,
+    on_dequeue: Option<DequeueHook>,
+    slow_start: Duration,
+    bounded: Option<(usize, OverflowPolicy)>,
 }
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+impl ThreadPoolBuilder {
+    pub fn new(size: usize) -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            size,
+            max_in_flight: None,
+            fairness: Fairness::Strict,
+            blocking_size: None,
+            on_submit: None,
+            on_dequeue: None,
+            slow_start: Duration::ZERO,
+            bounded: None,
+        }
+    }
+
+    /// Limits the jobs queued or running at once to `max`, however many
+    /// workers there are, for jobs that each hold a scarce resource such as
+    /// a database connection. Past the limit,
+    /// [`execute`](ThreadPool::execute) waits for a job to finish and
+    /// [`try_execute`](ThreadPool::try_execute) fails with [`Full`].
+    ///
+    /// A job that queues another job and waits for it can deadlock once the
+    /// limit is reached.
+    pub fn max_in_flight(mut self, max: usize) -> ThreadPoolBuilder {
+        assert!(max > 0);
+        self.max_in_flight = Some(max);
+        self
+    }
+
+    /// Sets how job priorities are weighed against how long jobs have
+    /// waited. The default, [`Fairness::Strict`], always runs
+    /// higher-priority jobs first.
+    pub fn fairness(mut self, fairness: Fairness) -> ThreadPoolBuilder {
+        self.fairness = fairness;
+        self
+    }
+
+    /// Sets how many workers run jobs queued with
+    /// [`spawn_blocking_with_result`](ThreadPool::spawn_blocking_with_result),
+    /// as many as the pool has by default.
+    pub fn blocking_pool_size(mut self, size: usize) -> ThreadPoolBuilder {
+        assert!(size > 0);
+        self.blocking_size = Some(size);
+        self
+    }
+
+    /// Calls `hook` each time a job is queued, with how many jobs are then
+    /// waiting for a worker, for recording queue depth without tying the
+    /// pool to a metrics backend. It runs on the thread queueing the job,
+    /// so it should be quick.
+    pub fn on_submit<F>(mut self, hook: F) -> ThreadPoolBuilder
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.on_submit = Some(Arc::new(hook));
+        self
+    }
+
+    /// Calls `hook` each time a worker picks up a queued job, with how long
+    /// the job waited in the queue. It runs on the worker just before the
+    /// job, so it should be quick. Jobs discarded before a worker gets to
+    /// them, and the polls of [`spawn_future`](ThreadPool::spawn_future)
+    /// futures, don't call it.
+    pub fn on_dequeue<F>(mut self, hook: F) -> ThreadPoolBuilder
+    where
+        F: Fn(Duration) + Send + Sync + 'static,
+    {
+        self.on_dequeue = Some(Arc::new(hook));
+        self
+    }
+
+    /// Limits the jobs waiting for a worker to `capacity`, with `policy`
+    /// deciding what happens to a job queued once that many are waiting.
+    /// Unlike [`max_in_flight`](ThreadPoolBuilder::max_in_flight), jobs
+    /// already running don't count. The polls of
+    /// [`spawn_future`](ThreadPool::spawn_future) futures are always queued.
+    pub fn bounded_queue(mut self, capacity: usize, policy: OverflowPolicy) -> ThreadPoolBuilder {
+        assert!(capacity > 0);
+        self.bounded = Some((capacity, policy));
+        self
+    }
+
+    /// Staggers the workers' start over `ramp`, so that a pool started cold
+    /// doesn't send its full load to downstream services at once: worker
+    /// `id` of `size` waits `ramp * id / size` before taking its first job,
+    /// so only the first worker takes jobs straight away. Workers added
+    /// later by [`set_size`](ThreadPool::set_size) start at once.
+    ///
+    /// A worker still waiting to start only sees a shutdown once it has,
+    /// so shutting down during the ramp can take up to `ramp`.
+    pub fn slow_start(mut self, ramp: Duration) -> ThreadPoolBuilder {
+        self.slow_start = ramp;
+        self
+    }
+
+    pub fn build(self) -> ThreadPool {
+        assert!(self.size > 0);
+
+        let (sender, receiver) = queue::with_fairness(self.fairness);
+        let running = RunningJobs::default();
+        let counters = Arc::new(JobCounters::default());
+
+        let mut workers = Vec::with_capacity(self.size);
+
+        for id in 0..self.size {
+            workers.push(Worker::new(
+                id,
+                receiver.clone(),
+                running.clone(),
+                Arc::clone(&counters),
+                self.slow_start * id as u32 / self.size as u32,
+            ));
+        }
+
+        ThreadPool {
+            workers,
+            sender: Some(sender),
+            receiver,
+            next_id: self.size,
+            token: CancellationToken::new(),
+            running,
+            counters,
+            reaper: None,
+            in_flight: self.max_in_flight.map(Semaphore::new),
+            blocking: OnceLock::new(),
+            blocking_size: self.blocking_size.unwrap_or(self.size),
+            on_submit: self.on_submit,
+            on_dequeue: self.on_dequeue,
+            bounded: self.bounded,
+        }
+    }
+}
 
 enum Message {
-    NewJob(Job),
+    /// A job to run, with the name it was queued under, if any.
+    NewJob(Job, Option<String>),
     Terminate,
+    /// Like `Terminate`, but the worker reports its id on the channel before
+    /// exiting so that the pool knows which worker left.
+    Retire(mpsc::Sender<usize>),
 }
 
 impl ThreadPool {
     pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
+        ThreadPoolBuilder::new(size).build()
+    }
 
-        let (sender, receiver) = mpsc::channel();
+    /// Starts configuring a pool of `size` workers.
+    pub fn builder(size: usize) -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new(size)
+    }
 
-        let receiver = Arc::new(Mutex::new(receiver));
+    /// Number of workers currently in the pool.
+    pub fn current_size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Ids of the workers currently in the pool. Ids are never reused, so
+    /// workers added after a shrink get fresh ones.
+    pub fn worker_ids(&self) -> Vec<usize> {
+        self.workers.iter().map(|worker| worker.id).collect()
+    }
 
-        let mut workers = Vec::with_capacity(size);
+    /// Number of jobs queued and waiting for a free worker.
+    pub fn queued_jobs(&self) -> usize {
+        self.sender.as_ref().map_or(0, queue::Sender::len)
+    }
 
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+    /// Reads the pool's queue length, running and finished job counts and
+    /// size in one go, for dashboards.
+    pub fn metrics_snapshot(&self) -> PoolMetrics {
+        PoolMetrics {
+            queued: self.queued_jobs(),
+            active: self.running.len(),
+            completed: self.counters.completed(),
+            panicked: self.counters.panicked(),
+            current_size: self.current_size(),
+            worker_completed: self
+                .workers
+                .iter()
+                .map(|worker| (worker.id, worker.completed.load(Ordering::Relaxed)))
+                .collect(),
         }
+    }
 
-        ThreadPool {
+    /// Reports whether each worker is idle or busy, and with which job for
+    /// how long, along with the queue length: a dump for working out why a
+    /// pool has stopped making progress. Each worker is read on its own, so
+    /// the report isn't one instant across the pool.
+    pub fn diagnostics(&self) -> PoolDiagnostics {
+        let mut workers: Vec<_> = self
+            .workers
+            .iter()
+            .map(|worker| WorkerDiagnostics {
+                id: worker.id,
+                state: match self.running.current(worker.id) {
+                    Some((name, running_for)) => WorkerState::Busy { name, running_for },
+                    None => WorkerState::Idle,
+                },
+            })
+            .collect();
+        workers.sort_by_key(|worker| worker.id);
+
+        PoolDiagnostics {
+            queued: self.queued_jobs(),
             workers,
-            sender: Some(sender),
         }
     }
 
+    /// Grows or shrinks the pool to `size` workers, first replacing any
+    /// that died from a panicking job.
+    ///
+    /// Shrinking sends a retirement notice for each surplus worker ahead of
+    /// any queued jobs, and blocks until that many workers have picked one
+    /// up and exited, which takes as long as the surplus workers' current
+    /// jobs; which workers leave depends on which are free first. A worker
+    /// dying meanwhile counts towards the surplus. Fails with [`ShutDown`]
+    /// once the pool is shut down.
+    pub fn set_size(&mut self, size: usize) -> Result<(), ShutDown> {
+        assert!(size > 0);
+
+        let Some(sender) = self.sender.clone() else {
+            return Err(ShutDown);
+        };
+        self.reap_dead_workers();
+        self.grow_to(size);
+
+        let surplus = self.workers.len() - size;
+        let (retired, wait_retired) = mpsc::channel();
+        for _ in 0..surplus {
+            sender
+                .send_urgent(Message::Retire(retired.clone()))
+                .map_err(|_| ShutDown)?;
+        }
+        drop(retired);
+
+        let mut reported = 0;
+        while self.workers.len() > size {
+            match wait_retired.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+                Ok(id) => {
+                    reported += 1;
+                    // A worker reaped as dead in the meantime isn't tracked.
+                    if let Some(index) = self.workers.iter().position(|worker| worker.id == id) {
+                        self.workers.remove(index).join();
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => self.reap_dead_workers(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        // Workers that died left notices behind, which would retire more
+        // workers later; any already picked up are waited for instead.
+        let withdrawn = sender
+            .remove_where(|message| matches!(message, Message::Retire(_)))
+            .len();
+        for id in wait_retired.iter().take(surplus - reported - withdrawn) {
+            if let Some(index) = self.workers.iter().position(|worker| worker.id == id) {
+                self.workers.remove(index).join();
+            }
+        }
+        self.grow_to(size);
+        Ok(())
+    }
+
+    /// Starts workers until there are `size`.
+    fn grow_to(&mut self, size: usize) {
+        while self.workers.len() < size {
+            self.workers.push(Worker::new(
+                self.next_id,
+                self.receiver.clone(),
+                self.running.clone(),
+                Arc::clone(&self.counters),
+                Duration::ZERO,
+            ));
+            self.next_id += 1;
+        }
+    }
+
+    /// Forgets workers whose thread has exited, logging any panic.
+    fn reap_dead_workers(&mut self) {
+        self.workers.retain_mut(|worker| {
+            if worker.is_finished() {
+                worker.join();
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Queues `f` to run on the next free worker. With
+    /// [`max_in_flight`](ThreadPoolBuilder::max_in_flight) set, first waits
+    /// until fewer than that many jobs are queued or running.
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(move || {
-            let _ = f();
+        let permit = self.in_flight.as_ref().map(Semaphore::acquire);
+        self.send_job(holding(permit, f), None);
+    }
+
+    /// Like [`execute`](ThreadPool::execute), but fails with [`Full`]
+    /// instead of waiting when
+    /// [`max_in_flight`](ThreadPoolBuilder::max_in_flight) jobs are already
+    /// queued or running. A queue bounded with
+    /// [`bounded_queue`](ThreadPoolBuilder::bounded_queue) that is full is
+    /// handled by its [`OverflowPolicy`], except that
+    /// [`OverflowPolicy::Block`] fails with [`Full`] rather than wait. So
+    /// `Ok` means `f` was queued, though under
+    /// [`OverflowPolicy::DropOldest`] it can still be pushed out by a later
+    /// job.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), Full>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let permit = match &self.in_flight {
+            Some(in_flight) => Some(in_flight.try_acquire().ok_or(Full)?),
+            None => None,
+        };
+        let policy = match self.bounded {
+            Some((_, OverflowPolicy::Block)) | None => OverflowPolicy::Reject,
+            Some((_, policy)) => policy,
+        };
+        self.queue_job(holding(permit, f), None, Priority::Normal, policy)
+    }
+
+    /// Like [`execute`](ThreadPool::execute), but names the job so that it
+    /// can be told apart in logs such as the slow-job reports of
+    /// [`reap_slow_jobs`](ThreadPool::reap_slow_jobs).
+    pub fn execute_named<F>(&self, name: impl Into<String>, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let permit = self.in_flight.as_ref().map(Semaphore::acquire);
+        self.send_job(holding(permit, f), Some(name.into()));
+    }
+
+    /// Like [`execute`](ThreadPool::execute), but queues the job with
+    /// `priority`: free workers take higher-priority jobs first, and jobs
+    /// of one priority in the order they were queued. Under
+    /// [`Fairness::Aging`] a job waiting long enough is promoted.
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let permit = self.in_flight.as_ref().map(Semaphore::acquire);
+        self.send_job_with_priority(holding(permit, f), None, priority);
+    }
+
+    fn send_job(&self, job: Job, name: Option<String>) {
+        self.send_job_with_priority(job, name, Priority::Normal);
+    }
+
+    fn send_job_with_priority(&self, job: Job, name: Option<String>, priority: Priority) {
+        let policy = self
+            .bounded
+            .map_or(OverflowPolicy::Block, |(_, policy)| policy);
+        if self.queue_job(job, name, priority, policy).is_err() && policy == OverflowPolicy::Reject
+        {
+            eprintln!("Error sending job: queue is full");
+        }
+    }
+
+    /// Queues `job`, applying `policy` rather than the pool's own if the
+    /// queue is bounded and full. Fails with [`Full`] if the job was
+    /// dropped for lack of room.
+    fn queue_job(
+        &self,
+        job: Job,
+        name: Option<String>,
+        priority: Priority,
+        policy: OverflowPolicy,
+    ) -> Result<(), Full> {
+        let Some(sender) = self.sender.as_ref() else {
+            eprintln!("Error sending job: pool is shut down");
+            return Ok(());
+        };
+
+        let job = match &self.on_dequeue {
+            Some(on_dequeue) => {
+                let on_dequeue = Arc::clone(on_dequeue);
+                let queued = Instant::now();
+                Job::new(move || {
+                    on_dequeue(queued.elapsed());
+                    job.run();
+                })
+            }
+            None => job,
+        };
+
+        let message = Message::NewJob(job, name);
+        let sent = match self.bounded {
+            Some((capacity, _)) => sender.send_bounded(message, priority, capacity, policy, |m| {
+                matches!(m, Message::NewJob(..))
+            }),
+            None => sender
+                .send_with_priority(message, priority)
+                .map(|()| Sent::Queued(None)),
+        };
+        // A job dropped here is dropped without the queue's lock held, since
+        // dropping it may run arbitrary code.
+        match sent {
+            Ok(Sent::Queued(_evicted)) => {}
+            Ok(Sent::Full(_refused)) => return Err(Full),
+            Err(e) => {
+                eprintln!("Error sending job: {}", e);
+                return Ok(());
+            }
+        }
+        if let Some(on_submit) = &self.on_submit {
+            on_submit(sender.len());
+        }
+        Ok(())
+    }
+
+    /// Like [`execute`](ThreadPool::execute), but hands the job the pool's
+    /// [`CancellationToken`], which is cancelled when the pool shuts down so
+    /// that long-running jobs can bail out early.
+    pub fn execute_cancellable<F>(&self, f: F)
+    where
+        F: FnOnce(CancellationToken) + Send + 'static,
+    {
+        let token = self.token.clone();
+        self.execute(move || f(token));
+    }
+
+    /// Runs `future` to completion on the pool, polling it on whichever
+    /// worker is free each time its waker is called. Suits futures that
+    /// mostly wait on something else; a future that blocks while being
+    /// polled ties up its worker like any other job.
+    pub fn spawn_future<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let Some(sender) = self.sender.as_ref() else {
+            eprintln!("Error spawning future: pool is shut down");
+            return;
+        };
+
+        Task::spawn(future, sender.clone());
+    }
+
+    /// Runs `f` on the pool and returns a handle to its result.
+    ///
+    /// A panic inside `f` is caught and reported through the handle instead
+    /// of taking down the worker.
+    pub fn submit<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let worker = Arc::new(OnceLock::new());
+        let started = Arc::clone(&worker);
+
+        self.execute(move || {
+            if let Some(id) = job::worker_id() {
+                let _ = started.set(id);
+            }
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            let _ = sender.send(result);
         });
 
-        if let Err(e) = self.sender.as_ref().unwrap().send(Message::NewJob(job)) {
-            eprintln!("Error sending job: {}", e);
+        JobHandle { receiver, worker }
+    }
+
+    /// Runs `f` on the pool and waits for its result, as
+    /// `submit(f).join()` does. Fails with [`JobError::Panicked`] if `f`
+    /// panics and [`JobError::Cancelled`] if the pool is shut down.
+    ///
+    /// Called from one of the pool's own jobs it can deadlock, since the
+    /// job waits for a worker that may never come free.
+    pub fn run<F, T>(&self, f: F) -> Result<T, JobError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.submit(f).join()
+    }
+
+    /// Like [`submit`](ThreadPool::submit), but runs `f` on a separate pool
+    /// of [`blocking_pool_size`](ThreadPoolBuilder::blocking_pool_size)
+    /// workers, for work that blocks for a long time such as file IO or a
+    /// database query. However many such jobs are waiting, they can't
+    /// occupy this pool's workers and hold back its other jobs.
+    ///
+    /// The separate pool is started the first time it is needed, and shut
+    /// down after this one.
+    pub fn spawn_blocking_with_result<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        if self.sender.is_none() {
+            // Refused like any other job, so the handle reports it cancelled.
+            return self.submit(f);
         }
+
+        self.blocking
+            .get_or_init(|| Box::new(ThreadPool::new(self.blocking_size)))
+            .submit(f)
     }
-}
 
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        for _ in &self.workers {
-            self.sender
-                .as_ref()
-                .unwrap()
-                .send(Message::Terminate)
-                .unwrap();
+    /// Discards every job that is queued but hasn't started, without running
+    /// it, and returns how many were discarded. Jobs already running are
+    /// left to finish, and handles to discarded jobs report
+    /// [`JobError::Cancelled`].
+    pub fn drain_queue(&self) -> usize {
+        let Some(sender) = self.sender.as_ref() else {
+            return 0;
+        };
+
+        sender
+            .remove_where(|message| matches!(message, Message::NewJob(..)))
+            .len()
+    }
+
+    /// Starts a thread that watches for jobs running longer than
+    /// `threshold`, logging each one, with its name if it was queued with
+    /// [`execute_named`](ThreadPool::execute_named), and counting it in
+    /// [`Metrics::job_timeouts`]. Slow jobs are only observed, never
+    /// stopped, and each is counted once. Replaces any reaper started
+    /// before; the reaper stops when the pool shuts down.
+    pub fn reap_slow_jobs(&mut self, threshold: Duration, metrics: Arc<Metrics>) {
+        if self.sender.is_none() {
+            return;
+        }
+
+        self.reaper = None;
+        self.reaper = Some(Reaper::spawn(self.running.clone(), threshold, metrics));
+    }
+
+    /// Cancels the pool's [`CancellationToken`], lets the jobs already queued
+    /// run, and waits for every worker to exit. Jobs queued afterwards are
+    /// dropped. Called automatically when the pool is dropped.
+    pub fn shutdown(&mut self) {
+        self.terminate();
+        self.sender = None;
+
+        for worker in &mut self.workers {
+            if worker.thread.is_some() {
+                println!("Shutting down worker {}", worker.id);
+            }
+            worker.join();
+        }
+
+        // Jobs still running while the workers finish up are watched to the end.
+        self.reaper = None;
+
+        if let Some(blocking) = self.blocking.get_mut() {
+            blocking.shutdown();
+        }
+    }
+
+    /// Like [`shutdown`](ThreadPool::shutdown), but waits at most `timeout`
+    /// for the queued jobs to run. Jobs still queued by then are discarded,
+    /// and workers still running a job are abandoned: their threads are
+    /// left to finish on their own and are never joined. Returns how many
+    /// jobs were discarded or left running.
+    pub fn shutdown_with_timeout(&mut self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        self.terminate();
+
+        while !self.workers.iter().all(Worker::is_finished) && Instant::now() < deadline {
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
         }
+        let mut dropped = self.drain_queue() + self.running.len();
+        self.sender = None;
 
         for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+            if worker.is_finished() {
+                worker.join();
+            } else if worker.thread.take().is_some() {
+                println!("Abandoning worker {}", worker.id);
+            }
+        }
+        self.reaper = None;
 
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+        if let Some(blocking) = self.blocking.get_mut() {
+            dropped +=
+                blocking.shutdown_with_timeout(deadline.saturating_duration_since(Instant::now()));
+        }
+        dropped
+    }
+
+    /// Cancels the pool's token and queues one [`Message::Terminate`] per
+    /// worker, behind every job already queued.
+    fn terminate(&mut self) {
+        self.token.cancel();
+
+        if let Some(sender) = &self.sender {
+            for _ in &self.workers {
+                sender.send_barrier(Message::Terminate).unwrap();
             }
         }
     }
 }
 
+/// How often [`ThreadPool::shutdown_with_timeout`] checks whether every
+/// worker has exited.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Wraps `f` in a job that keeps `permit` until it has run, or until it is
+/// dropped without running.
+fn holding<F>(permit: Option<Permit>, f: F) -> Job
+where
+    F: FnOnce() + Send + 'static,
+{
+    match permit {
+        Some(permit) => Job::new(move || {
+            let _permit = permit;
+            f();
+        }),
+        None => Job::new(f),
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
 struct Worker {
     id: usize,
     thread: Option<thread::JoinHandle<()>>,
+    /// Jobs this worker has run to completion.
+    completed: Arc<AtomicU64>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
-
-            match message {
-                Message::NewJob(job) => {
-                    println!("Worker {id} got a job; executing.");
-                    job();
-                }
-                Message::Terminate => {
-                    println!("Worker {} was told to terminate.", id);
-                    break;
+    fn new(
+        id: usize,
+        receiver: queue::Receiver<Message>,
+        running: RunningJobs,
+        counters: Arc<JobCounters>,
+        start_after: Duration,
+    ) -> Worker {
+        let completed = Arc::new(AtomicU64::new(0));
+        let worker_completed = Arc::clone(&completed);
+        let thread = thread::spawn(move || {
+            job::set_worker_id(id);
+            if !start_after.is_zero() {
+                thread::sleep(start_after);
+            }
+            loop {
+                match receiver.recv() {
+                    Ok(Message::NewJob(job, name)) => {
+                        println!("Worker {id} got a job; executing.");
+                        let _running = running.start(id, name);
+                        let _outcome = JobOutcome {
+                            pool: &counters,
+                            worker: &worker_completed,
+                        };
+                        job.run();
+                    }
+                    Ok(Message::Terminate) => {
+                        println!("Worker {} was told to terminate.", id);
+                        break;
+                    }
+                    Ok(Message::Retire(retired)) => {
+                        println!("Worker {} was retired.", id);
+                        let _ = retired.send(id);
+                        break;
+                    }
+                    // Every sender is gone, so no more work can ever arrive.
+                    Err(_) => {
+                        println!("Worker {} disconnected; shutting down.", id);
+                        break;
+                    }
                 }
             }
         });
@@ -94,6 +811,29 @@ impl Worker {
         Worker {
             id,
             thread: Some(thread),
+            completed,
+        }
+    }
+
+    /// Whether the worker's thread has exited, or was joined or abandoned.
+    fn is_finished(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_none_or(thread::JoinHandle::is_finished)
+    }
+
+    /// Waits for the worker's thread to exit. A thread that died from a
+    /// panic is logged with its panic message rather than passing the panic
+    /// on, since this runs while the pool is being dropped.
+    fn join(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            if let Err(payload) = thread.join() {
+                eprintln!(
+                    "Worker {} panicked: {}",
+                    self.id,
+                    job::panic_message(&*payload)
+                );
+            }
         }
     }
 }
@@ -101,8 +841,9 @@ impl Worker {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
     use std::sync::{Arc, Mutex};
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn test_thread_pool_new() {
@@ -138,10 +879,671 @@ mod tests {
 
     #[test]
     fn test_worker_new() {
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        let worker = Worker::new(0, Arc::clone(&receiver));
+        let (_sender, receiver) = queue::channel();
+        let worker = Worker::new(
+            0,
+            receiver,
+            RunningJobs::default(),
+            Arc::default(),
+            Duration::ZERO,
+        );
 
         assert_eq!(worker.id, 0);
     }
+
+    #[test]
+    fn test_shutdown_survives_dead_worker() {
+        let mut pool = ThreadPool::new(2);
+        pool.execute(|| panic!("worker died"));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !pool.workers.iter().any(|worker| {
+            worker
+                .thread
+                .as_ref()
+                .is_some_and(|thread| thread.is_finished())
+        }) {
+            assert!(Instant::now() < deadline, "worker never died");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        pool.shutdown();
+
+        assert!(pool.workers.iter().all(|worker| worker.thread.is_none()));
+    }
+
+    #[test]
+    fn test_worker_exits_when_sender_dropped() {
+        let (sender, receiver) = queue::channel();
+        let mut worker = Worker::new(
+            0,
+            receiver,
+            RunningJobs::default(),
+            Arc::default(),
+            Duration::ZERO,
+        );
+        drop(sender);
+
+        let thread = worker.thread.take().unwrap();
+        assert!(thread.join().is_ok());
+    }
+
+    #[test]
+    fn test_set_size_shrinks_worker_ids() {
+        let mut pool = ThreadPool::new(4);
+        assert_eq!(pool.worker_ids(), [0, 1, 2, 3]);
+
+        pool.set_size(2).unwrap();
+
+        let ids = pool.worker_ids();
+        assert_eq!(pool.current_size(), 2);
+        assert_eq!(ids.len(), 2);
+        assert!(ids.iter().all(|id| *id < 4));
+
+        pool.set_size(3).unwrap();
+        assert_eq!(pool.current_size(), 3);
+        assert!(pool.worker_ids().contains(&4));
+
+        let handle = pool.submit(|| 6 * 7);
+        assert_eq!(handle.join(), Ok(42));
+
+        pool.shutdown();
+        assert_eq!(pool.set_size(1), Err(ShutDown));
+    }
+
+    #[test]
+    fn test_set_size_replaces_dead_workers() {
+        let mut pool = ThreadPool::new(2);
+        pool.execute(|| panic!("worker killer"));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !pool.workers.iter().any(Worker::is_finished) {
+            assert!(Instant::now() < deadline, "worker never died");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // Only one worker is left to take a notice, and it must not be
+        // retired on top of the dead one.
+        pool.set_size(1).unwrap();
+        assert_eq!(pool.current_size(), 1);
+        assert_eq!(pool.submit(|| 7).join(), Ok(7));
+
+        pool.execute(|| panic!("worker killer"));
+        while !pool.workers.iter().all(Worker::is_finished) {
+            assert!(Instant::now() < deadline, "worker never died");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        pool.set_size(2).unwrap();
+        assert_eq!(pool.current_size(), 2);
+        assert_eq!(pool.submit(|| 7).join(), Ok(7));
+    }
+
+    #[test]
+    fn test_shrink_skips_queued_jobs() {
+        let mut pool = ThreadPool::new(2);
+        let (release, gate) = mpsc::channel::<()>();
+        let gate = Arc::new(Mutex::new(gate));
+        let (started, wait_started) = mpsc::channel();
+        for _ in 0..5 {
+            let (gate, started) = (Arc::clone(&gate), started.clone());
+            pool.execute(move || {
+                let _ = started.send(());
+                let _ = gate.lock().unwrap().recv();
+            });
+        }
+        // The first job holds the gate's lock, the second waits for it.
+        wait_started.recv().unwrap();
+        wait_started.recv().unwrap();
+        assert_eq!(pool.queued_jobs(), 3);
+
+        std::thread::scope(|scope| {
+            let shrink = scope.spawn(|| pool.set_size(1));
+            release.send(()).unwrap();
+            // The freed worker retires rather than taking a queued job.
+            assert_eq!(shrink.join().unwrap(), Ok(()));
+        });
+        assert_eq!(pool.current_size(), 1);
+        assert_eq!(pool.queued_jobs(), 3);
+        drop(release);
+    }
+
+    #[test]
+    fn test_submit_returns_result() {
+        let pool = ThreadPool::new(2);
+        let handle = pool.submit(|| 6 * 7);
+
+        assert_eq!(handle.join(), Ok(42));
+    }
+
+    #[test]
+    fn test_handle_reports_worker_id() {
+        let pool = ThreadPool::new(2);
+        let (release, gate) = mpsc::channel::<()>();
+        let gate = Arc::new(Mutex::new(gate));
+        let blockers: Vec<_> = (0..2)
+            .map(|_| {
+                let gate = Arc::clone(&gate);
+                pool.submit(move || gate.lock().unwrap().recv().unwrap())
+            })
+            .collect();
+
+        let (started, running) = mpsc::channel();
+        let handle = pool.submit(move || started.send(()).unwrap());
+        assert_eq!(handle.worker_id(), None, "still queued");
+
+        for _ in 0..2 {
+            release.send(()).unwrap();
+        }
+        running.recv().unwrap();
+        let id = handle.worker_id().expect("set once the job starts");
+        assert!(pool.worker_ids().contains(&id));
+        assert_eq!(handle.join(), Ok(()));
+        for blocker in blockers {
+            assert!(pool.worker_ids().contains(&blocker.worker_id().unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_submit_reports_panic() {
+        let pool = ThreadPool::new(1);
+        let handle = pool.submit(|| -> i32 { panic!("boom") });
+
+        assert_eq!(handle.join(), Err(JobError::Panicked("boom".to_string())));
+    }
+
+    #[test]
+    fn test_slow_start_ramps_up_workers() {
+        let pool = ThreadPool::builder(4)
+            .slow_start(Duration::from_secs(1))
+            .build();
+        let workers_used = |delay: Duration| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    pool.submit(move || {
+                        thread::sleep(delay);
+                        job::worker_id().unwrap()
+                    })
+                })
+                .collect();
+            let ids: std::collections::HashSet<_> =
+                handles.into_iter().map(|job| job.join().unwrap()).collect();
+            ids.len()
+        };
+
+        // Only worker 0 has started; the next starts after 250ms.
+        let early = workers_used(Duration::from_millis(10));
+        thread::sleep(Duration::from_millis(1100));
+        let late = workers_used(Duration::from_millis(100));
+
+        assert_eq!(early, 1);
+        assert!(late > early, "{} workers used after the ramp", late);
+    }
+
+    #[test]
+    fn test_run_waits_for_result() {
+        let mut pool = ThreadPool::new(2);
+
+        assert_eq!(pool.run(|| 6 * 7), Ok(42));
+        assert_eq!(
+            pool.run(|| -> i32 { panic!("boom") }),
+            Err(JobError::Panicked("boom".to_string()))
+        );
+        assert_eq!(pool.run(|| "still serving"), Ok("still serving"));
+
+        pool.shutdown();
+        assert_eq!(pool.run(|| 1), Err(JobError::Cancelled));
+    }
+
+    #[test]
+    fn test_join_timeout_gives_up() {
+        let pool = ThreadPool::new(1);
+        let handle = pool.submit(|| std::thread::sleep(Duration::from_millis(200)));
+
+        assert_eq!(
+            handle.join_timeout(Duration::from_millis(10)),
+            Err(JobError::TimedOut)
+        );
+    }
+
+    #[test]
+    fn test_shutdown_cancels_looping_job() {
+        let mut pool = ThreadPool::new(2);
+        let observed = Arc::new(AtomicBool::new(false));
+
+        let (started, wait_started) = mpsc::channel();
+        let flag = Arc::clone(&observed);
+        pool.execute_cancellable(move |token| {
+            started.send(()).unwrap();
+            while !token.is_cancelled() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            flag.store(true, Ordering::SeqCst);
+        });
+        wait_started.recv().unwrap();
+
+        let start = Instant::now();
+        pool.shutdown();
+
+        assert!(observed.load(Ordering::SeqCst));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_execute_after_shutdown_is_dropped() {
+        let mut pool = ThreadPool::new(1);
+        pool.shutdown();
+
+        let (sender, receiver) = mpsc::channel::<()>();
+        pool.execute(move || sender.send(()).unwrap());
+
+        assert!(receiver.recv().is_err());
+    }
+
+    #[test]
+    fn test_drain_queue_discards_pending_jobs() {
+        let pool = ThreadPool::new(1);
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        let (started, wait_started) = mpsc::channel();
+        let (release, wait_release) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            wait_release.recv().unwrap();
+        });
+        wait_started.recv().unwrap();
+
+        for _ in 0..5 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        let handle = pool.submit(|| 42);
+
+        assert_eq!(pool.drain_queue(), 6);
+        release.send(()).unwrap();
+        drop(pool);
+
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+        assert_eq!(handle.join(), Err(JobError::Cancelled));
+    }
+
+    #[test]
+    fn test_spawn_blocking_with_result_leaves_workers_free() {
+        let pool = ThreadPool::builder(1).blocking_pool_size(2).build();
+        let (release, wait_release) = mpsc::channel::<()>();
+        let wait_release = Mutex::new(wait_release);
+
+        let blocked = pool.spawn_blocking_with_result(move || {
+            wait_release.lock().unwrap().recv().unwrap();
+            "read"
+        });
+        let sum = pool.spawn_blocking_with_result(|| (1..=10).sum::<i32>());
+        assert_eq!(sum.join(), Ok(55));
+
+        // The pool's only worker is free while the blocking job waits.
+        let quick = pool.submit(|| 7);
+        assert_eq!(quick.join_timeout(Duration::from_secs(5)), Ok(7));
+
+        release.send(()).unwrap();
+        assert_eq!(blocked.join(), Ok("read"));
+    }
+
+    #[test]
+    fn test_shutdown_with_timeout_abandons_stuck_worker() {
+        let mut pool = ThreadPool::new(2);
+        let (release, wait_release) = mpsc::channel::<()>();
+        let stuck = pool.submit(move || wait_release.recv().unwrap());
+        let quick = pool.submit(|| 7);
+        assert_eq!(quick.join(), Ok(7));
+
+        let (started, wait_started) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        });
+        wait_started.recv().unwrap();
+
+        let start = Instant::now();
+        assert_eq!(pool.shutdown_with_timeout(Duration::from_millis(100)), 1);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(pool.current_size(), 2);
+
+        // The abandoned worker is still running its job.
+        release.send(()).unwrap();
+        assert_eq!(stuck.join(), Ok(()));
+    }
+
+    /// A future that is pending `polls_left` times, waking itself each
+    /// time, before completing.
+    struct YieldNow {
+        polls_left: usize,
+    }
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<()> {
+            if self.polls_left == 0 {
+                return std::task::Poll::Ready(());
+            }
+            self.polls_left -= 1;
+            cx.waker().wake_by_ref();
+            std::task::Poll::Pending
+        }
+    }
+
+    #[test]
+    fn test_spawn_future_runs_to_completion() {
+        let pool = ThreadPool::new(2);
+        let (sender, receiver) = mpsc::channel();
+
+        pool.spawn_future(async move {
+            YieldNow { polls_left: 3 }.await;
+            YieldNow { polls_left: 2 }.await;
+            sender.send(42).unwrap();
+        });
+
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(42));
+    }
+
+    #[test]
+    fn test_idle_workers_wait_concurrently() {
+        let pool = ThreadPool::new(8);
+        let sender = pool.sender.as_ref().unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while sender.waiting() < 8 {
+            assert!(Instant::now() < deadline, "workers never all parked");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_reaper_counts_slow_job_once() {
+        let mut pool = ThreadPool::new(2);
+        let metrics = Arc::new(Metrics::new());
+        pool.reap_slow_jobs(Duration::from_millis(20), Arc::clone(&metrics));
+
+        pool.execute_named("slow", || std::thread::sleep(Duration::from_millis(200)));
+        pool.execute_named("fast", || {});
+        pool.shutdown();
+
+        assert_eq!(metrics.job_timeouts(), 1);
+    }
+
+    #[test]
+    fn test_max_in_flight_bounds_running_jobs() {
+        let pool = ThreadPool::builder(4).max_in_flight(2).build();
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let (done, wait_done) = mpsc::channel();
+
+        for _ in 0..12 {
+            let running = Arc::clone(&running);
+            let most = Arc::clone(&most);
+            let done = done.clone();
+            pool.execute(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(10));
+                running.fetch_sub(1, Ordering::SeqCst);
+                done.send(()).unwrap();
+            });
+        }
+
+        assert_eq!(wait_done.iter().take(12).count(), 12);
+        assert!(most.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn test_metrics_snapshot_counts_completed_jobs() {
+        let mut pool = ThreadPool::new(3);
+        for _ in 0..20 {
+            pool.execute(|| std::thread::sleep(Duration::from_millis(1)));
+        }
+
+        pool.shutdown();
+        let metrics = pool.metrics_snapshot();
+
+        assert_eq!(metrics.completed, 20);
+        assert_eq!(metrics.panicked, 0);
+        assert_eq!((metrics.queued, metrics.active), (0, 0));
+        assert_eq!(metrics.current_size, 3);
+        let per_worker: u64 = metrics.worker_completed.iter().map(|&(_, n)| n).sum();
+        assert_eq!(per_worker, 20);
+    }
+
+    #[test]
+    fn test_diagnostics_report_busy_worker() {
+        let pool = ThreadPool::new(2);
+        let (started, wait_started) = mpsc::channel();
+        let (release, wait_release) = mpsc::channel::<()>();
+        pool.execute_named("/sleep", move || {
+            started.send(()).unwrap();
+            let _ = wait_release.recv();
+        });
+        wait_started.recv().unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let diagnostics = pool.diagnostics();
+        assert_eq!(diagnostics.queued, 0);
+        assert_eq!(diagnostics.workers.len(), 2);
+        let busy: Vec<_> = diagnostics
+            .workers
+            .iter()
+            .filter_map(|worker| match &worker.state {
+                WorkerState::Busy { name, running_for } => Some((name, *running_for)),
+                WorkerState::Idle => None,
+            })
+            .collect();
+        assert_eq!(busy.len(), 1);
+        assert_eq!(busy[0].0.as_deref(), Some("/sleep"));
+        assert!(busy[0].1 >= Duration::from_millis(50));
+
+        let dump = diagnostics.to_string();
+        assert!(dump.starts_with("0 jobs queued\n"));
+        assert!(dump.contains(": idle\n"));
+        assert!(dump.contains(" running /sleep\n"));
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_hooks_report_queue_depth_and_wait() {
+        let depths = Arc::new(Mutex::new(Vec::new()));
+        let waits = Arc::new(Mutex::new(Vec::new()));
+        let pool = {
+            let (depths, waits) = (Arc::clone(&depths), Arc::clone(&waits));
+            ThreadPool::builder(1)
+                .on_submit(move |depth| depths.lock().unwrap().push(depth))
+                .on_dequeue(move |waited| waits.lock().unwrap().push(waited))
+                .build()
+        };
+
+        let (started, wait_started) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        });
+        wait_started.recv().unwrap();
+        let second = pool.submit(|| ());
+        second.join().unwrap();
+
+        // The first job may or may not have been picked up by the time its
+        // depth is read; the second waits behind it.
+        let depths = depths.lock().unwrap();
+        assert_eq!(depths.len(), 2, "one call per job");
+        assert_eq!(depths[1], 1);
+        let waits = waits.lock().unwrap();
+        assert_eq!(waits.len(), 2, "one call per job");
+        assert!(waits[1] >= Duration::from_millis(40), "{:?}", waits[1]);
+    }
+
+    #[test]
+    fn test_try_execute_full() {
+        let pool = ThreadPool::builder(2).max_in_flight(1).build();
+        let (release, wait_release) = mpsc::channel::<()>();
+
+        pool.execute(move || {
+            let _ = wait_release.recv();
+        });
+        assert_eq!(pool.try_execute(|| {}), Err(Full));
+
+        drop(release);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.try_execute(|| {}).is_err() {
+            assert!(Instant::now() < deadline, "permit never returned");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// A single-worker pool whose queue holds two jobs under `policy`,
+    /// with its worker kept busy until the returned sender is dropped.
+    fn busy_bounded_pool(policy: OverflowPolicy) -> (ThreadPool, mpsc::Sender<()>) {
+        let pool = ThreadPool::builder(1).bounded_queue(2, policy).build();
+        let (release, wait_release) = mpsc::channel::<()>();
+        let (started, wait_started) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = wait_release.recv();
+        });
+        wait_started.recv().unwrap();
+        (pool, release)
+    }
+
+    #[test]
+    fn test_overflow_policies_drop_jobs_unrun() {
+        for policy in [
+            OverflowPolicy::Reject,
+            OverflowPolicy::DropNewest,
+            OverflowPolicy::DropOldest,
+        ] {
+            let (pool, release) = busy_bounded_pool(policy);
+            let ran = Arc::new(Mutex::new(Vec::new()));
+            let handles: Vec<_> = (1..=3)
+                .map(|i| {
+                    let ran = Arc::clone(&ran);
+                    pool.submit(move || ran.lock().unwrap().push(i))
+                })
+                .collect();
+            let refused = pool.try_execute(|| {});
+            drop(release);
+
+            let outcomes: Vec<_> = handles.into_iter().map(|job| job.join().is_ok()).collect();
+            drop(pool);
+            let ran = ran.lock().unwrap().clone();
+            match policy {
+                OverflowPolicy::DropOldest => {
+                    // The job queued by `try_execute` pushed out the second.
+                    assert_eq!(outcomes, [false, false, true]);
+                    assert_eq!(ran, [3]);
+                    assert_eq!(refused, Ok(()));
+                }
+                _ => {
+                    assert_eq!(outcomes, [true, true, false]);
+                    assert_eq!(ran, [1, 2]);
+                    assert_eq!(refused, Err(Full));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_overflow_block_waits_for_room() {
+        let (pool, release) = busy_bounded_pool(OverflowPolicy::Block);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let record = |i| {
+            let ran = Arc::clone(&ran);
+            move || ran.lock().unwrap().push(i)
+        };
+        pool.execute(record(1));
+        pool.execute(record(2));
+        assert_eq!(pool.try_execute(record(0)), Err(Full));
+
+        std::thread::scope(|scope| {
+            let third = scope.spawn(|| pool.run(record(3)));
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!third.is_finished(), "queued past capacity");
+            drop(release);
+            assert!(third.join().unwrap().is_ok());
+        });
+        assert_eq!(*ran.lock().unwrap(), [1, 2, 3]);
+    }
+
+    /// Keeps a single-worker pool with `fairness` busy with high-priority
+    /// jobs, queues one low-priority job, and returns how long it waited to
+    /// run, or `None` if it hadn't run after `limit`.
+    fn low_priority_wait(fairness: Fairness, limit: Duration) -> Option<Duration> {
+        let pool = Arc::new(ThreadPool::builder(1).fairness(fairness).build());
+        let stop = Arc::new(AtomicBool::new(false));
+
+        // High-priority jobs arrive faster than the worker runs them.
+        let feeder = {
+            let pool = Arc::clone(&pool);
+            let stop = Arc::clone(&stop);
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    pool.execute_with_priority(Priority::High, || {
+                        std::thread::sleep(Duration::from_millis(2));
+                    });
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            })
+        };
+        std::thread::sleep(Duration::from_millis(20));
+
+        let (ran, wait_ran) = mpsc::channel();
+        let queued = Instant::now();
+        pool.execute_with_priority(Priority::Low, move || ran.send(queued.elapsed()).unwrap());
+        let waited = wait_ran.recv_timeout(limit).ok();
+
+        stop.store(true, Ordering::SeqCst);
+        feeder.join().unwrap();
+        pool.drain_queue();
+        waited
+    }
+
+    #[test]
+    fn test_aging_runs_low_priority_job_under_load() {
+        let waited = low_priority_wait(
+            Fairness::Aging(Duration::from_millis(20)),
+            Duration::from_secs(5),
+        );
+
+        // Promoted to high after two periods, then ahead of newer jobs.
+        assert!(waited.unwrap() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_strict_priority_runs_low_priority_job_last() {
+        let mut pool = ThreadPool::builder(1).fairness(Fairness::Strict).build();
+        let (release, wait_release) = mpsc::channel::<()>();
+        let (started, wait_started) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = wait_release.recv();
+        });
+        wait_started.recv().unwrap();
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for (priority, name) in [
+            (Priority::Low, "low"),
+            (Priority::High, "high 1"),
+            (Priority::Normal, "normal"),
+            (Priority::High, "high 2"),
+        ] {
+            let order = Arc::clone(&order);
+            pool.execute_with_priority(priority, move || order.lock().unwrap().push(name));
+        }
+        drop(release);
+        // Shutdown runs every job queued before it, the low one included.
+        pool.shutdown();
+
+        assert_eq!(
+            *order.lock().unwrap(),
+            ["high 1", "high 2", "normal", "low"]
+        );
+    }
 }
@@ -0,0 +1,162 @@
+//! Warnings for requests refused because they went over one of the limits
+//! protecting the server, so that an operator can tell which clients trip
+//! them. Each limit is warned about at most once per [`WINDOW`]; warnings
+//! held back in the meantime are counted in the next one.
+
+use std::{
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{Config, HttpError};
+
+/// Shortest time between two warnings about the same limit.
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// A limit a request can go over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Limit {
+    UriLength,
+    HeaderLine,
+    HeaderCount,
+    BodySize,
+}
+
+impl Limit {
+    const COUNT: usize = 4;
+
+    /// The limit `error` reports going over, if any.
+    fn of(error: &HttpError) -> Option<Limit> {
+        match error {
+            HttpError::UriTooLong => Some(Limit::UriLength),
+            HttpError::HeaderTooLarge => Some(Limit::HeaderLine),
+            HttpError::TooManyHeaders => Some(Limit::HeaderCount),
+            HttpError::PayloadTooLarge => Some(Limit::BodySize),
+            _ => None,
+        }
+    }
+
+    /// The setting the limit comes from.
+    fn setting(self) -> &'static str {
+        match self {
+            Limit::UriLength => "max_uri_length",
+            Limit::HeaderLine => "max_header_line",
+            Limit::HeaderCount => "max_headers",
+            Limit::BodySize => "max_body",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    last: Option<Instant>,
+    suppressed: u64,
+}
+
+/// When each limit was last warned about, and how many warnings about it
+/// have been held back since.
+struct RateLimiter(Mutex<[Slot; Limit::COUNT]>);
+
+impl RateLimiter {
+    const fn new() -> RateLimiter {
+        RateLimiter(Mutex::new(
+            [Slot {
+                last: None,
+                suppressed: 0,
+            }; Limit::COUNT],
+        ))
+    }
+
+    /// Whether a warning about `limit` going over at `now` should be
+    /// logged, and if so, how many were held back before it.
+    fn admit(&self, limit: Limit, now: Instant) -> Option<u64> {
+        let mut slots = self.0.lock().unwrap();
+        let slot = &mut slots[limit as usize];
+        if slot
+            .last
+            .is_some_and(|last| now.saturating_duration_since(last) < WINDOW)
+        {
+            slot.suppressed += 1;
+            return None;
+        }
+        slot.last = Some(now);
+        Some(std::mem::take(&mut slot.suppressed))
+    }
+}
+
+static LIMITER: RateLimiter = RateLimiter::new();
+
+/// Warns on stderr that a request from `client` was refused with `error`,
+/// when `config.log_limits` is set and `error` is for going over a limit.
+pub(crate) fn report(error: &HttpError, client: Option<IpAddr>, config: &Config) {
+    if !config.log_limits {
+        return;
+    }
+    let Some(limit) = Limit::of(error) else {
+        return;
+    };
+    if let Some(suppressed) = LIMITER.admit(limit, Instant::now()) {
+        eprintln!("{}", warning(limit, client, suppressed));
+    }
+}
+
+fn warning(limit: Limit, client: Option<IpAddr>, suppressed: u64) -> String {
+    let client = client.map_or_else(|| "-".to_string(), |client| client.to_string());
+    let mut line = format!(
+        "Warning: request from {} refused for going over {}",
+        client,
+        limit.setting()
+    );
+    if suppressed > 0 {
+        line.push_str(&format!(" ({} similar warnings suppressed)", suppressed));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_warning_per_limit_per_window() {
+        let limiter = RateLimiter::new();
+        let start = Instant::now();
+
+        assert_eq!(limiter.admit(Limit::BodySize, start), Some(0));
+        for second in 1..5 {
+            let now = start + Duration::from_secs(second);
+            assert_eq!(limiter.admit(Limit::BodySize, now), None);
+        }
+        // Other limits have windows of their own.
+        assert_eq!(limiter.admit(Limit::UriLength, start), Some(0));
+
+        assert_eq!(limiter.admit(Limit::BodySize, start + WINDOW), Some(4));
+        assert_eq!(limiter.admit(Limit::BodySize, start + WINDOW), None);
+    }
+
+    #[test]
+    fn test_warning_names_client_and_setting() {
+        let client = "203.0.113.7".parse().ok();
+
+        assert_eq!(
+            warning(Limit::UriLength, client, 0),
+            "Warning: request from 203.0.113.7 refused for going over max_uri_length"
+        );
+        assert_eq!(
+            warning(Limit::BodySize, None, 3),
+            "Warning: request from - refused for going over max_body \
+             (3 similar warnings suppressed)"
+        );
+        assert_eq!(Limit::of(&HttpError::NotFound), None);
+        // Both kinds of 431 are told apart.
+        assert_eq!(
+            Limit::of(&HttpError::HeaderTooLarge),
+            Some(Limit::HeaderLine)
+        );
+        assert_eq!(
+            Limit::of(&HttpError::TooManyHeaders).map(Limit::setting),
+            Some("max_headers")
+        );
+    }
+}
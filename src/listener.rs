@@ -0,0 +1,503 @@
+use std::{
+    io::{self, Read, Write},
+    net::{
+        IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+    },
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+};
+
+use crate::{sendfile, Stream};
+
+/// The socket a [`Server`](crate::Server) accepts connections on.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixSocket),
+}
+
+impl Listener {
+    pub(crate) fn accept(&self) -> io::Result<Accepted> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Accepted::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix(socket) => socket
+                .listener
+                .accept()
+                .map(|(stream, _)| Accepted::Unix(stream)),
+        }
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr(),
+            #[cfg(unix)]
+            Listener::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "listening on a Unix socket, which has no IP address",
+            )),
+        }
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(socket) => socket.listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Where to connect to wake a thread blocked in
+    /// [`accept`](Listener::accept).
+    pub(crate) fn wake_addr(&self) -> io::Result<WakeAddr> {
+        match self {
+            Listener::Tcp(listener) => {
+                let mut addr = listener.local_addr()?;
+                // A listener on every interface is reached through loopback.
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(match addr {
+                        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                    });
+                }
+                Ok(WakeAddr::Tcp(addr))
+            }
+            #[cfg(unix)]
+            Listener::Unix(socket) => Ok(WakeAddr::Unix(socket.path.clone())),
+        }
+    }
+
+    /// The path of the socket file, when listening on a Unix socket.
+    #[cfg(unix)]
+    pub(crate) fn unix_path(&self) -> Option<&Path> {
+        match self {
+            Listener::Tcp(_) => None,
+            Listener::Unix(socket) => Some(&socket.path),
+        }
+    }
+}
+
+/// Listens on `addr` with a queue of up to `backlog` connections waiting to
+/// be accepted. With `reuse_port`, the socket is bound with `SO_REUSEPORT`,
+/// so that several processes can listen on the same port and have the
+/// kernel spread connections among them. Each address `addr` resolves to is
+/// tried in turn, as [`TcpListener::bind`] does.
+pub(crate) fn bind_tcp(addr: &str, backlog: u32, reuse_port: bool) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        match socket::open(addr, backlog, reuse_port) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+/// Builds listening sockets by hand, since [`TcpListener::bind`] neither
+/// takes a backlog nor sets `SO_REUSEPORT`.
+#[cfg(target_os = "linux")]
+mod socket {
+    use std::{
+        ffi::{c_int, c_void},
+        io, mem,
+        net::{SocketAddr, TcpListener},
+        os::fd::{FromRawFd, OwnedFd},
+    };
+
+    const AF_INET: c_int = 2;
+    const AF_INET6: c_int = 10;
+    const SOCK_STREAM: c_int = 1;
+    const SOCK_CLOEXEC: c_int = 0o2_000_000;
+    const SOL_SOCKET: c_int = 1;
+    const SO_REUSEADDR: c_int = 2;
+    const SO_REUSEPORT: c_int = 15;
+
+    extern "C" {
+        fn socket(domain: c_int, kind: c_int, protocol: c_int) -> c_int;
+        fn setsockopt(
+            fd: c_int,
+            level: c_int,
+            name: c_int,
+            value: *const c_void,
+            len: u32,
+        ) -> c_int;
+        fn bind(fd: c_int, addr: *const c_void, len: u32) -> c_int;
+        fn listen(fd: c_int, backlog: c_int) -> c_int;
+    }
+
+    #[repr(C)]
+    struct SockAddrV4 {
+        family: u16,
+        port: [u8; 2],
+        addr: [u8; 4],
+        zero: [u8; 8],
+    }
+
+    #[repr(C)]
+    struct SockAddrV6 {
+        family: u16,
+        port: [u8; 2],
+        flowinfo: u32,
+        addr: [u8; 16],
+        scope_id: u32,
+    }
+
+    fn check(result: c_int) -> io::Result<c_int> {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result)
+        }
+    }
+
+    pub(super) fn open(
+        addr: SocketAddr,
+        backlog: u32,
+        reuse_port: bool,
+    ) -> io::Result<TcpListener> {
+        use std::os::fd::AsRawFd;
+
+        let domain = match addr {
+            SocketAddr::V4(_) => AF_INET,
+            SocketAddr::V6(_) => AF_INET6,
+        };
+        // SAFETY: `socket` takes no pointers, and the descriptor it returns
+        // is owned by nothing else.
+        let fd =
+            unsafe { OwnedFd::from_raw_fd(check(socket(domain, SOCK_STREAM | SOCK_CLOEXEC, 0))?) };
+
+        let enable: c_int = 1;
+        // Set as `TcpListener::bind` does, so a restarted server can bind
+        // while connections from the old one are in TIME_WAIT.
+        let mut options = vec![SO_REUSEADDR];
+        if reuse_port {
+            options.push(SO_REUSEPORT);
+        }
+        for option in options {
+            // SAFETY: the value points to a live `c_int` of the given size.
+            check(unsafe {
+                setsockopt(
+                    fd.as_raw_fd(),
+                    SOL_SOCKET,
+                    option,
+                    (&enable as *const c_int).cast(),
+                    mem::size_of::<c_int>() as u32,
+                )
+            })?;
+        }
+
+        // SAFETY: each address is a live `repr(C)` struct laid out as the
+        // kernel's `sockaddr_in` or `sockaddr_in6`, passed with its size.
+        check(match addr {
+            SocketAddr::V4(addr) => {
+                let raw = SockAddrV4 {
+                    family: AF_INET as u16,
+                    port: addr.port().to_be_bytes(),
+                    addr: addr.ip().octets(),
+                    zero: [0; 8],
+                };
+                unsafe {
+                    bind(
+                        fd.as_raw_fd(),
+                        (&raw as *const SockAddrV4).cast(),
+                        mem::size_of::<SockAddrV4>() as u32,
+                    )
+                }
+            }
+            SocketAddr::V6(addr) => {
+                let raw = SockAddrV6 {
+                    family: AF_INET6 as u16,
+                    port: addr.port().to_be_bytes(),
+                    flowinfo: addr.flowinfo().to_be(),
+                    addr: addr.ip().octets(),
+                    scope_id: addr.scope_id(),
+                };
+                unsafe {
+                    bind(
+                        fd.as_raw_fd(),
+                        (&raw as *const SockAddrV6).cast(),
+                        mem::size_of::<SockAddrV6>() as u32,
+                    )
+                }
+            }
+        })?;
+
+        let backlog = backlog.min(c_int::MAX as u32) as c_int;
+        // SAFETY: `listen` takes no pointers.
+        check(unsafe { listen(fd.as_raw_fd(), backlog) })?;
+
+        Ok(TcpListener::from(fd))
+    }
+}
+
+/// Elsewhere the listener is bound by [`TcpListener::bind`], with its
+/// default backlog, and `SO_REUSEPORT` isn't supported.
+#[cfg(not(target_os = "linux"))]
+mod socket {
+    use std::{
+        io,
+        net::{SocketAddr, TcpListener},
+    };
+
+    pub(super) fn open(
+        addr: SocketAddr,
+        _backlog: u32,
+        reuse_port: bool,
+    ) -> io::Result<TcpListener> {
+        if reuse_port {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "reuse_port is only supported on Linux",
+            ));
+        }
+        TcpListener::bind(addr)
+    }
+}
+
+/// The address of a [`Listener`], kept to wake its accept loop.
+#[derive(Debug, Clone)]
+pub(crate) enum WakeAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl WakeAddr {
+    /// Opens a connection to the listener and closes it straight away, so
+    /// that a blocked `accept` returns.
+    pub(crate) fn wake(&self) -> io::Result<WakeConnection> {
+        match self {
+            WakeAddr::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)?;
+                Ok(WakeConnection::Tcp(stream.local_addr()?))
+            }
+            #[cfg(unix)]
+            WakeAddr::Unix(path) => {
+                UnixStream::connect(path)?.write_all(WAKE_TOKEN)?;
+                Ok(WakeConnection::Unix)
+            }
+        }
+    }
+}
+
+/// How the listener can tell the connection [`WakeAddr::wake`] made from a
+/// client's, with [`Accepted::is_wake`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum WakeConnection {
+    /// The connection's own address, which the listener sees as its peer's.
+    Tcp(SocketAddr),
+    /// Unix socket clients have no address, so the connection sends
+    /// [`WAKE_TOKEN`] instead.
+    #[cfg(unix)]
+    Unix,
+}
+
+#[cfg(unix)]
+const WAKE_TOKEN: &[u8] = b"\0hello shutdown wake\0";
+
+/// A Unix domain socket listening at `path`. The socket file is removed
+/// when it is dropped.
+#[cfg(unix)]
+pub(crate) struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixSocket {
+    /// Listens at `path`. A socket file left behind by a server that is no
+    /// longer running, which nothing answers on, is replaced.
+    pub(crate) fn bind(path: &Path) -> io::Result<UnixSocket> {
+        let listener = match UnixListener::bind(path) {
+            Err(e)
+                if e.kind() == io::ErrorKind::AddrInUse && UnixStream::connect(path).is_err() =>
+            {
+                fs::remove_file(path)?;
+                UnixListener::bind(path)?
+            }
+            result => result?,
+        };
+
+        Ok(UnixSocket {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A connection accepted by a [`Listener`].
+pub(crate) enum Accepted {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Accepted {
+    pub(crate) fn into_tcp(self) -> Option<TcpStream> {
+        match self {
+            Accepted::Tcp(stream) => Some(stream),
+            #[cfg(unix)]
+            Accepted::Unix(_) => None,
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Accepted::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Accepted::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Accepted::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Accepted::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Whether this is the connection [`WakeAddr::wake`] made. On a Unix
+    /// socket this reads what the client has sent, so only call it on
+    /// connections that are being turned away.
+    pub(crate) fn is_wake(&self, wake: &WakeConnection) -> bool {
+        match (self, wake) {
+            (Accepted::Tcp(stream), WakeConnection::Tcp(addr)) => {
+                stream.peer_addr().is_ok_and(|peer| peer == *addr)
+            }
+            // The token was written before `wake` returned, so it is
+            // already waiting.
+            #[cfg(unix)]
+            (Accepted::Unix(stream), WakeConnection::Unix) => {
+                let mut token = [0; WAKE_TOKEN.len()];
+                let read = stream
+                    .set_nonblocking(true)
+                    .and_then(|()| (&*stream).read(&mut token));
+                let _ = stream.set_nonblocking(false);
+                matches!(read, Ok(n) if token[..n] == *WAKE_TOKEN)
+            }
+            #[cfg(unix)]
+            _ => false,
+        }
+    }
+}
+
+impl Read for &Accepted {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Accepted::Tcp(stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            Accepted::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Accepted {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Accepted::Tcp(stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            Accepted::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Accepted::Tcp(stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            Accepted::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+impl Stream for &Accepted {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Accepted::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Accepted::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Accepted::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Accepted::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    fn peer_addr(&self) -> Option<IpAddr> {
+        match self {
+            Accepted::Tcp(stream) => Stream::peer_addr(&stream),
+            #[cfg(unix)]
+            Accepted::Unix(stream) => Stream::peer_addr(&stream),
+        }
+    }
+
+    fn socket_fd(&self) -> Option<sendfile::Fd> {
+        match self {
+            Accepted::Tcp(stream) => Stream::socket_fd(&stream),
+            #[cfg(unix)]
+            Accepted::Unix(stream) => Stream::socket_fd(&stream),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_tcp_accepts_connections() {
+        let listener = bind_tcp("127.0.0.1:0", 16, false).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        client.write_all(b"ping").unwrap();
+
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_reuse_port_shares_a_port() {
+        let first = bind_tcp("127.0.0.1:0", 128, true).unwrap();
+        let addr = first.local_addr().unwrap().to_string();
+
+        let second = bind_tcp(&addr, 128, true).unwrap();
+        assert_eq!(second.local_addr().unwrap(), first.local_addr().unwrap());
+
+        // Without the option on the new socket, the port is taken.
+        let err = bind_tcp(&addr, 128, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    #[test]
+    fn test_reuse_port_unsupported() {
+        let err = bind_tcp("127.0.0.1:0", 128, true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::reload;
+
+/// Where log lines, such as the access log's, are written: stdout, stderr,
+/// a file or any writer.
+///
+/// Lines are written whole under a lock, so lines logged by several workers
+/// at once never interleave.
+pub struct LogSink {
+    writer: Mutex<Box<dyn Write + Send>>,
+    /// The file being appended to, if the sink writes to one, so it can be
+    /// reopened.
+    path: Option<PathBuf>,
+}
+
+impl LogSink {
+    pub fn stdout() -> LogSink {
+        LogSink::from_writer(io::stdout())
+    }
+
+    pub fn stderr() -> LogSink {
+        LogSink::from_writer(io::stderr())
+    }
+
+    /// Appends to the file at `path`, creating it if needed.
+    pub fn file(path: impl AsRef<Path>) -> io::Result<LogSink> {
+        let path = path.as_ref().to_path_buf();
+        Ok(LogSink {
+            writer: Mutex::new(Box::new(open_append(&path)?)),
+            path: Some(path),
+        })
+    }
+
+    pub fn from_writer(writer: impl Write + Send + 'static) -> LogSink {
+        LogSink {
+            writer: Mutex::new(Box::new(writer)),
+            path: None,
+        }
+    }
+
+    /// Writes `line` followed by a newline, and flushes it.
+    pub fn write_line(&self, line: &str) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(line.len() + 1);
+        buf.extend_from_slice(line.as_bytes());
+        buf.push(b'\n');
+
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(&buf)?;
+        writer.flush()
+    }
+
+    /// Opens the sink's file again by path, so that after a log rotation
+    /// moved the old file away, lines go to a fresh file in its place. Does
+    /// nothing for sinks that don't write to a file.
+    pub fn reopen(&self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            let file = open_append(path)?;
+            *self.writer.lock().unwrap() = Box::new(file);
+        }
+        Ok(())
+    }
+
+    /// Calls [`reopen`](LogSink::reopen) every time the process receives
+    /// `SIGHUP`, which is how tools like logrotate signal that they moved
+    /// the file. If reopening fails the sink keeps writing to the old file.
+    pub fn reopen_on_sighup(self: &Arc<Self>) -> io::Result<()> {
+        let sink = Arc::clone(self);
+        reload::on_sighup(move || {
+            if let Err(e) = sink.reopen() {
+                eprintln!("Error reopening log file: {}", e);
+            }
+        })
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process, thread};
+
+    /// A writer that hands each `write` call's bytes to a shared buffer in
+    /// small pieces, so that unserialized writers would interleave.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(3);
+            self.0.lock().unwrap().extend_from_slice(&buf[..n]);
+            thread::yield_now();
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_concurrent_lines_do_not_interleave() {
+        let buffer = SharedBuffer::default();
+        let sink = Arc::new(LogSink::from_writer(buffer.clone()));
+
+        let threads: Vec<_> = (0..8)
+            .map(|id| {
+                let sink = Arc::clone(&sink);
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        sink.write_line(&format!("worker {} says hello", id))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 400);
+        for line in lines {
+            let id = line
+                .strip_prefix("worker ")
+                .and_then(|rest| rest.strip_suffix(" says hello"))
+                .unwrap_or_else(|| panic!("interleaved line: {:?}", line));
+            assert!(id.parse::<usize>().unwrap() < 8);
+        }
+    }
+
+    #[test]
+    fn test_reopen_follows_rotated_file() {
+        let path = env::temp_dir().join(format!("hello-log-sink-{}.log", process::id()));
+        let rotated = path.with_extension("log.1");
+        let sink = LogSink::file(&path).unwrap();
+
+        sink.write_line("before").unwrap();
+        fs::rename(&path, &rotated).unwrap();
+        sink.reopen().unwrap();
+        sink.write_line("after").unwrap();
+
+        assert_eq!(fs::read_to_string(&rotated).unwrap(), "before\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "after\n");
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+}
@@ -1,60 +1,142 @@
 use std::{
     fs::File,
-    io::{copy, prelude::*, BufReader, Error},
-    net::{TcpListener, TcpStream},
+    io::{self, ErrorKind},
+    path::Path,
+    sync::{Arc, RwLock},
     thread,
     time::Duration,
 };
 
-use hello::ThreadPool;
+use hello::{
+    reload_on_sighup, Config, HttpError, LogSink, Method, Metrics, Readiness, Response, Router,
+    Server, StatusCode,
+};
+
+/// Settings file read at startup and again on `SIGHUP`. The defaults are
+/// used if it doesn't exist.
+const CONFIG_PATH: &str = "server.conf";
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind("127.0.0.1:7878")?;
-    let pool = ThreadPool::new(4);
-
-    for stream in listener.incoming() {
-        let stream = stream?;
-        pool.execute(move || {
-            if let Err(e) = handle_connection(stream) {
-                eprintln!("Error handling connection: {}", e);
-            }
-        });
+    let mut server = Server::bind(load_config()?)?;
+    server.access_log(Arc::new(LogSink::stdout()));
+    let config = server.config();
+    if let Err(e) = reload_on_sighup(Arc::clone(&config), load_config) {
+        eprintln!("Config reload on SIGHUP unavailable: {}", e);
     }
+    if let Err(e) = server.drain_on_sigterm() {
+        eprintln!("Graceful shutdown on SIGTERM unavailable: {}", e);
+    }
+
+    let router = router(config, server.metrics(), server.readiness());
+    server.run(router)?;
 
     Ok(())
 }
 
-fn handle_connection(mut stream: TcpStream) -> Result<(), Error> {
-    let mut buf_reader = BufReader::new(&stream);
-    let mut request_line = String::new();
-    buf_reader.read_line(&mut request_line)?;
+fn load_config() -> io::Result<Config> {
+    match Config::from_file(CONFIG_PATH) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Config::default()),
+        result => result,
+    }
+}
 
-    let (status_line, filename) = match request_line.trim() {
-        "GET / HTTP/1.1" => ("HTTP/1.1 200 OK", "hello.html"),
-        "GET /sleep HTTP/1.1" => {
+/// Builds the routes. Pages are looked up under the static root on every
+/// request, so a reloaded root takes effect immediately.
+fn router(config: Arc<RwLock<Config>>, metrics: Arc<Metrics>, readiness: Readiness) -> Router {
+    let mut router = Router::new();
+    router.get("/healthz", |_| {
+        Response::with_body_str(StatusCode::OK, "ok\n")
+    });
+    router.route_handler(Method::Get, "/readyz", readiness);
+    let live = Arc::clone(&config);
+    router.get("/", move |_| index_page(&live));
+    let live = Arc::clone(&config);
+    router
+        .get("/sleep", move |_| {
             thread::sleep(Duration::from_secs(5));
-            ("HTTP/1.1 200 OK", "hello.html")
+            index_page(&live)
+        })
+        .blocking();
+    // Unauthenticated, so capture_requests is only for servers that can't be
+    // reached from outside.
+    if metrics.capture().is_enabled() {
+        let metrics = Arc::clone(&metrics);
+        router.get("/debug/requests", move |_| {
+            Response::with_body_str(StatusCode::OK, &metrics.capture().render())
+        });
+    }
+    router.get("/metrics", move |_| {
+        Response::new(StatusCode::OK)
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(metrics.render())
+    });
+    router.fallback(move |_| {
+        let path = {
+            let config = config.read().unwrap();
+            config.static_root.join(&config.not_found_page)
+        };
+        if path.is_file() {
+            serve_file(StatusCode::NOT_FOUND, &path)
+        } else {
+            Response::new(StatusCode::NOT_FOUND)
         }
-        _ => ("HTTP/1.1 404 NOT FOUND", "404.html"),
-    };
-
-    let mut file = File::open(filename)?;
-    let length = file.metadata()?.len();
+    });
+    router
+}
 
-    write_response(&mut stream, status_line, length, &mut file)?;
+/// Serves `hello.html` from the static root, or the configured welcome page
+/// if there is no such file.
+fn index_page(config: &RwLock<Config>) -> Response {
+    let config = config.read().unwrap();
+    match Response::file(config.static_root.join("hello.html")) {
+        Ok(response) => response,
+        Err(HttpError::NotFound) => Response::new(StatusCode::OK)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(config.welcome_page.as_str()),
+        Err(e) => e.into_response(),
+    }
+}
 
-    Ok(())
+fn serve_file(status: StatusCode, path: &Path) -> Response {
+    match File::open(path).and_then(|file| Response::from_file(status, file)) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Error opening {}: {}", path.display(), e);
+            Response::new(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
 }
 
-fn write_response(
-    stream: &mut TcpStream,
-    status_line: &str,
-    length: u64,
-    file: &mut File,
-) -> Result<(), Error> {
-    let mut response = format!("{}\r\nContent-Length: {}\r\n\r\n", status_line, length);
-    stream.write_all(response.as_bytes())?;
-    copy(file, stream)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hello::Request;
+    use std::{env, fs, process};
 
-    Ok(())
+    #[test]
+    fn test_serves_welcome_page_without_files() {
+        let root = env::temp_dir().join(format!("hello-main-empty-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let server = Server::bind(Config {
+            bind_addr: "127.0.0.1:0".to_string(),
+            static_root: root,
+            ..Config::default()
+        })
+        .unwrap();
+        let router = router(server.config(), server.metrics(), server.readiness());
+
+        let response = router.dispatch(Request::new(Method::Get, "/"));
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("It works!"));
+
+        let response = router.dispatch(Request::new(Method::Get, "/missing"));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        for path in ["/healthz", "/readyz"] {
+            let response = router.dispatch(Request::new(Method::Get, path));
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+        }
+    }
 }
@@ -0,0 +1,584 @@
+use std::{
+    cell::Cell,
+    fmt::Write,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{RequestCapture, StatusCode};
+
+/// The status classes counted by [`Metrics::responses`], from 2xx to 5xx.
+const STATUS_CLASSES: std::ops::RangeInclusive<u16> = 2..=5;
+
+/// Upper bounds of the latency buckets, in microseconds. Anything slower
+/// than the last bound falls into a final overflow bucket.
+const BUCKET_BOUNDS_US: [u64; 13] = [
+    1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 2_500_000,
+    5_000_000, 10_000_000,
+];
+const BUCKETS: usize = BUCKET_BOUNDS_US.len() + 1;
+
+/// Number of independent shards a histogram spreads its counters over.
+const SHARDS: usize = 16;
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static SHARD: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// The shard the current thread records into, assigned round-robin the
+/// first time a thread records anything.
+fn current_shard() -> usize {
+    SHARD.with(|shard| match shard.get() {
+        Some(index) => index,
+        None => {
+            let index = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARDS;
+            shard.set(Some(index));
+            index
+        }
+    })
+}
+
+struct Shard {
+    buckets: [AtomicU64; BUCKETS],
+}
+
+/// A fixed-bucket histogram of request durations.
+///
+/// Each thread records into its own shard, so workers recording at the
+/// same time don't contend on the same counters; the shards are only
+/// summed when the histogram is read.
+pub struct LatencyHistogram {
+    shards: Vec<Shard>,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            shards: (0..SHARDS)
+                .map(|_| Shard {
+                    buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn record(&self, duration: Duration) {
+        let micros = duration.as_micros();
+        let bucket = BUCKET_BOUNDS_US
+            .iter()
+            .position(|&bound| micros <= bound as u128)
+            .unwrap_or(BUCKETS - 1);
+
+        self.shards[current_shard()].buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the count in each bucket, summed across shards. The last
+    /// entry is the overflow bucket.
+    pub fn bucket_counts(&self) -> [u64; BUCKETS] {
+        let mut counts = [0; BUCKETS];
+        for shard in &self.shards {
+            for (count, bucket) in counts.iter_mut().zip(&shard.buckets) {
+                *count += bucket.load(Ordering::Relaxed);
+            }
+        }
+        counts
+    }
+
+    pub fn count(&self) -> u64 {
+        self.bucket_counts().iter().sum()
+    }
+
+    /// Returns the upper bound of the bucket holding the `percentile`th
+    /// (0.0 to 1.0) fastest request, or `None` if nothing was recorded.
+    /// Requests in the overflow bucket report `Duration::MAX`.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let counts = self.bucket_counts();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+
+        let rank = ((percentile * total as f64).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        for (bucket, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(bucket_bound(bucket));
+            }
+        }
+        unreachable!("rank is at most the total count")
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> LatencyHistogram {
+        LatencyHistogram::new()
+    }
+}
+
+fn bucket_bound(bucket: usize) -> Duration {
+    BUCKET_BOUNDS_US
+        .get(bucket)
+        .map_or(Duration::MAX, |&micros| Duration::from_micros(micros))
+}
+
+fn format_bound(bound: Duration) -> String {
+    if bound == Duration::MAX {
+        "+Inf".to_string()
+    } else {
+        bound.as_secs_f64().to_string()
+    }
+}
+
+/// Why a connection the server was serving ended, as counted by
+/// [`Metrics::connections_closed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The client closed the connection between requests.
+    ClientClosed,
+    /// No request started within `config.idle_timeout` of the last one.
+    IdleTimeout,
+    /// The connection served `config.max_keep_alive_requests` requests.
+    MaxRequests,
+    /// The last request wasn't to be kept alive: the client asked for the
+    /// connection to be closed, keep-alive is off, or the response had no
+    /// length and ended with the connection.
+    NoKeepAlive,
+    /// The connection was upgraded to a WebSocket.
+    Upgraded,
+    /// A request was malformed or timed out, or reading or writing failed.
+    Error,
+}
+
+impl CloseReason {
+    const ALL: [CloseReason; 6] = [
+        CloseReason::ClientClosed,
+        CloseReason::IdleTimeout,
+        CloseReason::MaxRequests,
+        CloseReason::NoKeepAlive,
+        CloseReason::Upgraded,
+        CloseReason::Error,
+    ];
+
+    /// The `reason` label the count is rendered with.
+    fn label(self) -> &'static str {
+        match self {
+            CloseReason::ClientClosed => "client_closed",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::MaxRequests => "max_requests",
+            CloseReason::NoKeepAlive => "no_keep_alive",
+            CloseReason::Upgraded => "upgraded",
+            CloseReason::Error => "error",
+        }
+    }
+}
+
+/// Upper bounds of the requests-per-connection buckets. Connections serving
+/// more fall into a final overflow bucket.
+const REQUESTS_PER_CONNECTION_BOUNDS: [u64; 7] = [1, 2, 5, 10, 20, 50, 100];
+
+/// Why the accept loop turned a connection away, as counted by
+/// [`Metrics::rejections`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// `config.max_connections` connections were already open.
+    TooManyConnections,
+    /// `config.max_queued` connections were already waiting for a worker.
+    Saturated,
+    /// The server was draining for shutdown.
+    Draining,
+    /// Accepting the connection failed, such as when the client gave up
+    /// before it was accepted.
+    AcceptError,
+}
+
+impl RejectReason {
+    const ALL: [RejectReason; 4] = [
+        RejectReason::TooManyConnections,
+        RejectReason::Saturated,
+        RejectReason::Draining,
+        RejectReason::AcceptError,
+    ];
+
+    /// The `reason` label the count is rendered with.
+    fn label(self) -> &'static str {
+        match self {
+            RejectReason::TooManyConnections => "max_connections",
+            RejectReason::Saturated => "saturated",
+            RejectReason::Draining => "draining",
+            RejectReason::AcceptError => "accept_error",
+        }
+    }
+}
+
+/// Server-wide request metrics, rendered by the `/metrics` endpoint.
+#[derive(Default)]
+pub struct Metrics {
+    latency: LatencyHistogram,
+    job_timeouts: AtomicU64,
+    /// Responses sent, by status class from 2xx to 5xx.
+    responses: [AtomicU64; 4],
+    /// Connections turned away, by [`RejectReason`].
+    rejections: [AtomicU64; RejectReason::ALL.len()],
+    connections_accepted: AtomicU64,
+    /// Connections closed, by [`CloseReason`].
+    closes: [AtomicU64; CloseReason::ALL.len()],
+    /// Closed connections by how many requests they served, bucketed by
+    /// `REQUESTS_PER_CONNECTION_BOUNDS`.
+    requests_per_connection: [AtomicU64; REQUESTS_PER_CONNECTION_BOUNDS.len() + 1],
+    /// Requests served by every closed connection together.
+    connection_requests: AtomicU64,
+    capture: RequestCapture,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Like [`new`](Metrics::new), but also keeps the last `capacity`
+    /// requests served, read with [`capture`](Metrics::capture).
+    pub fn with_capture(capacity: usize) -> Metrics {
+        Metrics {
+            capture: RequestCapture::new(capacity),
+            ..Metrics::default()
+        }
+    }
+
+    /// The last requests served, when capture was enabled with
+    /// [`with_capture`](Metrics::with_capture).
+    pub fn capture(&self) -> &RequestCapture {
+        &self.capture
+    }
+
+    /// Records one request that took `duration` from its first byte to its
+    /// last response byte.
+    pub fn record_request(&self, duration: Duration) {
+        self.latency.record(duration);
+    }
+
+    /// Records one response sent with `status`. Informational (1xx)
+    /// responses aren't counted.
+    pub fn record_response(&self, status: StatusCode) {
+        let class = status.as_u16() / 100;
+        if STATUS_CLASSES.contains(&class) {
+            self.responses[usize::from(class - 2)].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of responses sent in status class `class`, 2 for 2xx up to 5
+    /// for 5xx. Other classes are always 0.
+    pub fn responses(&self, class: u16) -> u64 {
+        if STATUS_CLASSES.contains(&class) {
+            self.responses[usize::from(class - 2)].load(Ordering::Relaxed)
+        } else {
+            0
+        }
+    }
+
+    /// Records one connection turned away for `reason`.
+    pub fn record_rejection(&self, reason: RejectReason) {
+        self.rejections[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of connections turned away for `reason`.
+    pub fn rejections(&self, reason: RejectReason) -> u64 {
+        self.rejections[reason as usize].load(Ordering::Relaxed)
+    }
+
+    /// Records one connection accepted, whether it is then served or turned
+    /// away.
+    pub fn record_connection_accepted(&self) {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of connections accepted.
+    pub fn connections_accepted(&self) -> u64 {
+        self.connections_accepted.load(Ordering::Relaxed)
+    }
+
+    /// Records one connection ending for `reason` after serving `requests`
+    /// requests.
+    pub fn record_connection_closed(&self, reason: CloseReason, requests: u64) {
+        self.closes[reason as usize].fetch_add(1, Ordering::Relaxed);
+        let bucket = REQUESTS_PER_CONNECTION_BOUNDS
+            .iter()
+            .position(|&bound| requests <= bound)
+            .unwrap_or(REQUESTS_PER_CONNECTION_BOUNDS.len());
+        self.requests_per_connection[bucket].fetch_add(1, Ordering::Relaxed);
+        self.connection_requests
+            .fetch_add(requests, Ordering::Relaxed);
+    }
+
+    /// Number of connections that ended for `reason`.
+    pub fn connections_closed(&self, reason: CloseReason) -> u64 {
+        self.closes[reason as usize].load(Ordering::Relaxed)
+    }
+
+    /// Mean number of requests served by a closed connection, or `None`
+    /// before any has closed.
+    pub fn requests_per_connection(&self) -> Option<f64> {
+        let closed: u64 = CloseReason::ALL
+            .iter()
+            .map(|&reason| self.connections_closed(reason))
+            .sum();
+        let requests = self.connection_requests.load(Ordering::Relaxed);
+        (closed > 0).then(|| requests as f64 / closed as f64)
+    }
+
+    pub fn latency(&self) -> &LatencyHistogram {
+        &self.latency
+    }
+
+    /// Records one job that ran past the threshold given to
+    /// [`ThreadPool::reap_slow_jobs`](crate::ThreadPool::reap_slow_jobs).
+    pub fn record_job_timeout(&self) {
+        self.job_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of jobs that ran past the pool's job timeout.
+    pub fn job_timeouts(&self) -> u64 {
+        self.job_timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let counts = self.latency.bucket_counts();
+        let mut out = String::new();
+
+        writeln!(out, "http_requests_total {}", self.latency.count()).unwrap();
+
+        for class in STATUS_CLASSES {
+            writeln!(
+                out,
+                "http_responses_total{{class=\"{}xx\"}} {}",
+                class,
+                self.responses(class)
+            )
+            .unwrap();
+        }
+
+        let mut cumulative = 0;
+        for (bucket, count) in counts.iter().enumerate() {
+            cumulative += count;
+            writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                format_bound(bucket_bound(bucket)),
+                cumulative
+            )
+            .unwrap();
+        }
+
+        // Prometheus reads quantile labels as numbers.
+        for percentile in [0.5, 0.9, 0.99] {
+            if let Some(bound) = self.latency.percentile(percentile) {
+                writeln!(
+                    out,
+                    "http_request_duration_seconds{{quantile=\"{}\"}} {}",
+                    percentile,
+                    format_bound(bound)
+                )
+                .unwrap();
+            }
+        }
+
+        writeln!(out, "pool_job_timeouts_total {}", self.job_timeouts()).unwrap();
+
+        for reason in RejectReason::ALL {
+            writeln!(
+                out,
+                "http_connections_rejected_total{{reason=\"{}\"}} {}",
+                reason.label(),
+                self.rejections(reason)
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            out,
+            "http_connections_accepted_total {}",
+            self.connections_accepted()
+        )
+        .unwrap();
+
+        for reason in CloseReason::ALL {
+            writeln!(
+                out,
+                "http_connections_closed_total{{reason=\"{}\"}} {}",
+                reason.label(),
+                self.connections_closed(reason)
+            )
+            .unwrap();
+        }
+
+        let mut cumulative = 0;
+        for (bucket, count) in self.requests_per_connection.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let bound = REQUESTS_PER_CONNECTION_BOUNDS
+                .get(bucket)
+                .map_or_else(|| "+Inf".to_string(), u64::to_string);
+            writeln!(
+                out,
+                "http_requests_per_connection_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "http_requests_per_connection_sum {}",
+            self.connection_requests.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        writeln!(out, "http_requests_per_connection_count {}", cumulative).unwrap();
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread};
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_percentiles_land_in_buckets() {
+        let histogram = LatencyHistogram::new();
+
+        // 50 fast, 40 medium and 10 slow requests.
+        for _ in 0..50 {
+            histogram.record(ms(3));
+        }
+        for _ in 0..40 {
+            histogram.record(ms(80));
+        }
+        for _ in 0..10 {
+            histogram.record(ms(700));
+        }
+
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.percentile(0.5), Some(ms(5)));
+        assert_eq!(histogram.percentile(0.9), Some(ms(100)));
+        assert_eq!(histogram.percentile(0.99), Some(ms(1000)));
+    }
+
+    #[test]
+    fn test_empty_and_overflow() {
+        let histogram = LatencyHistogram::new();
+        assert_eq!(histogram.percentile(0.5), None);
+
+        histogram.record(Duration::from_secs(60));
+        assert_eq!(histogram.percentile(0.5), Some(Duration::MAX));
+        assert_eq!(histogram.bucket_counts()[BUCKETS - 1], 1);
+    }
+
+    #[test]
+    fn test_shards_merge_across_threads() {
+        let histogram = Arc::new(LatencyHistogram::new());
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let histogram = Arc::clone(&histogram);
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        histogram.record(ms(1));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(histogram.bucket_counts()[0], 800);
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.record_request(ms(20));
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("http_requests_total 1\n"));
+        assert!(rendered.contains("http_request_duration_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(rendered.contains("http_request_duration_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(rendered.contains("http_request_duration_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(rendered.contains("http_request_duration_seconds{quantile=\"0.5\"} 0.025\n"));
+        assert!(rendered.contains("http_request_duration_seconds{quantile=\"0.99\"} 0.025\n"));
+    }
+
+    #[test]
+    fn test_responses_counted_by_status_class() {
+        let metrics = Metrics::new();
+        metrics.record_response(StatusCode::OK);
+        metrics.record_response(StatusCode::NO_CONTENT);
+        metrics.record_response(StatusCode::NOT_FOUND);
+        metrics.record_response(StatusCode::INTERNAL_SERVER_ERROR);
+        metrics.record_response(StatusCode::SWITCHING_PROTOCOLS);
+
+        assert_eq!(metrics.responses(2), 2);
+        assert_eq!(metrics.responses(3), 0);
+        assert_eq!(metrics.responses(4), 1);
+        assert_eq!(metrics.responses(5), 1);
+        assert_eq!(metrics.responses(1), 0);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("http_responses_total{class=\"2xx\"} 2\n"));
+        assert!(rendered.contains("http_responses_total{class=\"3xx\"} 0\n"));
+    }
+
+    #[test]
+    fn test_rejections_counted_by_reason() {
+        let metrics = Metrics::new();
+        metrics.record_rejection(RejectReason::AcceptError);
+        metrics.record_rejection(RejectReason::AcceptError);
+        metrics.record_rejection(RejectReason::Draining);
+
+        assert_eq!(metrics.rejections(RejectReason::AcceptError), 2);
+        assert_eq!(metrics.rejections(RejectReason::Draining), 1);
+        assert_eq!(metrics.rejections(RejectReason::Saturated), 0);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("http_connections_rejected_total{reason=\"accept_error\"} 2\n"));
+        assert!(
+            rendered.contains("http_connections_rejected_total{reason=\"max_connections\"} 0\n")
+        );
+    }
+
+    #[test]
+    fn test_connection_reuse() {
+        let metrics = Metrics::new();
+        assert_eq!(metrics.requests_per_connection(), None);
+
+        metrics.record_connection_accepted();
+        metrics.record_connection_closed(CloseReason::ClientClosed, 1);
+        metrics.record_connection_closed(CloseReason::IdleTimeout, 4);
+        metrics.record_connection_closed(CloseReason::MaxRequests, 100);
+        metrics.record_connection_closed(CloseReason::Error, 0);
+
+        assert_eq!(metrics.connections_closed(CloseReason::IdleTimeout), 1);
+        assert_eq!(metrics.requests_per_connection(), Some(105.0 / 4.0));
+
+        let rendered = metrics.render();
+        for line in [
+            "http_connections_accepted_total 1\n",
+            "http_connections_closed_total{reason=\"client_closed\"} 1\n",
+            "http_connections_closed_total{reason=\"upgraded\"} 0\n",
+            "http_requests_per_connection_bucket{le=\"1\"} 2\n",
+            "http_requests_per_connection_bucket{le=\"5\"} 3\n",
+            "http_requests_per_connection_bucket{le=\"100\"} 4\n",
+            "http_requests_per_connection_bucket{le=\"+Inf\"} 4\n",
+            "http_requests_per_connection_sum 105\n",
+            "http_requests_per_connection_count 4\n",
+        ] {
+            assert!(rendered.contains(line), "missing {:?}", line);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use crate::{content_type::split_params, HttpError};
+
+/// One part of a `multipart/form-data` body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    /// The `name` parameter of the part's `Content-Disposition`.
+    pub name: Option<String>,
+    /// The `filename` parameter of the part's `Content-Disposition`.
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    /// All headers of the part, in the order they appeared.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Parses `body` using the boundary named in `content_type`.
+pub(crate) fn parse(content_type: &str, body: &[u8]) -> Result<Vec<Part>, HttpError> {
+    let (mime, params) = split_params(content_type);
+    if !mime.eq_ignore_ascii_case("multipart/form-data") {
+        return Err(bad_request("not a multipart/form-data body"));
+    }
+
+    let boundary = params
+        .iter()
+        .find(|(name, _)| name == "boundary")
+        .map(|(_, value)| value.as_str())
+        .filter(|boundary| !boundary.is_empty())
+        .ok_or_else(|| bad_request("missing multipart boundary"))?;
+
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut close_delimiter = b"\r\n".to_vec();
+    close_delimiter.extend_from_slice(&delimiter);
+
+    let mut pos = find(body, &delimiter)
+        .ok_or_else(|| bad_request("boundary not found in body"))?
+        + delimiter.len();
+    let mut parts = Vec::new();
+
+    loop {
+        let rest = &body[pos..];
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        if !rest.starts_with(b"\r\n") {
+            return Err(bad_request("malformed multipart delimiter"));
+        }
+        pos += 2;
+
+        let mut part = Part {
+            name: None,
+            filename: None,
+            content_type: None,
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+
+        loop {
+            let line_end = find(&body[pos..], b"\r\n")
+                .ok_or_else(|| bad_request("unterminated part headers"))?;
+            let line = std::str::from_utf8(&body[pos..pos + line_end])
+                .map_err(|_| bad_request("part headers are not UTF-8"))?;
+            pos += line_end + 2;
+
+            if line.is_empty() {
+                break;
+            }
+
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| bad_request("malformed part header"))?;
+            let (name, value) = (name.trim(), value.trim());
+
+            if name.eq_ignore_ascii_case("Content-Disposition") {
+                let (_, params) = split_params(value);
+                for (key, value) in params {
+                    match key.as_str() {
+                        "name" => part.name = Some(value),
+                        "filename" => part.filename = Some(value),
+                        _ => {}
+                    }
+                }
+            } else if name.eq_ignore_ascii_case("Content-Type") {
+                part.content_type = Some(value.to_string());
+            }
+            part.headers.push((name.to_string(), value.to_string()));
+        }
+
+        let body_len = find(&body[pos..], &close_delimiter)
+            .ok_or_else(|| bad_request("unterminated multipart part"))?;
+        part.body = body[pos..pos + body_len].to_vec();
+        pos += body_len + close_delimiter.len();
+
+        parts.push(part);
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn bad_request(reason: &str) -> HttpError {
+    HttpError::BadRequest(reason.to_string())
+}
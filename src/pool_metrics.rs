@@ -0,0 +1,114 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::Duration,
+};
+
+/// A pool's activity, read in one call by
+/// [`ThreadPool::metrics_snapshot`](crate::ThreadPool::metrics_snapshot).
+///
+/// Each field is read on its own, so a job finishing while the snapshot is
+/// taken may show up in one field and not yet in another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolMetrics {
+    /// Jobs waiting for a free worker.
+    pub queued: usize,
+    /// Jobs being run right now.
+    pub active: usize,
+    /// Jobs that have run to completion since the pool started.
+    pub completed: u64,
+    /// Jobs that panicked, taking their worker down with them.
+    pub panicked: u64,
+    /// Number of workers in the pool.
+    pub current_size: usize,
+    /// Jobs completed by each current worker, by worker id. Workers retired
+    /// by shrinking the pool are left out, but their jobs still count in
+    /// `completed`.
+    pub worker_completed: Vec<(usize, u64)>,
+}
+
+/// What every worker of a pool is doing, read by
+/// [`ThreadPool::diagnostics`](crate::ThreadPool::diagnostics) to find out
+/// why a pool has stopped making progress, such as every worker being stuck
+/// in a slow job. Its `Display` form is a dump with one line per worker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolDiagnostics {
+    /// Jobs waiting for a free worker.
+    pub queued: usize,
+    /// Each current worker, by ascending id.
+    pub workers: Vec<WorkerDiagnostics>,
+}
+
+/// One worker's part of [`PoolDiagnostics`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerDiagnostics {
+    pub id: usize,
+    pub state: WorkerState,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WorkerState {
+    /// Waiting for a job.
+    Idle,
+    /// Running a job, with its name if it was queued with
+    /// [`execute_named`](crate::ThreadPool::execute_named).
+    Busy {
+        name: Option<String>,
+        running_for: Duration,
+    },
+}
+
+impl fmt::Display for PoolDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} jobs queued", self.queued)?;
+        for worker in &self.workers {
+            match &worker.state {
+                WorkerState::Idle => writeln!(f, "worker {}: idle", worker.id)?,
+                WorkerState::Busy { name, running_for } => {
+                    write!(f, "worker {}: busy for {:?}", worker.id, running_for)?;
+                    match name {
+                        Some(name) => writeln!(f, " running {}", name)?,
+                        None => writeln!(f)?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pool-wide job counts, shared by every worker.
+#[derive(Default)]
+pub(crate) struct JobCounters {
+    completed: AtomicU64,
+    panicked: AtomicU64,
+}
+
+impl JobCounters {
+    pub(crate) fn completed(&self) -> u64 {
+        self.completed.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn panicked(&self) -> u64 {
+        self.panicked.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts the job a worker is running once it ends: as completed, or as
+/// panicked if the worker is unwinding from it.
+pub(crate) struct JobOutcome<'a> {
+    pub(crate) pool: &'a JobCounters,
+    pub(crate) worker: &'a AtomicU64,
+}
+
+impl Drop for JobOutcome<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.pool.panicked.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.pool.completed.fetch_add(1, Ordering::Relaxed);
+            self.worker.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! The `If-Match` and `If-Unmodified-Since` preconditions, which let a
+//! client make a change only if the resource is still the version it last
+//! saw.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{response::unencoded_etag, Request, Response, StatusCode};
+
+/// Evaluates the write preconditions of `request` against the resource's
+/// current `etag` (quoted, as sent in an `ETag` header) and `last_modified`
+/// time, in the order RFC 9110 gives: `If-Unmodified-Since` is ignored when
+/// `If-Match` is present.
+///
+/// `If-Match` compares entity tags strongly, so a weak tag never matches,
+/// though a tag carrying the suffix [`Response::gzip`] adds matches the
+/// uncompressed `etag`, and `If-Match: *` holds if the resource exists at all, taken to be when
+/// either validator is known. `If-Unmodified-Since` is ignored when the date
+/// can't be parsed or the modification time isn't known.
+pub(crate) fn check(
+    request: &Request,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> Result<(), Response> {
+    let holds = if !request.header_all("If-Match").is_empty() {
+        if_match(request, etag, last_modified.is_some())
+    } else if let (Some(since), Some(modified)) = (
+        request
+            .header("If-Unmodified-Since")
+            .and_then(parse_http_date),
+        last_modified,
+    ) {
+        // HTTP dates have whole seconds, so sub-second changes can't count.
+        truncate_to_seconds(modified) <= since
+    } else {
+        true
+    };
+
+    if holds {
+        Ok(())
+    } else {
+        Err(Response::new(StatusCode::PRECONDITION_FAILED))
+    }
+}
+
+fn if_match(request: &Request, etag: Option<&str>, exists: bool) -> bool {
+    let mut tags = request
+        .header_all("If-Match")
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .peekable();
+
+    if tags.peek() == Some(&"*") {
+        return etag.is_some() || exists;
+    }
+    match etag {
+        Some(etag) if !etag.starts_with("W/") => tags.any(|tag| unencoded_etag(tag) == etag),
+        _ => false,
+    }
+}
+
+fn truncate_to_seconds(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => UNIX_EPOCH + Duration::from_secs(since.as_secs()),
+        Err(_) => time,
+    }
+}
+
+/// Parses an HTTP date in the preferred IMF-fixdate format,
+/// `Sun, 06 Nov 1994 08:49:37 GMT`. The obsolete RFC 850 and asctime
+/// formats aren't accepted, nor are years past 9999, which a client could
+/// otherwise send to overflow the arithmetic.
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let (_weekday, rest) = value.trim().split_once(", ")?;
+    let fields: Vec<&str> = rest.split(' ').collect();
+    let [day, month, year, time, "GMT"] = fields[..] else {
+        return None;
+    };
+
+    let day: u64 = day.parse().ok()?;
+    let month = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ]
+    .iter()
+    .position(|&name| name == month)? as u64
+        + 1;
+    let year: u64 = year.parse().ok()?;
+    let mut clock = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (Some(Some(hour)), Some(Some(minute)), Some(Some(second)), None) =
+        (clock.next(), clock.next(), clock.next(), clock.next())
+    else {
+        return None;
+    };
+    if !(1970..=9999).contains(&year)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    let seconds = days
+        .checked_mul(86_400)?
+        .checked_add(hour * 3_600 + minute * 60 + second)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+}
+
+/// Days from 1970-01-01 to the given date in the proleptic Gregorian
+/// calendar, for dates from 1970 on.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    // Count years from March, so the leap day falls at the end of a year.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Method;
+
+    fn put(header: &str, value: &str) -> Request {
+        let mut request = Request::new(Method::Put, "/notes/1");
+        request.insert_header(header, value);
+        request
+    }
+
+    #[test]
+    fn test_if_match_satisfied() {
+        let request = put("If-Match", "\"v1\", \"v2\"");
+
+        assert!(check(&request, Some("\"v2\""), None).is_ok());
+    }
+
+    #[test]
+    fn test_if_match_failed() {
+        let request = put("If-Match", "\"v1\"");
+
+        let response = check(&request, Some("\"v2\""), None).unwrap_err();
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        // Weak tags never match, even against themselves.
+        let request = put("If-Match", "W/\"v1\"");
+        assert!(check(&request, Some("W/\"v1\""), None).is_err());
+    }
+
+    #[test]
+    fn test_if_match_gzip_etag_round_trip() {
+        let get = Response::new(StatusCode::OK)
+            .header("ETag", "\"v1\"")
+            .body("note")
+            .gzip();
+        let learned = get.header_value("ETag").unwrap();
+        assert_eq!(learned, "\"v1-gzip\"");
+
+        let request = put("If-Match", learned);
+        assert!(check(&request, Some("\"v1\""), None).is_ok());
+        assert!(check(&request, Some("\"v2\""), None).is_err());
+        // A weak compressed tag still never matches.
+        let request = put("If-Match", "W/\"v1-gzip\"");
+        assert!(check(&request, Some("\"v1\""), None).is_err());
+    }
+
+    #[test]
+    fn test_if_match_star_requires_resource() {
+        let request = put("If-Match", "*");
+
+        assert!(check(&request, Some("\"v1\""), None).is_ok());
+        assert!(check(&request, None, None).is_err());
+    }
+
+    #[test]
+    fn test_if_unmodified_since() {
+        let request = put("If-Unmodified-Since", "Sun, 06 Nov 1994 08:49:37 GMT");
+        let date = UNIX_EPOCH + Duration::from_secs(784_111_777);
+
+        assert!(check(&request, None, Some(date + Duration::from_millis(500))).is_ok());
+        assert!(check(&request, None, Some(date + Duration::from_secs(1))).is_err());
+        // An unparsable date is ignored.
+        let request = put("If-Unmodified-Since", "yesterday");
+        assert!(check(&request, None, Some(date)).is_ok());
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        assert_eq!(
+            parse_http_date("Thu, 29 Feb 2024 23:59:59 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(1_709_251_199))
+        );
+        assert_eq!(parse_http_date("Thu, 29 Feb 2024 23:59:59 UTC"), None);
+        assert_eq!(parse_http_date("Thursday, 29-Feb-24 23:59:59 GMT"), None);
+    }
+
+    #[test]
+    fn test_huge_year_ignored() {
+        for year in ["10000", "584554051223", &u64::MAX.to_string()] {
+            let date = format!("Thu, 01 Jan {} 00:00:00 GMT", year);
+            assert_eq!(parse_http_date(&date), None, "{}", year);
+
+            let request = put("If-Unmodified-Since", &date);
+            assert!(check(&request, None, Some(UNIX_EPOCH)).is_ok());
+        }
+        assert!(parse_http_date("Fri, 31 Dec 9999 23:59:59 GMT").is_some());
+    }
+}
@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+/// How urgently a job queued with
+/// [`ThreadPool::execute_with_priority`](crate::ThreadPool::execute_with_priority)
+/// should run. A free worker takes the oldest job of the highest priority
+/// queued, so jobs of one priority run in the order they were queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub(crate) const COUNT: usize = 3;
+
+    /// The priority at `index` counting up from `Low`, or `High` past it.
+    pub(crate) fn from_index(index: usize) -> Priority {
+        match index {
+            0 => Priority::Low,
+            1 => Priority::Normal,
+            _ => Priority::High,
+        }
+    }
+}
+
+/// How a pool weighs priorities against how long jobs have waited. Set
+/// with [`ThreadPoolBuilder::fairness`](crate::ThreadPoolBuilder::fairness).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fairness {
+    /// Always runs higher-priority jobs first, so a steady stream of them
+    /// can hold lower-priority jobs back indefinitely.
+    #[default]
+    Strict,
+    /// Promotes a job one priority for every period it has waited, so a
+    /// `Low` job runs as if it were `High` after two periods and, from
+    /// then on, ahead of any `High` job queued after it.
+    Aging(Duration),
+}
+
+impl Fairness {
+    /// The priority a job queued with `priority` is treated as having once
+    /// it has waited for `waited`.
+    pub(crate) fn effective(self, priority: Priority, waited: Duration) -> Priority {
+        match self {
+            Fairness::Strict => priority,
+            Fairness::Aging(period) => {
+                let promotions = match waited.as_nanos().checked_div(period.as_nanos()) {
+                    Some(promotions) => promotions.min(Priority::COUNT as u128) as usize,
+                    None => Priority::COUNT,
+                };
+                Priority::from_index(priority as usize + promotions)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aging_promotes_one_level_per_period() {
+        let aging = Fairness::Aging(Duration::from_millis(100));
+        let ms = Duration::from_millis;
+
+        assert_eq!(aging.effective(Priority::Low, ms(99)), Priority::Low);
+        assert_eq!(aging.effective(Priority::Low, ms(100)), Priority::Normal);
+        assert_eq!(aging.effective(Priority::Low, ms(250)), Priority::High);
+        assert_eq!(aging.effective(Priority::High, ms(1000)), Priority::High);
+        assert_eq!(
+            Fairness::Strict.effective(Priority::Low, ms(1000)),
+            Priority::Low
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    error, fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use crate::Request;
+
+/// A block of IP addresses in CIDR notation, such as `10.0.0.0/8` or
+/// `2001:db8::/32`. A bare address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// Creates the block of addresses sharing the first `prefix_len` bits
+    /// of `addr`. Returns `None` if `prefix_len` is longer than the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<IpNet> {
+        (prefix_len <= max_prefix_len(addr)).then_some(IpNet { addr, prefix_len })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Whether `addr` falls inside this block. IPv4 addresses never match
+    /// an IPv6 block or the other way round.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => prefix_matches(
+                u32::from(net).into(),
+                u32::from(addr).into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(net.into(), addr.into(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn max_prefix_len(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn prefix_matches(net: u128, addr: u128, bits: u8, prefix_len: u8) -> bool {
+    let shift = u32::from(bits - prefix_len);
+    net.checked_shr(shift).unwrap_or(0) == addr.checked_shr(shift).unwrap_or(0)
+}
+
+impl FromStr for IpNet {
+    type Err = InvalidIpNet;
+
+    fn from_str(s: &str) -> Result<IpNet, InvalidIpNet> {
+        let invalid = || InvalidIpNet(s.to_string());
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| invalid())?,
+            None => max_prefix_len(addr),
+        };
+        IpNet::new(addr, prefix_len).ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// The error returned when a string isn't a valid [`IpNet`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidIpNet(String);
+
+impl fmt::Display for InvalidIpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid IP network: {}", self.0)
+    }
+}
+
+impl error::Error for InvalidIpNet {}
+
+/// Works out which client a request came from when it arrived over a
+/// connection from `peer`.
+///
+/// Forwarding headers are only believed when `peer` is one of the
+/// `trusted` proxies. `X-Forwarded-For` is then read from the right, each
+/// proxy having appended the address it received the request from, and the
+/// first address that isn't itself a trusted proxy is the client; anything
+/// further left was supplied by the client and can't be trusted. Without
+/// `X-Forwarded-For`, `X-Real-IP` is used instead.
+pub(crate) fn client_addr(peer: IpAddr, request: &Request, trusted: &[IpNet]) -> IpAddr {
+    let is_trusted = |addr: IpAddr| trusted.iter().any(|net| net.contains(addr));
+    if !is_trusted(peer) {
+        return peer;
+    }
+
+    let forwarded_for = request.header_all("X-Forwarded-For");
+    if forwarded_for.is_empty() {
+        return request
+            .header("X-Real-IP")
+            .and_then(parse_forwarded_addr)
+            .unwrap_or(peer);
+    }
+
+    let mut client = peer;
+    let hops = forwarded_for.iter().flat_map(|value| value.split(','));
+    for hop in hops.rev() {
+        // An entry that can't be parsed can't be trusted either, so the
+        // hop that forwarded it is as far back as can be known.
+        let Some(addr) = parse_forwarded_addr(hop) else {
+            break;
+        };
+        client = addr;
+        if !is_trusted(addr) {
+            break;
+        }
+    }
+    client
+}
+
+/// Parses one forwarded address, which some proxies send with a port.
+fn parse_forwarded_addr(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    value
+        .parse()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Method;
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn forwarded(headers: &[(&str, &str)]) -> Request {
+        let mut request = Request::new(Method::Get, "/");
+        for (name, value) in headers {
+            request.append_header(name, value);
+        }
+        request
+    }
+
+    #[test]
+    fn test_ip_net_contains() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(addr("10.1.200.3")));
+        assert!(!net.contains(addr("10.2.0.1")));
+        assert!(!net.contains(addr("::ffff:10.1.0.1")));
+
+        let any: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(addr("203.0.113.7")));
+
+        let host: IpNet = "2001:db8::1".parse().unwrap();
+        assert_eq!(host.prefix_len(), 128);
+        assert!(host.contains(addr("2001:db8::1")));
+
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("proxy".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_trusted_proxy_uses_rightmost_untrusted_hop() {
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        let request = forwarded(&[
+            ("X-Forwarded-For", "198.51.100.1, 203.0.113.7"),
+            ("X-Forwarded-For", "10.0.0.2"),
+        ]);
+
+        assert_eq!(
+            client_addr(addr("10.0.0.1"), &request, &trusted),
+            addr("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn test_trusted_proxy_falls_back_to_real_ip() {
+        let trusted = ["10.0.0.1".parse().unwrap()];
+        let request = forwarded(&[("X-Real-IP", "203.0.113.7")]);
+
+        assert_eq!(
+            client_addr(addr("10.0.0.1"), &request, &trusted),
+            addr("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn test_untrusted_peer_ignores_forwarding_headers() {
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        let request = forwarded(&[
+            ("X-Forwarded-For", "203.0.113.7"),
+            ("X-Real-IP", "203.0.113.7"),
+        ]);
+
+        assert_eq!(
+            client_addr(addr("198.51.100.1"), &request, &trusted),
+            addr("198.51.100.1")
+        );
+    }
+}
@@ -0,0 +1,628 @@
+//! The job queue shared by the pool's workers.
+//!
+//! `mpsc::Receiver` can only be shared by wrapping it in a `Mutex`, and the
+//! worker that holds that mutex keeps it for the whole blocking `recv`, so at
+//! most one idle worker is ever actually waiting for work while the rest
+//! queue up on the lock. Here the mutex only guards the `VecDeque` itself:
+//! idle workers park on a `Condvar`, which releases the lock while they wait,
+//! so any number of them can be ready to receive at once.
+//!
+//! Items are kept in one FIFO per [`Priority`]. A receiver takes from the
+//! front of the queue whose front item ranks highest under the channel's
+//! [`Fairness`], the older one on a tie, so only the fronts need comparing.
+//!
+//! Barriers, sent with [`Sender::send_barrier`], sit outside the priority
+//! FIFOs: one is received as soon as every item queued before it has been,
+//! so a steady flow of newer, higher-priority items can't hold it back.
+//! Urgent items, sent with [`Sender::send_urgent`], are received before
+//! anything else.
+//!
+//! A sender may also bound the queue, with an [`OverflowPolicy`] saying
+//! what to do once it is full. Senders blocked waiting for room park on a
+//! second `Condvar`, woken as receivers take items.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        mpsc::{RecvError, SendError},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    time::Instant,
+};
+
+use crate::{Fairness, Priority};
+
+struct Queued<T> {
+    item: T,
+    enqueued: Instant,
+    /// Position in the order items were sent, barriers included.
+    seq: u64,
+}
+
+struct State<T> {
+    /// Queued items, indexed by priority.
+    items: [VecDeque<Queued<T>>; Priority::COUNT],
+    /// Barriers, in the order they were sent.
+    barriers: VecDeque<Queued<T>>,
+    /// Items received ahead of all others, in the order they were sent.
+    urgent: VecDeque<T>,
+    next_seq: u64,
+    senders: usize,
+    receivers: usize,
+    waiting: usize,
+    /// Senders blocked in [`Sender::send_bounded`] waiting for room.
+    blocked: usize,
+}
+
+impl<T> State<T> {
+    /// Takes the item to receive next. While a barrier is queued, only the
+    /// items sent before it are candidates, and the barrier itself once
+    /// they are gone.
+    fn pop(&mut self, fairness: Fairness) -> Option<T> {
+        if let Some(item) = self.urgent.pop_front() {
+            return Some(item);
+        }
+        let before = self
+            .barriers
+            .front()
+            .map_or(u64::MAX, |barrier| barrier.seq);
+        let now = Instant::now();
+        let Some((index, _)) = self
+            .items
+            .iter()
+            .enumerate()
+            .filter_map(|(index, queue)| {
+                let front = queue.front().filter(|front| front.seq < before)?;
+                let waited = now.saturating_duration_since(front.enqueued);
+                let rank = fairness.effective(Priority::from_index(index), waited);
+                Some((index, (rank, std::cmp::Reverse(front.seq))))
+            })
+            .max_by_key(|&(_, key)| key)
+        else {
+            return self.barriers.pop_front().map(|barrier| barrier.item);
+        };
+        self.items[index].pop_front().map(|queued| queued.item)
+    }
+
+    fn queued(&mut self, item: T) -> Queued<T> {
+        self.next_seq += 1;
+        Queued {
+            item,
+            enqueued: Instant::now(),
+            seq: self.next_seq,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.items.iter().map(VecDeque::len).sum()
+    }
+
+    /// Takes the item queued longest ago of those `evictable` allows.
+    fn evict_oldest<F>(&mut self, evictable: F) -> Option<T>
+    where
+        F: Fn(&T) -> bool,
+    {
+        let (index, position, _) = self
+            .items
+            .iter()
+            .enumerate()
+            .filter_map(|(index, queue)| {
+                let position = queue.iter().position(|queued| evictable(&queued.item))?;
+                Some((index, position, queue[position].seq))
+            })
+            .min_by_key(|&(_, _, seq)| seq)?;
+        self.items[index].remove(position).map(|queued| queued.item)
+    }
+}
+
+/// What a pool does with a job queued while its queue, bounded with
+/// [`ThreadPoolBuilder::bounded_queue`](crate::ThreadPoolBuilder::bounded_queue),
+/// is full. Jobs dropped are dropped without running, so handles to them
+/// report [`JobError::Cancelled`](crate::JobError::Cancelled).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// The caller waits until a worker takes a job and makes room, except
+    /// in [`try_execute`](crate::ThreadPool::try_execute), which fails with
+    /// [`Full`](crate::Full) rather than wait.
+    #[default]
+    Block,
+    /// The new job is refused and the caller told:
+    /// [`try_execute`](crate::ThreadPool::try_execute) fails with
+    /// [`Full`](crate::Full), and the ways of queueing a job that can't
+    /// fail log an error.
+    Reject,
+    /// The job that has waited longest is dropped to make room for the new
+    /// one.
+    DropOldest,
+    /// The new job is dropped, for work that can be lost under load:
+    /// nothing is logged, though
+    /// [`try_execute`](crate::ThreadPool::try_execute) still fails with
+    /// [`Full`](crate::Full) so that its caller knows the job won't run.
+    DropNewest,
+}
+
+/// What [`Sender::send_bounded`] did with an item.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Sent<T> {
+    /// The item was queued, after evicting the one given, if any.
+    Queued(Option<T>),
+    /// The queue was full and the item is handed back.
+    Full(T),
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    available: Condvar,
+    /// Notified when an item is taken, for senders waiting for room.
+    space: Condvar,
+    fairness: Fairness,
+}
+
+/// Creates a multi-producer, multi-consumer queue with
+/// [`Fairness::Strict`], which is FIFO for items of one priority.
+#[cfg(test)]
+pub(crate) fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    with_fairness(Fairness::Strict)
+}
+
+/// Creates a multi-producer, multi-consumer queue that weighs item
+/// priorities by `fairness`.
+pub(crate) fn with_fairness<T>(fairness: Fairness) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: Default::default(),
+            barriers: VecDeque::new(),
+            urgent: VecDeque::new(),
+            next_seq: 0,
+            senders: 1,
+            receivers: 1,
+            waiting: 0,
+            blocked: 0,
+        }),
+        available: Condvar::new(),
+        space: Condvar::new(),
+        fairness,
+    });
+
+    (
+        Sender {
+            shared: Arc::clone(&shared),
+        },
+        Receiver { shared },
+    )
+}
+
+pub(crate) struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Queues `item` with [`Priority::Normal`], failing only if every
+    /// receiver has been dropped.
+    pub(crate) fn send(&self, item: T) -> Result<(), SendError<T>> {
+        self.send_with_priority(item, Priority::Normal)
+    }
+
+    /// Queues `item` behind every other item of `priority`.
+    pub(crate) fn send_with_priority(
+        &self,
+        item: T,
+        priority: Priority,
+    ) -> Result<(), SendError<T>> {
+        let state = self.shared.state.lock().unwrap();
+        self.push(state, item, priority)
+    }
+
+    /// Queues `item` under the lock `state` holds, then releases it.
+    fn push(
+        &self,
+        mut state: MutexGuard<'_, State<T>>,
+        item: T,
+        priority: Priority,
+    ) -> Result<(), SendError<T>> {
+        if state.receivers == 0 {
+            return Err(SendError(item));
+        }
+
+        let queued = state.queued(item);
+        state.items[priority as usize].push_back(queued);
+        let waiting = state.waiting > 0;
+        drop(state);
+
+        // Skip the wakeup when every receiver is busy; the next one to call
+        // `recv` finds the item without waiting.
+        if waiting {
+            self.shared.available.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Queues `item` to be received once every item sent before it has
+    /// been, ahead of any sent after it whatever their priority. Barriers
+    /// don't count towards [`len`](Sender::len) or a bound.
+    pub(crate) fn send_barrier(&self, item: T) -> Result<(), SendError<T>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.receivers == 0 {
+            return Err(SendError(item));
+        }
+        let queued = state.queued(item);
+        state.barriers.push_back(queued);
+        let waiting = state.waiting > 0;
+        drop(state);
+
+        if waiting {
+            self.shared.available.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Queues `item` to be received before anything else queued, urgent
+    /// items being received in the order they were sent. Urgent items don't
+    /// count towards [`len`](Sender::len) or a bound.
+    pub(crate) fn send_urgent(&self, item: T) -> Result<(), SendError<T>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.receivers == 0 {
+            return Err(SendError(item));
+        }
+        state.urgent.push_back(item);
+        let waiting = state.waiting > 0;
+        drop(state);
+
+        if waiting {
+            self.shared.available.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Queues `item` like [`send_with_priority`](Sender::send_with_priority)
+    /// unless `capacity` items are already queued, in which case `policy`
+    /// decides: under [`OverflowPolicy::Block`] the call waits for room, and
+    /// under [`OverflowPolicy::DropOldest`] the oldest item `evictable`
+    /// allows is taken out and returned. With no such item, the queue is
+    /// let past its capacity.
+    pub(crate) fn send_bounded<F>(
+        &self,
+        item: T,
+        priority: Priority,
+        capacity: usize,
+        policy: OverflowPolicy,
+        evictable: F,
+    ) -> Result<Sent<T>, SendError<T>>
+    where
+        F: Fn(&T) -> bool,
+    {
+        let mut state = self.shared.state.lock().unwrap();
+        let mut evicted = None;
+        while state.receivers > 0 && state.len() >= capacity {
+            match policy {
+                OverflowPolicy::Block => {
+                    state.blocked += 1;
+                    state = self.shared.space.wait(state).unwrap();
+                    state.blocked -= 1;
+                }
+                OverflowPolicy::Reject | OverflowPolicy::DropNewest => {
+                    return Ok(Sent::Full(item));
+                }
+                OverflowPolicy::DropOldest => {
+                    evicted = state.evict_oldest(&evictable);
+                    break;
+                }
+            }
+        }
+
+        // Queued under the lock that saw room, so that senders racing for
+        // the last place can't all take it.
+        self.push(state, item, priority)?;
+        Ok(Sent::Queued(evicted))
+    }
+
+    /// Removes and returns every queued item for which `remove` returns
+    /// true, leaving the rest in order.
+    pub(crate) fn remove_where<F>(&self, mut remove: F) -> Vec<T>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        let mut state = self.shared.state.lock().unwrap();
+        let (taken, kept): (VecDeque<_>, VecDeque<_>) =
+            state.urgent.drain(..).partition(|item| remove(item));
+        state.urgent = kept;
+        let mut removed: Vec<T> = taken.into();
+        let state = &mut *state;
+        for queue in state.items.iter_mut().chain([&mut state.barriers]) {
+            let (taken, kept): (VecDeque<_>, VecDeque<_>) =
+                queue.drain(..).partition(|queued| remove(&queued.item));
+            *queue = kept;
+            removed.extend(taken.into_iter().map(|queued| queued.item));
+        }
+        if !removed.is_empty() && state.blocked > 0 {
+            self.shared.space.notify_all();
+        }
+        removed
+    }
+
+    /// Number of items queued and not yet received.
+    pub(crate) fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().len()
+    }
+
+    /// Number of receivers currently blocked in [`Receiver::recv`].
+    #[cfg(test)]
+    pub(crate) fn waiting(&self) -> usize {
+        self.shared.state.lock().unwrap().waiting
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.state.lock().unwrap().senders += 1;
+        Sender {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.shared.available.notify_all();
+        }
+    }
+}
+
+pub(crate) struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Blocks until an item is available. Fails once the queue is empty and
+    /// every sender has been dropped.
+    pub(crate) fn recv(&self) -> Result<T, RecvError> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(item) = state.pop(self.shared.fairness) {
+                if state.blocked > 0 {
+                    self.shared.space.notify_one();
+                }
+                return Ok(item);
+            }
+            if state.senders == 0 {
+                return Err(RecvError);
+            }
+
+            state.waiting += 1;
+            state = self.shared.available.wait(state).unwrap();
+            state.waiting -= 1;
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Receiver<T> {
+        self.shared.state.lock().unwrap().receivers += 1;
+        Receiver {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.receivers -= 1;
+        // A sender waiting for room would otherwise wait forever.
+        if state.receivers == 0 && state.blocked > 0 {
+            self.shared.space.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_fifo_order() {
+        let (sender, receiver) = channel();
+        for i in 0..5 {
+            sender.send(i).unwrap();
+        }
+
+        let received: Vec<i32> = (0..5).map(|_| receiver.recv().unwrap()).collect();
+        assert_eq!(received, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_higher_priority_first() {
+        let (sender, receiver) = channel();
+        sender.send_with_priority("low", Priority::Low).unwrap();
+        sender.send("normal").unwrap();
+        sender.send_with_priority("high 1", Priority::High).unwrap();
+        sender.send_with_priority("high 2", Priority::High).unwrap();
+
+        let received: Vec<_> = (0..4).map(|_| receiver.recv().unwrap()).collect();
+        assert_eq!(received, ["high 1", "high 2", "normal", "low"]);
+    }
+
+    #[test]
+    fn test_aged_item_overtakes_newer_high_priority() {
+        let (sender, receiver) = with_fairness(Fairness::Aging(Duration::from_millis(10)));
+        sender.send_with_priority("low", Priority::Low).unwrap();
+        thread::sleep(Duration::from_millis(25));
+        sender.send_with_priority("high", Priority::High).unwrap();
+
+        assert_eq!(receiver.recv(), Ok("low"));
+        assert_eq!(receiver.recv(), Ok("high"));
+    }
+
+    #[test]
+    fn test_many_receivers_wait_concurrently() {
+        const RECEIVERS: usize = 8;
+        let (sender, receiver) = channel();
+
+        let threads: Vec<_> = (0..RECEIVERS)
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || receiver.recv().unwrap())
+            })
+            .collect();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while sender.waiting() < RECEIVERS {
+            assert!(Instant::now() < deadline, "receivers never all parked");
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        for i in 0..RECEIVERS {
+            sender.send(i).unwrap();
+        }
+        let mut received: Vec<usize> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        received.sort();
+
+        assert_eq!(received, (0..RECEIVERS).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_barrier_after_earlier_items_only() {
+        let (sender, receiver) = channel();
+        sender.send_with_priority("low", Priority::Low).unwrap();
+        sender.send("normal").unwrap();
+        sender.send_barrier("barrier").unwrap();
+        sender.send_with_priority("high", Priority::High).unwrap();
+        sender.send_barrier("last").unwrap();
+
+        assert_eq!(sender.len(), 3);
+        let received: Vec<_> = (0..5).map(|_| receiver.recv().unwrap()).collect();
+        assert_eq!(received, ["normal", "low", "barrier", "high", "last"]);
+    }
+
+    #[test]
+    fn test_urgent_received_first() {
+        let (sender, receiver) = channel();
+        sender.send_with_priority("high", Priority::High).unwrap();
+        sender.send_barrier("barrier").unwrap();
+        sender.send_urgent("urgent 1").unwrap();
+        sender.send_urgent("urgent 2").unwrap();
+
+        assert_eq!(
+            sender.remove_where(|&item| item == "urgent 2"),
+            ["urgent 2"]
+        );
+        let received: Vec<_> = (0..3).map(|_| receiver.recv().unwrap()).collect();
+        assert_eq!(received, ["urgent 1", "high", "barrier"]);
+    }
+
+    #[test]
+    fn test_remove_where_keeps_order() {
+        let (sender, receiver) = channel();
+        for i in 0..6 {
+            sender.send(i).unwrap();
+        }
+
+        assert_eq!(sender.remove_where(|i| i % 2 == 0), [0, 2, 4]);
+        let received: Vec<i32> = (0..3).map(|_| receiver.recv().unwrap()).collect();
+        assert_eq!(received, [1, 3, 5]);
+    }
+
+    #[test]
+    fn test_send_bounded_at_capacity() {
+        let send = |policy| {
+            let (sender, receiver) = channel();
+            for i in 0..2 {
+                let sent = sender.send_bounded(i, Priority::Normal, 2, policy, |_| true);
+                assert_eq!(sent, Ok(Sent::Queued(None)));
+            }
+            let sent = sender.send_bounded(2, Priority::Normal, 2, policy, |&i| i > 0);
+            let received: Vec<i32> =
+                std::iter::from_fn(|| (sender.len() > 0).then(|| receiver.recv().unwrap()))
+                    .collect();
+            (sent, received)
+        };
+
+        assert_eq!(
+            send(OverflowPolicy::Reject),
+            (Ok(Sent::Full(2)), vec![0, 1])
+        );
+        assert_eq!(
+            send(OverflowPolicy::DropNewest),
+            (Ok(Sent::Full(2)), vec![0, 1])
+        );
+        // The oldest item that may be evicted, not the oldest item.
+        assert_eq!(
+            send(OverflowPolicy::DropOldest),
+            (Ok(Sent::Queued(Some(1))), vec![0, 2])
+        );
+    }
+
+    #[test]
+    fn test_concurrent_send_bounded_respects_capacity() {
+        let (sender, _receiver) = channel();
+        let barrier = std::sync::Barrier::new(8);
+
+        let queued = thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|i| {
+                    let (sender, barrier) = (sender.clone(), &barrier);
+                    scope.spawn(move || {
+                        barrier.wait();
+                        sender
+                            .send_bounded(i, Priority::Normal, 1, OverflowPolicy::Reject, |_| true)
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .filter(|sent| matches!(sent, Ok(Sent::Queued(_))))
+                .count()
+        });
+
+        assert_eq!(queued, 1);
+        assert_eq!(sender.len(), 1);
+    }
+
+    #[test]
+    fn test_send_bounded_blocks_until_room() {
+        let (sender, receiver) = channel();
+        sender.send(0).unwrap();
+
+        thread::scope(|scope| {
+            let blocked = scope.spawn(|| {
+                sender.send_bounded(1, Priority::Normal, 1, OverflowPolicy::Block, |_| true)
+            });
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while sender.shared.state.lock().unwrap().blocked == 0 {
+                assert!(Instant::now() < deadline, "sender never blocked");
+                thread::sleep(Duration::from_millis(1));
+            }
+
+            assert_eq!(receiver.recv(), Ok(0));
+            assert_eq!(blocked.join().unwrap(), Ok(Sent::Queued(None)));
+        });
+        assert_eq!(receiver.recv(), Ok(1));
+    }
+
+    #[test]
+    fn test_recv_fails_after_senders_drop() {
+        let (sender, receiver) = channel();
+        sender.send(1).unwrap();
+        drop(sender);
+
+        assert_eq!(receiver.recv(), Ok(1));
+        assert_eq!(receiver.recv(), Err(RecvError));
+    }
+
+    #[test]
+    fn test_send_fails_without_receivers() {
+        let (sender, receiver) = channel();
+        drop(receiver);
+
+        assert_eq!(sender.send(1), Err(SendError(1)));
+    }
+}
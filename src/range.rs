@@ -0,0 +1,270 @@
+//! `Range: bytes=...` requests against files of a known length.
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, prelude::*, SeekFrom},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{Response, StatusCode};
+
+/// Most ranges honoured in one request. Asking for more is answered with
+/// the whole file rather than spending effort on a pathological request.
+const MAX_RANGES: usize = 16;
+
+static NEXT_BOUNDARY: AtomicU64 = AtomicU64::new(0);
+
+/// A span of bytes, `start` inclusive and `end` exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ByteRange {
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+}
+
+impl ByteRange {
+    fn len(self) -> u64 {
+        self.end - self.start
+    }
+
+    /// The range as written in `Content-Range`.
+    fn content_range(self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end - 1, total)
+    }
+}
+
+/// What a `Range` header asks for from a file of a given length.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Ranges {
+    /// No usable `Range` header; serve the whole file.
+    Whole,
+    /// One or more ranges, sorted and with overlapping or adjacent ranges
+    /// merged.
+    Satisfiable(Vec<ByteRange>),
+    /// Every range starts past the end of the file.
+    Unsatisfiable,
+}
+
+/// Resolves a `Range` header against a file of `len` bytes. Headers that
+/// are malformed, use a unit other than `bytes` or ask for too many ranges
+/// are ignored, as RFC 9110 allows.
+pub(crate) fn parse(header: &str, len: u64) -> Ranges {
+    let Some(specs) = header.trim().strip_prefix("bytes=") else {
+        return Ranges::Whole;
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+    {
+        let Some((first, last)) = spec.split_once('-') else {
+            return Ranges::Whole;
+        };
+        let (Ok(first), Ok(last)) = (parse_position(first), parse_position(last)) else {
+            return Ranges::Whole;
+        };
+
+        let range = match (first, last) {
+            // A suffix: the last `last` bytes.
+            (None, Some(suffix)) if suffix > 0 => ByteRange {
+                start: len.saturating_sub(suffix),
+                end: len,
+            },
+            (Some(first), None) => ByteRange {
+                start: first,
+                end: len,
+            },
+            (Some(first), Some(last)) if first <= last => ByteRange {
+                start: first,
+                end: last.saturating_add(1).min(len),
+            },
+            _ => return Ranges::Whole,
+        };
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.len() > MAX_RANGES {
+        return Ranges::Whole;
+    }
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    Ranges::Satisfiable(merged)
+}
+
+/// Parses one side of a range spec, which may be empty.
+fn parse_position(position: &str) -> Result<Option<u64>, ()> {
+    if position.is_empty() {
+        return Ok(None);
+    }
+    if !position.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(());
+    }
+    position.parse().map(Some).map_err(|_| ())
+}
+
+/// Builds the `206 Partial Content` response for `ranges` of `file`, which
+/// is `total` bytes long. A single range is sent as is; several are sent as
+/// a `multipart/byteranges` body with one part per range, each labelled
+/// with `content_type`.
+pub(crate) fn partial_response(
+    mut file: File,
+    total: u64,
+    content_type: &str,
+    ranges: &[ByteRange],
+) -> io::Result<Response> {
+    if let [range] = ranges {
+        file.seek(SeekFrom::Start(range.start))?;
+        return Ok(
+            Response::from_file_range(StatusCode::PARTIAL_CONTENT, file, range.len())?
+                .header("Content-Range", &range.content_range(total))
+                .header("Content-Type", content_type),
+        );
+    }
+
+    let boundary = boundary();
+    let mut pieces = VecDeque::new();
+    for range in ranges {
+        let head = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            boundary,
+            content_type,
+            range.content_range(total)
+        );
+        pieces.push_back(Piece::Bytes(io::Cursor::new(head.into_bytes())));
+        pieces.push_back(Piece::File(*range));
+    }
+    pieces.push_back(Piece::Bytes(io::Cursor::new(
+        format!("\r\n--{}--\r\n", boundary).into_bytes(),
+    )));
+
+    let len = pieces.iter().map(Piece::len).sum();
+    let body = ByteRangesReader {
+        file,
+        pieces,
+        seeked: false,
+    };
+    Ok(
+        Response::from_reader(StatusCode::PARTIAL_CONTENT, body, len).header(
+            "Content-Type",
+            &format!("multipart/byteranges; boundary={}", boundary),
+        ),
+    )
+}
+
+/// A boundary that won't appear by chance in the file's bytes.
+fn boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_nanos());
+    let count = NEXT_BOUNDARY.fetch_add(1, Ordering::Relaxed);
+    format!("byteranges-{:08x}{:08x}", nanos, count)
+}
+
+enum Piece {
+    Bytes(io::Cursor<Vec<u8>>),
+    File(ByteRange),
+}
+
+impl Piece {
+    fn len(&self) -> u64 {
+        match self {
+            Piece::Bytes(bytes) => bytes.get_ref().len() as u64,
+            Piece::File(range) => range.len(),
+        }
+    }
+}
+
+/// Streams a `multipart/byteranges` body, seeking the file to each range
+/// in turn.
+struct ByteRangesReader {
+    file: File,
+    pieces: VecDeque<Piece>,
+    /// Whether the file is positioned within the range at the front.
+    seeked: bool,
+}
+
+impl Read for ByteRangesReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = match self.pieces.front_mut() {
+                None => return Ok(0),
+                Some(Piece::Bytes(bytes)) => bytes.read(buf)?,
+                Some(Piece::File(range)) => {
+                    if !self.seeked {
+                        self.file.seek(SeekFrom::Start(range.start))?;
+                        self.seeked = true;
+                    }
+                    let want = buf.len().min(range.len() as usize);
+                    let read = self.file.read(&mut buf[..want])?;
+                    if read == 0 && want > 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    range.start += read as u64;
+                    read
+                }
+            };
+
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            self.pieces.pop_front();
+            self.seeked = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        assert_eq!(
+            parse("bytes=0-99", 1000),
+            Ranges::Satisfiable(vec![range(0, 100)])
+        );
+        assert_eq!(
+            parse("bytes=-100", 1000),
+            Ranges::Satisfiable(vec![range(900, 1000)])
+        );
+        assert_eq!(
+            parse("bytes=900-", 1000),
+            Ranges::Satisfiable(vec![range(900, 1000)])
+        );
+        assert_eq!(
+            parse("bytes=990-2000", 1000),
+            Ranges::Satisfiable(vec![range(990, 1000)])
+        );
+        assert_eq!(parse("bytes=1000-", 1000), Ranges::Unsatisfiable);
+        assert_eq!(parse("items=0-1", 1000), Ranges::Whole);
+        assert_eq!(parse("bytes=5-1", 1000), Ranges::Whole);
+        assert_eq!(parse("bytes=a-b", 1000), Ranges::Whole);
+    }
+
+    #[test]
+    fn test_parse_coalesces_ranges() {
+        assert_eq!(
+            parse("bytes=200-299, 0-99, 50-149, 150-160", 1000),
+            Ranges::Satisfiable(vec![range(0, 161), range(200, 300)])
+        );
+    }
+}
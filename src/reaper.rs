@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::Metrics;
+
+/// The job a worker is running.
+struct RunningJob {
+    started: Instant,
+    name: Option<String>,
+    /// Whether the reaper has already counted this job as timed out, so a
+    /// job is counted once however long it keeps running.
+    reported: bool,
+}
+
+/// When each busy worker started its current job, keyed by worker id.
+/// Workers record a job as they pick it up and clear it when it ends.
+#[derive(Clone, Default)]
+pub(crate) struct RunningJobs(Arc<Mutex<HashMap<usize, RunningJob>>>);
+
+impl RunningJobs {
+    /// Records that `worker` started a job. The returned guard clears the
+    /// record when dropped, so a job that panics doesn't look like it's
+    /// still running.
+    pub(crate) fn start(&self, worker: usize, name: Option<String>) -> RunningGuard<'_> {
+        let job = RunningJob {
+            started: Instant::now(),
+            name,
+            reported: false,
+        };
+        self.0.lock().unwrap().insert(worker, job);
+
+        RunningGuard { jobs: self, worker }
+    }
+
+    /// The name and running time of the job `worker` is running, if any.
+    pub(crate) fn current(&self, worker: usize) -> Option<(Option<String>, Duration)> {
+        let jobs = self.0.lock().unwrap();
+        let job = jobs.get(&worker)?;
+        Some((job.name.clone(), job.started.elapsed()))
+    }
+
+    /// Number of jobs running right now.
+    pub(crate) fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    /// Marks every job that has run longer than `threshold` and wasn't
+    /// already reported, and returns the worker id, name and running time of
+    /// each.
+    fn overdue(&self, threshold: Duration) -> Vec<(usize, Option<String>, Duration)> {
+        let mut jobs = self.0.lock().unwrap();
+
+        jobs.iter_mut()
+            .filter(|(_, job)| !job.reported && job.started.elapsed() > threshold)
+            .map(|(&worker, job)| {
+                job.reported = true;
+                (worker, job.name.clone(), job.started.elapsed())
+            })
+            .collect()
+    }
+}
+
+pub(crate) struct RunningGuard<'a> {
+    jobs: &'a RunningJobs,
+    worker: usize,
+}
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        self.jobs.0.lock().unwrap().remove(&self.worker);
+    }
+}
+
+/// A thread watching a pool's running jobs for ones that take longer than a
+/// threshold. It can't stop them; it only logs each one and counts it in
+/// [`Metrics::job_timeouts`]. Stops when dropped.
+pub(crate) struct Reaper {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Reaper {
+    pub(crate) fn spawn(jobs: RunningJobs, threshold: Duration, metrics: Arc<Metrics>) -> Reaper {
+        let (stop, stopped) = mpsc::channel::<()>();
+        // Check often enough that a timeout is noticed soon after it
+        // happens, without spinning on very short thresholds.
+        let interval = (threshold / 4).clamp(Duration::from_millis(10), Duration::from_secs(1));
+
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                for (worker, name, elapsed) in jobs.overdue(threshold) {
+                    metrics.record_job_timeout();
+                    eprintln!(
+                        "Job {} on worker {} has run for {:?}, past the {:?} job timeout",
+                        name.as_deref().unwrap_or("(unnamed)"),
+                        worker,
+                        elapsed,
+                        threshold
+                    );
+                }
+            }
+        });
+
+        Reaper {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::Config;
+
+/// How often the reload thread checks whether a `SIGHUP` has arrived.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Number of `SIGHUP`s received so far. Each watcher compares it with the
+/// count it last saw, so several can react to the same signal.
+static SIGHUPS_RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+/// Reloads `config` with the result of `load` every time the process
+/// receives `SIGHUP`, so that settings can change without a restart.
+///
+/// The signal handler itself only counts the signal, since taking a lock
+/// inside it could deadlock; a background thread notices and swaps the
+/// new settings in through [`Config::reload`]. If `load` fails the current
+/// settings stay in place. Readers holding a clone of the settings from
+/// before the reload keep using them until they read `config` again.
+pub fn reload_on_sighup<F>(config: Arc<RwLock<Config>>, load: F) -> io::Result<()>
+where
+    F: Fn() -> io::Result<Config> + Send + 'static,
+{
+    on_sighup(move || reload(&config, &load))
+}
+
+/// Calls `f` on a background thread after each `SIGHUP` the process
+/// receives from now on. Signals arriving close together may be handled
+/// with a single call.
+pub(crate) fn on_sighup<F>(mut f: F) -> io::Result<()>
+where
+    F: FnMut() + Send + 'static,
+{
+    signal::install()?;
+
+    let mut seen = SIGHUPS_RECEIVED.load(Ordering::SeqCst);
+    thread::spawn(move || loop {
+        thread::sleep(POLL_INTERVAL);
+        let received = SIGHUPS_RECEIVED.load(Ordering::SeqCst);
+        if received != seen {
+            seen = received;
+            f();
+        }
+    });
+
+    Ok(())
+}
+
+fn reload<F>(config: &RwLock<Config>, load: &F)
+where
+    F: Fn() -> io::Result<Config>,
+{
+    match load() {
+        Ok(new) => {
+            config.write().unwrap().reload(new);
+            println!("Reloaded config");
+        }
+        Err(e) => eprintln!("Error reloading config: {}", e),
+    }
+}
+
+#[cfg(unix)]
+mod signal {
+    use std::{ffi::c_int, io};
+
+    use super::SIGHUPS_RECEIVED;
+
+    const SIGHUP: c_int = 1;
+    const SIG_ERR: usize = !0;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+    }
+
+    extern "C" fn on_sighup(_: c_int) {
+        SIGHUPS_RECEIVED.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    pub(super) fn install() -> io::Result<()> {
+        let handler = on_sighup as extern "C" fn(c_int) as usize;
+        // SAFETY: `on_sighup` only updates an atomic, which is
+        // async-signal-safe.
+        if unsafe { signal(SIGHUP, handler) } == SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod signal {
+    use std::io;
+
+    pub(super) fn install() -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SIGHUP is only available on Unix",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Method, Request, Response, StatusCode};
+    use std::{env, fs, path::PathBuf, process};
+
+    #[test]
+    fn test_reload_changes_static_root() {
+        let dir = |name: &str| {
+            let dir = env::temp_dir().join(format!("hello-reload-{}-{}", process::id(), name));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("hello.html"), name).unwrap();
+            dir
+        };
+        let (old_root, new_root) = (dir("old"), dir("new"));
+
+        let config = Arc::new(RwLock::new(Config {
+            static_root: old_root,
+            ..Config::default()
+        }));
+        let live = Arc::clone(&config);
+        let handler = move |_: &Request| {
+            let root = live.read().unwrap().static_root.clone();
+            Response::new(StatusCode::OK).body(fs::read(root.join("hello.html")).unwrap())
+        };
+        let body = |response: Response| {
+            let mut out = Vec::new();
+            response.write_to(&mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert!(body(handler(&Request::new(Method::Get, "/"))).ends_with("old"));
+
+        let load = move || {
+            Ok(Config {
+                static_root: new_root.clone(),
+                pool_size: 99,
+                ..Config::default()
+            })
+        };
+        reload(&config, &load);
+
+        assert!(body(handler(&Request::new(Method::Get, "/"))).ends_with("new"));
+        assert_eq!(
+            config.read().unwrap().pool_size,
+            Config::default().pool_size
+        );
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_config() {
+        let config = RwLock::new(Config {
+            static_root: PathBuf::from("/srv/www"),
+            ..Config::default()
+        });
+
+        reload(&config, &|| Err(io::ErrorKind::NotFound.into()));
+
+        assert_eq!(
+            config.read().unwrap().static_root,
+            PathBuf::from("/srv/www")
+        );
+    }
+}
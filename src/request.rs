@@ -0,0 +1,1113 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    io::{prelude::*, ErrorKind},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use crate::{
+    accept,
+    body_stream::BodyReader,
+    gzip::{self, DecompressError},
+    head,
+    multipart::{self, Part},
+    precondition, Config, ConnectionInfo, ContentType, HttpError, Response,
+};
+
+/// The request method.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Patch,
+    /// Echoes the request back. Refused with `501 Not Implemented`
+    /// unless `config.allow_trace` is set, since echoing headers lets a
+    /// script read cookies it otherwise couldn't (cross-site tracing).
+    Trace,
+    /// Asks for a tunnel to another host. Refused with
+    /// `501 Not Implemented` unless `config.allow_connect` is set.
+    Connect,
+    Other(String),
+}
+
+impl Method {
+    pub fn parse(token: &str) -> Method {
+        match token {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            "PATCH" => Method::Patch,
+            "TRACE" => Method::Trace,
+            "CONNECT" => Method::Connect,
+            other => Method::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+            Method::Trace => "TRACE",
+            Method::Connect => "CONNECT",
+            Method::Other(other) => other,
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The protocol version named in the request line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    /// Parses a version token. A well-formed `HTTP/x.y` token for a version
+    /// other than 1.0 or 1.1 is reported as unsupported rather than malformed.
+    pub fn parse(token: &str) -> Result<Version, HttpError> {
+        match token {
+            "HTTP/1.0" => return Ok(Version::Http10),
+            "HTTP/1.1" => return Ok(Version::Http11),
+            _ => {}
+        }
+
+        let well_formed = token
+            .strip_prefix("HTTP/")
+            .and_then(|number| number.split_once('.'))
+            .is_some_and(|(major, minor)| {
+                !major.is_empty()
+                    && !minor.is_empty()
+                    && major.bytes().all(|b| b.is_ascii_digit())
+                    && minor.bytes().all(|b| b.is_ascii_digit())
+            });
+
+        if well_formed {
+            Err(HttpError::VersionNotSupported)
+        } else {
+            Err(HttpError::BadRequest(format!("invalid version: {}", token)))
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A parsed HTTP request.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    pub path: String,
+    pub query: Option<String>,
+    pub version: Version,
+    /// Header values keyed by lowercased header name, in the order they were
+    /// received. Repeated headers keep every value.
+    pub headers: HashMap<String, Vec<String>>,
+    pub body: Vec<u8>,
+    /// The address of the client that sent the request, looking through
+    /// trusted proxies, which is the address to log or rate limit by. `None`
+    /// when the connection has no peer address.
+    pub client_addr: Option<IpAddr>,
+    pub(crate) connection: ConnectionInfo,
+    /// The body still on the connection, for a route that streams it, until
+    /// the handler takes it.
+    pub(crate) body_stream: Arc<Mutex<Option<BodyReader>>>,
+}
+
+impl Request {
+    /// Creates an HTTP/1.1 request for `target` with no headers or body.
+    pub fn new(method: Method, target: &str) -> Request {
+        let (path, query) = split_target(target);
+        Request {
+            method,
+            path,
+            query,
+            version: Version::Http11,
+            headers: HashMap::new(),
+            body: Vec::new(),
+            client_addr: None,
+            connection: ConnectionInfo::default(),
+            body_stream: Arc::default(),
+        }
+    }
+
+    /// Reads a request line, its header block and any `Content-Length`
+    /// delimited body from `reader`.
+    pub fn parse<R: BufRead>(reader: &mut R, config: &Config) -> Result<Request, HttpError> {
+        let mut request = Request::parse_head(reader, config)?;
+        request.read_body(reader, config.max_body)?;
+        Ok(request)
+    }
+
+    /// Reads a request line and its header block from `reader`, leaving any
+    /// body unread for [`read_body`](Request::read_body). The head may
+    /// arrive over any number of reads.
+    pub(crate) fn parse_head<R: BufRead>(
+        reader: &mut R,
+        config: &Config,
+    ) -> Result<Request, HttpError> {
+        head::read(reader, config)
+    }
+
+    /// Reads the body that follows the head, delimited by `Content-Length`
+    /// or sent with `Transfer-Encoding`, refusing bodies larger than
+    /// `max_body` bytes with [`HttpError::PayloadTooLarge`].
+    ///
+    /// The transfer codings must end with `chunked`, the only way to tell
+    /// where such a body ends, and may only put `gzip` before it, which is
+    /// decoded once the chunks are in. Any other coding is refused with
+    /// [`HttpError::NotImplemented`]. A request with both headers is
+    /// refused, since the two could disagree about where the body ends.
+    pub(crate) fn read_body<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        max_body: usize,
+    ) -> Result<(), HttpError> {
+        let codings = self.body_codings()?;
+        if !codings.is_empty() {
+            self.read_chunked(reader, max_body)?;
+            for _ in &codings[..codings.len() - 1] {
+                self.body = gzip::decompress(&self.body, max_body).map_err(|e| match e {
+                    DecompressError::TooLarge => HttpError::PayloadTooLarge,
+                    DecompressError::Invalid => {
+                        HttpError::BadRequest("invalid gzip body".to_string())
+                    }
+                })?;
+            }
+            return Ok(());
+        }
+
+        let length = self.content_length()?;
+
+        // A missing or zero Content-Length means there is no body at all; on a
+        // keep-alive connection any bytes after the header block belong to the
+        // next request, so nothing may be read here.
+        if length > 0 {
+            if length > max_body {
+                return Err(HttpError::PayloadTooLarge);
+            }
+
+            // The body grows as bytes actually arrive rather than being
+            // allocated up front, so a client can't make the server reserve
+            // `max_body` bytes just by declaring them.
+            reader.take(length as u64).read_to_end(&mut self.body)?;
+            if self.body.len() < length {
+                return Err(HttpError::Io(ErrorKind::UnexpectedEof.into()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The codings the body was sent with, as for
+    /// [`transfer_codings`](Request::transfer_codings), refusing a request
+    /// that also has a `Content-Length`.
+    pub(crate) fn body_codings(&self) -> Result<Vec<String>, HttpError> {
+        let codings = self.transfer_codings()?;
+        if !codings.is_empty() && !self.header_all("Content-Length").is_empty() {
+            return Err(HttpError::BadRequest(
+                "both Transfer-Encoding and Content-Length".to_string(),
+            ));
+        }
+        Ok(codings)
+    }
+
+    /// The codings listed by `Transfer-Encoding`, lowercased and in the
+    /// order they were applied, after checking that they are ones
+    /// [`read_body`](Request::read_body) can decode.
+    fn transfer_codings(&self) -> Result<Vec<String>, HttpError> {
+        let codings: Vec<String> = self
+            .header_tokens("Transfer-Encoding")
+            .map(|coding| {
+                let name = coding.split(';').next().unwrap_or_default();
+                name.trim().to_ascii_lowercase()
+            })
+            .collect();
+
+        let Some((last, rest)) = codings.split_last() else {
+            return Ok(codings);
+        };
+        if last != "chunked" {
+            return Err(HttpError::BadRequest(
+                "chunked must be the final transfer coding".to_string(),
+            ));
+        }
+        for coding in rest {
+            match coding.as_str() {
+                "gzip" | "x-gzip" => {}
+                "chunked" => {
+                    return Err(HttpError::BadRequest(
+                        "chunked applied more than once".to_string(),
+                    ))
+                }
+                _ => {
+                    return Err(HttpError::NotImplemented(format!(
+                        "transfer coding {}",
+                        coding
+                    )))
+                }
+            }
+        }
+        Ok(codings)
+    }
+
+    /// Reads a chunked body, then the trailer section after it, which is
+    /// discarded. The chunks may add up to at most `max_body` bytes.
+    fn read_chunked<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        max_body: usize,
+    ) -> Result<(), HttpError> {
+        let mut line = Vec::new();
+        loop {
+            read_chunk_line(reader, &mut line)?;
+            let size = chunk_size(&line)?;
+            if size == 0 {
+                break;
+            }
+            if size > max_body - self.body.len() {
+                return Err(HttpError::PayloadTooLarge);
+            }
+
+            let before = self.body.len();
+            reader.take(size as u64).read_to_end(&mut self.body)?;
+            if self.body.len() - before < size {
+                return Err(HttpError::Io(ErrorKind::UnexpectedEof.into()));
+            }
+            read_chunk_line(reader, &mut line)?;
+            if !line.is_empty() {
+                return Err(HttpError::BadRequest(
+                    "chunk longer than its size".to_string(),
+                ));
+            }
+        }
+
+        read_trailers(reader, &mut line)
+    }
+
+    /// The body length declared by `Content-Length`, or 0 without one. The
+    /// value must be plain digits that fit in a `usize`, and repeated values
+    /// must agree.
+    pub(crate) fn content_length(&self) -> Result<usize, HttpError> {
+        let mut length = None;
+        for value in self.header_all("Content-Length") {
+            let invalid = || HttpError::BadRequest(format!("invalid Content-Length: {}", value));
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid());
+            }
+
+            let parsed: usize = value.parse().map_err(|_| invalid())?;
+            if length.is_some_and(|length| length != parsed) {
+                return Err(HttpError::BadRequest(
+                    "conflicting Content-Length".to_string(),
+                ));
+            }
+            length = Some(parsed);
+        }
+        Ok(length.unwrap_or(0))
+    }
+
+    /// The connection the request arrived over.
+    pub fn connection(&self) -> &ConnectionInfo {
+        &self.connection
+    }
+
+    /// The port of the peer the request arrived from, if it has one.
+    pub fn remote_port(&self) -> Option<u16> {
+        self.connection.remote_port()
+    }
+
+    /// Whether the client wants the connection kept open after this request.
+    /// HTTP/1.1 connections persist unless a `Connection` header lists
+    /// `close`; HTTP/1.0 ones only when one lists `keep-alive` and none lists
+    /// `close`.
+    pub fn keep_alive(&self) -> bool {
+        let has = |token: &str| self.connection_tokens().any(|item| item == token);
+        match self.version {
+            Version::Http11 => !has("close"),
+            Version::Http10 => has("keep-alive") && !has("close"),
+        }
+    }
+
+    /// The tokens of every `Connection` header, lowercased, such as
+    /// `keep-alive` and `upgrade` from `Connection: keep-alive, Upgrade`.
+    pub fn connection_tokens(&self) -> impl Iterator<Item = String> + '_ {
+        self.header_tokens("Connection")
+            .map(|token| token.to_ascii_lowercase())
+    }
+
+    /// Removes the hop-by-hop headers, which describe this connection rather
+    /// than the request: `Connection` itself, every header it names, and the
+    /// standard ones such as `Keep-Alive`, `TE` and `Upgrade`. The server
+    /// does this before passing a request to its handler, after it has
+    /// decided whether to keep the connection open.
+    pub fn strip_hop_by_hop(&mut self) {
+        let named: Vec<String> = self.connection_tokens().collect();
+        for name in HOP_BY_HOP
+            .into_iter()
+            .chain(named.iter().map(String::as_str))
+        {
+            self.headers.remove(name);
+        }
+    }
+
+    /// The comma-separated items of every value of a header, trimmed, with
+    /// empty items skipped.
+    fn header_tokens(&self, name: &str) -> impl Iterator<Item = &str> {
+        self.header_all(name)
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+    }
+
+    /// The segments of the path, percent-decoded, with empty and `.`
+    /// segments dropped and each `..` removing the segment before it. A `..`
+    /// with nothing before it is dropped too, so the segments never climb
+    /// above the root: `/a/./b/../c` and `/../a//c` both give `a`, `c`.
+    ///
+    /// Dot segments are resolved after decoding, so `%2e%2e` can't slip a
+    /// `..` past them, but a decoded segment may still contain `/`; code
+    /// mapping segments onto files must refuse those.
+    pub fn path_segments(&self) -> impl Iterator<Item = Cow<'_, str>> {
+        let mut segments = Vec::new();
+        for raw in self.path.split('/') {
+            let segment = percent_decode(raw);
+            match &*segment {
+                "" | "." => {}
+                ".." => {
+                    segments.pop();
+                }
+                _ => segments.push(segment),
+            }
+        }
+        segments.into_iter()
+    }
+
+    /// Routes a `POST` as the method named in its `X-HTTP-Method-Override`
+    /// header or, failing that, in the `_method` field of its
+    /// `application/x-www-form-urlencoded` body. Only `PUT`, `PATCH` and
+    /// `DELETE` can be named, ignoring case; anything else, and requests
+    /// other than `POST`, are left alone.
+    pub fn apply_method_override(&mut self) {
+        if self.method != Method::Post {
+            return;
+        }
+
+        let form_field = || {
+            let is_form = self.content_type().is_some_and(|content_type| {
+                content_type.mime == "application/x-www-form-urlencoded"
+            });
+            if !is_form {
+                return None;
+            }
+            std::str::from_utf8(&self.body)
+                .ok()?
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("_method="))
+                .map(|value| percent_decode(value).into_owned())
+        };
+        let name = match self.header("X-HTTP-Method-Override") {
+            Some(name) => Some(name.trim().to_string()),
+            None => form_field(),
+        };
+
+        let method = name.map(|name| Method::parse(&name.to_ascii_uppercase()));
+        if let Some(method @ (Method::Put | Method::Patch | Method::Delete)) = method {
+            self.method = method;
+        }
+    }
+
+    /// Parses the `Content-Type` header, if there is one.
+    pub fn content_type(&self) -> Option<ContentType> {
+        self.header("Content-Type").and_then(ContentType::parse)
+    }
+
+    /// Whether this is a WebSocket opening handshake: a `GET` with
+    /// `Upgrade: websocket`, a `Connection` header listing `Upgrade` and a
+    /// `Sec-WebSocket-Key`.
+    pub fn is_websocket_upgrade(&self) -> bool {
+        let lists = |name: &str, token: &str| {
+            self.header_tokens(name)
+                .any(|item| item.eq_ignore_ascii_case(token))
+        };
+
+        self.method == Method::Get
+            && lists("Upgrade", "websocket")
+            && lists("Connection", "upgrade")
+            && self.header("Sec-WebSocket-Key").is_some()
+    }
+
+    /// The body of a request to a route marked
+    /// [`stream_body`](crate::Route::stream_body), read from the connection
+    /// as the handler asks for it rather than buffered into `body`, which is
+    /// left empty. Only the first call gets the reader; any other request
+    /// has `None`.
+    pub fn body_reader(&self) -> Option<BodyReader> {
+        self.body_stream.lock().unwrap().take()
+    }
+
+    /// Splits a `multipart/form-data` body into its parts.
+    ///
+    /// The whole payload was already bounded by `max_body` when the body was
+    /// read, so no part can exceed it either.
+    pub fn multipart(&self) -> Result<Vec<Part>, HttpError> {
+        let content_type = self
+            .header("Content-Type")
+            .ok_or_else(|| HttpError::BadRequest("missing Content-Type".to_string()))?;
+
+        multipart::parse(content_type, &self.body)
+    }
+
+    /// Whether the request's `Accept` header admits `media_type`, such as
+    /// `text/html`, through an exact match or a `text/*` or `*/*` wildcard.
+    /// The most specific range covering the type decides, so one weighted
+    /// `q=0` excludes it. A request without an `Accept` header accepts
+    /// anything.
+    pub fn accepts(&self, media_type: &str) -> bool {
+        accept::quality(self, media_type) > 0.0
+    }
+
+    /// The media type among `candidates` that the request's `Accept` header
+    /// weighs highest, preferring earlier candidates on a tie, or `None` if
+    /// it accepts none of them.
+    pub fn preferred<'a>(&self, candidates: &[&'a str]) -> Option<&'a str> {
+        accept::preferred(self, candidates)
+    }
+
+    /// Checks the request's `If-Match` and `If-Unmodified-Since` headers
+    /// against the resource it would change, given its current `ETag` and
+    /// modification time, where known. Handlers that modify a resource call
+    /// this first and return the `412 Precondition Failed` response in `Err`
+    /// instead of making the change.
+    pub fn check_preconditions(
+        &self,
+        etag: Option<&str>,
+        last_modified: Option<SystemTime>,
+    ) -> Result<(), Response> {
+        precondition::check(self, etag, last_modified)
+    }
+
+    /// Looks up the first value of a header by name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.header_all(name).first().map(String::as_str)
+    }
+
+    /// Returns every value of a repeated header, ignoring case, in the order
+    /// they were received.
+    pub fn header_all(&self, name: &str) -> &[String] {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// Sets a header, replacing any existing values. The name is stored
+    /// lowercased so that lookups through [`header`](Request::header) match
+    /// regardless of the client's casing.
+    pub fn insert_header(&mut self, name: &str, value: &str) {
+        self.headers
+            .insert(name.to_ascii_lowercase(), vec![value.to_string()]);
+    }
+
+    /// Adds a value to a header, keeping any values it already has.
+    pub fn append_header(&mut self, name: &str, value: &str) {
+        self.headers
+            .entry(name.to_ascii_lowercase())
+            .or_default()
+            .push(value.to_string());
+    }
+}
+
+/// Headers that only concern a single connection, so are never passed on
+/// beyond it.
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Decodes `%XX` escapes. Malformed escapes are kept as they are, and
+/// bytes that don't form UTF-8 are replaced.
+fn percent_decode(input: &str) -> Cow<'_, str> {
+    if !input.contains('%') {
+        return Cow::Borrowed(input);
+    }
+
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    Cow::Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+pub(crate) fn split_target(target: &str) -> (String, Option<String>) {
+    match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target.to_string(), None),
+    }
+}
+
+/// Longest chunk-size or trailer line accepted.
+const MAX_CHUNK_LINE: u64 = 4096;
+
+/// Reads one line of a chunked body into `line`, without its line ending.
+pub(crate) fn read_chunk_line<R: BufRead>(
+    reader: &mut R,
+    line: &mut Vec<u8>,
+) -> Result<(), HttpError> {
+    line.clear();
+    reader.take(MAX_CHUNK_LINE).read_until(b'\n', line)?;
+    if line.pop() != Some(b'\n') {
+        return Err(if line.len() as u64 >= MAX_CHUNK_LINE {
+            HttpError::BadRequest("chunk line too long".to_string())
+        } else {
+            HttpError::Io(ErrorKind::UnexpectedEof.into())
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(())
+}
+
+/// The size given by a chunk-size line, ignoring any chunk extensions.
+pub(crate) fn chunk_size(line: &[u8]) -> Result<usize, HttpError> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.split(';').next())
+        .map(str::trim)
+        .filter(|size| !size.is_empty() && size.bytes().all(|b| b.is_ascii_hexdigit()))
+        .and_then(|size| usize::from_str_radix(size, 16).ok())
+        .ok_or_else(|| HttpError::BadRequest("invalid chunk size".to_string()))
+}
+
+/// Reads the trailer section that ends a chunked body, discarding it.
+pub(crate) fn read_trailers<R: BufRead>(
+    reader: &mut R,
+    line: &mut Vec<u8>,
+) -> Result<(), HttpError> {
+    loop {
+        read_chunk_line(reader, line)?;
+        if line.is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StatusCode;
+
+    #[test]
+    fn test_parse_request_line_and_headers() {
+        let raw = b"GET /hello?name=world HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let request = Request::parse(&mut &raw[..], &Config::default()).unwrap();
+
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.path, "/hello");
+        assert_eq!(request.query.as_deref(), Some("name=world"));
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.header("Host"), Some("localhost"));
+    }
+
+    #[test]
+    fn test_bare_lf_lines() {
+        let raw = b"GET / HTTP/1.1\nHost: localhost\n\n";
+        let request = Request::parse(&mut &raw[..], &Config::default()).unwrap();
+        assert_eq!(request.header("Host"), Some("localhost"));
+
+        let strict = Config {
+            strict_crlf: true,
+            ..Config::default()
+        };
+        let mixed = b"GET / HTTP/1.1\r\nHost: localhost\n\r\n";
+        for raw in [&raw[..], &mixed[..]] {
+            let error = Request::parse(&mut &raw[..], &strict).unwrap_err();
+            assert_eq!(error.into_response().status(), StatusCode::BAD_REQUEST);
+        }
+
+        let raw = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert!(Request::parse(&mut &raw[..], &strict).is_ok());
+    }
+
+    #[test]
+    fn test_uri_too_long() {
+        let config = Config {
+            max_uri_length: 64,
+            ..Config::default()
+        };
+
+        let fits = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(63));
+        assert!(Request::parse(&mut fits.as_bytes(), &config).is_ok());
+
+        let over = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(64));
+        let err = Request::parse(&mut over.as_bytes(), &config).unwrap_err();
+        assert_eq!(err.status(), StatusCode::URI_TOO_LONG);
+
+        // A huge target is refused once the limit is reached, unread beyond.
+        let huge = format!("GET /{}", "a".repeat(1024 * 1024));
+        let mut reader = std::io::Cursor::new(huge.as_bytes());
+        let err = Request::parse(&mut reader, &config).unwrap_err();
+        assert_eq!(err.status(), StatusCode::URI_TOO_LONG);
+        assert!(reader.position() <= 64 + head::REQUEST_LINE_SLACK as u64);
+    }
+
+    #[test]
+    fn test_header_lookup_ignores_case() {
+        let mut request = Request::new(Method::Post, "/upload");
+        request.insert_header("Content-Type", "text/html");
+
+        assert_eq!(request.header("content-type"), Some("text/html"));
+        assert_eq!(request.header("CONTENT-TYPE"), Some("text/html"));
+        assert_eq!(request.headers["content-type"], ["text/html"]);
+    }
+
+    #[test]
+    fn test_repeated_headers_keep_all_values() {
+        let raw = b"GET / HTTP/1.1\r\n\
+                    X-Forwarded-For: 203.0.113.7\r\n\
+                    x-forwarded-for: 10.0.0.1\r\n\r\n";
+        let request = Request::parse(&mut &raw[..], &Config::default()).unwrap();
+
+        assert_eq!(request.header("X-Forwarded-For"), Some("203.0.113.7"));
+        assert_eq!(
+            request.header_all("X-Forwarded-For"),
+            ["203.0.113.7", "10.0.0.1"]
+        );
+        assert!(request.header_all("Accept").is_empty());
+    }
+
+    #[test]
+    fn test_parsed_header_names_are_normalized() {
+        let raw = b"GET / HTTP/1.1\r\nX-Request-ID: abc\r\n\r\n";
+        let request = Request::parse(&mut &raw[..], &Config::default()).unwrap();
+
+        assert_eq!(request.header("x-request-id"), Some("abc"));
+        assert!(request.headers.contains_key("x-request-id"));
+    }
+
+    #[test]
+    fn test_parse_empty_request() {
+        let result = Request::parse(&mut &b""[..], &Config::default());
+        assert!(matches!(result, Err(HttpError::BadRequest(_))));
+    }
+
+    #[test]
+    fn test_parse_rejects_extra_whitespace() {
+        let raw = b"GET  /  HTTP/1.1\r\n\r\n";
+        let err = Request::parse(&mut &raw[..], &Config::default()).unwrap_err();
+
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_parse_rejects_missing_version() {
+        let raw = b"GET /\r\n\r\n";
+        let err = Request::parse(&mut &raw[..], &Config::default()).unwrap_err();
+
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_parse_rejects_unsupported_version() {
+        let raw = b"GET / HTTP/2.0\r\n\r\n";
+        let err = Request::parse(&mut &raw[..], &Config::default()).unwrap_err();
+
+        assert_eq!(err.status(), StatusCode::HTTP_VERSION_NOT_SUPPORTED);
+    }
+
+    #[test]
+    fn test_parse_rejects_garbage_version() {
+        let raw = b"GET / HTTX/1.1\r\n\r\n";
+        let err = Request::parse(&mut &raw[..], &Config::default()).unwrap_err();
+
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_parse_body() {
+        let raw = b"POST /submit HTTP/1.1\r\ncontent-length: 5\r\n\r\nhello";
+        let request = Request::parse(&mut &raw[..], &Config::default()).unwrap();
+
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn test_parse_zero_length_body_reads_nothing() {
+        let raw = b"POST /submit HTTP/1.1\r\nContent-Length: 0\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+        let mut reader = &raw[..];
+        let request = Request::parse(&mut reader, &Config::default()).unwrap();
+
+        assert!(request.body.is_empty());
+        assert_eq!(reader, b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn test_parse_absent_body_reads_nothing() {
+        let raw = b"GET / HTTP/1.1\r\n\r\nGET /next HTTP/1.1\r\n\r\n";
+        let mut reader = &raw[..];
+        let request = Request::parse(&mut reader, &Config::default()).unwrap();
+
+        assert!(request.body.is_empty());
+        assert_eq!(reader, b"GET /next HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn test_keep_alive_defaults() {
+        let parse = |raw: &str| Request::parse(&mut raw.as_bytes(), &Config::default()).unwrap();
+
+        assert!(parse("GET / HTTP/1.1\r\n\r\n").keep_alive());
+        assert!(!parse("GET / HTTP/1.1\r\nConnection: close\r\n\r\n").keep_alive());
+        assert!(!parse("GET / HTTP/1.0\r\n\r\n").keep_alive());
+        assert!(parse("GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").keep_alive());
+    }
+
+    #[test]
+    fn test_keep_alive_reads_every_connection_token() {
+        let parse = |raw: &str| Request::parse(&mut raw.as_bytes(), &Config::default()).unwrap();
+
+        assert!(!parse("GET / HTTP/1.1\r\nConnection: Upgrade, Close\r\n\r\n").keep_alive());
+        assert!(
+            !parse("GET / HTTP/1.1\r\nConnection: te\r\nConnection: close\r\n\r\n").keep_alive()
+        );
+        assert!(parse("GET / HTTP/1.0\r\nConnection: TE, Keep-Alive\r\n\r\n").keep_alive());
+        assert!(!parse("GET / HTTP/1.0\r\nConnection: keep-alive, close\r\n\r\n").keep_alive());
+
+        let request = parse("GET / HTTP/1.1\r\nConnection: keep-alive,, X-Trace \r\n\r\n");
+        assert_eq!(
+            request.connection_tokens().collect::<Vec<_>>(),
+            ["keep-alive", "x-trace"]
+        );
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let raw = "GET / HTTP/1.1\r\n\
+                   Host: localhost\r\n\
+                   Connection: keep-alive, X-Trace\r\n\
+                   Keep-Alive: timeout=5\r\n\
+                   X-Trace: abc\r\n\
+                   TE: trailers\r\n\
+                   Accept: text/html\r\n\r\n";
+        let mut request = Request::parse(&mut raw.as_bytes(), &Config::default()).unwrap();
+        request.strip_hop_by_hop();
+
+        let mut names: Vec<_> = request.headers.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["accept", "host"]);
+    }
+
+    #[test]
+    fn test_parse_body_over_limit() {
+        let raw = b"POST /submit HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let config = Config {
+            max_body: 4,
+            ..Config::default()
+        };
+
+        let result = Request::parse(&mut &raw[..], &config);
+        assert!(matches!(result, Err(HttpError::PayloadTooLarge)));
+    }
+
+    #[test]
+    fn test_parse_overflowing_content_length() {
+        for length in ["99999999999999999999999999", "+5", "-1", "5 5"] {
+            let raw = format!(
+                "POST /submit HTTP/1.1\r\nContent-Length: {}\r\n\r\nhello",
+                length
+            );
+            let err = Request::parse(&mut raw.as_bytes(), &Config::default()).unwrap_err();
+
+            assert_eq!(err.status(), StatusCode::BAD_REQUEST, "{}", length);
+        }
+    }
+
+    #[test]
+    fn test_parse_conflicting_content_lengths() {
+        let raw = b"POST /submit HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello";
+        let err = Request::parse(&mut &raw[..], &Config::default()).unwrap_err();
+
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// `body` sent as chunks of at most `size` bytes, with a trailer.
+    fn chunked(body: &[u8], size: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in body.chunks(size) {
+            out.extend(format!("{:x};ext=1\r\n", chunk.len()).bytes());
+            out.extend(chunk);
+            out.extend(b"\r\n");
+        }
+        out.extend(b"0\r\nX-Checksum: 1\r\n\r\n");
+        out
+    }
+
+    #[test]
+    fn test_parse_chunked_body() {
+        let mut raw = b"POST /submit HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        raw.extend(chunked(b"hello, world", 5));
+        raw.extend(b"GET /next HTTP/1.1\r\n\r\n");
+        let mut reader = &raw[..];
+
+        let request = Request::parse(&mut reader, &Config::default()).unwrap();
+        assert_eq!(request.body, b"hello, world");
+        assert_eq!(reader, b"GET /next HTTP/1.1\r\n\r\n");
+
+        let config = Config {
+            max_body: 11,
+            ..Config::default()
+        };
+        let err = Request::parse(&mut &raw[..], &config).unwrap_err();
+        assert_eq!(err.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_parse_gzip_chunked_body() {
+        let text = "compressed, then chunked. ".repeat(20);
+        let mut raw = b"POST /submit HTTP/1.1\r\nTransfer-Encoding: gzip, Chunked\r\n\r\n".to_vec();
+        raw.extend(chunked(&gzip::compress_all(text.as_bytes()), 16));
+
+        let request = Request::parse(&mut &raw[..], &Config::default()).unwrap();
+        assert_eq!(request.body, text.as_bytes());
+
+        // The limit applies to the decoded body too.
+        let config = Config {
+            max_body: text.len() - 1,
+            ..Config::default()
+        };
+        let err = Request::parse(&mut &raw[..], &config).unwrap_err();
+        assert_eq!(err.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_parse_refuses_bad_transfer_codings() {
+        for (codings, status) in [
+            ("chunked, gzip", StatusCode::BAD_REQUEST),
+            ("gzip", StatusCode::BAD_REQUEST),
+            ("chunked, chunked", StatusCode::BAD_REQUEST),
+            ("br, chunked", StatusCode::NOT_IMPLEMENTED),
+        ] {
+            let raw = format!(
+                "POST /submit HTTP/1.1\r\nTransfer-Encoding: {}\r\n\r\n0\r\n\r\n",
+                codings
+            );
+            let err = Request::parse(&mut raw.as_bytes(), &Config::default()).unwrap_err();
+            assert_eq!(err.status(), status, "{}", codings);
+        }
+
+        let raw = b"POST /submit HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+                    Content-Length: 5\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+        let err = Request::parse(&mut &raw[..], &Config::default()).unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_parse_over_limit_reads_nothing() {
+        let raw = b"POST /submit HTTP/1.1\r\nContent-Length: 1099511627776\r\n\r\nhello";
+        let mut reader = &raw[..];
+        let err = Request::parse(&mut reader, &Config::default()).unwrap_err();
+
+        assert_eq!(err.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(reader, b"hello");
+    }
+
+    #[test]
+    fn test_parse_does_not_preallocate_declared_length() {
+        // Allocating the declared terabyte up front would abort the test.
+        let raw = b"POST /submit HTTP/1.1\r\nContent-Length: 1099511627776\r\n\r\nhello";
+        let config = Config {
+            max_body: usize::MAX,
+            ..Config::default()
+        };
+
+        let result = Request::parse(&mut &raw[..], &config);
+        assert!(matches!(result, Err(HttpError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_multipart_two_parts() {
+        let body = "--XyZ\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\
+            \r\n\
+            Holiday\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"photo\"; filename=\"beach.png\"\r\n\
+            Content-Type: image/png\r\n\
+            \r\n\
+            \x00PNG\r\n\
+            --XyZ--\r\n";
+        let raw = format!(
+            "POST /upload HTTP/1.1\r\n\
+             Content-Type: multipart/form-data; boundary=XyZ\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let request = Request::parse(&mut raw.as_bytes(), &Config::default()).unwrap();
+
+        let parts = request.multipart().unwrap();
+        assert_eq!(parts.len(), 2);
+
+        assert_eq!(parts[0].name.as_deref(), Some("title"));
+        assert_eq!(parts[0].filename, None);
+        assert_eq!(parts[0].body, b"Holiday");
+
+        assert_eq!(parts[1].name.as_deref(), Some("photo"));
+        assert_eq!(parts[1].filename.as_deref(), Some("beach.png"));
+        assert_eq!(parts[1].content_type.as_deref(), Some("image/png"));
+        assert_eq!(parts[1].body, "\x00PNG".as_bytes());
+    }
+
+    #[test]
+    fn test_multipart_missing_boundary() {
+        let raw = "POST /upload HTTP/1.1\r\n\
+                   Content-Type: multipart/form-data\r\n\
+                   Content-Length: 9\r\n\r\n--XyZ--\r\n";
+        let request = Request::parse(&mut raw.as_bytes(), &Config::default()).unwrap();
+
+        let err = request.multipart().unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_multipart_wrong_boundary() {
+        let raw = "POST /upload HTTP/1.1\r\n\
+                   Content-Type: multipart/form-data; boundary=AbC\r\n\
+                   Content-Length: 9\r\n\r\n--XyZ--\r\n";
+        let request = Request::parse(&mut raw.as_bytes(), &Config::default()).unwrap();
+
+        let err = request.multipart().unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_path_segments() {
+        let segments = |path: &str| {
+            Request::new(Method::Get, path)
+                .path_segments()
+                .map(|segment| segment.into_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(segments("/a/./b/../c"), ["a", "c"]);
+        assert_eq!(segments("//a///c/"), ["a", "c"]);
+        assert_eq!(
+            segments("/hello%20world/caf%C3%A9"),
+            ["hello world", "café"]
+        );
+        assert_eq!(segments("/100%/%zz"), ["100%", "%zz"]);
+        assert!(segments("/").is_empty());
+    }
+
+    #[test]
+    fn test_path_segments_stay_below_root() {
+        let segments = |path: &str| {
+            Request::new(Method::Get, path)
+                .path_segments()
+                .map(|segment| segment.into_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(segments("/../../etc/passwd"), ["etc", "passwd"]);
+        assert_eq!(segments("/a/../../b"), ["b"]);
+        assert_eq!(segments("/%2e%2e/%2E%2E/etc"), ["etc"]);
+        assert_eq!(segments("/a/%2e/b/%2e%2e"), ["a"]);
+        assert_eq!(segments("/a/..%2fetc"), ["a", "../etc"]);
+    }
+
+    #[test]
+    fn test_is_websocket_upgrade() {
+        let raw = "GET /chat HTTP/1.1\r\n\
+                   Host: localhost\r\n\
+                   Upgrade: websocket\r\n\
+                   Connection: keep-alive, Upgrade\r\n\
+                   Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        let mut request = Request::parse(&mut raw.as_bytes(), &Config::default()).unwrap();
+        assert!(request.is_websocket_upgrade());
+
+        request.insert_header("Connection", "keep-alive");
+        assert!(!request.is_websocket_upgrade());
+        assert!(!Request::new(Method::Get, "/chat").is_websocket_upgrade());
+    }
+
+    #[test]
+    fn test_method_override_header() {
+        let mut request = Request::new(Method::Post, "/notes/1");
+        request.insert_header("X-HTTP-Method-Override", "delete");
+        request.apply_method_override();
+        assert_eq!(request.method, Method::Delete);
+
+        // Only POST can be overridden, and only to PUT, PATCH or DELETE.
+        let mut request = Request::new(Method::Get, "/notes/1");
+        request.insert_header("X-HTTP-Method-Override", "DELETE");
+        request.apply_method_override();
+        assert_eq!(request.method, Method::Get);
+        let mut request = Request::new(Method::Post, "/notes/1");
+        request.insert_header("X-HTTP-Method-Override", "TRACE");
+        request.apply_method_override();
+        assert_eq!(request.method, Method::Post);
+    }
+
+    #[test]
+    fn test_method_override_form_field() {
+        let mut request = Request::new(Method::Post, "/notes/1");
+        request.insert_header("Content-Type", "application/x-www-form-urlencoded");
+        request.body = b"title=Groceries&_method=PUT".to_vec();
+
+        request.apply_method_override();
+
+        assert_eq!(request.method, Method::Put);
+    }
+}
@@ -0,0 +1,932 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, copy, prelude::*, ErrorKind},
+    path::Path,
+};
+
+use crate::{
+    gzip::{self, GzipEncoder},
+    scratch, sendfile, static_files, Event, HttpError, StatusCode,
+};
+
+enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    Reader {
+        reader: Box<dyn Read + Send>,
+        len: u64,
+    },
+    File {
+        file: File,
+        len: u64,
+    },
+    /// Events written one at a time, with no length, until the source runs
+    /// out or the client goes away.
+    Events(Box<dyn Iterator<Item = Event> + Send>),
+    /// Pieces written with chunked transfer encoding as the iterator yields
+    /// them, each compressed first when `gzip` is set. Without `framed`,
+    /// for HTTP/1.0 clients, the pieces are written as they are and the
+    /// body ends when the connection closes.
+    Chunks {
+        source: Box<dyn Iterator<Item = Vec<u8>> + Send>,
+        gzip: bool,
+        framed: bool,
+    },
+}
+
+/// An HTTP response, written to the client with [`Response::write_to`].
+pub struct Response {
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    body: Body,
+    /// Target a [`Router`](crate::Router) serves the request again for, set
+    /// by [`internal_redirect`](Response::internal_redirect).
+    internal_redirect: Option<String>,
+}
+
+impl Response {
+    pub fn new(status: StatusCode) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Empty,
+            internal_redirect: None,
+        }
+    }
+
+    /// Creates a response with `body` as text, sent with
+    /// `Content-Type: text/plain; charset=utf-8` unless replaced with
+    /// [`content_type`](Response::content_type). Like every body held in
+    /// memory, its `Content-Length` is its length in bytes.
+    pub fn with_body_str(status: StatusCode, body: &str) -> Response {
+        Response::new(status)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(body)
+    }
+
+    /// Creates a response with `body` as raw bytes, sent with
+    /// `Content-Type: application/octet-stream` unless replaced with
+    /// [`content_type`](Response::content_type).
+    pub fn with_body_bytes(status: StatusCode, body: impl Into<Vec<u8>>) -> Response {
+        Response::new(status)
+            .header("Content-Type", "application/octet-stream")
+            .body(body)
+    }
+
+    /// A `204 No Content` response, for a request that succeeded with
+    /// nothing to send back.
+    pub fn no_content() -> Response {
+        Response::new(StatusCode::NO_CONTENT)
+    }
+
+    /// A `304 Not Modified` response, for a conditional `GET` whose cached
+    /// copy is still current. Add the `ETag` and `Last-Modified` headers
+    /// the full response would have had, so the client can refresh them.
+    pub fn not_modified() -> Response {
+        Response::new(StatusCode::NOT_MODIFIED)
+    }
+
+    /// A `400 Bad Request` response with `message` as its text body,
+    /// telling the client what was wrong with the request.
+    pub fn bad_request(message: &str) -> Response {
+        Response::with_body_str(StatusCode::BAD_REQUEST, message)
+    }
+
+    /// A `403 Forbidden` response with the status as its text body.
+    pub fn forbidden() -> Response {
+        Response::status_page(StatusCode::FORBIDDEN)
+    }
+
+    /// A `404 Not Found` response with the status as its text body.
+    pub fn not_found() -> Response {
+        Response::status_page(StatusCode::NOT_FOUND)
+    }
+
+    /// A `500 Internal Server Error` response with the status as its text
+    /// body, which tells the client nothing about what went wrong.
+    pub fn internal_error() -> Response {
+        Response::status_page(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    /// A `503 Service Unavailable` response with the status as its text
+    /// body.
+    pub fn service_unavailable() -> Response {
+        Response::status_page(StatusCode::SERVICE_UNAVAILABLE)
+    }
+
+    fn status_page(status: StatusCode) -> Response {
+        Response::with_body_str(status, &format!("{}\n", status))
+    }
+
+    /// Creates a response whose body is streamed from `reader`, which must
+    /// yield exactly `len` bytes.
+    pub fn from_reader<R>(status: StatusCode, reader: R, len: u64) -> Response
+    where
+        R: Read + Send + 'static,
+    {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Reader {
+                reader: Box::new(reader),
+                len,
+            },
+            internal_redirect: None,
+        }
+    }
+
+    /// Creates a response whose body is the rest of `file`, from its current
+    /// position. When written to a socket on Linux the file is sent with
+    /// `sendfile(2)`, without passing through userspace buffers.
+    pub fn from_file(status: StatusCode, file: File) -> io::Result<Response> {
+        let len = file
+            .metadata()?
+            .len()
+            .saturating_sub((&file).stream_position()?);
+        Response::from_file_range(status, file, len)
+    }
+
+    /// Creates a `200 OK` response with the file at `path` as its body, and a
+    /// `Content-Type` going by its extension. A missing file is
+    /// [`HttpError::NotFound`], and one the server may not read, or that
+    /// isn't a regular file, [`HttpError::Forbidden`].
+    pub fn file(path: impl AsRef<Path>) -> Result<Response, HttpError> {
+        let path = path.as_ref();
+        // Checked before opening, since opening a FIFO blocks until a writer
+        // appears.
+        if !fs::metadata(path).map_err(file_error)?.is_file() {
+            return Err(HttpError::Forbidden);
+        }
+
+        let file = File::open(path).map_err(file_error)?;
+        let response = Response::from_file(StatusCode::OK, file).map_err(file_error)?;
+        Ok(response.header(
+            "Content-Type",
+            &static_files::content_type(path, Some("utf-8")),
+        ))
+    }
+
+    /// Like [`from_file`](Response::from_file), but the body is only the
+    /// next `len` bytes of `file`, which must have at least that many left.
+    pub fn from_file_range(status: StatusCode, file: File, len: u64) -> io::Result<Response> {
+        Ok(Response {
+            status,
+            headers: Vec::new(),
+            body: Body::File { file, len },
+            internal_redirect: None,
+        })
+    }
+
+    /// Creates a `text/event-stream` response that sends each event from
+    /// `source` as soon as the iterator yields it, for clients listening with
+    /// `EventSource`.
+    ///
+    /// The body has no `Content-Length`; it runs until `source` is exhausted
+    /// and then the connection is closed. Each event is flushed to the client
+    /// as it is written, and writing stops at the first write error, which is
+    /// how a client disconnecting is noticed. The request timeout doesn't
+    /// apply to the stream.
+    pub fn event_stream<I>(source: I) -> Response
+    where
+        I: IntoIterator<Item = Event>,
+        I::IntoIter: Send + 'static,
+    {
+        Response {
+            status: StatusCode::OK,
+            headers: Vec::new(),
+            body: Body::Events(Box::new(source.into_iter())),
+            internal_redirect: None,
+        }
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+    }
+
+    /// Creates a response whose body is sent with
+    /// `Transfer-Encoding: chunked`, one chunk for each piece `source`
+    /// yields, for bodies generated as they are sent whose length isn't known
+    /// up front. Each chunk is flushed to the client as it is written, and
+    /// the connection can be kept alive afterwards. HTTP/1.0 has no chunked
+    /// encoding, so an HTTP/1.0 client is sent the pieces as they are, with
+    /// the connection closed to end the body.
+    pub fn chunked<I>(status: StatusCode, source: I) -> Response
+    where
+        I: IntoIterator,
+        I::Item: Into<Vec<u8>> + 'static,
+        I::IntoIter: Send + 'static,
+    {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Chunks {
+                source: Box::new(source.into_iter().map(Into::into)),
+                gzip: false,
+                framed: true,
+            },
+            internal_redirect: None,
+        }
+    }
+
+    /// Compresses the body with gzip and sets `Content-Encoding: gzip`. Only
+    /// call it for clients whose `Accept-Encoding` includes `gzip`.
+    ///
+    /// A [`chunked`](Response::chunked) body is compressed as it streams:
+    /// each chunk goes out compressed and flushed, so the client can
+    /// decompress it without waiting for the rest. Bodies set with
+    /// [`body`](Response::body) are compressed whole. File, reader and event
+    /// stream bodies are left as they are.
+    ///
+    /// A partial (`206`) response is left as it is too: its range counts
+    /// bytes of the uncompressed body, so compressing the slice would hand
+    /// the client bytes that fit nowhere. So is a body that already has a
+    /// `Content-Encoding`. An `ETag` is given a `-gzip` suffix, so that
+    /// caches don't take the compressed body for the uncompressed one.
+    pub fn gzip(mut self) -> Response {
+        if self.status == StatusCode::PARTIAL_CONTENT
+            || self.header_value("Content-Range").is_some()
+            || self.header_value("Content-Encoding").is_some()
+        {
+            return self;
+        }
+        match &mut self.body {
+            Body::Bytes(bytes) => *bytes = gzip::compress_all(bytes),
+            Body::Chunks { gzip, .. } => *gzip = true,
+            _ => return self,
+        }
+        for (name, value) in &mut self.headers {
+            if name.eq_ignore_ascii_case("ETag") {
+                *value = encoded_etag(value, "gzip");
+            }
+        }
+        self.header("Content-Encoding", "gzip")
+            .header("Vary", "Accept-Encoding")
+    }
+
+    /// Creates a redirect to `location` with a short HTML body linking to it
+    /// for clients that display the response instead of following it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `status` isn't a redirect status (see
+    /// [`StatusCode::is_redirect`]).
+    pub fn redirect(status: StatusCode, location: &str) -> Response {
+        assert!(status.is_redirect(), "{} is not a redirect status", status);
+
+        let href = escape_html(location);
+        Response::new(status)
+            .header("Location", location)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(format!(
+                "<!DOCTYPE html>\n<title>{}</title>\n<p>Redirecting to <a href=\"{}\">{}</a>.</p>\n",
+                status, href, href
+            ))
+    }
+
+    /// A `307 Temporary Redirect` to `location`: the client repeats the
+    /// request there with the same method and body, unlike after a
+    /// `302 Found`, which many clients follow with a `GET`.
+    pub fn temporary_redirect(location: &str) -> Response {
+        Response::redirect(StatusCode::TEMPORARY_REDIRECT, location)
+    }
+
+    /// A `308 Permanent Redirect` to `location`, which like
+    /// [`temporary_redirect`](Response::temporary_redirect) keeps the method
+    /// and body, for canonicalizing URLs that take `POST`s.
+    pub fn permanent_redirect(location: &str) -> Response {
+        Response::redirect(StatusCode::PERMANENT_REDIRECT, location)
+    }
+
+    /// Has the [`Router`](crate::Router) serving the request serve it again
+    /// for `target`, a path and optional query from the root of the router,
+    /// without the client seeing a redirect. The method, headers and body
+    /// are kept. A router follows at most ten internal redirects for one
+    /// request and answers with `500 Internal Server Error` past that, so
+    /// routes redirecting to each other can't loop forever.
+    ///
+    /// Outside a router the response is never followed, and is sent as a
+    /// `500 Internal Server Error`.
+    pub fn internal_redirect(target: &str) -> Response {
+        let mut response = Response::new(StatusCode::INTERNAL_SERVER_ERROR);
+        response.internal_redirect = Some(target.to_string());
+        response
+    }
+
+    /// Takes the target of an [`internal_redirect`](Response::internal_redirect).
+    pub(crate) fn take_internal_redirect(&mut self) -> Option<String> {
+        self.internal_redirect.take()
+    }
+
+    /// Adds a header. Calling this again with the same name adds another
+    /// line rather than replacing the first, as `Set-Cookie` needs.
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets the `Content-Type` header, replacing any set before.
+    pub fn content_type(mut self, value: &str) -> Response {
+        self.headers
+            .retain(|(name, _)| !name.eq_ignore_ascii_case("Content-Type"));
+        self.header("Content-Type", value)
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = Body::Bytes(body.into());
+        self
+    }
+
+    /// Adds each of `headers` that the response doesn't already have under
+    /// any case.
+    pub(crate) fn default_headers(mut self, headers: &[(String, String)]) -> Response {
+        for (name, value) in headers {
+            if self.header_value(name).is_none() {
+                self.headers.push((name.clone(), value.clone()));
+            }
+        }
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Returns the first value of the named header, ignoring case.
+    pub fn header_value(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns every value of the named header, ignoring case.
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Length of the body as it will be sent: `0` for a status that never
+    /// carries one, and `None` for a streamed body of no set length.
+    pub(crate) fn body_len(&self) -> Option<u64> {
+        if !self.status.allows_body() {
+            return Some(0);
+        }
+        match &self.body {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Reader { len, .. } | Body::File { len, .. } => Some(*len),
+            Body::Events(_) | Body::Chunks { .. } => None,
+        }
+    }
+
+    /// Length of the body if it is held in memory.
+    pub(crate) fn memory_body_len(&self) -> Option<usize> {
+        match &self.body {
+            Body::Bytes(bytes) => Some(bytes.len()),
+            _ => None,
+        }
+    }
+
+    /// Whether the body is an event stream, which ends the connection.
+    pub(crate) fn is_event_stream(&self) -> bool {
+        matches!(self.body, Body::Events(_))
+    }
+
+    /// Drops the chunked framing of a [`chunked`](Response::chunked) body,
+    /// for a client that doesn't understand it, and returns whether there
+    /// was any. The connection must then be closed to end the body.
+    pub(crate) fn unframe(&mut self) -> bool {
+        match &mut self.body {
+            Body::Chunks { framed, .. } => {
+                *framed = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// A copy of the response, or `None` if its body is read from a file,
+    /// a reader or an iterator and so can only be written once.
+    pub(crate) fn try_clone(&self) -> Option<Response> {
+        let body = match &self.body {
+            Body::Empty => Body::Empty,
+            Body::Bytes(bytes) => Body::Bytes(bytes.clone()),
+            _ => return None,
+        };
+        Some(Response {
+            status: self.status,
+            headers: self.headers.clone(),
+            body,
+            internal_redirect: self.internal_redirect.clone(),
+        })
+    }
+
+    /// Writes the status line, headers and body to `writer`.
+    ///
+    /// Statuses that never carry a body (1xx, 204 and 304) are written without
+    /// a body or `Content-Length`, even if one was attached, so that clients
+    /// don't wait for bytes that will never arrive.
+    pub fn write_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        self.write_to_socket(writer, None)
+    }
+
+    /// Like [`write_to`](Response::write_to), but a file body is sent
+    /// straight to `socket`, the descriptor `writer` ultimately writes to,
+    /// once everything before it has been flushed through `writer`.
+    pub(crate) fn write_to_socket<W: Write>(
+        self,
+        writer: &mut W,
+        socket: Option<sendfile::Fd>,
+    ) -> io::Result<()> {
+        let allows_body = self.status.allows_body();
+        let length = match &self.body {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Reader { len, .. } | Body::File { len, .. } => Some(*len),
+            Body::Events(_) | Body::Chunks { .. } => None,
+        };
+
+        // Writing to a `String` can't fail.
+        scratch::with_line(|head| {
+            let _ = write!(head, "HTTP/1.1 {}\r\n", self.status);
+            for (name, value) in &self.headers {
+                if !allows_body && name.eq_ignore_ascii_case("Content-Length") {
+                    continue;
+                }
+                let _ = write!(head, "{}: {}\r\n", name, value);
+            }
+            if let (true, Some(length)) = (allows_body, length) {
+                let _ = write!(head, "Content-Length: {}\r\n", length);
+            }
+            if allows_body && matches!(self.body, Body::Chunks { framed: true, .. }) {
+                head.push_str("Transfer-Encoding: chunked\r\n");
+            }
+            head.push_str("\r\n");
+            writer.write_all(head.as_bytes())
+        })?;
+
+        if !allows_body {
+            return writer.flush();
+        }
+
+        match self.body {
+            Body::Empty => {}
+            Body::Bytes(bytes) => writer.write_all(&bytes)?,
+            Body::Reader { reader, len } => {
+                copy(&mut reader.take(len), writer)?;
+            }
+            Body::File { file, len } => {
+                let mut sent = 0;
+                if let Some(socket) = socket {
+                    writer.flush()?;
+                    sent = sendfile::send_file(socket, &file, len)?;
+                }
+                copy(&mut file.take(len - sent), writer)?;
+            }
+            Body::Events(events) => {
+                writer.flush()?;
+                for event in events {
+                    writer.write_all(event.to_string().as_bytes())?;
+                    writer.flush()?;
+                }
+            }
+            Body::Chunks {
+                source,
+                gzip,
+                framed,
+            } => {
+                let write_piece = |writer: &mut W, piece: &[u8]| {
+                    if framed {
+                        write_chunk(writer, piece)
+                    } else {
+                        writer.write_all(piece)
+                    }
+                };
+                let mut encoder = gzip.then(GzipEncoder::new);
+                for chunk in source {
+                    let chunk = match &mut encoder {
+                        Some(encoder) => encoder.chunk(&chunk),
+                        None => chunk,
+                    };
+                    write_piece(writer, &chunk)?;
+                    writer.flush()?;
+                }
+                if let Some(encoder) = encoder {
+                    write_piece(writer, &encoder.finish())?;
+                }
+                if framed {
+                    writer.write_all(b"0\r\n\r\n")?;
+                }
+            }
+        }
+
+        writer.flush()
+    }
+}
+
+/// `etag` with `-encoding` added inside its quotes, keeping any `W/`. A
+/// value that isn't a quoted entity tag is left as it is.
+fn encoded_etag(etag: &str, encoding: &str) -> String {
+    let (weak, tag) = match etag.strip_prefix("W/") {
+        Some(tag) => ("W/", tag),
+        None => ("", etag),
+    };
+    match tag.strip_prefix('"').and_then(|tag| tag.strip_suffix('"')) {
+        Some(opaque) => format!("{}\"{}-{}\"", weak, opaque, encoding),
+        None => etag.to_string(),
+    }
+}
+
+/// `etag` with the suffix [`encoded_etag`] adds for gzip removed, so a tag a
+/// client saw on a compressed response names the same version as the
+/// uncompressed one. Other values are returned as they are.
+pub(crate) fn unencoded_etag(etag: &str) -> String {
+    match etag.strip_suffix("-gzip\"") {
+        Some(opaque) if opaque.starts_with('"') && opaque.len() > 1 => format!("{}\"", opaque),
+        _ => etag.to_string(),
+    }
+}
+
+/// Maps an error opening a file onto the response it calls for.
+fn file_error(e: io::Error) -> HttpError {
+    match e.kind() {
+        ErrorKind::NotFound => HttpError::NotFound,
+        ErrorKind::PermissionDenied => HttpError::Forbidden,
+        _ => HttpError::Io(e),
+    }
+}
+
+/// Writes one chunk of a chunked body. Empty chunks are skipped, since a
+/// zero-length chunk ends the body.
+fn write_chunk<W: Write>(writer: &mut W, chunk: &[u8]) -> io::Result<()> {
+    if chunk.is_empty() {
+        return Ok(());
+    }
+    write!(writer, "{:x}\r\n", chunk.len())?;
+    writer.write_all(chunk)?;
+    writer.write_all(b"\r\n")
+}
+
+pub(crate) fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_to_with_body() {
+        let response = Response::new(StatusCode::OK)
+            .header("Content-Type", "text/plain")
+            .body("hi");
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nhi"
+        );
+    }
+
+    #[test]
+    fn test_with_body_str_counts_bytes() {
+        let response = Response::with_body_str(StatusCode::OK, "héllo wörld ✓");
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: 17\r\n\r\nhéllo wörld ✓"
+        );
+    }
+
+    #[test]
+    fn test_content_type_replaces_default() {
+        let response =
+            Response::with_body_bytes(StatusCode::OK, vec![0x89, b'P']).content_type("image/png");
+
+        assert_eq!(
+            response.header_values("content-type").collect::<Vec<_>>(),
+            ["image/png"]
+        );
+    }
+
+    #[test]
+    fn test_file_sets_type_and_length() {
+        let dir = std::env::temp_dir().join(format!("hello-response-file-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes.txt");
+        fs::write(&path, "caf\u{e9}").unwrap();
+
+        let response = Response::file(&path).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.header_value("Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Content-Length: 5\r\n"));
+        assert!(out.ends_with("\r\n\r\ncaf\u{e9}"));
+
+        assert!(matches!(
+            Response::file(dir.join("missing.txt")),
+            Err(HttpError::NotFound)
+        ));
+        assert!(matches!(Response::file(&dir), Err(HttpError::Forbidden)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_to_from_reader() {
+        let response = Response::from_reader(StatusCode::NOT_FOUND, &b"missing"[..], 7);
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+
+        assert!(out.starts_with(b"HTTP/1.1 404 Not Found\r\nContent-Length: 7\r\n"));
+        assert!(out.ends_with(b"\r\n\r\nmissing"));
+    }
+
+    #[test]
+    fn test_repeated_response_headers() {
+        let response = Response::new(StatusCode::OK)
+            .header("Set-Cookie", "session=abc")
+            .header("Set-Cookie", "theme=dark");
+
+        assert_eq!(
+            response.header_values("set-cookie").collect::<Vec<_>>(),
+            ["session=abc", "theme=dark"]
+        );
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Set-Cookie: session=abc\r\nSet-Cookie: theme=dark\r\n"));
+    }
+
+    #[test]
+    fn test_event_stream() {
+        let events = (1..=3).map(|i| Event::new(format!("tick {}", i)).id(i.to_string()));
+        let response = Response::event_stream(events);
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let (head, body) = out.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("Content-Type: text/event-stream"));
+        assert!(!head.contains("Content-Length"));
+        assert_eq!(
+            body,
+            "id: 1\ndata: tick 1\n\nid: 2\ndata: tick 2\n\nid: 3\ndata: tick 3\n\n"
+        );
+    }
+
+    /// Splits a chunked body into its chunks, checking the framing.
+    fn dechunk(mut body: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        loop {
+            let line_end = body.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size = std::str::from_utf8(&body[..line_end]).unwrap();
+            let size = usize::from_str_radix(size, 16).unwrap();
+            body = &body[line_end + 2..];
+            if size == 0 {
+                assert_eq!(body, b"\r\n");
+                return chunks;
+            }
+            chunks.push(body[..size].to_vec());
+            assert_eq!(&body[size..size + 2], b"\r\n");
+            body = &body[size + 2..];
+        }
+    }
+
+    fn split_head(out: &[u8]) -> (String, &[u8]) {
+        let end = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        (
+            String::from_utf8(out[..end].to_vec()).unwrap(),
+            &out[end + 4..],
+        )
+    }
+
+    #[test]
+    fn test_chunked_body() {
+        let response = Response::chunked(StatusCode::OK, ["one ", "", "two"]);
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+
+        let (head, body) = split_head(&out);
+        assert!(head.contains("Transfer-Encoding: chunked"));
+        assert!(!head.contains("Content-Length"));
+        assert_eq!(dechunk(body), [b"one ".to_vec(), b"two".to_vec()]);
+    }
+
+    #[test]
+    fn test_gzip_chunked_round_trip() {
+        let pieces: Vec<String> = (0..5)
+            .map(|i| format!("line {} of a generated report\n", i).repeat(20))
+            .collect();
+        let response = Response::chunked(StatusCode::OK, pieces.clone()).gzip();
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+
+        let (head, body) = split_head(&out);
+        assert!(head.contains("Content-Encoding: gzip"));
+        assert!(head.contains("Transfer-Encoding: chunked"));
+        let chunks = dechunk(body);
+        // One compressed chunk for each piece, then the gzip trailer.
+        assert_eq!(chunks.len(), pieces.len() + 1);
+        assert_eq!(
+            gzip::decompress(&chunks.concat(), usize::MAX).unwrap(),
+            pieces.concat().as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_gzip_whole_body() {
+        let text = "compress me ".repeat(100);
+        let response = Response::new(StatusCode::OK).body(text.clone()).gzip();
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+
+        let (head, body) = split_head(&out);
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        assert_eq!(gzip::decompress(body, usize::MAX).unwrap(), text.as_bytes());
+    }
+
+    #[test]
+    fn test_gzip_leaves_ranges_and_marks_etag() {
+        let partial = Response::new(StatusCode::PARTIAL_CONTENT)
+            .header("Content-Range", "bytes 0-4/11")
+            .header("ETag", "\"v1\"")
+            .body("hello")
+            .gzip();
+        assert_eq!(partial.header_value("Content-Encoding"), None);
+        assert_eq!(partial.header_value("ETag"), Some("\"v1\""));
+        let mut out = Vec::new();
+        partial.write_to(&mut out).unwrap();
+        assert_eq!(split_head(&out).1, b"hello");
+
+        let text = "compress me once ".repeat(50);
+        let whole = Response::new(StatusCode::OK)
+            .header("ETag", "W/\"v1\"")
+            .body(text.clone())
+            .gzip()
+            .gzip();
+        assert_eq!(whole.header_value("ETag"), Some("W/\"v1-gzip\""));
+        assert_eq!(whole.header_values("Content-Encoding").count(), 1);
+        let mut out = Vec::new();
+        whole.write_to(&mut out).unwrap();
+        let body = split_head(&out).1;
+        assert_eq!(gzip::decompress(body, usize::MAX).unwrap(), text.as_bytes());
+
+        assert_eq!(encoded_etag("*", "gzip"), "*");
+    }
+
+    #[test]
+    fn test_redirect_moved_permanently() {
+        let response = Response::redirect(StatusCode::MOVED_PERMANENTLY, "/docs/");
+
+        assert_eq!(response.header_value("Location"), Some("/docs/"));
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(out.contains("<a href=\"/docs/\">/docs/</a>"));
+    }
+
+    #[test]
+    fn test_method_preserving_redirects() {
+        let temporary = Response::temporary_redirect("/v2/orders");
+        assert_eq!(temporary.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(temporary.header_value("Location"), Some("/v2/orders"));
+
+        let permanent = Response::permanent_redirect("/orders");
+        assert_eq!(permanent.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(permanent.header_value("Location"), Some("/orders"));
+    }
+
+    #[test]
+    fn test_redirect_permanent_escapes_location() {
+        let response = Response::redirect(
+            StatusCode::PERMANENT_REDIRECT,
+            "https://example.com/?a=1&b=\"2\"",
+        );
+
+        assert_eq!(
+            response.header_value("Location"),
+            Some("https://example.com/?a=1&b=\"2\"")
+        );
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 308 Permanent Redirect\r\n"));
+        assert!(out.contains("href=\"https://example.com/?a=1&amp;b=&quot;2&quot;\""));
+    }
+
+    #[test]
+    #[should_panic(expected = "not a redirect status")]
+    fn test_redirect_rejects_other_status() {
+        Response::redirect(StatusCode::OK, "/");
+    }
+
+    #[test]
+    fn test_not_modified_omits_body() {
+        let response = Response::new(StatusCode::NOT_MODIFIED)
+            .header("ETag", "\"abc\"")
+            .body("stale body");
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out, "HTTP/1.1 304 Not Modified\r\nETag: \"abc\"\r\n\r\n");
+        assert!(!out.contains("Content-Length"));
+    }
+
+    #[test]
+    fn test_status_constructors() {
+        let written = |response: Response| {
+            let mut out = Vec::new();
+            response.write_to(&mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            written(Response::no_content()),
+            "HTTP/1.1 204 No Content\r\n\r\n"
+        );
+        assert_eq!(
+            written(Response::not_modified().header("ETag", "\"v2\"")),
+            "HTTP/1.1 304 Not Modified\r\nETag: \"v2\"\r\n\r\n"
+        );
+        assert_eq!(
+            written(Response::bad_request("missing name")),
+            "HTTP/1.1 400 Bad Request\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: 12\r\n\r\nmissing name"
+        );
+
+        for (response, status) in [
+            (Response::forbidden(), StatusCode::FORBIDDEN),
+            (Response::not_found(), StatusCode::NOT_FOUND),
+            (
+                Response::internal_error(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                Response::service_unavailable(),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+        ] {
+            assert_eq!(response.status(), status);
+            let out = written(response);
+            assert!(out.starts_with(&format!("HTTP/1.1 {}\r\n", status)));
+            assert!(out.ends_with(&format!("\r\n\r\n{}\n", status)), "{:?}", out);
+        }
+    }
+
+    #[test]
+    fn test_no_body_statuses() {
+        for status in [
+            StatusCode::CONTINUE,
+            StatusCode::NO_CONTENT,
+            StatusCode::NOT_MODIFIED,
+        ] {
+            let mut out = Vec::new();
+            Response::new(status)
+                .header("Content-Length", "4")
+                .body("body")
+                .write_to(&mut out)
+                .unwrap();
+
+            assert!(out.ends_with(b"\r\n\r\n"), "{} wrote a body", status);
+            assert!(!String::from_utf8(out).unwrap().contains("Content-Length"));
+        }
+    }
+}
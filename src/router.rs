@@ -0,0 +1,649 @@
+use std::{
+    mem,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use crate::{request, Handler, JobError, Method, Request, Response, StatusCode, ThreadPool};
+
+/// Number of workers in the pool that runs handlers with a timeout.
+const TIMEOUT_POOL_SIZE: usize = 4;
+
+/// Most internal redirects followed for one request.
+const MAX_INTERNAL_REDIRECTS: usize = 10;
+
+type BoxedHandler = Arc<dyn Handler>;
+
+/// A single registered route, returned by [`Router::route`] so that
+/// per-route options can be chained onto it.
+pub struct Route {
+    method: Method,
+    path: String,
+    handler: BoxedHandler,
+    timeout: Option<Duration>,
+    blocking: bool,
+    max_body: Option<usize>,
+    stream_body: bool,
+}
+
+impl Route {
+    /// Abandons the handler with a `504 Gateway Timeout` if it runs longer
+    /// than `timeout`.
+    ///
+    /// Threads cannot be killed, so a handler that overruns keeps running on
+    /// its worker until it returns on its own; its response is discarded.
+    /// Handlers that routinely overrun will therefore tie up the timeout
+    /// workers and cause later requests to time out while they wait.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Route {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Marks the handler as one that blocks for a long time, such as on a
+    /// slow upstream. A [`Server`](crate::Server) serves requests for it on
+    /// a separate pool of `config.blocking_pool_size` workers, so that
+    /// however many are waiting they can't occupy every worker and starve
+    /// other requests. The connection is closed after the response.
+    pub fn blocking(&mut self) -> &mut Route {
+        self.blocking = true;
+        self
+    }
+
+    /// Accepts request bodies of up to `max_body` bytes on this route, in
+    /// place of `config.max_body`, such as a larger limit for uploads or a
+    /// tighter one for a JSON API. Larger bodies are refused with
+    /// `413 Payload Too Large` before they are read.
+    pub fn max_body(&mut self, max_body: usize) -> &mut Route {
+        self.max_body = Some(max_body);
+        self
+    }
+
+    /// Leaves request bodies on the connection for the handler to read
+    /// with [`Request::body_reader`] as they arrive, rather than reading
+    /// them into `body` first, such as for uploads too large to hold in
+    /// memory. Bodies are still limited by [`max_body`](Route::max_body).
+    /// Blocking routes are handed their request once it has been read, so
+    /// their bodies are read first whatever this says.
+    pub fn stream_body(&mut self) -> &mut Route {
+        self.stream_body = true;
+        self
+    }
+}
+
+/// Which way [`Router::redirect_trailing_slash`] redirects paths that only
+/// miss a route by a trailing slash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrailingSlash {
+    /// Redirects `/foo/` to a route registered as `/foo`.
+    Strip,
+    /// Redirects `/foo` to a route registered as `/foo/`.
+    Append,
+}
+
+/// Dispatches requests to handlers by method and exact path, after first
+/// handing requests for a virtual host to that host's own router. Requests
+/// matching no route go to the router [mounted](Router::mount) under their
+/// path, if any.
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: BoxedHandler,
+    timeout_pool: OnceLock<ThreadPool>,
+    hosts: Vec<(String, Router)>,
+    /// Mounted routers by prefix, kept without a trailing slash.
+    mounts: Vec<(String, Router)>,
+    trailing_slash: Option<TrailingSlash>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            fallback: Arc::new(not_found),
+            timeout_pool: OnceLock::new(),
+            hosts: Vec::new(),
+            mounts: Vec::new(),
+            trailing_slash: None,
+        }
+    }
+
+    /// Returns the router for requests whose `Host` header names `host`,
+    /// creating it the first time. Host names match regardless of case and
+    /// of any port in the header; requests for hosts without a router of
+    /// their own are dispatched through this one.
+    pub fn host(&mut self, host: &str) -> &mut Router {
+        let index = match self
+            .hosts
+            .iter()
+            .position(|(name, _)| name.eq_ignore_ascii_case(host))
+        {
+            Some(index) => index,
+            None => {
+                self.hosts.push((host.to_string(), Router::new()));
+                self.hosts.len() - 1
+            }
+        };
+        &mut self.hosts[index].1
+    }
+
+    /// Serves `router`'s routes under `prefix`: a request for `/api/users`
+    /// reaches a router mounted at `/api` as a request for `/users`, and one
+    /// for `/api` itself as `/`. The prefix only matches whole segments, so
+    /// `/apis` isn't under `/api`.
+    ///
+    /// Routes of this router win over mounted ones, and of routers mounted
+    /// at overlapping prefixes, the one with the longest prefix serves the
+    /// request, whatever order they were mounted in. A request under a
+    /// prefix is left to the mounted router entirely, its fallback included.
+    /// Mounting a second router at the same prefix replaces the first.
+    pub fn mount(&mut self, prefix: &str, router: Router) {
+        let prefix = prefix.trim_end_matches('/').to_string();
+        match self
+            .mounts
+            .iter_mut()
+            .find(|(mounted, _)| *mounted == prefix)
+        {
+            Some((_, mounted)) => *mounted = router,
+            None => self.mounts.push((prefix, router)),
+        }
+    }
+
+    pub fn route<F>(&mut self, method: Method, path: &str, handler: F) -> &mut Route
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route_handler(method, path, handler)
+    }
+
+    /// Like [`route`](Router::route), but for a [`Handler`] that isn't a
+    /// closure, such as one carrying its own state.
+    pub fn route_handler<H>(&mut self, method: Method, path: &str, handler: H) -> &mut Route
+    where
+        H: Handler,
+    {
+        self.routes.push(Route {
+            method,
+            path: path.to_string(),
+            handler: Arc::new(handler),
+            timeout: None,
+            blocking: false,
+            max_body: None,
+            stream_body: false,
+        });
+        self.routes.last_mut().unwrap()
+    }
+
+    pub fn get<F>(&mut self, path: &str, handler: F) -> &mut Route
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Get, path, handler)
+    }
+
+    /// Answers requests whose path matches no route, but would with a
+    /// trailing slash removed or added as `direction` says, with a
+    /// `308 Permanent Redirect` to that path, keeping the query. The redirect
+    /// only goes to a registered path, and only in one direction, so it
+    /// can't loop. Off by default; applies to this router and not to those
+    /// returned by [`host`](Router::host).
+    pub fn redirect_trailing_slash(&mut self, direction: TrailingSlash) {
+        self.trailing_slash = Some(direction);
+    }
+
+    /// Sets the handler used when no route matches the request path.
+    pub fn fallback<F>(&mut self, handler: F)
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.fallback_handler(handler);
+    }
+
+    /// Like [`fallback`](Router::fallback), but for a [`Handler`] that
+    /// isn't a closure.
+    pub fn fallback_handler<H>(&mut self, handler: H)
+    where
+        H: Handler,
+    {
+        self.fallback = Arc::new(handler);
+    }
+
+    /// Answers `request` with the handler it is routed to, following any
+    /// [internal redirects](Response::internal_redirect) that handler asks
+    /// for.
+    pub fn dispatch(&self, mut request: Request) -> Response {
+        let mut followed = 0;
+        loop {
+            let mut response = self.dispatch_under("", &mut request);
+            let Some(target) = response.take_internal_redirect() else {
+                return response;
+            };
+
+            followed += 1;
+            if followed > MAX_INTERNAL_REDIRECTS {
+                eprintln!(
+                    "Internal redirect loop: gave up after {} redirects, the last to {}",
+                    MAX_INTERNAL_REDIRECTS, target
+                );
+                return Response::with_body_str(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal redirect loop\n",
+                );
+            }
+            (request.path, request.query) = request::split_target(&target);
+        }
+    }
+
+    /// Dispatches `request`, whose path has had `prefix` stripped from it by
+    /// the routers this one is mounted in.
+    fn dispatch_under(&self, prefix: &str, request: &mut Request) -> Response {
+        if let Some(router) = self.host_router(request) {
+            return router.dispatch_under(prefix, request);
+        }
+
+        let mut path_matched = false;
+
+        for route in &self.routes {
+            if route.path != request.path {
+                continue;
+            }
+            path_matched = true;
+
+            if route.method == request.method {
+                return match route.timeout {
+                    Some(timeout) => self.call_with_timeout(route, request, timeout),
+                    None => route.handler.handle(request),
+                };
+            }
+        }
+
+        if path_matched {
+            return Response::new(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        if let Some((mounted, router, rest)) = self.mounted_router(&request.path) {
+            let prefix = format!("{}{}", prefix, mounted);
+            request.path = rest.to_string();
+            return router.dispatch_under(&prefix, request);
+        }
+
+        if let Some(location) = self.slash_redirect(prefix, request) {
+            Response::redirect(StatusCode::PERMANENT_REDIRECT, &location)
+        } else {
+            self.fallback.handle(request)
+        }
+    }
+
+    /// Where to redirect `request` to reach a route by fixing its trailing
+    /// slash, if redirecting is on and such a route exists. `prefix` is put
+    /// back in front of the path it was stripped from.
+    fn slash_redirect(&self, prefix: &str, request: &Request) -> Option<String> {
+        let path = &request.path;
+        let target = match self.trailing_slash? {
+            TrailingSlash::Strip if path.len() > 1 && path.ends_with('/') => {
+                path.trim_end_matches('/').to_string()
+            }
+            TrailingSlash::Append if !path.ends_with('/') => format!("{}/", path),
+            _ => return None,
+        };
+        if target.is_empty() || !self.routes.iter().any(|route| route.path == target) {
+            return None;
+        }
+
+        Some(match &request.query {
+            Some(query) => format!("{}{}?{}", prefix, target, query),
+            None => format!("{}{}", prefix, target),
+        })
+    }
+
+    /// The router mounted at the longest prefix of `path`, with that prefix
+    /// and the rest of the path.
+    fn mounted_router<'p>(&self, path: &'p str) -> Option<(&str, &Router, &'p str)> {
+        self.mounts
+            .iter()
+            .filter_map(|(prefix, router)| {
+                let rest = match path.strip_prefix(prefix.as_str())? {
+                    "" => "/",
+                    rest if rest.starts_with('/') => rest,
+                    _ => return None,
+                };
+                Some((prefix.as_str(), router, rest))
+            })
+            .max_by_key(|(prefix, _, _)| prefix.len())
+    }
+
+    /// Whether `request` would be dispatched to a route marked
+    /// [`blocking`](Route::blocking).
+    pub(crate) fn is_blocking(&self, request: &Request) -> bool {
+        self.matched_route(request)
+            .is_some_and(|route| route.blocking)
+    }
+
+    /// The body limit of the route `request` would be dispatched to, if it
+    /// sets one with [`Route::max_body`].
+    pub(crate) fn max_body(&self, request: &Request) -> Option<usize> {
+        self.matched_route(request).and_then(|route| route.max_body)
+    }
+
+    /// Whether `request` would be dispatched to a route that reads its body
+    /// itself, with [`Route::stream_body`].
+    pub(crate) fn streams_body(&self, request: &Request) -> bool {
+        self.matched_route(request)
+            .is_some_and(|route| route.stream_body && !route.blocking)
+    }
+
+    /// The route `request` would be dispatched to, going through the
+    /// router for its host and any mounted router.
+    fn matched_route(&self, request: &Request) -> Option<&Route> {
+        self.route_for(request, &request.path)
+    }
+
+    /// The route `request` would be dispatched to were its path `path`.
+    fn route_for(&self, request: &Request, path: &str) -> Option<&Route> {
+        if let Some(router) = self.host_router(request) {
+            return router.route_for(request, path);
+        }
+
+        if self.routes.iter().any(|route| route.path == path) {
+            return self
+                .routes
+                .iter()
+                .find(|route| route.path == path && route.method == request.method);
+        }
+        let (_, router, rest) = self.mounted_router(path)?;
+        router.route_for(request, rest)
+    }
+
+    fn host_router(&self, request: &Request) -> Option<&Router> {
+        let host = host_name(request.header("Host")?);
+        self.hosts
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(host))
+            .map(|(_, router)| router)
+    }
+
+    /// Runs the route's handler on the timeout pool. `request` is handed
+    /// back once the handler returns, for an internal redirect to reuse.
+    fn call_with_timeout(
+        &self,
+        route: &Route,
+        request: &mut Request,
+        timeout: Duration,
+    ) -> Response {
+        let handler = Arc::clone(&route.handler);
+        let pool = self
+            .timeout_pool
+            .get_or_init(|| ThreadPool::new(TIMEOUT_POOL_SIZE));
+
+        let taken = mem::replace(request, Request::new(Method::Get, ""));
+        match pool
+            .submit(move || {
+                let response = handler.handle(&taken);
+                (response, taken)
+            })
+            .join_timeout(timeout)
+        {
+            Ok((response, taken)) => {
+                *request = taken;
+                response
+            }
+            Err(JobError::TimedOut) => {
+                eprintln!("Handler for {} timed out after {:?}", route.path, timeout);
+                Response::new(StatusCode::GATEWAY_TIMEOUT)
+            }
+            Err(e) => {
+                eprintln!("Handler for {} failed: {}", route.path, e);
+                Response::new(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+/// Strips the port, if any, from a `Host` header value.
+fn host_name(host: &str) -> &str {
+    if host.starts_with('[') {
+        // An IPv6 literal, whose own colons aren't a port separator.
+        return host.split_inclusive(']').next().unwrap_or(host);
+    }
+    host.split(':').next().unwrap_or(host)
+}
+
+fn not_found(_: &Request) -> Response {
+    Response::new(StatusCode::NOT_FOUND)
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    fn get(path: &str) -> Request {
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", path);
+        Request::parse(&mut raw.as_bytes(), &Config::default()).unwrap()
+    }
+
+    #[test]
+    fn test_dispatch_matches_path() {
+        let mut router = Router::new();
+        router.get("/", |_| Response::new(StatusCode::OK));
+
+        assert_eq!(router.dispatch(get("/")).status(), StatusCode::OK);
+        assert_eq!(
+            router.dispatch(get("/nope")).status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    struct HitCounter {
+        hits: AtomicUsize,
+    }
+
+    impl Handler for HitCounter {
+        fn handle(&self, _request: &Request) -> Response {
+            let hits = self.hits.fetch_add(1, Ordering::SeqCst) + 1;
+            Response::new(StatusCode::OK).body(hits.to_string())
+        }
+    }
+
+    #[test]
+    fn test_stateful_handler() {
+        let mut router = Router::new();
+        router.route_handler(
+            Method::Get,
+            "/hits",
+            HitCounter {
+                hits: AtomicUsize::new(0),
+            },
+        );
+        router.get("/closure", |_| Response::new(StatusCode::OK));
+
+        let mut out = Vec::new();
+        router.dispatch(get("/hits"));
+        router.dispatch(get("/hits")).write_to(&mut out).unwrap();
+
+        assert!(out.ends_with(b"\r\n\r\n2"));
+        assert_eq!(router.dispatch(get("/closure")).status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_strip_trailing_slash_redirect() {
+        let mut router = Router::new();
+        router.get("/foo", |_| Response::new(StatusCode::OK));
+        router.redirect_trailing_slash(TrailingSlash::Strip);
+
+        let response = router.dispatch(get("/foo/?page=2"));
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.header_value("Location"), Some("/foo?page=2"));
+
+        assert_eq!(router.dispatch(get("/foo")).status(), StatusCode::OK);
+        assert_eq!(
+            router.dispatch(get("/bar/")).status(),
+            StatusCode::NOT_FOUND
+        );
+        // A root-only route can't be reached by stripping `/`.
+        assert_eq!(router.dispatch(get("/")).status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_append_trailing_slash_redirect() {
+        let mut router = Router::new();
+        router.get("/docs/", |_| Response::new(StatusCode::OK));
+        router.get("/foo", |_| Response::new(StatusCode::OK));
+
+        assert_eq!(
+            router.dispatch(get("/docs")).status(),
+            StatusCode::NOT_FOUND
+        );
+
+        router.redirect_trailing_slash(TrailingSlash::Append);
+        let response = router.dispatch(get("/docs"));
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.header_value("Location"), Some("/docs/"));
+        // Appending never strips, so `/foo/` isn't sent back to `/foo`.
+        assert_eq!(
+            router.dispatch(get("/foo/")).status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn test_dispatch_by_host() {
+        let mut router = Router::new();
+        router.get("/", |_| Response::new(StatusCode::OK).body("main"));
+        router
+            .host("api.example.com")
+            .get("/", |_| Response::new(StatusCode::OK).body("api"));
+
+        let body = |host: Option<&str>| {
+            let mut request = get("/");
+            if let Some(host) = host {
+                request.insert_header("Host", host);
+            }
+            let mut out = Vec::new();
+            router.dispatch(request).write_to(&mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert!(body(Some("api.example.com")).ends_with("api"));
+        assert!(body(Some("API.example.com:8080")).ends_with("api"));
+        assert!(body(Some("www.example.com")).ends_with("main"));
+        assert!(body(None).ends_with("main"));
+    }
+
+    #[test]
+    fn test_mounted_router_serves_under_prefix() {
+        let echo = |name: &'static str| {
+            move |request: &Request| {
+                Response::new(StatusCode::OK).body(format!("{} {}", name, request.path))
+            }
+        };
+        let mut api = Router::new();
+        api.get("/", echo("api"));
+        api.get("/users", echo("api"));
+        api.get("/slow", echo("api")).blocking();
+        api.redirect_trailing_slash(TrailingSlash::Strip);
+        let mut v2 = Router::new();
+        v2.get("/users", echo("v2"));
+
+        let mut router = Router::new();
+        router.get("/api/health", echo("main"));
+        router.mount("/api/v2/", v2);
+        router.mount("/api", api);
+
+        let body = |path: &str| {
+            let mut out = Vec::new();
+            router.dispatch(get(path)).write_to(&mut out).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            out.split_once("\r\n\r\n").unwrap().1.to_string()
+        };
+
+        assert_eq!(body("/api/users"), "api /users");
+        assert_eq!(body("/api"), "api /");
+        assert_eq!(body("/api/v2/users"), "v2 /users");
+        assert_eq!(body("/api/health"), "main /api/health");
+        assert_eq!(
+            router.dispatch(get("/apis")).status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let redirect = router.dispatch(get("/api/users/?page=2"));
+        assert_eq!(redirect.header_value("Location"), Some("/api/users?page=2"));
+        assert!(router.is_blocking(&get("/api/slow")));
+        assert!(!router.is_blocking(&get("/slow")));
+    }
+
+    #[test]
+    fn test_internal_redirect_keeps_request_and_stops_loops() {
+        let mut router = Router::new();
+        router.route(Method::Post, "/docs", |_| {
+            Response::internal_redirect("/docs/index?lang=en")
+        });
+        router.route(Method::Post, "/docs/index", |request| {
+            let body = format!(
+                "{} {:?} {}",
+                request.path,
+                request.query,
+                String::from_utf8_lossy(&request.body)
+            );
+            Response::new(StatusCode::OK).body(body)
+        });
+        router.get("/a", |_| Response::internal_redirect("/b"));
+        router
+            .get("/b", |_| Response::internal_redirect("/a"))
+            .timeout(Duration::from_secs(5));
+
+        let mut request = Request::new(Method::Post, "/docs");
+        request.body = b"draft".to_vec();
+        let mut out = Vec::new();
+        router.dispatch(request).write_to(&mut out).unwrap();
+        assert!(out.ends_with(b"/docs/index Some(\"lang=en\") draft"));
+
+        let response = router.dispatch(get("/a"));
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        assert!(out.ends_with(b"Internal redirect loop\n"));
+    }
+
+    #[test]
+    fn test_host_name_strips_port() {
+        assert_eq!(host_name("example.com"), "example.com");
+        assert_eq!(host_name("example.com:8080"), "example.com");
+        assert_eq!(host_name("[::1]:8080"), "[::1]");
+    }
+
+    #[test]
+    fn test_slow_handler_times_out() {
+        let mut router = Router::new();
+        router
+            .get("/slow", |_| {
+                thread::sleep(Duration::from_millis(500));
+                Response::new(StatusCode::OK)
+            })
+            .timeout(Duration::from_millis(50));
+
+        let start = Instant::now();
+        let response = router.dispatch(get("/slow"));
+
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_fast_handler_within_timeout() {
+        let mut router = Router::new();
+        router
+            .get("/fast", |_| Response::new(StatusCode::OK))
+            .timeout(Duration::from_secs(1));
+
+        assert_eq!(router.dispatch(get("/fast")).status(), StatusCode::OK);
+    }
+}
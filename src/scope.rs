@@ -0,0 +1,176 @@
+use std::{
+    marker::PhantomData,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+};
+
+use crate::ThreadPool;
+
+struct ScopeState {
+    pending: Mutex<usize>,
+    finished: Condvar,
+    panicked: AtomicBool,
+}
+
+impl ScopeState {
+    fn wait(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        while *pending > 0 {
+            pending = self.finished.wait(pending).unwrap();
+        }
+    }
+}
+
+/// Decrements the scope's pending count when dropped, whether or not the
+/// job it belongs to ever ran.
+struct PendingGuard(Arc<ScopeState>);
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        let mut pending = self.0.pending.lock().unwrap();
+        *pending -= 1;
+        if *pending == 0 {
+            self.0.finished.notify_all();
+        }
+    }
+}
+
+/// A job together with its guard. Fields drop in declaration order, so the
+/// job (and everything it borrows) is gone before the scope is released.
+struct ScopedJob<F> {
+    job: F,
+    guard: PendingGuard,
+}
+
+/// A scope for running jobs that borrow from the caller's stack, created by
+/// [`ThreadPool::scope`].
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'env ThreadPool,
+    state: Arc<ScopeState>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Queues `f` on the pool. `f` may borrow anything that outlives the
+    /// scope.
+    pub fn execute<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *self.state.pending.lock().unwrap() += 1;
+
+        let scoped = ScopedJob {
+            job: f,
+            guard: PendingGuard(Arc::clone(&self.state)),
+        };
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            let ScopedJob { job, guard } = scoped;
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                guard.0.panicked.store(true, Ordering::SeqCst);
+            }
+        });
+
+        // SAFETY: `ThreadPool::scope` does not return until the pending count
+        // drops back to zero, which only happens once this job has run or been
+        // dropped. Nothing borrowed for 'scope can be used after that.
+        let job: Box<dyn FnOnce() + Send + 'static> = unsafe { mem::transmute(job) };
+
+        self.pool.execute(job);
+    }
+}
+
+impl ThreadPool {
+    /// Runs `f` with a [`Scope`] whose jobs may borrow non-`'static` data.
+    ///
+    /// Every job queued through the scope has finished by the time `scope`
+    /// returns. If any of them panicked, `scope` panics as well once they are
+    /// all done.
+    ///
+    /// Calling `scope` from inside one of the pool's own jobs can deadlock if
+    /// every worker ends up waiting on a scope.
+    pub fn scope<'env, F, T>(&'env self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(ScopeState {
+                pending: Mutex::new(0),
+                finished: Condvar::new(),
+                panicked: AtomicBool::new(false),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+
+        scope.state.wait();
+
+        match result {
+            Err(payload) => panic::resume_unwind(payload),
+            Ok(_) if scope.state.panicked.load(Ordering::SeqCst) => {
+                panic!("a scoped job panicked")
+            }
+            Ok(result) => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn test_scope_borrows_local_buffer() {
+        let pool = ThreadPool::new(4);
+        let mut buffer: Vec<u32> = (1..=100).collect();
+
+        pool.scope(|s| {
+            for chunk in buffer.chunks_mut(10) {
+                s.execute(move || {
+                    for value in chunk {
+                        *value *= 2;
+                    }
+                });
+            }
+        });
+
+        assert_eq!(buffer, (1..=100).map(|v| v * 2).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_scope_waits_for_all_jobs() {
+        let pool = ThreadPool::new(4);
+        let input = [3, 1, 4, 1, 5, 9, 2, 6];
+        let total = AtomicUsize::new(0);
+
+        pool.scope(|s| {
+            for value in &input {
+                let total = &total;
+                s.execute(move || {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    total.fetch_add(*value, Ordering::SeqCst);
+                });
+            }
+        });
+
+        assert_eq!(total.load(Ordering::SeqCst), 31);
+    }
+
+    #[test]
+    #[should_panic(expected = "a scoped job panicked")]
+    fn test_scope_propagates_job_panic() {
+        let pool = ThreadPool::new(2);
+
+        pool.scope(|s| {
+            s.execute(|| panic!("boom"));
+        });
+    }
+}
@@ -0,0 +1,162 @@
+//! Buffers each thread keeps from one request to the next, so that serving
+//! a request on a worker doesn't allocate them afresh.
+//!
+//! Every buffer is emptied before it is handed out, so nothing from one
+//! request is visible while serving the next. A buffer that grew past
+//! `MAX_RETAINED` bytes for an unusually large request is shrunk back once
+//! it is handed back, so one big request doesn't pin memory on the thread.
+
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+};
+
+/// Most bytes of capacity a buffer keeps between requests.
+const MAX_RETAINED: usize = 64 * 1024;
+
+/// Fewest bytes an [`OutputWriter`] passes to its writer at once for a write
+/// that bypasses its buffer, however small the buffer.
+const MIN_SEGMENT: usize = 512;
+
+thread_local! {
+    static LINE: RefCell<String> = const { RefCell::new(String::new()) };
+    static OUTPUT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Runs `f` with the thread's line buffer, used for reading request heads
+/// and formatting response heads. A nested call, which finds the buffer
+/// already in use, gets a fresh one.
+pub(crate) fn with_line<T>(f: impl FnOnce(&mut String) -> T) -> T {
+    LINE.with(|cell| match cell.try_borrow_mut() {
+        Ok(mut line) => {
+            line.clear();
+            let result = f(&mut line);
+            line.clear();
+            line.shrink_to(MAX_RETAINED);
+            result
+        }
+        Err(_) => f(&mut String::new()),
+    })
+}
+
+/// Runs `f` with a writer that buffers up to `capacity` bytes on the way to
+/// `inner`, in the thread's output buffer. Writes of `capacity` bytes or
+/// more bypass the buffer, as with `BufWriter`, and go to `inner` in
+/// segments of at most `capacity` bytes (or 512, if that is larger), each
+/// flushed before the next, so that a large body held in memory leaves in
+/// bounded writes. Anything still buffered when `f` returns is discarded, so
+/// `f` must flush.
+pub(crate) fn with_output<W, T>(
+    capacity: usize,
+    inner: W,
+    f: impl FnOnce(&mut OutputWriter<'_, W>) -> T,
+) -> T
+where
+    W: Write,
+{
+    OUTPUT.with(|cell| {
+        let mut fresh = Vec::new();
+        let mut borrowed = cell.try_borrow_mut();
+        let buffer = match &mut borrowed {
+            Ok(buffer) => &mut **buffer,
+            Err(_) => &mut fresh,
+        };
+        buffer.clear();
+        buffer.reserve(capacity);
+
+        let result = f(&mut OutputWriter {
+            buffer,
+            capacity,
+            inner,
+        });
+
+        if let Ok(buffer) = &mut borrowed {
+            buffer.clear();
+            buffer.shrink_to(MAX_RETAINED);
+        }
+        result
+    })
+}
+
+/// A buffered writer over a borrowed buffer. See [`with_output`].
+pub(crate) struct OutputWriter<'a, W: Write> {
+    buffer: &'a mut Vec<u8>,
+    capacity: usize,
+    inner: W,
+}
+
+impl<W: Write> OutputWriter<'_, W> {
+    fn flush_buffer(&mut self) -> io::Result<()> {
+        let result = self.inner.write_all(self.buffer);
+        self.buffer.clear();
+        result
+    }
+}
+
+impl<W: Write> Write for OutputWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.len() + buf.len() > self.capacity {
+            self.flush_buffer()?;
+        }
+        if buf.len() >= self.capacity {
+            let segment = buf.len().min(self.capacity.max(MIN_SEGMENT));
+            let written = self.inner.write(&buf[..segment])?;
+            self.inner.flush()?;
+            Ok(written)
+        } else {
+            self.buffer.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buffer()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_is_cleared_between_uses() {
+        with_line(|line| line.push_str("Authorization: secret"));
+
+        with_line(|line| {
+            assert!(line.is_empty());
+            assert!(line.capacity() > 0);
+        });
+    }
+
+    #[test]
+    fn test_output_buffers_small_writes() {
+        let mut out = Vec::new();
+
+        with_output(16, &mut out, |writer| {
+            writer.write_all(b"head ").unwrap();
+            writer.write_all(b"more").unwrap();
+            assert!(writer.inner.is_empty());
+            writer.write_all(&[b'x'; 32]).unwrap();
+            writer.flush().unwrap();
+        });
+
+        assert_eq!(out.len(), 9 + 32);
+        assert!(out.starts_with(b"head more"));
+        with_output(16, Vec::new(), |writer| assert!(writer.buffer.is_empty()));
+    }
+
+    #[test]
+    fn test_large_write_goes_out_in_segments() {
+        let body: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        let mut out = Vec::new();
+
+        with_output(1024, &mut out, |writer| {
+            assert_eq!(writer.write(&body).unwrap(), 1024);
+            writer.write_all(&body[1024..]).unwrap();
+            writer.flush().unwrap();
+        });
+
+        assert_eq!(out, body);
+    }
+}
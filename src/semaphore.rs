@@ -0,0 +1,64 @@
+use std::{
+    error, fmt,
+    sync::{Arc, Condvar, Mutex},
+};
+
+/// Counts out a fixed number of permits, each returned when its [`Permit`]
+/// is dropped.
+pub(crate) struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    pub(crate) fn new(permits: usize) -> Arc<Semaphore> {
+        Arc::new(Semaphore {
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        })
+    }
+
+    /// Takes a permit, waiting for one to be returned if none is available.
+    pub(crate) fn acquire(self: &Arc<Self>) -> Permit {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+        Permit(Arc::clone(self))
+    }
+
+    /// Takes a permit if one is available right now.
+    pub(crate) fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        let mut available = self.available.lock().unwrap();
+        if *available == 0 {
+            return None;
+        }
+        *available -= 1;
+        Some(Permit(Arc::clone(self)))
+    }
+}
+
+pub(crate) struct Permit(Arc<Semaphore>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *self.0.available.lock().unwrap() += 1;
+        self.0.released.notify_one();
+    }
+}
+
+/// Returned by [`ThreadPool::try_execute`] when the pool already has
+/// `max_in_flight` jobs queued or running.
+///
+/// [`ThreadPool::try_execute`]: crate::ThreadPool::try_execute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Full;
+
+impl fmt::Display for Full {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("too many jobs in flight")
+    }
+}
+
+impl error::Error for Full {}
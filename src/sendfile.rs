@@ -0,0 +1,131 @@
+//! Zero-copy transfer of file bodies to a socket.
+//!
+//! On Linux with the `sendfile` feature, `sendfile(2)` moves file bytes to
+//! the socket inside the kernel instead of reading them into a buffer and
+//! writing them back out. Elsewhere, or when the call isn't supported for a
+//! pair of descriptors, nothing is sent here and the caller copies the body
+//! as usual.
+
+use std::{fs::File, io};
+
+/// A socket's file descriptor.
+#[cfg(unix)]
+pub(crate) type Fd = std::os::fd::RawFd;
+#[cfg(not(unix))]
+pub(crate) type Fd = std::convert::Infallible;
+
+/// Sends up to `len` bytes of `file`, from its current position, to
+/// `socket`, advancing the position past them, and returns how many were
+/// sent. Fewer than `len` are sent, possibly none, if the file ends early or
+/// `sendfile` can't be used; the caller copies whatever is left.
+#[cfg(all(target_os = "linux", feature = "sendfile"))]
+pub(crate) fn send_file(socket: Fd, file: &File, len: u64) -> io::Result<u64> {
+    use std::{ffi::c_int, io::ErrorKind, os::fd::AsRawFd, ptr};
+
+    extern "C" {
+        fn sendfile(out_fd: c_int, in_fd: c_int, offset: *mut i64, count: usize) -> isize;
+    }
+
+    /// The most Linux transfers in a single call.
+    const MAX_CHUNK: u64 = 0x7fff_f000;
+
+    let mut sent = 0;
+    while sent < len {
+        let count = (len - sent).min(MAX_CHUNK) as usize;
+        // SAFETY: both descriptors stay open for the duration of the call,
+        // and a null offset makes `sendfile` read from and advance the file
+        // position instead of writing through the pointer.
+        let written = unsafe { sendfile(socket, file.as_raw_fd(), ptr::null_mut(), count) };
+
+        if written < 0 {
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                ErrorKind::Interrupted => continue,
+                ErrorKind::InvalidInput | ErrorKind::Unsupported if sent == 0 => return Ok(0),
+                _ => return Err(e),
+            }
+        }
+        if written == 0 {
+            break;
+        }
+        sent += written as u64;
+    }
+    Ok(sent)
+}
+
+#[cfg(not(all(target_os = "linux", feature = "sendfile")))]
+pub(crate) fn send_file(_socket: Fd, _file: &File, _len: u64) -> io::Result<u64> {
+    Ok(0)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{Response, StatusCode};
+    use std::{
+        env, fs,
+        io::Read,
+        net::{TcpListener, TcpStream},
+        os::fd::AsRawFd,
+        process, thread,
+    };
+
+    /// Writes `response` to a real socket, through `sendfile` when
+    /// `zero_copy` is set, and returns what the client received.
+    fn deliver(response: Response, zero_copy: bool) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            (&client).read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let socket = zero_copy.then(|| server.as_raw_fd());
+        response.write_to_socket(&mut &server, socket).unwrap();
+        drop(server);
+
+        reader.join().unwrap()
+    }
+
+    #[test]
+    fn test_file_body_same_over_both_paths() {
+        let path = env::temp_dir().join(format!("hello-sendfile-{}", process::id()));
+        let contents: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &contents).unwrap();
+
+        let response =
+            || Response::from_file(StatusCode::OK, fs::File::open(&path).unwrap()).unwrap();
+        let copied = deliver(response(), false);
+        let sent = deliver(response(), true);
+
+        assert_eq!(copied, sent);
+        assert!(sent.starts_with(b"HTTP/1.1 200 OK\r\nContent-Length: 200000\r\n\r\n"));
+        assert!(sent.ends_with(&contents));
+    }
+
+    #[test]
+    fn test_send_file_sends_whole_file() {
+        let path = env::temp_dir().join(format!("hello-sendfile-raw-{}", process::id()));
+        fs::write(&path, b"zero copy").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let file = fs::File::open(&path).unwrap();
+        let sent = send_file(server.as_raw_fd(), &file, 9).unwrap();
+        drop(server);
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        if cfg!(all(target_os = "linux", feature = "sendfile")) {
+            assert_eq!(sent, 9);
+            assert_eq!(received, b"zero copy");
+        } else {
+            assert_eq!(sent, 0);
+        }
+    }
+}
@@ -0,0 +1,1181 @@
+#[cfg(unix)]
+use std::{fs, path::Path};
+use std::{
+    io::{self, ErrorKind, Read},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    connection::{self, Divert, Handoff},
+    listener::{Accepted, Listener},
+    reject_draining,
+    shutdown::{self, ShutdownHandle, ShutdownState},
+    Config, Handler, LogSink, Metrics, RejectReason, Request, Response, Router, StatusCode,
+    ThreadPool, Upgraded,
+};
+
+type WebSocketHandler = dyn Fn(Request, Upgraded) + Send + Sync;
+
+/// A listening server: the accept loop, the pool of workers serving
+/// connections, and the settings they are served with.
+///
+/// Bind it with [`Server::bind`], give it a listener bound elsewhere with
+/// [`Server::bind_listener`], or on Unix bind it to a socket file with
+/// [`Server::bind_unix`], then hand it a [`Router`] with
+/// [`run`](Server::run), or any function from request to response with
+/// [`run_with`](Server::run_with).
+pub struct Server {
+    listener: Listener,
+    config: Arc<RwLock<Config>>,
+    metrics: Arc<Metrics>,
+    /// `None` once the pool has been taken to drain it.
+    pool: Arc<Mutex<Option<ThreadPool>>>,
+    /// Serves requests for blocking routes, apart from `pool`.
+    blocking_pool: Arc<Mutex<Option<ThreadPool>>>,
+    draining: Arc<AtomicBool>,
+    /// Connections handed to the pool that haven't finished yet.
+    connections: Arc<AtomicUsize>,
+    websocket: Option<Arc<WebSocketHandler>>,
+    access_log: Option<Arc<LogSink>>,
+    shutdown: Arc<ShutdownState>,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.shutdown.finish();
+    }
+}
+
+/// Why a connection was turned away on the accept thread, or the server
+/// isn't ready for more.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rejection {
+    Draining,
+    TooManyConnections,
+    Saturated,
+}
+
+impl Rejection {
+    fn describe(self) -> &'static str {
+        match self {
+            Rejection::Draining => "draining",
+            Rejection::TooManyConnections => "too many connections",
+            Rejection::Saturated => "saturated",
+        }
+    }
+
+    /// The reason the rejection is counted under in [`Metrics::rejections`].
+    fn reason(self) -> RejectReason {
+        match self {
+            Rejection::Draining => RejectReason::Draining,
+            Rejection::TooManyConnections => RejectReason::TooManyConnections,
+            Rejection::Saturated => RejectReason::Saturated,
+        }
+    }
+}
+
+/// Counts a connection as open until the job serving it ends, however it
+/// ends.
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Server {
+    /// Listens on `config.bind_addr` and starts `config.pool_size` workers.
+    /// The socket is bound with `config.listen_backlog`, and with
+    /// `SO_REUSEPORT` if `config.reuse_port` is set, which fails on
+    /// platforms other than Linux.
+    pub fn bind(config: Config) -> io::Result<Server> {
+        let listener =
+            crate::listener::bind_tcp(&config.bind_addr, config.listen_backlog, config.reuse_port)?;
+        Ok(Server::new(Listener::Tcp(listener), config))
+    }
+
+    /// Serves connections from `listener`, already bound by the caller,
+    /// instead of binding `config.bind_addr`, and starts `config.pool_size`
+    /// workers. This takes a socket inherited from a parent process, as
+    /// with systemd socket activation or a handoff between an old and a new
+    /// server, or one bound to a port picked in a test.
+    pub fn bind_listener(config: Config, listener: TcpListener) -> Server {
+        Server::new(Listener::Tcp(listener), config)
+    }
+
+    /// Listens on a Unix domain socket at `path` instead of
+    /// `config.bind_addr`, for local clients such as a sidecar proxy, and
+    /// starts `config.pool_size` workers. A socket file left at `path` by a
+    /// server that is no longer running is replaced. The file is removed when
+    /// the server is dropped or drains on `SIGTERM`.
+    ///
+    /// Requests over the socket have no
+    /// [`client_addr`](Request::client_addr), and WebSocket upgrades
+    /// aren't accepted on it.
+    #[cfg(unix)]
+    pub fn bind_unix(config: Config, path: impl AsRef<Path>) -> io::Result<Server> {
+        let socket = crate::listener::UnixSocket::bind(path.as_ref())?;
+        Ok(Server::new(Listener::Unix(socket), config))
+    }
+
+    fn new(listener: Listener, config: Config) -> Server {
+        let metrics = Arc::new(Metrics::with_capture(config.capture_requests));
+        let mut pool = ThreadPool::new(config.pool_size);
+        let mut blocking_pool = ThreadPool::new(config.blocking_pool_size);
+        if let Some(threshold) = config.job_timeout {
+            pool.reap_slow_jobs(threshold, Arc::clone(&metrics));
+            blocking_pool.reap_slow_jobs(threshold, Arc::clone(&metrics));
+        }
+
+        Server {
+            listener,
+            config: Arc::new(RwLock::new(config)),
+            metrics,
+            pool: Arc::new(Mutex::new(Some(pool))),
+            blocking_pool: Arc::new(Mutex::new(Some(blocking_pool))),
+            draining: Arc::new(AtomicBool::new(false)),
+            connections: Arc::new(AtomicUsize::new(0)),
+            websocket: None,
+            access_log: None,
+            shutdown: Arc::default(),
+        }
+    }
+
+    /// The address the server is listening on, which tells which port was
+    /// picked when binding to port 0. Fails for a server listening on a
+    /// Unix socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The server's settings. Each connection is served with the settings
+    /// current when it was accepted, so changes, such as a reload through
+    /// [`reload_on_sighup`](crate::reload_on_sighup), apply to later
+    /// connections.
+    pub fn config(&self) -> Arc<RwLock<Config>> {
+        Arc::clone(&self.config)
+    }
+
+    /// The metrics every request is recorded in.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Whether the server is ready to take more traffic, answered as a
+    /// handler for a readiness probe such as `/readyz`.
+    pub fn readiness(&self) -> Readiness {
+        Readiness {
+            config: Arc::clone(&self.config),
+            pool: Arc::clone(&self.pool),
+            draining: Arc::clone(&self.draining),
+            connections: Arc::clone(&self.connections),
+            shutdown: Arc::clone(&self.shutdown),
+        }
+    }
+
+    /// Drains the server when the process receives `SIGTERM`: connections
+    /// accepted from then on are turned away with [`reject_draining`], and
+    /// once the ones in flight have finished the process exits, removing
+    /// the socket file first if listening on a Unix socket.
+    pub fn drain_on_sigterm(&self) -> io::Result<()> {
+        let pool = Arc::clone(&self.pool);
+        let blocking_pool = Arc::clone(&self.blocking_pool);
+        let config = Arc::clone(&self.config);
+        #[cfg(unix)]
+        let socket_path = self.listener.unix_path().map(Path::to_path_buf);
+        shutdown::drain_on_sigterm(Arc::clone(&self.draining), move || {
+            let grace = config.read().unwrap().shutdown_grace;
+            drain_pools(&pool, &blocking_pool, grace);
+            // Exiting skips destructors, so the listener won't remove it.
+            #[cfg(unix)]
+            if let Some(path) = &socket_path {
+                let _ = fs::remove_file(path);
+            }
+            process::exit(0);
+        })
+    }
+
+    /// A handle for stopping the server from another thread once it is
+    /// running, as an alternative to [`drain_on_sigterm`](Server::drain_on_sigterm).
+    /// After [`ShutdownHandle::shutdown`], the accept loop stops, the server
+    /// is [shut down](Server::shutdown), and `run` returns `Ok(())`.
+    pub fn shutdown_handle(&self) -> io::Result<ShutdownHandle> {
+        Ok(ShutdownHandle::new(
+            Arc::clone(&self.shutdown),
+            self.listener.wake_addr()?,
+        ))
+    }
+
+    /// Accepts WebSocket upgrade requests (see
+    /// [`Request::is_websocket_upgrade`]) instead of passing them to the
+    /// handler: the server answers with `101 Switching Protocols` and calls
+    /// `callback` with the request and the connection, on the worker that was
+    /// serving it. The connection is closed when `callback` returns, and
+    /// counts towards `config.max_connections` until then.
+    pub fn on_websocket<F>(&mut self, callback: F)
+    where
+        F: Fn(Request, Upgraded) + Send + Sync + 'static,
+    {
+        self.websocket = Some(Arc::new(callback));
+    }
+
+    /// Writes a line to `sink` for every request passed to the handler, with
+    /// the client address, request line, response status and how long the
+    /// handler took.
+    pub fn access_log(&mut self, sink: Arc<LogSink>) {
+        self.access_log = Some(sink);
+    }
+
+    /// Serves connections with `router` until accepting one fails or the
+    /// server is stopped through a [`ShutdownHandle`]. Requests
+    /// for routes marked [`blocking`](crate::Route::blocking) are handed
+    /// over to a separate pool of `config.blocking_pool_size` workers.
+    pub fn run(self, router: Router) -> io::Result<()> {
+        let router = Arc::new(router);
+        let blocking = Arc::clone(&router);
+        let limits = Arc::clone(&router);
+        let streams = Arc::clone(&router);
+        self.serve(
+            move |request| router.dispatch(request),
+            move |request| blocking.is_blocking(request),
+            move |request| limits.max_body(request),
+            move |request| streams.streams_body(request),
+        )
+    }
+
+    /// Serves connections until accepting one fails or the server is stopped
+    /// through a [`ShutdownHandle`], answering every request with `handler`.
+    /// Keep-alive, timeouts, body limits and the rest of the connection
+    /// handling are as for [`handle_connection`], with `handler` in place of
+    /// its router.
+    ///
+    /// Connections that can't be served, because the server is draining or
+    /// is over `config.max_connections` or `config.max_queued`, are answered
+    /// with `503 Service Unavailable` on the accept thread and closed, so
+    /// they never take up a place in the pool's queue or a worker.
+    ///
+    /// [`handle_connection`]: crate::handle_connection
+    pub fn run_with<H>(self, handler: H) -> io::Result<()>
+    where
+        H: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        self.serve(
+            handler,
+            |_: &Request| false,
+            |_: &Request| None,
+            |_: &Request| false,
+        )
+    }
+
+    fn serve<H, B, L, T>(
+        self,
+        handler: H,
+        is_blocking: B,
+        max_body: L,
+        stream_body: T,
+    ) -> io::Result<()>
+    where
+        H: Fn(Request) -> Response + Send + Sync + 'static,
+        B: Fn(&Request) -> bool + Send + Sync + 'static,
+        L: Fn(&Request) -> Option<usize> + Send + Sync + 'static,
+        T: Fn(&Request) -> bool + Send + Sync + 'static,
+    {
+        let handler = Arc::new(logged(handler, self.access_log.clone()));
+        let is_blocking = Arc::new(is_blocking);
+        let max_body = Arc::new(max_body);
+        let stream_body = Arc::new(stream_body);
+
+        // A listener handed over with `bind_listener` may be non-blocking.
+        // The loop blocks in `accept`, and a `ShutdownHandle` connects to wake
+        // it.
+        self.listener.set_nonblocking(false)?;
+        loop {
+            let stream = match self.listener.accept() {
+                Ok(stream) => stream,
+                // A client that gave up before its connection was accepted
+                // doesn't stop the server.
+                Err(e) if is_transient(&e) => {
+                    eprintln!("Error accepting connection: {}", e);
+                    self.metrics.record_rejection(RejectReason::AcceptError);
+                    continue;
+                }
+                Err(e) => {
+                    self.metrics.record_rejection(RejectReason::AcceptError);
+                    return Err(e);
+                }
+            };
+            let config = self.config.read().unwrap().clone();
+            // A client that raced the wake-up connection is turned away with
+            // the rest of the backlog.
+            if self.shutdown.is_requested() {
+                if !self.shutdown.is_wake(&stream) {
+                    self.metrics.record_rejection(RejectReason::Draining);
+                    if let Err(e) = reject(&stream, &config, Rejection::Draining) {
+                        eprintln!("Error rejecting connection: {}", e);
+                    }
+                }
+                break;
+            }
+            self.metrics.record_connection_accepted();
+            let pool = self.pool.lock().unwrap();
+
+            let admitted = match pool.as_ref() {
+                Some(pool) => self.admit(pool, &config).map(|()| pool),
+                None => Err(Rejection::Draining),
+            };
+            let pool = match admitted {
+                Ok(pool) => pool,
+                Err(rejection) => {
+                    self.metrics.record_rejection(rejection.reason());
+                    if let Err(e) = reject(&stream, &config, rejection) {
+                        eprintln!("Error rejecting connection: {}", e);
+                    }
+                    continue;
+                }
+            };
+
+            self.connections.fetch_add(1, Ordering::SeqCst);
+            let guard = ConnectionGuard(Arc::clone(&self.connections));
+            let handler = Arc::clone(&handler);
+            let is_blocking = Arc::clone(&is_blocking);
+            let max_body = Arc::clone(&max_body);
+            let stream_body = Arc::clone(&stream_body);
+            let metrics = Arc::clone(&self.metrics);
+            let websocket = self.websocket.clone();
+            let blocking_pool = Arc::clone(&self.blocking_pool);
+            pool.execute(move || {
+                let divert = |request: &Request| {
+                    if websocket.is_some()
+                        && matches!(stream, Accepted::Tcp(_))
+                        && request.is_websocket_upgrade()
+                    {
+                        Some(Divert::WebSocket)
+                    } else if is_blocking(request) {
+                        Some(Divert::Blocking)
+                    } else {
+                        None
+                    }
+                };
+                let handoff = match connection::serve(
+                    &stream,
+                    &*handler,
+                    divert,
+                    &*max_body,
+                    &*stream_body,
+                    &config,
+                    &metrics,
+                ) {
+                    Ok(Some(handoff)) => handoff,
+                    Ok(None) => return,
+                    Err(e) => return eprintln!("Error handling connection: {}", e),
+                };
+
+                match handoff.divert {
+                    Divert::WebSocket => {
+                        let stream = stream
+                            .into_tcp()
+                            .expect("upgrades are only diverted on TCP");
+                        if let Err(e) = hand_off(stream, handoff, &*websocket.unwrap()) {
+                            eprintln!("Error upgrading connection: {}", e);
+                        }
+                    }
+                    Divert::Blocking => {
+                        let blocking_pool = blocking_pool.lock().unwrap();
+                        // The blocking pool is only gone once the server is
+                        // draining.
+                        let Some(blocking_pool) = blocking_pool.as_ref() else {
+                            metrics.record_response(StatusCode::SERVICE_UNAVAILABLE);
+                            if let Err(e) = reject_draining(&stream, &config) {
+                                eprintln!("Error rejecting connection: {}", e);
+                            }
+                            return;
+                        };
+                        blocking_pool.execute(move || {
+                            let _guard = guard;
+                            if let Err(e) = connection::serve_diverted(
+                                &stream,
+                                handoff.request,
+                                handoff.deadline,
+                                &*handler,
+                                &config,
+                                &metrics,
+                            ) {
+                                eprintln!("Error handling connection: {}", e);
+                            }
+                        });
+                    }
+                }
+            });
+        }
+
+        self.shutdown();
+        Ok(())
+    }
+
+    /// Stops the server, in the only order that loses no connection:
+    ///
+    /// 1. Stop accepting connections. Those already waiting in the
+    ///    listener's backlog are turned away with [`reject_draining`] rather
+    ///    than reset when the listener closes.
+    /// 2. Let the main pool serve every connection it was handed, then join
+    ///    its workers. Connections move from it to the blocking pool, so it
+    ///    goes first.
+    /// 3. Likewise for the blocking pool.
+    ///
+    /// The last two steps take at most [`Config::shutdown_grace`] between
+    /// them. Requests not finished by then are dropped and counted in a
+    /// warning, and the workers serving them are abandoned.
+    ///
+    /// `run` ends this way once stopped through a [`ShutdownHandle`], whose
+    /// [`wait`](ShutdownHandle::wait) returns after the last step. Call it
+    /// directly to stop a server that isn't running.
+    pub fn shutdown(self) {
+        self.draining.store(true, Ordering::SeqCst);
+        if let Err(e) = self.reject_backlog() {
+            eprintln!("Error rejecting waiting connections: {}", e);
+        }
+        let grace = self.config.read().unwrap().shutdown_grace;
+        drain_pools(&self.pool, &self.blocking_pool, grace);
+    }
+
+    /// Turns away every connection the listener has accepted but the
+    /// server hasn't taken yet.
+    fn reject_backlog(&self) -> io::Result<()> {
+        self.listener.set_nonblocking(true)?;
+        let config = self.config.read().unwrap().clone();
+        loop {
+            let stream = match self.listener.accept() {
+                Ok(stream) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if is_transient(&e) => {
+                    self.metrics.record_rejection(RejectReason::AcceptError);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if self.shutdown.is_wake(&stream) {
+                continue;
+            }
+            self.metrics.record_rejection(RejectReason::Draining);
+            if let Err(e) = stream
+                .set_nonblocking(false)
+                .and_then(|()| reject(&stream, &config, Rejection::Draining))
+            {
+                eprintln!("Error rejecting connection: {}", e);
+            }
+        }
+    }
+
+    /// Checks whether another connection may be handed to `pool`.
+    fn admit(&self, pool: &ThreadPool, config: &Config) -> Result<(), Rejection> {
+        if self.draining.load(Ordering::SeqCst) {
+            return Err(Rejection::Draining);
+        }
+        if config
+            .max_connections
+            .is_some_and(|max| self.connections.load(Ordering::SeqCst) >= max)
+        {
+            return Err(Rejection::TooManyConnections);
+        }
+        if config
+            .max_queued
+            .is_some_and(|max| pool.queued_jobs() >= max)
+        {
+            return Err(Rejection::Saturated);
+        }
+        Ok(())
+    }
+}
+
+/// Whether a [`Server`] is ready to take more traffic, from
+/// [`Server::readiness`]. Unlike liveness, which only says the process is
+/// up, readiness turns false while the server drains or stops, while it is
+/// at `config.max_connections`, and while `config.ready_max_queued` or more
+/// connections wait for a worker, so that an orchestrator sends traffic
+/// elsewhere.
+///
+/// As a [`Handler`](crate::Handler) it answers `200 OK` when ready and
+/// `503 Service Unavailable`, naming why, when not.
+#[derive(Clone)]
+pub struct Readiness {
+    config: Arc<RwLock<Config>>,
+    pool: Arc<Mutex<Option<ThreadPool>>>,
+    draining: Arc<AtomicBool>,
+    connections: Arc<AtomicUsize>,
+    shutdown: Arc<ShutdownState>,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.check().is_ok()
+    }
+
+    fn check(&self) -> Result<(), Rejection> {
+        if self.draining.load(Ordering::SeqCst) || self.shutdown.is_requested() {
+            return Err(Rejection::Draining);
+        }
+        let config = self.config.read().unwrap();
+        if config
+            .max_connections
+            .is_some_and(|max| self.connections.load(Ordering::SeqCst) >= max)
+        {
+            return Err(Rejection::TooManyConnections);
+        }
+        let queued = match self.pool.lock().unwrap().as_ref() {
+            Some(pool) => pool.queued_jobs(),
+            None => return Err(Rejection::Draining),
+        };
+        if config.ready_max_queued.is_some_and(|max| queued >= max) {
+            return Err(Rejection::Saturated);
+        }
+        Ok(())
+    }
+}
+
+impl Handler for Readiness {
+    fn handle(&self, _request: &Request) -> Response {
+        match self.check() {
+            Ok(()) => Response::with_body_str(StatusCode::OK, "ready\n"),
+            Err(rejection) => Response::with_body_str(
+                StatusCode::SERVICE_UNAVAILABLE,
+                &format!("not ready: {}\n", rejection.describe()),
+            ),
+        }
+    }
+}
+
+/// Takes the pools from the server and joins their workers once they have
+/// run every job they were given, or once `grace` has passed, whichever is
+/// first. Connections move from the main pool to the blocking one, so the
+/// main pool finishes first.
+fn drain_pools(
+    pool: &Mutex<Option<ThreadPool>>,
+    blocking_pool: &Mutex<Option<ThreadPool>>,
+    grace: Option<Duration>,
+) {
+    let deadline = grace.map(|grace| Instant::now() + grace);
+    let mut dropped = 0;
+    for pool in [pool, blocking_pool] {
+        let Some(mut pool) = pool.lock().unwrap().take() else {
+            continue;
+        };
+        match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                dropped += pool.shutdown_with_timeout(left);
+            }
+            None => pool.shutdown(),
+        }
+    }
+    if dropped > 0 {
+        eprintln!(
+            "Warning: shutdown grace period ran out; dropped {} requests",
+            dropped
+        );
+    }
+}
+
+/// Wraps `handler` to write each request to `access_log`, if there is one.
+fn logged<H>(handler: H, access_log: Option<Arc<LogSink>>) -> impl Fn(Request) -> Response
+where
+    H: Fn(Request) -> Response,
+{
+    move |request| {
+        let Some(access_log) = &access_log else {
+            return handler(request);
+        };
+
+        let client = request
+            .client_addr
+            .map_or_else(|| "-".to_string(), |addr| addr.to_string());
+        let query = request
+            .query
+            .as_ref()
+            .map_or_else(String::new, |query| format!("?{}", query));
+        let request_line = format!(
+            "{} {}{} {}",
+            request.method, request.path, query, request.version
+        );
+
+        let start = Instant::now();
+        let response = handler(request);
+        let line = format!(
+            "{} \"{}\" {} {}ms",
+            client,
+            request_line,
+            response.status().as_u16(),
+            start.elapsed().as_millis()
+        );
+        if let Err(e) = access_log.write_line(&line) {
+            eprintln!("Error writing access log: {}", e);
+        }
+        response
+    }
+}
+
+fn hand_off(stream: TcpStream, handoff: Handoff, callback: &WebSocketHandler) -> io::Result<()> {
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    callback(handoff.request, Upgraded::new(stream, handoff.buffered));
+    Ok(())
+}
+
+fn reject(stream: &Accepted, config: &Config, rejection: Rejection) -> io::Result<()> {
+    if rejection == Rejection::Draining {
+        reject_draining(stream, config)?;
+    } else {
+        connection::reject(
+            stream,
+            config,
+            Response::new(StatusCode::SERVICE_UNAVAILABLE)
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .body("Server is busy\n"),
+        )?;
+    }
+
+    // Closing a socket with unread input resets the connection, which can
+    // discard the response before the client reads it. Sending the FIN
+    // first and throwing away whatever of the request has already arrived,
+    // without waiting for more, makes that less likely at no cost to the
+    // accept thread.
+    stream.shutdown(Shutdown::Write)?;
+    stream.set_nonblocking(true)?;
+    let mut discard = [0; 4096];
+    while matches!((&*stream).read(&mut discard), Ok(n) if n > 0) {}
+    Ok(())
+}
+
+/// Whether an `accept` error concerns only the connection being accepted,
+/// rather than the listener.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::Interrupted
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, sync::mpsc, thread, time::Duration};
+
+    fn bind(config: Config) -> Server {
+        Server::bind(Config {
+            bind_addr: "127.0.0.1:0".to_string(),
+            ..config
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_readiness_follows_draining_and_queue() {
+        let server = bind(Config {
+            pool_size: 1,
+            ready_max_queued: Some(1),
+            ..Config::default()
+        });
+        let readiness = server.readiness();
+        let probe = || readiness.handle(&Request::new(crate::Method::Get, "/readyz"));
+        assert_eq!(probe().status(), StatusCode::OK);
+
+        // One job holds the only worker, and another waits behind it.
+        let (release, wait_release) = mpsc::channel::<()>();
+        let (started, wait_started) = mpsc::channel();
+        {
+            let pool = server.pool.lock().unwrap();
+            let pool = pool.as_ref().unwrap();
+            pool.execute(move || {
+                started.send(()).unwrap();
+                let _ = wait_release.recv();
+            });
+            wait_started.recv().unwrap();
+            pool.execute(|| {});
+        }
+        assert!(!readiness.is_ready());
+        drop(release);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !readiness.is_ready() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(readiness.is_ready());
+
+        server.draining.store(true, Ordering::SeqCst);
+        let response = probe();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .ends_with("not ready: draining\n"));
+    }
+
+    /// Sends a request and returns the whole response.
+    fn get(address: SocketAddr, path: &str) -> String {
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+            .write_all(
+                format!(
+                    "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                    path
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        read_response(client)
+    }
+
+    /// Reads until the server closes the connection. A rejected connection
+    /// may be reset once the response has arrived, which also ends it.
+    fn read_response(mut client: TcpStream) -> String {
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            match client.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => response.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => break,
+                Err(e) => panic!("reading response: {}", e),
+            }
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    /// Runs a server whose `/block` requests wait until the returned sender
+    /// is dropped, each reporting on the returned receiver once it has
+    /// started.
+    fn run_blocking(server: Server) -> (SocketAddr, mpsc::Sender<()>, mpsc::Receiver<()>) {
+        let address = server.local_addr().unwrap();
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let (started, wait_started) = mpsc::channel();
+        let started = Mutex::new(started);
+
+        thread::spawn(move || {
+            server.run_with(move |request: Request| {
+                if request.path == "/block" {
+                    started.lock().unwrap().send(()).unwrap();
+                    let _ = released.lock().unwrap().recv();
+                }
+                Response::new(StatusCode::OK).body("done")
+            })
+        });
+
+        (address, release, wait_started)
+    }
+
+    #[test]
+    fn test_run_with_custom_handler() {
+        let server = bind(Config {
+            pool_size: 2,
+            ..Config::default()
+        });
+        let address = server.local_addr().unwrap();
+        let metrics = server.metrics();
+
+        thread::spawn(move || {
+            server.run_with(|request: Request| {
+                Response::new(StatusCode::OK).body(format!("you asked for {}", request.path))
+            })
+        });
+
+        let response = get(address, "/anything");
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nyou asked for /anything"));
+        assert_eq!(metrics.latency().count(), 1);
+    }
+
+    #[test]
+    fn test_bind_listener_serves_provided_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Server::bind_listener(Config::default(), listener);
+        assert_eq!(server.local_addr().unwrap(), address);
+        let handle = server.shutdown_handle().unwrap();
+
+        let running = thread::spawn(move || {
+            server.run_with(|request| Response::new(StatusCode::OK).body(request.path))
+        });
+        let response = get(address, "/inherited");
+        handle.shutdown();
+
+        assert!(response.ends_with("\r\n\r\n/inherited"));
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_handle_stops_run() {
+        let server = bind(Config::default());
+        let address = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let (returned, wait_returned) = mpsc::channel();
+
+        thread::spawn(move || {
+            let result = server.run_with(|_| Response::new(StatusCode::OK).body("up"));
+            returned.send(result.is_ok()).unwrap();
+        });
+        assert!(get(address, "/").ends_with("\r\n\r\nup"));
+
+        handle.shutdown();
+
+        assert_eq!(wait_returned.recv_timeout(Duration::from_secs(5)), Ok(true));
+        handle.wait();
+        assert!(TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_answers_every_waiting_request() {
+        let server = bind(Config {
+            pool_size: 2,
+            blocking_pool_size: 1,
+            ..Config::default()
+        });
+        let address = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let metrics = server.metrics();
+
+        let mut router = Router::new();
+        router.get("/", |_| {
+            thread::sleep(Duration::from_millis(10));
+            Response::new(StatusCode::OK).body("fast")
+        });
+        router
+            .get("/slow", |_| {
+                thread::sleep(Duration::from_millis(30));
+                Response::new(StatusCode::OK).body("slow")
+            })
+            .blocking();
+        let running = thread::spawn(move || server.run(router));
+
+        // Every client is connected and has sent its request before the
+        // shutdown, some still in the listener's backlog.
+        let clients: Vec<_> = (0..16)
+            .map(|i| {
+                let mut client = TcpStream::connect(address).unwrap();
+                client
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+                let path = if i % 2 == 0 { "/" } else { "/slow" };
+                write!(
+                    client,
+                    "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                    path
+                )
+                .unwrap();
+                client
+            })
+            .collect();
+        handle.shutdown();
+
+        let mut rejected = 0;
+        for client in clients {
+            let response = read_response(client);
+            if response.starts_with("HTTP/1.1 503 Service Unavailable\r\n") {
+                rejected += 1;
+                continue;
+            }
+            assert!(
+                response.starts_with("HTTP/1.1 200 OK\r\n"),
+                "lost request: {:?}",
+                response
+            );
+        }
+        running.join().unwrap().unwrap();
+        handle.wait();
+        // The handle's wake-up connection isn't a client, so isn't counted.
+        assert_eq!(metrics.rejections(RejectReason::Draining), rejected);
+    }
+
+    #[test]
+    fn test_shutdown_gives_up_on_slow_request_after_grace() {
+        let server = bind(Config {
+            pool_size: 1,
+            shutdown_grace: Some(Duration::from_millis(100)),
+            ..Config::default()
+        });
+        let address = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+
+        let (started, wait_started) = mpsc::channel();
+        let (release, wait_release) = mpsc::channel::<()>();
+        let (started, wait_release) = (Mutex::new(started), Mutex::new(wait_release));
+        let mut router = Router::new();
+        router.get("/slow", move |_| {
+            started.lock().unwrap().send(()).unwrap();
+            wait_release.lock().unwrap().recv().unwrap();
+            Response::new(StatusCode::OK).body("late")
+        });
+        let running = thread::spawn(move || server.run(router));
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        wait_started.recv().unwrap();
+
+        let start = Instant::now();
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // The request was abandoned with its worker, not cut off.
+        release.send(()).unwrap();
+        assert!(read_response(client).ends_with("late"));
+    }
+
+    #[test]
+    fn test_shutdown_wakes_idle_accept_loop() {
+        let server = bind(Config::default());
+        let handle = server.shutdown_handle().unwrap();
+        let (returned, wait_returned) = mpsc::channel();
+
+        thread::spawn(move || {
+            let result = server.run_with(|_| Response::new(StatusCode::OK));
+            returned.send(result.is_ok()).unwrap();
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        // No client connects; the handle's own connection wakes the loop.
+        let requested = Instant::now();
+        handle.shutdown();
+
+        assert_eq!(wait_returned.recv_timeout(Duration::from_secs(5)), Ok(true));
+        assert!(requested.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_access_log_lines() {
+        let path = std::env::temp_dir().join(format!("hello-access-{}.log", process::id()));
+        let mut server = bind(Config::default());
+        server.access_log(Arc::new(LogSink::file(&path).unwrap()));
+        let address = server.local_addr().unwrap();
+
+        thread::spawn(move || server.run_with(|_| Response::new(StatusCode::NOT_FOUND)));
+        get(address, "/missing?page=2");
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(
+            log.starts_with("127.0.0.1 \"GET /missing?page=2 HTTP/1.1\" 404 "),
+            "{}",
+            log
+        );
+        assert!(log.ends_with("ms\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        use std::os::unix::net::UnixStream;
+
+        let path = std::env::temp_dir().join(format!("hello-server-{}.sock", process::id()));
+        let server = Server::bind_unix(Config::default(), &path).unwrap();
+        assert!(server.local_addr().is_err());
+        let handle = server.shutdown_handle().unwrap();
+        let metrics = server.metrics();
+
+        let running = thread::spawn(move || {
+            server.run_with(|request: Request| {
+                let client = request.client_addr.map(|addr| addr.to_string());
+                Response::new(StatusCode::OK).body(format!("client {:?}", client))
+            })
+        });
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nclient None"));
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+        assert_eq!(metrics.rejections(RejectReason::Draining), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_file_removed_on_drop() {
+        let path = std::env::temp_dir().join(format!("hello-drop-{}.sock", process::id()));
+        // A file left behind by a server that is no longer running.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let server = Server::bind_unix(Config::default(), &path).unwrap();
+        assert!(path.exists());
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_websocket_handshake_and_handoff() {
+        let mut server = bind(Config::default());
+        let address = server.local_addr().unwrap();
+        server.on_websocket(|request: Request, mut upgraded: Upgraded| {
+            assert_eq!(request.path, "/chat");
+            // Echo raw bytes back; framing is the callback's business.
+            let mut buf = [0; 4];
+            upgraded.read_exact(&mut buf).unwrap();
+            upgraded.write_all(&buf).unwrap();
+        });
+        thread::spawn(move || server.run_with(|_: Request| Response::new(StatusCode::NOT_FOUND)));
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // The first raw bytes ride along with the handshake.
+        client
+            .write_all(
+                b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                  Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Version: 13\r\n\r\nping",
+            )
+            .unwrap();
+
+        let response = read_response(client);
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Upgrade: websocket\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(!response.contains("Content-Length"));
+        assert!(response.ends_with("\r\n\r\nping"));
+    }
+
+    #[test]
+    fn test_blocking_routes_do_not_starve_others() {
+        let server = bind(Config {
+            pool_size: 1,
+            blocking_pool_size: 1,
+            ..Config::default()
+        });
+        let address = server.local_addr().unwrap();
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let (started, wait_started) = mpsc::channel();
+        let started = Mutex::new(started);
+
+        let mut router = Router::new();
+        router
+            .get("/slow", move |_| {
+                started.lock().unwrap().send(()).unwrap();
+                let _ = released.lock().unwrap().recv();
+                Response::new(StatusCode::OK).body("slow")
+            })
+            .blocking();
+        router.get("/fast", |_| Response::new(StatusCode::OK).body("fast"));
+        thread::spawn(move || server.run(router));
+
+        // One slow request occupies the blocking pool and another waits for
+        // it, but the single main worker is free again.
+        let slow: Vec<_> = (0..2)
+            .map(|_| thread::spawn(move || get(address, "/slow")))
+            .collect();
+        wait_started.recv().unwrap();
+
+        let fast = get(address, "/fast");
+        assert!(fast.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(fast.ends_with("fast"));
+
+        drop(release);
+        for slow in slow {
+            let slow = slow.join().unwrap();
+            assert!(slow.contains("Connection: close\r\n"));
+            assert!(slow.ends_with("slow"));
+        }
+    }
+
+    #[test]
+    fn test_rejects_over_max_connections_inline() {
+        let server = bind(Config {
+            pool_size: 2,
+            max_connections: Some(1),
+            ..Config::default()
+        });
+        let metrics = server.metrics();
+        let (address, release, started) = run_blocking(server);
+
+        let blocked = thread::spawn(move || get(address, "/block"));
+        started.recv().unwrap();
+
+        // A worker is still free, but the connection limit is reached.
+        let rejected = get(address, "/");
+        assert!(rejected.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(rejected.contains("Connection: close\r\n"));
+        assert_eq!(metrics.rejections(RejectReason::TooManyConnections), 1);
+        assert_eq!(metrics.rejections(RejectReason::Saturated), 0);
+
+        drop(release);
+        assert!(blocked.join().unwrap().ends_with("done"));
+    }
+
+    #[test]
+    fn test_rejects_when_saturated_inline() {
+        let server = bind(Config {
+            pool_size: 1,
+            max_queued: Some(1),
+            ..Config::default()
+        });
+        let pool = Arc::clone(&server.pool);
+        let metrics = server.metrics();
+        let (address, release, started) = run_blocking(server);
+
+        let blocked = thread::spawn(move || get(address, "/block"));
+        started.recv().unwrap();
+        // Waits behind the blocked request, filling the queue.
+        let mut queued = TcpStream::connect(address).unwrap();
+        queued
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        while pool.lock().unwrap().as_ref().unwrap().queued_jobs() == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let rejected = get(address, "/");
+        assert!(rejected.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert_eq!(pool.lock().unwrap().as_ref().unwrap().queued_jobs(), 1);
+        assert_eq!(metrics.rejections(RejectReason::Saturated), 1);
+        assert_eq!(metrics.rejections(RejectReason::TooManyConnections), 0);
+
+        drop(release);
+        assert!(blocked.join().unwrap().ends_with("done"));
+        assert!(read_response(queued).ends_with("done"));
+    }
+
+    #[test]
+    fn test_rejects_while_draining_inline() {
+        let server = bind(Config {
+            drain_retry_after: 9,
+            ..Config::default()
+        });
+        server.draining.store(true, Ordering::SeqCst);
+        let pool = Arc::clone(&server.pool);
+        let metrics = server.metrics();
+        let (address, _release, _started) = run_blocking(server);
+
+        let rejected = get(address, "/");
+
+        assert!(rejected.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(rejected.contains("Retry-After: 9\r\n"));
+        assert_eq!(pool.lock().unwrap().as_ref().unwrap().queued_jobs(), 0);
+        assert_eq!(metrics.rejections(RejectReason::Draining), 1);
+    }
+}
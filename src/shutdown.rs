@@ -0,0 +1,199 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::listener::{Accepted, WakeAddr, WakeConnection};
+
+/// How often the drain thread checks whether a `SIGTERM` has arrived.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Longest the accept loop waits to learn which connection a
+/// [`ShutdownHandle`] made, after taking one once the server was asked to
+/// stop.
+const WAKE_WAIT: Duration = Duration::from_secs(1);
+
+static SIGTERM_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+/// Starts draining when the process receives `SIGTERM`: `draining` is set,
+/// so that the accept loop can turn new connections away with
+/// [`reject_draining`](crate::reject_draining), and then `drain` is run to
+/// let the connections already being served finish.
+///
+/// As with [`reload_on_sighup`](crate::reload_on_sighup), the signal
+/// handler only sets a flag and a background thread does the work.
+pub(crate) fn drain_on_sigterm<F>(draining: Arc<AtomicBool>, drain: F) -> io::Result<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    signal::install()?;
+
+    thread::spawn(move || {
+        while !SIGTERM_RECEIVED.load(Ordering::SeqCst) {
+            thread::sleep(POLL_INTERVAL);
+        }
+        draining.store(true, Ordering::SeqCst);
+        drain();
+    });
+
+    Ok(())
+}
+
+/// Whether a [`Server`](crate::Server) has been asked to stop, and whether
+/// it has.
+#[derive(Default)]
+pub(crate) struct ShutdownState {
+    requested: AtomicBool,
+    stopped: Mutex<bool>,
+    stopped_changed: Condvar,
+    wake: Mutex<Wake>,
+    wake_changed: Condvar,
+}
+
+/// The connection a [`ShutdownHandle`] makes to wake the accept loop.
+#[derive(Default)]
+enum Wake {
+    /// Not made yet.
+    #[default]
+    Pending,
+    Made(WakeConnection),
+    /// Already taken, or never made.
+    Done,
+}
+
+impl ShutdownState {
+    pub(crate) fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Asks the server to stop, and returns whether it had already been
+    /// asked.
+    pub(crate) fn request(&self) -> bool {
+        self.requested.swap(true, Ordering::SeqCst)
+    }
+
+    /// Whether `stream`, taken once the server was asked to stop, is the
+    /// connection the [`ShutdownHandle`] made to wake the accept loop. That
+    /// one is closed without an answer, and isn't counted as a rejected
+    /// client.
+    pub(crate) fn is_wake(&self, stream: &Accepted) -> bool {
+        if !self.is_requested() {
+            return false;
+        }
+        // The connection can be accepted before the handle has seen it made.
+        let (mut wake, waited) = self
+            .wake_changed
+            .wait_timeout_while(self.wake.lock().unwrap(), WAKE_WAIT, |wake| {
+                matches!(wake, Wake::Pending)
+            })
+            .unwrap();
+        if waited.timed_out() {
+            *wake = Wake::Done;
+        }
+        match &*wake {
+            Wake::Made(connection) if stream.is_wake(connection) => {
+                *wake = Wake::Done;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn set_wake(&self, wake: Wake) {
+        *self.wake.lock().unwrap() = wake;
+        self.wake_changed.notify_all();
+    }
+
+    /// Records that the server has stopped, waking every
+    /// [`ShutdownHandle::wait`].
+    pub(crate) fn finish(&self) {
+        *self.stopped.lock().unwrap() = true;
+        self.stopped_changed.notify_all();
+    }
+}
+
+/// Stops a running [`Server`](crate::Server) from another thread, without
+/// signals. Get one with [`Server::shutdown_handle`](crate::Server::shutdown_handle)
+/// before calling `run`; clones all control the same server.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+    wake: WakeAddr,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(state: Arc<ShutdownState>, wake: WakeAddr) -> ShutdownHandle {
+        ShutdownHandle { state, wake }
+    }
+
+    /// Asks the server to stop: it stops accepting connections, lets the
+    /// ones it is serving finish, and then `run` returns. Returns without
+    /// waiting for that; see [`wait`](ShutdownHandle::wait).
+    pub fn shutdown(&self) {
+        if self.state.request() {
+            return;
+        }
+        // The accept loop blocks until a connection arrives, and checks for
+        // a request once one does.
+        match self.wake.wake() {
+            Ok(connection) => self.state.set_wake(Wake::Made(connection)),
+            Err(e) => {
+                self.state.set_wake(Wake::Done);
+                eprintln!("Error waking accept loop: {}", e);
+            }
+        }
+    }
+
+    /// Blocks until the server has stopped, or is dropped without running.
+    pub fn wait(&self) {
+        let mut stopped = self.state.stopped.lock().unwrap();
+        while !*stopped {
+            stopped = self.state.stopped_changed.wait(stopped).unwrap();
+        }
+    }
+}
+
+#[cfg(unix)]
+mod signal {
+    use std::{ffi::c_int, io};
+
+    use super::SIGTERM_RECEIVED;
+
+    const SIGTERM: c_int = 15;
+    const SIG_ERR: usize = !0;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+    }
+
+    extern "C" fn on_sigterm(_: c_int) {
+        SIGTERM_RECEIVED.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    pub(super) fn install() -> io::Result<()> {
+        let handler = on_sigterm as extern "C" fn(c_int) as usize;
+        // SAFETY: `on_sigterm` only stores to an atomic, which is
+        // async-signal-safe.
+        if unsafe { signal(SIGTERM, handler) } == SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod signal {
+    use std::io;
+
+    pub(super) fn install() -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SIGTERM is only available on Unix",
+        ))
+    }
+}
@@ -0,0 +1,64 @@
+use std::fmt;
+
+/// One Server-Sent Event, sent in a response built with
+/// [`Response::event_stream`](crate::Response::event_stream).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub data: String,
+    /// The event type, which the client dispatches on instead of `message`.
+    pub event: Option<String>,
+    /// The id the client reports in `Last-Event-ID` when it reconnects.
+    pub id: Option<String>,
+}
+
+impl Event {
+    pub fn new(data: impl Into<String>) -> Event {
+        Event {
+            data: data.into(),
+            event: None,
+            id: None,
+        }
+    }
+
+    pub fn event(mut self, event: impl Into<String>) -> Event {
+        self.event = Some(event.into());
+        self
+    }
+
+    pub fn id(mut self, id: impl Into<String>) -> Event {
+        self.id = Some(id.into());
+        self
+    }
+}
+
+/// Writes the event in the `text/event-stream` format, ending with the blank
+/// line that dispatches it. Multi-line data is split over several `data:`
+/// lines, which the client joins back together.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {}", event)?;
+        }
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", id)?;
+        }
+        for line in self.data.split('\n') {
+            writeln!(f, "data: {}", line)?;
+        }
+        writeln!(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_format() {
+        assert_eq!(Event::new("hello").to_string(), "data: hello\n\n");
+        assert_eq!(
+            Event::new("one\ntwo").event("update").id("7").to_string(),
+            "event: update\nid: 7\ndata: one\ndata: two\n\n"
+        );
+    }
+}
@@ -0,0 +1,381 @@
+use std::{
+    fs::{self, File, Metadata},
+    io::{self, ErrorKind},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    range::{self, Ranges},
+    Handler, Method, Request, Response, StatusCode,
+};
+
+/// Serves files from a directory on disk, mapping the request path onto a
+/// path under `root`. Directory paths serve their `index.html`.
+///
+/// The path is normalized with [`Request::path_segments`], so `..` never
+/// climbs out of `root`. Files that don't exist, and paths with a segment
+/// that decodes to something other than a plain file name, are answered
+/// with `404 Not Found`. Only regular files are served: FIFOs, sockets and
+/// devices, which could block a worker forever or never end, are answered
+/// with `403 Forbidden`.
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    pub root: PathBuf,
+    /// The shell of a single-page app, relative to `root`. When set, a `GET`
+    /// for a missing file whose last path segment has no extension, from a
+    /// client that accepts HTML, is answered with this file instead of a 404
+    /// so that client-side routes such as `/about` load the app. Missing
+    /// assets such as `/missing.js` still get a 404.
+    pub spa_fallback: Option<PathBuf>,
+    /// Charset named in the `Content-Type` of text files, `utf-8` by
+    /// default. `None` sends their bare media type. Binary files never carry
+    /// one.
+    pub default_charset: Option<String>,
+}
+
+impl StaticFiles {
+    pub fn new(root: impl Into<PathBuf>) -> StaticFiles {
+        StaticFiles {
+            root: root.into(),
+            spa_fallback: None,
+            default_charset: Some("utf-8".to_string()),
+        }
+    }
+
+    /// Maps the request path onto a file under `root`, or `None` if a
+    /// segment isn't a plain file name.
+    fn resolve(&self, request: &Request) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+        for segment in request.path_segments() {
+            // A decoded segment could still hold a separator or name a root.
+            let mut components = Path::new(&*segment).components();
+            let plain = matches!(
+                (components.next(), components.next()),
+                (Some(Component::Normal(_)), None)
+            );
+            if !plain || segment.contains(['/', '\\']) {
+                return None;
+            }
+            path.push(&*segment);
+        }
+
+        if request.path.ends_with('/') || path.is_dir() {
+            path.push("index.html");
+        }
+        Some(path)
+    }
+
+    /// Serves the file at `path`, or the parts of it named by the request's
+    /// `Range` header.
+    fn serve_file(&self, path: &Path, request: &Request) -> Response {
+        let content_type = content_type(path, self.default_charset.as_deref());
+        // Check before opening, since opening a FIFO blocks until a writer
+        // appears, and again after, in case the path was swapped meanwhile.
+        let response = fs::metadata(path).and_then(regular_file).and_then(|_| {
+            let file = File::open(path)?;
+            let len = regular_file(file.metadata()?)?.len();
+            let ranges = match request.header("Range") {
+                Some(range) => range::parse(range, len),
+                None => Ranges::Whole,
+            };
+
+            match ranges {
+                Ranges::Whole => Ok(Response::from_file(StatusCode::OK, file)?
+                    .header("Content-Type", &content_type)),
+                Ranges::Satisfiable(ranges) => {
+                    range::partial_response(file, len, &content_type, &ranges)
+                }
+                Ranges::Unsatisfiable => Ok(Response::new(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header("Content-Range", &format!("bytes */{}", len))),
+            }
+        });
+
+        match response {
+            Ok(response) => response.header("Accept-Ranges", "bytes"),
+            Err(e) => {
+                eprintln!("Error opening {}: {}", path.display(), e);
+                Response::new(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+
+    fn wants_shell(&self, request: &Request) -> bool {
+        let last_segment = request.path.rsplit('/').next().unwrap_or_default();
+        request.method == Method::Get && !last_segment.contains('.') && request.accepts("text/html")
+    }
+}
+
+impl Handler for StaticFiles {
+    fn handle(&self, request: &Request) -> Response {
+        if request.method != Method::Get {
+            return Response::new(StatusCode::METHOD_NOT_ALLOWED);
+        }
+
+        if let Some(path) = self.resolve(request) {
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.is_file() => return self.serve_file(&path, request),
+                Ok(metadata) if !metadata.is_dir() => {
+                    return Response::new(StatusCode::FORBIDDEN);
+                }
+                _ => {}
+            }
+        }
+
+        match &self.spa_fallback {
+            Some(shell) if self.wants_shell(request) => {
+                self.serve_file(&self.root.join(shell), request)
+            }
+            _ => Response::new(StatusCode::NOT_FOUND),
+        }
+    }
+}
+
+/// Passes on the metadata of a regular file, and turns anything else into
+/// an error.
+fn regular_file(metadata: Metadata) -> io::Result<Metadata> {
+    if metadata.is_file() {
+        Ok(metadata)
+    } else {
+        Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "not a regular file",
+        ))
+    }
+}
+
+/// Maps a file's extension onto its media type, adding `charset` to
+/// `text/*` types.
+pub(crate) fn content_type(path: &Path, charset: Option<&str>) -> String {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let media_type = match extension.map(str::to_ascii_lowercase).as_deref() {
+        Some("html" | "htm") => "text/html",
+        Some("css") => "text/css",
+        Some("js" | "mjs") => "text/javascript",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("ico") => "image/x-icon",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    };
+
+    match charset {
+        Some(charset) if media_type.starts_with("text/") => {
+            format!("{}; charset={}", media_type, charset)
+        }
+        _ => media_type.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    /// Creates a fresh directory holding an SPA shell and one script.
+    fn site(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("hello-static-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("index.html"), "<html>shell</html>").unwrap();
+        fs::write(root.join("app.js"), "boot();").unwrap();
+        root
+    }
+
+    fn get(path: &str, accept: &str) -> Request {
+        let mut request = Request::new(Method::Get, path);
+        request.insert_header("Accept", accept);
+        request
+    }
+
+    fn body(response: Response) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        out.split_once("\r\n\r\n").unwrap().1.to_string()
+    }
+
+    #[test]
+    fn test_serves_existing_file() {
+        let files = StaticFiles::new(site("existing"));
+
+        let response = files.handle(&get("/app.js", "*/*"));
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.header_value("Content-Type"),
+            Some("text/javascript; charset=utf-8")
+        );
+        assert_eq!(body(response), "boot();");
+    }
+
+    #[test]
+    fn test_content_type_charset() {
+        assert_eq!(
+            content_type(Path::new("index.html"), Some("utf-8")),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            content_type(Path::new("logo.png"), Some("utf-8")),
+            "image/png"
+        );
+        assert_eq!(content_type(Path::new("index.HTML"), None), "text/html");
+    }
+
+    #[test]
+    fn test_spa_fallback_serves_shell() {
+        let files = StaticFiles {
+            spa_fallback: Some(PathBuf::from("index.html")),
+            ..StaticFiles::new(site("shell"))
+        };
+
+        let response = files.handle(&get("/about", "text/html,application/xhtml+xml"));
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response), "<html>shell</html>");
+    }
+
+    #[test]
+    fn test_spa_fallback_missing_asset_is_not_found() {
+        let files = StaticFiles {
+            spa_fallback: Some(PathBuf::from("index.html")),
+            ..StaticFiles::new(site("asset"))
+        };
+
+        let response = files.handle(&get("/missing.js", "text/html"));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = files.handle(&get("/about", "application/json"));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_whole_file_advertises_ranges() {
+        let files = StaticFiles::new(site("accept-ranges"));
+
+        let response = files.handle(&get("/app.js", "*/*"));
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.header_value("Accept-Ranges"), Some("bytes"));
+    }
+
+    #[test]
+    fn test_single_range() {
+        let files = StaticFiles::new(site("single-range"));
+        let mut request = get("/app.js", "*/*");
+        request.insert_header("Range", "bytes=2-4");
+
+        let response = files.handle(&request);
+
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.header_value("Content-Range"), Some("bytes 2-4/7"));
+        assert_eq!(body(response), "ot(");
+    }
+
+    #[test]
+    fn test_two_ranges_are_multipart() {
+        let root = site("multi-range");
+        let digits: String = (0..400)
+            .map(|i| char::from(b'0' + (i % 10) as u8))
+            .collect();
+        fs::write(root.join("digits.txt"), &digits).unwrap();
+        let files = StaticFiles::new(root);
+        let mut request = get("/digits.txt", "*/*");
+        request.insert_header("Range", "bytes=200-209,0-9");
+
+        let response = files.handle(&request);
+
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.header_value("Accept-Ranges"), Some("bytes"));
+        let content_type = response.header_value("Content-Type").unwrap().to_string();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let (head, body) = out.split_once("\r\n\r\n").unwrap();
+
+        let expected = format!(
+            "\r\n--{b}\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Range: bytes 0-9/400\r\n\r\n\
+             0123456789\
+             \r\n--{b}\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Range: bytes 200-209/400\r\n\r\n\
+             0123456789\
+             \r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(body, expected);
+        assert!(head.ends_with(&format!("Content-Length: {}", expected.len())));
+    }
+
+    #[test]
+    fn test_unsatisfiable_range() {
+        let files = StaticFiles::new(site("unsatisfiable"));
+        let mut request = get("/app.js", "*/*");
+        request.insert_header("Range", "bytes=100-");
+
+        let response = files.handle(&request);
+
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.header_value("Content-Range"), Some("bytes */7"));
+    }
+
+    #[test]
+    fn test_rejects_parent_directory() {
+        let public = site("escape").join("public");
+        fs::create_dir_all(&public).unwrap();
+        let files = StaticFiles::new(public);
+
+        for path in ["/../index.html", "/%2e%2e/index.html", "/..%2findex.html"] {
+            let response = files.handle(&get(path, "*/*"));
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fifo_is_not_served() {
+        use std::{process::Command, sync::mpsc, thread, time::Duration};
+
+        let root = site("fifo");
+        let status = Command::new("mkfifo")
+            .arg(root.join("pipe.txt"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let files = StaticFiles {
+            spa_fallback: Some(PathBuf::from("pipe.txt")),
+            ..StaticFiles::new(root)
+        };
+
+        // Opening the FIFO would block with no writer, so serve from another
+        // thread and give up on it if it hangs.
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let direct = files.handle(&get("/pipe.txt", "*/*")).status();
+            let fallback = files.handle(&get("/about", "text/html")).status();
+            sender.send((direct, fallback)).unwrap();
+        });
+
+        let (direct, fallback) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(direct, StatusCode::FORBIDDEN);
+        assert_eq!(fallback, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_resolves_dot_segments() {
+        let files = StaticFiles::new(site("dots"));
+
+        let response = files.handle(&get("/scripts/./../app%2Ejs", "*/*"));
+
+        assert_eq!(body(response), "boot();");
+    }
+}
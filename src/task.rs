@@ -0,0 +1,89 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Wake, Waker},
+};
+
+use crate::{job::Job, queue, Message};
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A future spawned on the pool with [`ThreadPool::spawn_future`].
+///
+/// The task is polled as an ordinary job. When the future returns
+/// `Pending` it is parked in the task until its waker is called, which
+/// queues another job to poll it again; nothing occupies a worker while it
+/// waits.
+///
+/// [`ThreadPool::spawn_future`]: crate::ThreadPool::spawn_future
+pub(crate) struct Task {
+    /// The future, or `None` once it has completed.
+    future: Mutex<Option<BoxFuture>>,
+    /// Whether a job to poll the task is already queued, so that waking a
+    /// task several times before it is polled only queues it once.
+    scheduled: AtomicBool,
+    sender: queue::Sender<Message>,
+}
+
+impl Task {
+    pub(crate) fn spawn<F>(future: F, sender: queue::Sender<Message>)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(future))),
+            scheduled: AtomicBool::new(false),
+            sender,
+        });
+        task.schedule();
+    }
+
+    fn schedule(self: &Arc<Task>) {
+        if self.scheduled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let task = Arc::clone(self);
+        if self
+            .sender
+            .send(Message::NewJob(Job::new(move || task.poll()), None))
+            .is_err()
+        {
+            eprintln!("Error scheduling future: pool is shut down");
+        }
+    }
+
+    fn poll(self: Arc<Task>) {
+        // Cleared before polling so that a wake arriving while the future
+        // runs queues it again rather than being lost.
+        self.scheduled.store(false, Ordering::Release);
+
+        let mut slot = self.future.lock().unwrap();
+        let Some(future) = slot.as_mut() else {
+            return;
+        };
+
+        let waker = Waker::from(Arc::clone(&self));
+        if future
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_ready()
+        {
+            *slot = None;
+        }
+    }
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Task>) {
+        self.schedule();
+    }
+
+    fn wake_by_ref(self: &Arc<Task>) {
+        self.schedule();
+    }
+}
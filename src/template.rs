@@ -0,0 +1,213 @@
+//! Minimal templates for dynamic HTML pages: files in which each `{{key}}`
+//! is replaced by a value from a context, without a template engine.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, ErrorKind},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use crate::{response::escape_html, HttpError};
+
+/// Templates loaded from files under a directory.
+///
+/// In a template, `{{key}}` is replaced by the value of `key` in the context
+/// with `&`, `<`, `>` and quotes escaped, so that values can't inject markup,
+/// and `{{{key}}}` by the value as it is, for markup the handler built
+/// itself. Spaces around the key are ignored. A `{{` without a closing `}}`
+/// is left as it is.
+///
+/// Each template is read once and kept, then read again whenever its
+/// modification time changes, so edits show up without a restart.
+pub struct Templates {
+    dir: PathBuf,
+    cache: Mutex<HashMap<PathBuf, Cached>>,
+}
+
+struct Cached {
+    modified: SystemTime,
+    source: String,
+}
+
+impl Templates {
+    pub fn new(dir: impl Into<PathBuf>) -> Templates {
+        Templates {
+            dir: dir.into(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Renders the template at `name`, a path relative to the directory,
+    /// with the values in `context`.
+    ///
+    /// A template that doesn't exist, or a name that would leave the
+    /// directory, is [`HttpError::NotFound`]. A key missing from `context`
+    /// is an [`HttpError::Io`] error of kind `InvalidData`, answered with
+    /// `500 Internal Server Error`, rather than a page with a hole in it.
+    pub fn render(
+        &self,
+        name: &str,
+        context: &HashMap<String, String>,
+    ) -> Result<String, HttpError> {
+        let path = self.resolve(name).ok_or(HttpError::NotFound)?;
+        let source = self.load(&path)?;
+        substitute(&source, context).map_err(|key| {
+            HttpError::Io(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("template {} uses {}, missing from its context", name, key),
+            ))
+        })
+    }
+
+    /// The path of the template `name`, or `None` if it isn't made only of
+    /// plain file names.
+    fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let relative = Path::new(name);
+        let plain = relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        (plain && !name.is_empty()).then(|| self.dir.join(relative))
+    }
+
+    /// The source of the template at `path`, from the cache unless the file
+    /// changed since it was read.
+    fn load(&self, path: &Path) -> Result<String, HttpError> {
+        let modified = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(template_error)?;
+        if let Some(cached) = self.cache.lock().unwrap().get(path) {
+            if cached.modified == modified {
+                return Ok(cached.source.clone());
+            }
+        }
+
+        let source = fs::read_to_string(path).map_err(template_error)?;
+        self.cache.lock().unwrap().insert(
+            path.to_path_buf(),
+            Cached {
+                modified,
+                source: source.clone(),
+            },
+        );
+        Ok(source)
+    }
+}
+
+fn template_error(e: io::Error) -> HttpError {
+    match e.kind() {
+        ErrorKind::NotFound => HttpError::NotFound,
+        _ => HttpError::Io(e),
+    }
+}
+
+/// Replaces every placeholder in `source`, or fails with the first key
+/// missing from `context`.
+fn substitute(source: &str, context: &HashMap<String, String>) -> Result<String, String> {
+    let mut out = String::with_capacity(source.len());
+    let mut rest = source;
+
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start..];
+        let (raw, open, close) = if after.starts_with("{{{") {
+            (true, "{{{", "}}}")
+        } else {
+            (false, "{{", "}}")
+        };
+        let Some(end) = after[open.len()..].find(close) else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+
+        let key = after[open.len()..open.len() + end].trim();
+        let value = context.get(key).ok_or_else(|| key.to_string())?;
+        if raw {
+            out.push_str(value);
+        } else {
+            out.push_str(&escape_html(value));
+        }
+        rest = &after[open.len() + end + close.len()..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::File, time::Duration};
+
+    fn context(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|&(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_substitute_escapes_values() {
+        let context = context(&[("name", "<b>Ann & \"Bo\"</b>"), ("list", "<li>1</li>")]);
+
+        assert_eq!(
+            substitute("<p>Hi {{ name }}!</p><ul>{{{list}}}</ul>", &context).unwrap(),
+            "<p>Hi &lt;b&gt;Ann &amp; &quot;Bo&quot;&lt;/b&gt;!</p><ul><li>1</li></ul>"
+        );
+        assert_eq!(
+            substitute("{{name}} {{ unclosed", &context).unwrap(),
+            "&lt;b&gt;Ann &amp; &quot;Bo&quot;&lt;/b&gt; {{ unclosed"
+        );
+    }
+
+    #[test]
+    fn test_missing_key_is_an_error() {
+        assert_eq!(
+            substitute("{{title}}: {{missing}}", &context(&[("title", "t")])),
+            Err("missing".to_string())
+        );
+    }
+
+    #[test]
+    fn test_render_reloads_changed_template() {
+        let dir = std::env::temp_dir().join(format!("hello-templates-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("page.html");
+        fs::write(&path, "<h1>{{title}}</h1>").unwrap();
+
+        let templates = Templates::new(&dir);
+        let context = context(&[("title", "News")]);
+        assert_eq!(
+            templates.render("page.html", &context).unwrap(),
+            "<h1>News</h1>"
+        );
+
+        // Moved on explicitly, since some filesystems keep modification
+        // times to the second.
+        fs::write(&path, "<h2>{{title}}</h2>").unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(
+            templates.render("page.html", &context).unwrap(),
+            "<h2>News</h2>"
+        );
+
+        assert!(matches!(
+            templates.render("other.html", &context),
+            Err(HttpError::NotFound)
+        ));
+        assert!(matches!(
+            templates.render("../page.html", &context),
+            Err(HttpError::NotFound)
+        ));
+        let err = templates.render("page.html", &HashMap::new()).unwrap_err();
+        assert_eq!(err.status(), crate::StatusCode::INTERNAL_SERVER_ERROR);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
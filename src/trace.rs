@@ -0,0 +1,290 @@
+//! Spans emitted through the `tracing` crate when the `tracing` feature is
+//! enabled.
+//!
+//! Each connection gets a `connection` span carrying its id and remote
+//! address, and each request on it a `request` span recording the method,
+//! path, response status and duration, with `parse` and `write` spans
+//! inside. Without the feature every span here is an empty no-op.
+//!
+//! Spans are closed when the last handle to them is dropped, so returning
+//! early, on an error or otherwise, closes every span that was open.
+
+#[cfg(feature = "tracing")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{net::IpAddr, time::Duration};
+
+use crate::{Method, StatusCode};
+
+#[cfg(feature = "tracing")]
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) struct Span {
+    #[cfg(feature = "tracing")]
+    inner: tracing::Span,
+}
+
+impl Span {
+    pub(crate) fn connection(remote: Option<IpAddr>) -> Span {
+        #[cfg(feature = "tracing")]
+        {
+            let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+            let inner = tracing::info_span!("connection", id, remote = tracing::field::Empty);
+            if let Some(remote) = remote {
+                inner.record("remote", tracing::field::display(remote));
+            }
+            Span { inner }
+        }
+        #[cfg(not(feature = "tracing"))]
+        {
+            let _ = remote;
+            Span {}
+        }
+    }
+
+    /// A request on the connection whose span is currently entered.
+    pub(crate) fn request() -> Span {
+        Span {
+            #[cfg(feature = "tracing")]
+            inner: tracing::info_span!(
+                "request",
+                method = tracing::field::Empty,
+                path = tracing::field::Empty,
+                status = tracing::field::Empty,
+                duration_us = tracing::field::Empty,
+            ),
+        }
+    }
+
+    pub(crate) fn parse() -> Span {
+        Span {
+            #[cfg(feature = "tracing")]
+            inner: tracing::debug_span!("parse"),
+        }
+    }
+
+    pub(crate) fn write() -> Span {
+        Span {
+            #[cfg(feature = "tracing")]
+            inner: tracing::debug_span!("write"),
+        }
+    }
+
+    /// Enters the span until the returned guard is dropped.
+    pub(crate) fn enter(&self) -> Entered<'_> {
+        Entered {
+            #[cfg(feature = "tracing")]
+            _inner: self.inner.enter(),
+            #[cfg(not(feature = "tracing"))]
+            _span: std::marker::PhantomData,
+        }
+    }
+
+    pub(crate) fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _entered = self.enter();
+        f()
+    }
+
+    pub(crate) fn record_request(&self, method: &Method, path: &str) {
+        #[cfg(feature = "tracing")]
+        {
+            self.inner.record("method", method.as_str());
+            self.inner.record("path", path);
+        }
+        #[cfg(not(feature = "tracing"))]
+        let _ = (method, path);
+    }
+
+    pub(crate) fn record_status(&self, status: StatusCode) {
+        #[cfg(feature = "tracing")]
+        self.inner.record("status", status.as_u16());
+        #[cfg(not(feature = "tracing"))]
+        let _ = status;
+    }
+
+    pub(crate) fn record_duration(&self, duration: Duration) {
+        #[cfg(feature = "tracing")]
+        self.inner
+            .record("duration_us", duration.as_micros() as u64);
+        #[cfg(not(feature = "tracing"))]
+        let _ = duration;
+    }
+}
+
+pub(crate) struct Entered<'a> {
+    #[cfg(feature = "tracing")]
+    _inner: tracing::span::Entered<'a>,
+    #[cfg(not(feature = "tracing"))]
+    _span: std::marker::PhantomData<&'a Span>,
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use crate::{handle_connection, Config, Metrics, Response, Router, StatusCode};
+    use std::{
+        collections::HashMap,
+        fmt,
+        io::{self, Cursor, Read, Write},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+    };
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
+    };
+
+    #[derive(Debug, Default)]
+    struct RecordedSpan {
+        name: &'static str,
+        parent: Option<u64>,
+        fields: HashMap<&'static str, String>,
+        closed: bool,
+    }
+
+    /// A subscriber that keeps every span it sees, with its fields.
+    #[derive(Default)]
+    struct Recorder {
+        next_id: AtomicU64,
+        spans: Mutex<HashMap<u64, RecordedSpan>>,
+        stack: Mutex<Vec<u64>>,
+    }
+
+    /// Lets the test keep hold of the recorder it installs.
+    struct Shared(Arc<Recorder>);
+
+    impl std::ops::Deref for Shared {
+        type Target = Recorder;
+
+        fn deref(&self) -> &Recorder {
+            &self.0
+        }
+    }
+
+    struct Fields<'a>(&'a mut HashMap<&'static str, String>);
+
+    impl Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.insert(field.name(), format!("{:?}", value));
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name(), value.to_string());
+        }
+    }
+
+    impl Subscriber for Shared {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+            let mut span = RecordedSpan {
+                name: attrs.metadata().name(),
+                parent: attrs
+                    .parent()
+                    .map(span::Id::into_u64)
+                    .or_else(|| self.stack.lock().unwrap().last().copied()),
+                ..RecordedSpan::default()
+            };
+            attrs.record(&mut Fields(&mut span.fields));
+            self.spans.lock().unwrap().insert(id, span);
+            span::Id::from_u64(id)
+        }
+
+        fn record(&self, id: &span::Id, values: &span::Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            let span = spans.get_mut(&id.into_u64()).unwrap();
+            values.record(&mut Fields(&mut span.fields));
+        }
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, _: &Event<'_>) {}
+
+        fn enter(&self, id: &span::Id) {
+            self.stack.lock().unwrap().push(id.into_u64());
+        }
+
+        fn exit(&self, _: &span::Id) {
+            self.stack.lock().unwrap().pop();
+        }
+
+        fn try_close(&self, id: span::Id) -> bool {
+            self.spans
+                .lock()
+                .unwrap()
+                .get_mut(&id.into_u64())
+                .unwrap()
+                .closed = true;
+            true
+        }
+    }
+
+    struct MemoryStream {
+        input: Cursor<Vec<u8>>,
+    }
+
+    impl crate::Stream for MemoryStream {}
+
+    impl Read for MemoryStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MemoryStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_connection_and_request_spans() {
+        let mut router = Router::new();
+        router.get("/", |_| Response::new(StatusCode::OK));
+        let stream = MemoryStream {
+            input: Cursor::new(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\nGET /nope HTTP/1.1\r\n\r\n".to_vec(),
+            ),
+        };
+
+        let recorder = Arc::new(Recorder::default());
+        tracing::subscriber::with_default(Shared(Arc::clone(&recorder)), || {
+            handle_connection(stream, &router, &Config::default(), &Metrics::new()).unwrap();
+        });
+
+        let spans = recorder.spans.lock().unwrap();
+        let named = |name: &str| -> Vec<(&u64, &RecordedSpan)> {
+            let mut found: Vec<_> = spans.iter().filter(|(_, s)| s.name == name).collect();
+            found.sort_by_key(|(id, _)| **id);
+            found
+        };
+
+        let connections = named("connection");
+        assert_eq!(connections.len(), 1);
+        let (connection_id, _) = connections[0];
+
+        let requests = named("request");
+        assert_eq!(requests.len(), 2);
+        let (ok_id, ok) = requests[0];
+        assert_eq!(ok.parent, Some(*connection_id));
+        assert_eq!(ok.fields["method"], "GET");
+        assert_eq!(ok.fields["path"], "/");
+        assert_eq!(ok.fields["status"], "200");
+        assert!(ok.fields.contains_key("duration_us"));
+
+        // The second request is missing its Host header and is rejected.
+        let (_, rejected) = requests[1];
+        assert_eq!(rejected.fields["status"], "400");
+
+        assert!(named("parse").iter().any(|(_, s)| s.parent == Some(*ok_id)));
+        assert!(named("write").iter().any(|(_, s)| s.parent == Some(*ok_id)));
+        assert!(spans.values().all(|span| span.closed));
+    }
+}
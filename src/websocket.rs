@@ -0,0 +1,528 @@
+//! The WebSocket opening handshake (RFC 6455, section 4) and the server
+//! side of its data framing (section 5).
+
+use std::{
+    io::{self, prelude::*, Cursor, ErrorKind},
+    net::TcpStream,
+};
+
+use crate::{Request, Response, StatusCode};
+
+/// Appended to the client's key before hashing, as fixed by the RFC.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message [`WebSocketStream::read_message`] accepts, summed over
+/// its fragments, so that a client can't make the server buffer without
+/// bound.
+const MAX_MESSAGE: u64 = 16 * 1024 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// A connection switched to the WebSocket protocol, handed to the callback
+/// registered with [`Server::on_websocket`](crate::Server::on_websocket)
+/// once the `101 Switching Protocols` response has been sent.
+///
+/// Reads first return any bytes the client sent after its upgrade request
+/// that were already buffered, then continue from the socket. The socket has
+/// no read or write timeout. Wrap it in a [`WebSocketStream`] to exchange
+/// messages.
+pub struct Upgraded {
+    buffered: Cursor<Vec<u8>>,
+    stream: TcpStream,
+}
+
+impl Upgraded {
+    pub(crate) fn new(stream: TcpStream, buffered: Vec<u8>) -> Upgraded {
+        Upgraded {
+            buffered: Cursor::new(buffered),
+            stream,
+        }
+    }
+
+    /// The underlying socket, for setting timeouts or shutting it down.
+    /// Reading from it directly skips any buffered bytes.
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if (self.buffered.position() as usize) < self.buffered.get_ref().len() {
+            return self.buffered.read(buf);
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// A WebSocket message, or a control frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// A close frame, with its status code and reason if it carried one.
+    Close(Option<(u16, String)>),
+}
+
+/// The server end of a WebSocket connection, reading and writing whole
+/// messages over an upgraded stream.
+///
+/// Client frames must be masked and are unmasked on arrival; frames sent to
+/// the client are not masked, as the RFC requires of a server. Fragmented
+/// messages are reassembled, with control frames that arrive between the
+/// fragments handled as they come. Pings are answered with a pong, and a
+/// close frame from the client is echoed back, before being returned to
+/// the caller.
+pub struct WebSocketStream<S = Upgraded> {
+    stream: S,
+    /// Whether a close frame has been sent, after which nothing else may be.
+    close_sent: bool,
+    /// The opcode and payload so far of a fragmented message, kept across
+    /// calls when a control frame interrupts it.
+    partial: Option<(u8, Vec<u8>)>,
+}
+
+impl<S: Read + Write> WebSocketStream<S> {
+    pub fn new(stream: S) -> WebSocketStream<S> {
+        WebSocketStream {
+            stream,
+            close_sent: false,
+            partial: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Reads the next message. Fails with `InvalidData` on a frame that
+    /// breaks the protocol, such as an unmasked one, a control frame longer
+    /// than 125 bytes or text that isn't UTF-8, and with `UnexpectedEof` if
+    /// the connection ends mid-frame.
+    pub fn read_message(&mut self) -> io::Result<WebSocketMessage> {
+        loop {
+            let frame = self.read_frame()?;
+            match frame.opcode {
+                OPCODE_PING => {
+                    self.send(WebSocketMessage::Pong(frame.payload.clone()))?;
+                    return Ok(WebSocketMessage::Ping(frame.payload));
+                }
+                OPCODE_PONG => return Ok(WebSocketMessage::Pong(frame.payload)),
+                OPCODE_CLOSE => {
+                    let close = close_payload(&frame.payload)?;
+                    if !self.close_sent {
+                        self.send(WebSocketMessage::Close(close.clone()))?;
+                    }
+                    return Ok(WebSocketMessage::Close(close));
+                }
+                OPCODE_TEXT | OPCODE_BINARY if self.partial.is_none() => {
+                    if frame.fin {
+                        return message(frame.opcode, frame.payload);
+                    }
+                    self.partial = Some((frame.opcode, frame.payload));
+                }
+                OPCODE_CONTINUATION => {
+                    let Some((opcode, mut payload)) = self.partial.take() else {
+                        return Err(invalid("continuation frame without a message"));
+                    };
+                    if (payload.len() + frame.payload.len()) as u64 > MAX_MESSAGE {
+                        return Err(invalid("message too large"));
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        return message(opcode, payload);
+                    }
+                    self.partial = Some((opcode, payload));
+                }
+                OPCODE_TEXT | OPCODE_BINARY => {
+                    return Err(invalid("new message before the last one finished"))
+                }
+                _ => return Err(invalid("unknown opcode")),
+            }
+        }
+    }
+
+    /// Sends `message` as a single unmasked frame. A close message may only
+    /// be sent once; nothing can be sent after it.
+    pub fn send(&mut self, message: WebSocketMessage) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(
+                ErrorKind::BrokenPipe,
+                "WebSocket close frame already sent",
+            ));
+        }
+
+        let (opcode, payload) = match message {
+            WebSocketMessage::Text(text) => (OPCODE_TEXT, text.into_bytes()),
+            WebSocketMessage::Binary(data) => (OPCODE_BINARY, data),
+            WebSocketMessage::Ping(data) => (OPCODE_PING, data),
+            WebSocketMessage::Pong(data) => (OPCODE_PONG, data),
+            WebSocketMessage::Close(close) => {
+                self.close_sent = true;
+                let mut payload = Vec::new();
+                if let Some((code, reason)) = close {
+                    payload.extend_from_slice(&code.to_be_bytes());
+                    payload.extend_from_slice(reason.as_bytes());
+                }
+                (OPCODE_CLOSE, payload)
+            }
+        };
+        if opcode >= OPCODE_CLOSE && payload.len() > 125 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "control frame payload longer than 125 bytes",
+            ));
+        }
+
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&payload);
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+
+    fn read_frame(&mut self) -> io::Result<Frame> {
+        let mut head = [0; 2];
+        self.stream.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        if head[0] & 0x70 != 0 {
+            return Err(invalid("reserved bits set"));
+        }
+        if head[1] & 0x80 == 0 {
+            return Err(invalid("client frame not masked"));
+        }
+
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut len = [0; 2];
+                self.stream.read_exact(&mut len)?;
+                u64::from(u16::from_be_bytes(len))
+            }
+            127 => {
+                let mut len = [0; 8];
+                self.stream.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => u64::from(len),
+        };
+        if opcode >= OPCODE_CLOSE && (len > 125 || !fin) {
+            return Err(invalid("malformed control frame"));
+        }
+        if len > MAX_MESSAGE {
+            return Err(invalid("message too large"));
+        }
+
+        let mut key = [0; 4];
+        self.stream.read_exact(&mut key)?;
+        let mut payload = Vec::new();
+        (&mut self.stream).take(len).read_to_end(&mut payload)?;
+        if (payload.len() as u64) < len {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= key[i % 4];
+        }
+
+        Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        })
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+fn message(opcode: u8, payload: Vec<u8>) -> io::Result<WebSocketMessage> {
+    if opcode == OPCODE_TEXT {
+        String::from_utf8(payload)
+            .map(WebSocketMessage::Text)
+            .map_err(|_| invalid("text message is not UTF-8"))
+    } else {
+        Ok(WebSocketMessage::Binary(payload))
+    }
+}
+
+fn close_payload(payload: &[u8]) -> io::Result<Option<(u16, String)>> {
+    match payload {
+        [] => Ok(None),
+        [high, low, reason @ ..] => {
+            let reason =
+                std::str::from_utf8(reason).map_err(|_| invalid("close reason is not UTF-8"))?;
+            Ok(Some((
+                u16::from_be_bytes([*high, *low]),
+                reason.to_string(),
+            )))
+        }
+        [_] => Err(invalid("truncated close frame")),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+/// The `101 Switching Protocols` response accepting `request`, which must be
+/// a WebSocket upgrade request.
+pub(crate) fn handshake(request: &Request) -> Response {
+    let key = request.header("Sec-WebSocket-Key").unwrap_or("");
+    Response::new(StatusCode::SWITCHING_PROTOCOLS)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", &accept_key(key))
+}
+
+/// The `Sec-WebSocket-Accept` value answering a `Sec-WebSocket-Key`.
+pub(crate) fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key.trim(), ACCEPT_GUID).as_bytes()))
+}
+
+fn sha1(message: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut padded = message.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&(message.len() as u64 * 8).to_be_bytes());
+
+    for block in padded.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, word) in digest.chunks_mut(4).zip(h) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (u32::from(chunk[0]) << 16)
+            | (u32::from(*chunk.get(1).unwrap_or(&0)) << 8)
+            | u32::from(*chunk.get(2).unwrap_or(&0));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A client's end of the connection: frames it sent, and what the server
+    /// wrote back.
+    struct Client {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Client {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Client {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn socket(frames: &[&[u8]]) -> WebSocketStream<Client> {
+        WebSocketStream::new(Client {
+            input: Cursor::new(frames.concat()),
+            output: Vec::new(),
+        })
+    }
+
+    /// Masks `payload` with the key from the RFC's examples.
+    fn masked(first: u8, payload: &[u8]) -> Vec<u8> {
+        let key = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![first, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&key);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn test_read_masked_text() {
+        // RFC 6455, section 5.7: a single-frame masked text message.
+        let mut socket = socket(&[&[
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ]]);
+
+        assert_eq!(
+            socket.read_message().unwrap(),
+            WebSocketMessage::Text("Hello".to_string())
+        );
+    }
+
+    #[test]
+    fn test_send_unmasked_frames() {
+        let mut socket = socket(&[]);
+
+        socket
+            .send(WebSocketMessage::Text("Hello".to_string()))
+            .unwrap();
+        socket.send(WebSocketMessage::Binary(vec![0; 256])).unwrap();
+
+        let output = socket.into_inner().output;
+        // RFC 6455, section 5.7: unmasked "Hello", then a 256-byte binary
+        // message with a 16-bit length.
+        assert_eq!(output[..7], [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]);
+        assert_eq!(output[7..11], [0x82, 0x7E, 0x01, 0x00]);
+        assert_eq!(output.len(), 11 + 256);
+    }
+
+    #[test]
+    fn test_fragments_reassembled_around_ping() {
+        let mut socket = socket(&[
+            &masked(0x01, b"Hel"),
+            &masked(0x89, b"Hello"),
+            &masked(0x80, b"lo"),
+        ]);
+
+        assert_eq!(
+            socket.read_message().unwrap(),
+            WebSocketMessage::Ping(b"Hello".to_vec())
+        );
+        assert_eq!(
+            socket.read_message().unwrap(),
+            WebSocketMessage::Text("Hello".to_string())
+        );
+        // The ping was answered with an unmasked pong.
+        assert_eq!(
+            socket.into_inner().output,
+            [0x8a, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]
+        );
+    }
+
+    #[test]
+    fn test_close_is_echoed() {
+        let mut payload = 1000u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"bye");
+        let mut socket = socket(&[&masked(0x88, &payload)]);
+
+        assert_eq!(
+            socket.read_message().unwrap(),
+            WebSocketMessage::Close(Some((1000, "bye".to_string())))
+        );
+        assert!(socket
+            .send(WebSocketMessage::Text("late".to_string()))
+            .is_err());
+
+        let mut echoed = vec![0x88, 0x05];
+        echoed.extend_from_slice(&payload);
+        assert_eq!(socket.into_inner().output, echoed);
+    }
+
+    #[test]
+    fn test_rejects_unmasked_client_frame() {
+        let mut socket = socket(&[&[0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]]);
+
+        let err = socket.read_message().unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_accept_key_matches_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_sha1_and_base64() {
+        assert_eq!(base64(&sha1(b"abc")), "qZk+NkcGgWq6PiVxeFDCbJzQ2J0=");
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+    }
+}